@@ -0,0 +1,567 @@
+// Job compatibility advice.
+//
+// Looks at a job as a whole (action, scheme, filesystem, device size and the
+// inspected image) and reports combinations that fail outright or produce a
+// stick that will not boot where the user expects it to. Every finding names
+// a correction, and the same list is served to the form before submission so
+// problems show up while the settings can still be changed.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::filesystem::Filesystem;
+use crate::inspect::{IsoReport, LargeFile, OsFamily, FAT32_MAX_FILE_SIZE};
+use crate::multiboot::{MultibootImage, Operation};
+use crate::partition::{self, PartitionScheme};
+use crate::persistence::{self, PersistencePlan};
+use crate::provision::{self, PayloadKind, ProvisionPlan};
+use crate::wininstall;
+
+/// Largest disk an MBR can address with 512-byte sectors.
+const MBR_MAX_BYTES: u64 = (u32::MAX as u64 + 1) * 512;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// How a create job puts the image on the device.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// The image is written byte for byte.
+    Raw,
+    /// Windows installer media: the image's files on a FAT32 partition.
+    Windows,
+    /// Linux media: the image's files on a FAT32 partition with a generated
+    /// boot menu.
+    Extract,
+}
+
+impl WriteMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "raw" => Ok(WriteMode::Raw),
+            "windows" => Ok(WriteMode::Windows),
+            "extract" => Ok(WriteMode::Extract),
+            _ => Err(format!("Unsupported write mode: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for WriteMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteMode::Raw => write!(f, "raw"),
+            WriteMode::Windows => write!(f, "Windows installer"),
+            WriteMode::Extract => write!(f, "extract"),
+        }
+    }
+}
+
+/// The mode a create job runs in: the requested one, otherwise whatever the
+/// image needs to boot from a stick.
+pub fn choose_mode(requested: Option<WriteMode>, image: Option<&IsoReport>) -> WriteMode {
+    if let Some(mode) = requested {
+        return mode;
+    }
+    match image {
+        Some(image) if !image.isohybrid && image.os_family == OsFamily::Windows => WriteMode::Windows,
+        Some(image) if !image.isohybrid && image.os_family == OsFamily::Linux => WriteMode::Extract,
+        _ => WriteMode::Raw,
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Advice {
+    pub severity: Severity,
+    /// Stable identifier the form can key on.
+    pub code: &'static str,
+    pub message: String,
+    pub suggestion: String,
+}
+
+impl Advice {
+    fn warning(code: &'static str, message: String, suggestion: impl Into<String>) -> Self {
+        Advice { severity: Severity::Warning, code, message, suggestion: suggestion.into() }
+    }
+
+    fn error(code: &'static str, message: String, suggestion: impl Into<String>) -> Self {
+        Advice { severity: Severity::Error, code, message, suggestion: suggestion.into() }
+    }
+}
+
+/// What is known about a job before anything is written.
+pub struct JobFacts<'a> {
+    pub action: &'a str,
+    pub mode: WriteMode,
+    pub filesystem: Filesystem,
+    pub scheme: PartitionScheme,
+    pub device_size: Option<u64>,
+    /// Image size once decompressed, when known.
+    pub image_size: Option<u64>,
+    /// Inspection of the image, when it is a readable ISO.
+    pub image: Option<&'a IsoReport>,
+    /// Files in the job's `files` tree that FAT32 cannot store.
+    pub large_files: &'a [LargeFile],
+    /// wimlib-imagex is installed to split an oversized install.wim.
+    pub wimlib: bool,
+    /// syslinux is installed to make file-copied media boot on legacy BIOS.
+    pub syslinux: bool,
+    /// GRUB can build an EFI loader for extracted media that lacks one.
+    pub grub_efi: bool,
+    /// What a multiboot job does to the stick.
+    pub operation: Option<Operation>,
+    /// Images a multiboot job puts on the stick.
+    pub images: &'a [MultibootImage],
+    /// The job asks for a persistence partition.
+    pub persistence: bool,
+    /// Boot config patches for it, when the image can take them.
+    pub persistence_plan: Option<&'a PersistencePlan>,
+    /// Unattended-install data the job places on the media.
+    pub provision: Option<PayloadKind>,
+    /// Where it goes, when the media has room for it.
+    pub provision_plan: Option<&'a ProvisionPlan>,
+}
+
+pub fn advise(facts: &JobFacts) -> Vec<Advice> {
+    let mut advice = Vec::new();
+
+    if let (Some(image_size), Some(device_size)) = (facts.image_size, facts.device_size) {
+        if image_size > device_size {
+            advice.push(Advice::error(
+                "device-too-small",
+                format!("The image needs {} but the device only holds {}", human_size(image_size), human_size(device_size)),
+                format!("Use a device of at least {}", human_size(image_size)),
+            ));
+        }
+    }
+
+    if let Some(device_size) = facts.device_size.filter(|size| *size > MBR_MAX_BYTES) {
+        if facts.scheme == PartitionScheme::Mbr {
+            advice.push(Advice::error(
+                "mbr-too-large",
+                format!("MBR cannot address more than 2 TiB, and the device holds {}", human_size(device_size)),
+                "Use the GPT scheme",
+            ));
+        }
+    }
+
+    if facts.filesystem == Filesystem::Fat32 {
+        for LargeFile { path, size } in facts.large_files {
+            let suggestion = if path.to_lowercase().ends_with(".wim") {
+                "Split the WIM into .swm parts (wimlib-imagex split), or choose exFAT or NTFS"
+            } else {
+                "Choose exFAT or NTFS"
+            };
+            advice.push(Advice::error(
+                "file-too-large",
+                format!("{} is {}, more than FAT32 can store in one file", path, human_size(*size)),
+                suggestion,
+            ));
+        }
+    }
+
+    if facts.action == "create" {
+        if let Some(image) = facts.image {
+            match facts.mode {
+                WriteMode::Raw => advise_image(facts, image, &mut advice),
+                WriteMode::Windows => advise_windows(facts, image, &mut advice),
+                WriteMode::Extract => advise_extract(facts, image, &mut advice),
+            }
+        }
+    }
+
+    if let Some(operation) = facts.operation {
+        advise_multiboot(facts, operation, &mut advice);
+    }
+
+    if facts.persistence {
+        advise_persistence(facts, &mut advice);
+    }
+
+    if let Some(kind) = facts.provision {
+        advise_provision(facts, kind, &mut advice);
+    }
+
+    advice
+}
+
+// A raw stick is the image written as-is, so what matters is whether the
+// image can boot from a USB disk and what it does with the chosen settings.
+fn advise_image(facts: &JobFacts, image: &IsoReport, advice: &mut Vec<Advice>) {
+    let name = image.label.as_deref().unwrap_or("The image");
+
+    if !image.isohybrid && (image.bios_boot || image.efi_boot) {
+        let suggestion = match image.os_family {
+            OsFamily::Windows => "Use Windows installer mode, which copies the files onto a FAT32 partition",
+            OsFamily::Linux => "Use extract mode, which copies the files onto a FAT32 partition with a boot loader",
+            _ => "Extract the image onto a FAT32 partition with a boot loader rather than writing it raw",
+        };
+        advice.push(Advice::error(
+            "not-hybrid",
+            format!("{} is not a hybrid image; written raw, the stick will not boot", name),
+            suggestion,
+        ));
+        return;
+    }
+
+    if let Some(table) = image.partition_table.as_deref() {
+        if !table.eq_ignore_ascii_case(&facts.scheme.to_string()) || facts.filesystem != Filesystem::Fat32 {
+            advice.push(Advice::warning(
+                "layout-replaced",
+                format!(
+                    "{} brings its own {} layout; the selected {} scheme and {} filesystem are replaced when it is written",
+                    name,
+                    table.to_uppercase(),
+                    facts.scheme,
+                    facts.filesystem
+                ),
+                "Use raw image mode: scheme and filesystem settings do not apply to hybrid images",
+            ));
+        }
+    }
+
+    if image.efi_boot && !image.bios_boot {
+        advice.push(Advice::warning(
+            "uefi-only",
+            format!("{} only boots through UEFI; legacy BIOS machines will not start it", name),
+            "Enable UEFI boot (and disable CSM) in the target machine's firmware",
+        ));
+    } else if image.bios_boot && !image.efi_boot {
+        advice.push(Advice::warning(
+            "bios-only",
+            format!("{} only boots in legacy BIOS mode", name),
+            "Enable CSM or legacy boot in the target machine's firmware",
+        ));
+    }
+}
+
+// Windows installer media is always FAT32, the one filesystem every UEFI
+// firmware reads, so the image's files have to fit it and BIOS boot depends
+// on syslinux being around to chain-load bootmgr.
+fn advise_windows(facts: &JobFacts, image: &IsoReport, advice: &mut Vec<Advice>) {
+    let name = image.label.as_deref().unwrap_or("The image");
+
+    if image.os_family != OsFamily::Windows {
+        advice.push(Advice::error(
+            "not-windows",
+            format!("{} is not Windows installation media", name),
+            "Use raw image mode",
+        ));
+        return;
+    }
+
+    advise_fat32_layout(facts, advice);
+
+    for LargeFile { path, size } in &image.large_files {
+        if path.eq_ignore_ascii_case(wininstall::INSTALL_WIM) {
+            if !facts.wimlib {
+                advice.push(Advice::error(
+                    "wim-split-unavailable",
+                    format!("{} is {} and has to be split for FAT32, but wimlib-imagex is not installed", path, human_size(*size)),
+                    "Install wimlib (wimtools) so install.wim can be split into .swm parts",
+                ));
+            }
+        } else {
+            advice.push(Advice::error(
+                "file-too-large",
+                format!("{} is {}, more than FAT32 can store in one file", path, human_size(*size)),
+                "Use raw image mode, or write the image with a tool that supports NTFS installer media",
+            ));
+        }
+    }
+
+    if !facts.syslinux {
+        advice.push(Advice::warning(
+            "uefi-only",
+            format!("syslinux is not installed, so {} will only boot through UEFI", name),
+            "Install syslinux to make the stick boot on legacy BIOS machines too",
+        ));
+    }
+}
+
+// Extracted Linux media is FAT32 too, so every file has to fit it, and each
+// firmware type needs its own loader: syslinux for BIOS, and for UEFI either
+// the image's own EFI/BOOT loader or one built with GRUB.
+fn advise_extract(facts: &JobFacts, image: &IsoReport, advice: &mut Vec<Advice>) {
+    let name = image.label.as_deref().unwrap_or("The image");
+
+    if image.os_family != OsFamily::Linux {
+        advice.push(Advice::error(
+            "not-linux",
+            format!("{} is not a Linux image; only Linux media can be extracted", name),
+            "Use raw image mode",
+        ));
+        return;
+    }
+
+    advise_fat32_layout(facts, advice);
+
+    for LargeFile { path, size } in &image.large_files {
+        advice.push(Advice::error(
+            "file-too-large",
+            format!("{} is {}, more than FAT32 can store in one file", path, human_size(*size)),
+            "This image cannot be extracted; write it in raw image mode",
+        ));
+    }
+
+    let uefi = !image.efi_loaders.is_empty() || facts.grub_efi;
+    match (facts.syslinux, uefi) {
+        (false, false) => advice.push(Advice::error(
+            "no-boot-loader",
+            format!("Neither syslinux nor GRUB is installed, and {} has no EFI loader, so the stick cannot boot", name),
+            "Install syslinux for legacy BIOS boot and GRUB for UEFI boot",
+        )),
+        (false, true) => advice.push(Advice::warning(
+            "uefi-only",
+            format!("syslinux is not installed, so {} will only boot through UEFI", name),
+            "Install syslinux to make the stick boot on legacy BIOS machines too",
+        )),
+        (true, false) => advice.push(Advice::warning(
+            "bios-only",
+            format!("{} has no EFI loader and GRUB is not installed to build one, so it will only boot in legacy BIOS mode", name),
+            "Install GRUB's EFI modules (grub-efi-amd64-bin or grub2-efi-x64-modules) for UEFI boot",
+        )),
+        (true, true) => {}
+    }
+}
+
+// A multiboot stick boots through GRUB on its ESP alone, and only images a
+// boot recipe knows how to find from a file show up in its menu.
+fn advise_multiboot(facts: &JobFacts, operation: Operation, advice: &mut Vec<Advice>) {
+    if operation == Operation::Prepare {
+        if !matches!(facts.filesystem, Filesystem::Fat32 | Filesystem::Exfat) {
+            advice.push(Advice::error(
+                "filesystem-unsupported",
+                format!("Multiboot images go onto a FAT32 or exFAT data partition, not {}", facts.filesystem),
+                "Choose exFAT, which also holds images over 4 GiB",
+            ));
+        }
+        if !facts.grub_efi {
+            advice.push(Advice::error(
+                "no-boot-loader",
+                "GRUB is not installed, so the multiboot stick cannot get a boot loader".to_string(),
+                "Install GRUB's EFI modules (grub-efi-amd64-bin or grub2-efi-x64-modules)",
+            ));
+        }
+        advice.push(Advice::warning(
+            "uefi-only",
+            "Multiboot sticks boot through GRUB's EFI loader only; legacy BIOS machines will not start them".to_string(),
+            "Enable UEFI boot (and disable CSM) in the target machine's firmware",
+        ));
+    }
+
+    for image in facts.images {
+        if image.recipe.is_none() {
+            let (message, suggestion) = match image.os_family {
+                OsFamily::Windows => (
+                    format!("{} is Windows installation media, which cannot boot from an ISO file", image.name),
+                    "Write it to a stick of its own in Windows installer mode",
+                ),
+                _ => (
+                    format!("{} is not an image the multiboot menu knows how to boot from a file", image.name),
+                    "Write it to a stick of its own in raw or extract mode",
+                ),
+            };
+            advice.push(Advice::error("no-multiboot-recipe", message, suggestion));
+        }
+        if operation == Operation::Prepare && facts.filesystem == Filesystem::Fat32 && image.size > FAT32_MAX_FILE_SIZE {
+            advice.push(Advice::error(
+                "file-too-large",
+                format!("{} is {}, more than FAT32 can store in one file", image.name, human_size(image.size)),
+                "Choose exFAT",
+            ));
+        }
+    }
+}
+
+// Persistence lives on a partition after a hybrid image written as-is, and
+// only casper and live-boot know to look for it there.
+fn advise_persistence(facts: &JobFacts, advice: &mut Vec<Advice>) {
+    if facts.action != "create" || facts.mode != WriteMode::Raw {
+        advice.push(Advice::error(
+            "persistence-unsupported",
+            "A persistence partition can only follow an image written in raw image mode".to_string(),
+            "Use raw image mode, or turn persistence off",
+        ));
+        return;
+    }
+    let Some(image) = facts.image else {
+        advice.push(Advice::error(
+            "no-persistence",
+            "The image could not be inspected, so there is no telling whether it can use a persistence partition".to_string(),
+            "Turn persistence off",
+        ));
+        return;
+    };
+    let name = image.label.as_deref().unwrap_or("The image");
+
+    if !image.isohybrid || persistence::flavour(image.distribution.as_deref()).is_none() {
+        advice.push(Advice::error(
+            "no-persistence",
+            format!("{} is not an Ubuntu or Debian live image, so it cannot keep changes on a persistence partition", name),
+            "Turn persistence off",
+        ));
+        return;
+    }
+
+    if let (Some(image_size), Some(device_size)) = (facts.image_size, facts.device_size) {
+        let free = device_size.saturating_sub(image_size.div_ceil(partition::ALIGNMENT) * partition::ALIGNMENT);
+        if image_size <= device_size && free < persistence::MIN_SIZE {
+            advice.push(Advice::error(
+                "no-room-for-persistence",
+                format!("Only {} is left after the image; a persistence partition needs at least {}", human_size(free), human_size(persistence::MIN_SIZE)),
+                "Use a larger device, or turn persistence off",
+            ));
+        }
+    }
+
+    if let Some(plan) = facts.persistence_plan {
+        let skipped: usize = plan.configs.iter().map(|config| config.skipped).sum();
+        let total: usize = plan.configs.iter().map(|config| config.patched + config.skipped).sum();
+        if skipped > 0 {
+            advice.push(Advice::warning(
+                "persistence-partial",
+                format!("{} of {} boot entries in {} have no room for the {} parameter and will start without persistence", skipped, total, name, plan.parameter),
+                "Pick the first live entry in the boot menu to keep changes",
+            ));
+        }
+    }
+}
+
+// Installer answers go where each installer looks for them, which depends
+// on both the distribution and how its image is written.
+fn advise_provision(facts: &JobFacts, kind: PayloadKind, advice: &mut Vec<Advice>) {
+    if facts.action != "create" {
+        advice.push(Advice::error(
+            "provision-unsupported",
+            "Unattended-install data only goes onto media created from an image".to_string(),
+            "Create bootable media, or remove the provisioning file",
+        ));
+        return;
+    }
+    if facts.persistence {
+        advice.push(Advice::error(
+            "provision-with-persistence",
+            "Persistence and unattended-install data cannot both be added to one stick".to_string(),
+            "Turn persistence off, or remove the provisioning file",
+        ));
+        return;
+    }
+    let Some(image) = facts.image else {
+        advice.push(Advice::error(
+            "provision-unsupported",
+            format!("The image could not be inspected, so there is no telling where its installer looks for {} data", kind),
+            "Remove the provisioning file",
+        ));
+        return;
+    };
+    let name = image.label.as_deref().unwrap_or("The image");
+
+    if !provision::fits(kind, facts.mode, image) {
+        let (message, suggestion) = match kind {
+            PayloadKind::Autoinstall => (
+                format!("{} is not an Ubuntu image written in raw image mode, so nothing reads its autoinstall data", name),
+                "Use raw image mode with an Ubuntu live server image",
+            ),
+            PayloadKind::NoCloud => (
+                format!("{} is not a hybrid Linux image written in raw image mode, so it gets no NoCloud seed partition", name),
+                "Use raw image mode with a hybrid Linux image",
+            ),
+            PayloadKind::Kickstart => (
+                format!("{} is not a Fedora or RHEL-family image, so nothing reads a Kickstart file", name),
+                "Use a Fedora, RHEL or derivative image",
+            ),
+            PayloadKind::Preseed => (
+                format!("The Debian installer only reads a preseed file named on its command line, which {} mode cannot change", facts.mode),
+                "Use extract mode with a Debian installer image",
+            ),
+            PayloadKind::Autounattend => (
+                format!("Windows Setup reads autounattend.xml from installer media, and {} mode does not make it", facts.mode),
+                "Use Windows installer mode",
+            ),
+        };
+        advice.push(Advice::error("provision-unsupported", message, suggestion));
+        return;
+    }
+
+    if facts.mode == WriteMode::Raw {
+        if let (Some(image_size), Some(device_size)) = (facts.image_size, facts.device_size) {
+            let free = device_size.saturating_sub(image_size.div_ceil(partition::ALIGNMENT) * partition::ALIGNMENT);
+            if image_size <= device_size && free < provision::SEED_SIZE {
+                advice.push(Advice::error(
+                    "no-room-for-seed",
+                    format!("Only {} is left after the image; the {} seed partition needs {}", human_size(free), kind, human_size(provision::SEED_SIZE)),
+                    "Use a larger device",
+                ));
+            }
+        }
+    }
+
+    if let Some(plan) = facts.provision_plan {
+        let skipped: usize = plan.configs.iter().map(|config| config.skipped).sum();
+        let total: usize = plan.configs.iter().map(|config| config.patched + config.skipped).sum();
+        if skipped > 0 {
+            advice.push(Advice::warning(
+                "provision-partial",
+                format!("{} of {} boot entries in {} have no room for the autoinstall parameter and will ask before installing", skipped, total, name),
+                "Pick the first install entry in the boot menu to install unattended",
+            ));
+        }
+    }
+}
+
+// File-copy modes always format FAT32, the one filesystem every UEFI
+// firmware reads.
+fn advise_fat32_layout(facts: &JobFacts, advice: &mut Vec<Advice>) {
+    if facts.filesystem != Filesystem::Fat32 {
+        advice.push(Advice::warning(
+            "filesystem-replaced",
+            format!("In {} mode the files go onto FAT32 so UEFI firmware can boot the stick; {} is not used", facts.mode, facts.filesystem),
+            "Choose FAT32",
+        ));
+    }
+}
+
+/// Files below `root` larger than FAT32 can store, with paths relative to
+/// `root`.
+pub fn oversized_files(root: &Path) -> io::Result<Vec<LargeFile>> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else if metadata.len() > FAT32_MAX_FILE_SIZE {
+                let path = entry.path();
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                found.push(LargeFile { path: relative.display().to_string(), size: metadata.len() });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(found)
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,132 @@
+// Boot loader installation for file-copied media.
+//
+// A stick laid out file by file has no boot code of its own. For legacy BIOS
+// syslinux supplies both halves: an MBR that jumps to the active (or
+// legacy-bootable GPT) partition, and a FAT boot sector with ldlinux that
+// reads syslinux.cfg. Its installer works on the unmounted partition through
+// mtools, so nothing here needs a mount either. UEFI only needs a loader
+// file, which grub-mkstandalone can build around a given grub.cfg.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::capabilities;
+use crate::fatfs::{Volume, VolumeError};
+use crate::partition::PartitionScheme;
+
+/// Where distributions install syslinux's BIOS MBRs and COM32 modules.
+const SYSLINUX_DIRS: &[&str] = &[
+    "/usr/lib/syslinux/mbr",
+    "/usr/lib/syslinux/modules/bios",
+    "/usr/lib/syslinux/bios",
+    "/usr/share/syslinux",
+    "/usr/lib/syslinux",
+];
+
+/// Where distributions install GRUB's x86_64 EFI modules.
+const GRUB_EFI_DIRS: &[&str] = &["/usr/lib/grub/x86_64-efi", "/usr/lib/grub2/x86_64-efi"];
+
+/// Boot code in front of the disk signature and partition table.
+const MBR_CODE_SIZE: usize = 440;
+
+/// Where UEFI firmware looks for a removable-media loader.
+pub const EFI_LOADER: &str = "EFI/BOOT/BOOTX64.EFI";
+
+/// What a file-copied stick gets to boot with.
+pub struct BootSetup {
+    /// COM32 modules `syslinux_config` uses.
+    pub modules: &'static [&'static str],
+    pub syslinux_config: String,
+    /// A loader to place at `EFI_LOADER`, when the copied files lack one.
+    pub efi_loader: Option<PathBuf>,
+}
+
+/// Locate one of syslinux's BIOS files (an MBR image or a .c32 module).
+pub fn find_syslinux_file(name: &str) -> Option<PathBuf> {
+    SYSLINUX_DIRS.iter().map(|dir| Path::new(dir).join(name)).find(|path| path.is_file())
+}
+
+/// Whether syslinux and the files it needs for `modules` are installed.
+pub fn syslinux_available(scheme: PartitionScheme, modules: &[&str]) -> bool {
+    capabilities::find_tool("syslinux").is_some()
+        && find_syslinux_file(mbr_file(scheme)).is_some()
+        && modules.iter().all(|module| find_syslinux_file(module).is_some())
+}
+
+fn mbr_file(scheme: PartitionScheme) -> &'static str {
+    match scheme {
+        PartitionScheme::Mbr => "mbr.bin",
+        PartitionScheme::Gpt => "gptmbr.bin",
+    }
+}
+
+/// `syslinux --install` onto the unmounted FAT partition at `partition`.
+pub fn syslinux_command(partition: &str) -> Option<Command> {
+    let tool = capabilities::find_tool("syslinux")?;
+    let mut command = Command::new("sudo");
+    command.arg(tool).arg("--install").arg(partition);
+    Some(command)
+}
+
+/// Copy `modules` (ldlinux's COM32 modules and their libraries) and
+/// `config` as syslinux.cfg into the root of the volume.
+pub fn install_syslinux_files<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    modules: &[&str],
+    config: &str,
+) -> Result<(), VolumeError> {
+    for module in modules {
+        let path = find_syslinux_file(module)
+            .ok_or_else(|| VolumeError::Io(io::Error::new(io::ErrorKind::NotFound, format!("syslinux module {} not found", module))))?;
+        let mut source = File::open(&path)?;
+        let len = source.metadata()?.len();
+        volume.write_file(module, &mut source, len)?;
+    }
+    volume.write_file("syslinux.cfg", &mut config.as_bytes(), config.len() as u64)?;
+    volume.flush()
+}
+
+/// Copy the EFI loader at `loader` to the removable-media path.
+pub fn install_efi_loader<D: Read + Write + Seek>(volume: &mut Volume<D>, loader: &Path) -> Result<(), VolumeError> {
+    let mut source = File::open(loader)?;
+    let len = source.metadata()?.len();
+    volume.write_file(EFI_LOADER, &mut source, len)?;
+    volume.flush()
+}
+
+/// Write syslinux's MBR boot code for `scheme` into the first sector of
+/// `disk`, leaving the disk signature and partition table alone.
+pub fn install_mbr_code<D: Write + Seek>(disk: &mut D, scheme: PartitionScheme) -> io::Result<()> {
+    let name = mbr_file(scheme);
+    let path = find_syslinux_file(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("syslinux {} not found", name)))?;
+    let mut code = std::fs::read(path)?;
+    code.truncate(MBR_CODE_SIZE);
+
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&code)?;
+    disk.flush()
+}
+
+fn grub_mkstandalone() -> Option<PathBuf> {
+    ["grub-mkstandalone", "grub2-mkstandalone"].iter().find_map(|tool| capabilities::find_tool(tool))
+}
+
+/// Whether a standalone GRUB EFI loader can be built.
+pub fn grub_efi_available() -> bool {
+    grub_mkstandalone().is_some() && GRUB_EFI_DIRS.iter().any(|dir| Path::new(dir).is_dir())
+}
+
+/// `grub-mkstandalone` building an x86_64 EFI loader at `output` with
+/// `config` embedded as its grub.cfg.
+pub fn grub_efi_command(output: &Path, config: &Path) -> Option<Command> {
+    let tool = grub_mkstandalone()?;
+    let mut command = Command::new(tool);
+    command
+        .args(["--format", "x86_64-efi", "--output"])
+        .arg(output)
+        .arg(format!("boot/grub/grub.cfg={}", config.display()));
+    Some(command)
+}
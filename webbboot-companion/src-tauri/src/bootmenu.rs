@@ -0,0 +1,244 @@
+// Boot menus of Linux install media.
+//
+// Media that is laid out file by file needs a boot menu of its own, and the
+// kernels, initrds and command lines for it are taken from the menus the ISO
+// already ships: isolinux/syslinux configs and grub.cfg. Only plain entries
+// are kept, a kernel with its initrds and arguments; entries built from GRUB
+// variables or handing off to a COM32 module are left out. The same entries
+// render back out as syslinux.cfg and grub.cfg.
+
+use serde::Serialize;
+
+use crate::iso9660::IsoFile;
+
+/// Kernels that only chain to something else, not Linux.
+const NON_LINUX_SUFFIXES: &[&str] = &[".c32", ".com", ".bin", ".0", ".efi"];
+
+pub const MENU_TIMEOUT_SECONDS: u32 = 10;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MenuEntry {
+    pub title: String,
+    /// Absolute path of the kernel on the media.
+    pub kernel: String,
+    /// Absolute paths of the initrds, in load order.
+    pub initrds: Vec<String>,
+    pub args: String,
+}
+
+impl MenuEntry {
+    fn new(title: &str) -> Self {
+        MenuEntry { title: title.to_string(), kernel: String::new(), initrds: Vec::new(), args: String::new() }
+    }
+
+    fn is_linux(&self) -> bool {
+        let kernel = self.kernel.to_lowercase();
+        !kernel.is_empty() && !NON_LINUX_SUFFIXES.iter().any(|suffix| kernel.ends_with(suffix))
+    }
+
+    /// Whether every path and argument is literal, with nothing left for a
+    /// boot loader to expand.
+    fn is_literal(&self) -> bool {
+        !self.kernel.contains(['$', '(']) && !self.initrds.iter().any(|initrd| initrd.contains(['$', '('])) && !self.args.contains('$')
+    }
+}
+
+/// Entries of an isolinux/syslinux config read from `dir`, which relative
+/// paths resolve against.
+pub fn parse_syslinux(text: &str, dir: &str) -> Vec<MenuEntry> {
+    let mut entries = Vec::new();
+    let mut current: Option<MenuEntry> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        let (keyword, value) = match line.split_once(char::is_whitespace) {
+            Some((keyword, value)) => (keyword.to_lowercase(), value.trim()),
+            None => (line.to_lowercase(), ""),
+        };
+        match keyword.as_str() {
+            "label" => {
+                entries.extend(current.take());
+                current = Some(MenuEntry::new(value));
+            }
+            "menu" => {
+                let title = value.split_once(char::is_whitespace).filter(|(word, _)| word.eq_ignore_ascii_case("label"));
+                if let (Some(entry), Some((_, title))) = (current.as_mut(), title) {
+                    entry.title = title.trim().replace('^', "");
+                }
+            }
+            "kernel" | "linux" => {
+                if let Some(entry) = current.as_mut() {
+                    entry.kernel = resolve(dir, value);
+                }
+            }
+            "initrd" => {
+                if let Some(entry) = current.as_mut() {
+                    entry.initrds = value.split(',').map(|initrd| resolve(dir, initrd.trim())).collect();
+                }
+            }
+            "append" => {
+                if let Some(entry) = current.as_mut() {
+                    let mut args = Vec::new();
+                    for arg in value.split_whitespace().filter(|arg| *arg != "-") {
+                        match arg.strip_prefix("initrd=") {
+                            Some(initrds) => entry.initrds.extend(initrds.split(',').map(|initrd| resolve(dir, initrd))),
+                            None => args.push(arg),
+                        }
+                    }
+                    entry.args = args.join(" ");
+                }
+            }
+            _ => {}
+        }
+    }
+    entries.extend(current);
+    entries.retain(|entry| entry.is_linux() && entry.is_literal());
+    entries
+}
+
+/// Entries of a grub.cfg. Paths in GRUB menus are already absolute.
+pub fn parse_grub(text: &str) -> Vec<MenuEntry> {
+    let mut entries = Vec::new();
+    let mut current: Option<MenuEntry> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command {
+            "menuentry" => {
+                entries.extend(current.take());
+                current = Some(MenuEntry::new(&grub_title(rest)));
+            }
+            "linux" | "linuxefi" | "linux16" => {
+                if let Some(entry) = current.as_mut() {
+                    let (kernel, args) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+                    entry.kernel = kernel.to_string();
+                    entry.args = args.split_whitespace().collect::<Vec<_>>().join(" ");
+                }
+            }
+            "initrd" | "initrdefi" | "initrd16" => {
+                if let Some(entry) = current.as_mut() {
+                    entry.initrds = rest.split_whitespace().map(str::to_string).collect();
+                }
+            }
+            "}" => entries.extend(current.take()),
+            _ => {}
+        }
+    }
+    entries.extend(current);
+    entries.retain(|entry| entry.is_linux() && entry.is_literal());
+    entries
+}
+
+// The first quoted (or bare) word after `menuentry`.
+fn grub_title(rest: &str) -> String {
+    let rest = rest.trim();
+    match rest.chars().next() {
+        Some(quote @ ('\'' | '"')) => rest[1..].split(quote).next().unwrap_or_default().to_string(),
+        _ => rest.split_whitespace().next().unwrap_or_default().to_string(),
+    }
+}
+
+fn resolve(dir: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else if dir.is_empty() {
+        format!("/{}", path)
+    } else {
+        format!("/{}/{}", dir.trim_matches('/'), path)
+    }
+}
+
+/// Entries for kernels found by name, for media whose menus could not be
+/// used. `args` is the distribution's command line for a kernel in the
+/// given directory.
+pub fn discover(files: &[IsoFile], args: impl Fn(&str) -> String) -> Vec<MenuEntry> {
+    let mut entries = Vec::new();
+    for kernel in files.iter().filter(|file| !file.is_dir && is_kernel_name(file_name(&file.path))) {
+        let dir = parent(&kernel.path);
+        let initrds = files
+            .iter()
+            .filter(|file| !file.is_dir && parent(&file.path) == dir && is_initrd_name(file_name(&file.path)))
+            .map(|file| format!("/{}", file.path))
+            .collect();
+        entries.push(MenuEntry {
+            title: format!("Boot /{}", kernel.path),
+            kernel: format!("/{}", kernel.path),
+            initrds,
+            args: args(dir),
+        });
+    }
+    entries
+}
+
+fn is_kernel_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    (name.starts_with("vmlinuz") || name == "bzimage" || name == "linux") && !name.ends_with(".sig")
+}
+
+fn is_initrd_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    (name.starts_with("initrd") || name.starts_with("initramfs")) && !name.ends_with(".sig")
+}
+
+pub fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+pub fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+/// syslinux.cfg showing `entries` through menu.c32.
+pub fn syslinux_config(title: &str, entries: &[MenuEntry]) -> String {
+    let mut config = format!(
+        "UI menu.c32\nPROMPT 0\nTIMEOUT {}\nMENU TITLE {}\n",
+        MENU_TIMEOUT_SECONDS * 10,
+        title
+    );
+    for (index, entry) in entries.iter().enumerate() {
+        config.push_str(&format!("\nLABEL entry{}\n  MENU LABEL {}\n  LINUX {}\n", index, entry.title, entry.kernel));
+        if !entry.initrds.is_empty() {
+            config.push_str(&format!("  INITRD {}\n", entry.initrds.join(",")));
+        }
+        if !entry.args.is_empty() {
+            config.push_str(&format!("  APPEND {}\n", entry.args));
+        }
+    }
+    config
+}
+
+/// grub.cfg showing `entries`, finding its files on the volume labelled
+/// `label`.
+pub fn grub_config(label: &str, entries: &[MenuEntry]) -> String {
+    let mut config = format!(
+        "set timeout={}\nset default=0\nsearch --no-floppy --set=root --label '{}'\n",
+        MENU_TIMEOUT_SECONDS,
+        grub_quote(label)
+    );
+    for entry in entries {
+        config.push_str(&format!("\nmenuentry '{}' {{\n  linux {} {}\n", grub_quote(&entry.title), entry.kernel, entry.args));
+        if !entry.initrds.is_empty() {
+            config.push_str(&format!("  initrd {}\n", entry.initrds.join(" ")));
+        }
+        config.push_str("}\n");
+    }
+    config
+}
+
+/// `text` made safe inside single quotes in a GRUB script.
+pub fn grub_quote(text: &str) -> String {
+    text.replace('\'', "'\\''")
+}
+
+/// `text` with every reference to the volume label `from` pointed at `to`,
+/// including the `\x20` escaped form GRUB and udev use for spaces.
+pub fn replace_label(text: &str, from: &str, to: &str) -> String {
+    if from.is_empty() || from == to {
+        return text.to_string();
+    }
+    let text = text.replace(from, to);
+    if from.contains(' ') {
+        text.replace(&from.replace(' ', "\\x20"), to)
+    } else {
+        text
+    }
+}
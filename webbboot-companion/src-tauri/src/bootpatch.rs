@@ -0,0 +1,190 @@
+// Boot config patches for images written as-is.
+//
+// A raw write puts the same bytes at the same offsets on the stick, so the
+// boot configs inside the ISO9660 tree can be patched where they lie once the
+// image is down. Each config grows into the unused tail of its last sector,
+// and every directory record of the file gets the new size. The patches are
+// worked out from the ISO file before anything is written.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::iso9660::{self, IsoError, SECTOR_SIZE};
+
+/// Boot configs are small; anything larger is not one.
+const MAX_CONFIG_SIZE: u64 = 64 * 1024;
+
+/// A boot config and what patching it changes.
+#[derive(Serialize, Debug, Clone)]
+pub struct ConfigPatch {
+    pub path: String,
+    /// Boot entries that get the parameter.
+    pub patched: usize,
+    /// Boot entries left alone because the file had no room left to grow.
+    pub skipped: usize,
+    /// Byte offset of the file's data.
+    #[serde(skip)]
+    offset: u64,
+    #[serde(skip)]
+    contents: Vec<u8>,
+    /// Byte offsets of the directory records holding the file's size.
+    #[serde(skip)]
+    records: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    Image(IsoError),
+    /// UDF keeps its own copy of every file size, which is not patched.
+    Udf,
+    NoBootEntries,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "{}", e),
+            PatchError::Image(e) => write!(f, "{}", e),
+            PatchError::Udf => write!(f, "boot configs on UDF images cannot be patched"),
+            PatchError::NoBootEntries => write!(f, "no matching boot entries found to patch"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<io::Error> for PatchError {
+    fn from(e: io::Error) -> Self {
+        PatchError::Io(e)
+    }
+}
+
+impl From<IsoError> for PatchError {
+    fn from(e: IsoError) -> Self {
+        PatchError::Image(e)
+    }
+}
+
+/// Work out the patches that add `parameter` to the kernel command lines of
+/// the ISO at `path` containing one of `markers`.
+pub fn plan(path: &Path, markers: &[&str], parameter: &str) -> Result<Vec<ConfigPatch>, PatchError> {
+    let mut image = BufReader::new(File::open(path)?);
+    let descriptors = iso9660::read_descriptors(&mut image)?;
+    if descriptors.udf {
+        return Err(PatchError::Udf);
+    }
+
+    let mut configs = Vec::new();
+    for file in iso9660::files(&mut image, &descriptors)? {
+        let lowercase = file.path.to_lowercase();
+        if file.is_dir || !lowercase.ends_with(".cfg") || file.size == 0 || file.size > MAX_CONFIG_SIZE {
+            continue;
+        }
+        // Only files in one recorded extent can grow into their last sector
+        let offset = match file.extents.as_slice() {
+            [extent] => match extent.offset {
+                Some(offset) => offset,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let mut text = String::new();
+        if file.reader(&mut image).read_to_string(&mut text).is_err() {
+            continue;
+        }
+        let syslinux = lowercase.contains("isolinux") || lowercase.contains("syslinux");
+        let capacity = (file.size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE) as usize;
+        let (patched_text, patched, skipped) = add_parameter(&text, markers, parameter, syslinux, capacity);
+        if patched + skipped == 0 {
+            continue;
+        }
+
+        let records = iso9660::records_at(&mut image, &descriptors, offset)?;
+        if records.is_empty() {
+            continue;
+        }
+        configs.push(ConfigPatch { path: file.path, patched, skipped, offset, contents: patched_text.into_bytes(), records });
+    }
+
+    if configs.iter().all(|config| config.patched == 0) {
+        return Err(PatchError::NoBootEntries);
+    }
+    Ok(configs)
+}
+
+/// Patch the boot configs of the image written at the start of `disk`.
+pub fn apply<D: Write + Seek>(disk: &mut D, configs: &[ConfigPatch]) -> io::Result<()> {
+    for config in configs.iter().filter(|config| config.patched > 0) {
+        disk.seek(SeekFrom::Start(config.offset))?;
+        disk.write_all(&config.contents)?;
+
+        // Sizes are recorded both-endian
+        let size = config.contents.len() as u32;
+        for record in &config.records {
+            disk.seek(SeekFrom::Start(record + 10))?;
+            disk.write_all(&size.to_le_bytes())?;
+            disk.write_all(&size.to_be_bytes())?;
+        }
+    }
+    disk.flush()
+}
+
+/// Add `parameter` to each kernel command line in `text` that contains one
+/// of `markers`, or to every one when there are none: `append` lines in
+/// syslinux configs, `linux` lines in GRUB's. Lines are patched for as long
+/// as the result fits in `capacity` bytes. Returns the new text with the
+/// number of lines patched and left out.
+pub fn add_parameter(text: &str, markers: &[&str], parameter: &str, syslinux: bool, capacity: usize) -> (String, usize, usize) {
+    let mut out = String::with_capacity(capacity.min(text.len() * 2));
+    let mut room = capacity.saturating_sub(text.len());
+    let (mut patched, mut skipped) = (0, 0);
+    for line in text.split_inclusive('\n') {
+        match patch_line(line, markers, parameter, syslinux) {
+            Some(new) if new.len() - line.len() <= room => {
+                room -= new.len() - line.len();
+                out.push_str(&new);
+                patched += 1;
+            }
+            Some(_) => {
+                out.push_str(line);
+                skipped += 1;
+            }
+            None => out.push_str(line),
+        }
+    }
+    (out, patched, skipped)
+}
+
+/// `args` with `parameter` added, ahead of any `---` separator: casper hands
+/// whatever follows it on to the installed system.
+pub fn insert_parameter(args: &str, parameter: &str) -> String {
+    let body = args.trim_end();
+    let at = body
+        .match_indices("---")
+        .map(|(at, _)| at)
+        .find(|at| body[..*at].ends_with(char::is_whitespace) && body[at + 3..].chars().next().is_none_or(char::is_whitespace));
+    match at {
+        Some(at) => format!("{}{} {}", &args[..at], parameter, &args[at..]),
+        None if body.is_empty() => format!("{}{}", parameter, args),
+        None => format!("{} {}{}", body, parameter, &args[body.len()..]),
+    }
+}
+
+fn patch_line(line: &str, markers: &[&str], parameter: &str, syslinux: bool) -> Option<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = words.first()?.to_lowercase();
+    let boots_kernel = match syslinux {
+        true => command == "append",
+        false => command == "linux" || command == "linuxefi",
+    };
+    let marked = markers.is_empty() || markers.iter().any(|marker| line.contains(marker));
+    if !boots_kernel || !marked || parameter.split_whitespace().all(|word| words.contains(&word)) {
+        return None;
+    }
+    Some(insert_parameter(line, parameter))
+}
@@ -0,0 +1,362 @@
+// Boot tests of written sticks.
+//
+// A stick can be written and verified and still not boot, so a job may start
+// it in QEMU before it leaves the bench: headless, under TCG so no KVM is
+// needed, with the device attached as a USB disk through a snapshot so
+// nothing the guest writes reaches it. Legacy BIOS and OVMF UEFI get a run
+// each. A run passes once the marker shows up on the serial console (OVMF
+// mirrors its console there) or, under BIOS, on the VGA text screen, which is
+// read out of guest memory through the monitor. Each run leaves its serial
+// log and a screendump of where it ended up.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::capabilities;
+use crate::jobs::CancelToken;
+
+const QEMU: &str = "qemu-system-x86_64";
+
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 120;
+const MAX_TIMEOUT_SECONDS: u64 = 1800;
+
+const MEMORY_MIB: u32 = 2048;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long the monitor gets to answer a command.
+const MONITOR_DELAY: Duration = Duration::from_millis(500);
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The 80x25 VGA text screen, as 16-bit character and attribute words.
+const VGA_TEXT_ADDRESS: u64 = 0xb8000;
+const VGA_COLUMNS: usize = 80;
+const VGA_WORDS: usize = VGA_COLUMNS * 25;
+
+/// OVMF code and variable store pairs, as distributions install them.
+const OVMF_FILES: &[(&str, &str)] = &[
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd"),
+    ("/usr/share/edk2/ovmf/OVMF_CODE.fd", "/usr/share/edk2/ovmf/OVMF_VARS.fd"),
+    ("/usr/share/edk2/x64/OVMF_CODE.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd"),
+    ("/usr/share/edk2-ovmf/x64/OVMF_CODE.fd", "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd"),
+    ("/usr/share/qemu/edk2-x86_64-code.fd", "/usr/share/qemu/edk2-i386-vars.fd"),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    Bios,
+    Uefi,
+}
+
+impl fmt::Display for Firmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Firmware::Bios => write!(f, "BIOS"),
+            Firmware::Uefi => write!(f, "UEFI"),
+        }
+    }
+}
+
+/// What a job's boot test looks for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BootTest {
+    /// "bios", "uefi" or "both", the default.
+    pub firmware: Option<String>,
+    /// Text that shows the stick booted, such as a boot menu entry.
+    pub marker: String,
+    /// Seconds each run has to show the marker.
+    pub timeout: Option<u64>,
+}
+
+impl BootTest {
+    pub fn firmwares(&self) -> Result<Vec<Firmware>, String> {
+        match self.firmware.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("both") => Ok(vec![Firmware::Bios, Firmware::Uefi]),
+            Some("bios") => Ok(vec![Firmware::Bios]),
+            Some("uefi") => Ok(vec![Firmware::Uefi]),
+            Some(other) => Err(format!("Unsupported boot test firmware: {}", other)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECONDS))
+    }
+
+    /// Check the settings and that this machine can run them.
+    pub fn check(&self) -> Result<(), String> {
+        if self.marker.trim().is_empty() {
+            return Err("A boot test needs a marker to wait for".to_string());
+        }
+        if self.timeout.is_some_and(|timeout| timeout == 0 || timeout > MAX_TIMEOUT_SECONDS) {
+            return Err(format!("A boot test timeout is 1 to {} seconds", MAX_TIMEOUT_SECONDS));
+        }
+        let firmwares = self.firmwares()?;
+        if capabilities::find_tool(QEMU).is_none() {
+            return Err(format!("Boot tests need {}, which is not installed", QEMU));
+        }
+        if firmwares.contains(&Firmware::Uefi) && find_ovmf().is_none() {
+            return Err("UEFI boot tests need OVMF, which is not installed".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// How one run went.
+#[derive(Serialize, Debug, Clone)]
+pub struct BootTestResult {
+    pub firmware: Firmware,
+    pub passed: bool,
+    /// Seconds until the marker showed up or the run gave up.
+    pub seconds: u64,
+    /// Why QEMU stopped before the marker showed up, if it did.
+    pub error: Option<String>,
+    pub serial_log: PathBuf,
+    /// What QEMU itself printed.
+    pub qemu_log: PathBuf,
+    pub screendump: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum BootTestError {
+    Io(io::Error),
+    NoQemu,
+    NoOvmf,
+    Cancelled,
+}
+
+impl fmt::Display for BootTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootTestError::Io(e) => write!(f, "{}", e),
+            BootTestError::NoQemu => write!(f, "{} is not installed", QEMU),
+            BootTestError::NoOvmf => write!(f, "OVMF is not installed"),
+            BootTestError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for BootTestError {}
+
+impl From<io::Error> for BootTestError {
+    fn from(e: io::Error) -> Self {
+        BootTestError::Io(e)
+    }
+}
+
+fn find_ovmf() -> Option<(&'static Path, &'static Path)> {
+    OVMF_FILES
+        .iter()
+        .map(|(code, vars)| (Path::new(*code), Path::new(*vars)))
+        .find(|(code, vars)| code.is_file() && vars.is_file())
+}
+
+/// The QEMU command line booting `device` under `firmware`, with the monitor
+/// on stdio and the serial console logged to `serial_log`. UEFI runs get a
+/// copy of OVMF's variable store in `dir`.
+pub fn qemu_command(device: &Path, firmware: Firmware, dir: &Path, serial_log: &Path) -> Result<Command, BootTestError> {
+    let qemu = capabilities::find_tool(QEMU).ok_or(BootTestError::NoQemu)?;
+    let mut command = Command::new("sudo");
+    command
+        .arg(qemu)
+        // No NIC, so the firmware does not sit through network boot first
+        .args(["-nodefaults", "-machine", "q35", "-accel", "tcg", "-m"])
+        .arg(MEMORY_MIB.to_string())
+        .args(["-display", "none", "-vga", "std", "-monitor", "stdio", "-no-reboot", "-serial"])
+        .arg(format!("file:{}", serial_log.display()))
+        .arg("-drive")
+        .arg(format!("file={},format=raw,if=none,id=stick,snapshot=on", device.display()))
+        .args(["-device", "qemu-xhci,id=xhci", "-device", "usb-storage,bus=xhci.0,drive=stick,bootindex=0"]);
+
+    if firmware == Firmware::Uefi {
+        let (code, vars) = find_ovmf().ok_or(BootTestError::NoOvmf)?;
+        let vars_copy = dir.join("OVMF_VARS.fd");
+        fs::copy(vars, &vars_copy)?;
+        command
+            .arg("-drive")
+            .arg(format!("if=pflash,format=raw,readonly=on,file={}", code.display()))
+            .arg("-drive")
+            .arg(format!("if=pflash,format=raw,file={}", vars_copy.display()));
+    }
+    Ok(command)
+}
+
+// QEMU runs as root under sudo. sudo relays SIGTERM to it but SIGKILL would
+// only take sudo down and leave QEMU holding the device, so QEMU is asked to
+// quit through the monitor and otherwise terminated, including on drop.
+struct Qemu(tokio::process::Child);
+
+impl Qemu {
+    fn terminate(&self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0.id() {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+
+    async fn stop(&mut self, monitor: &mut tokio::process::ChildStdin) {
+        let _ = monitor.write_all(b"quit\n").await;
+        if tokio::time::timeout(QUIT_TIMEOUT, self.0.wait()).await.is_ok() {
+            return;
+        }
+        self.terminate();
+        let _ = tokio::time::timeout(QUIT_TIMEOUT, self.0.wait()).await;
+    }
+}
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        if matches!(self.0.try_wait(), Ok(None)) {
+            self.terminate();
+        }
+    }
+}
+
+/// Boot `device` under `firmware` until the marker shows up, the timeout
+/// passes or QEMU stops, keeping the run's files in `dir`, which must exist.
+/// `progress` gets the seconds spent so far.
+pub async fn run(
+    device: &Path,
+    firmware: Firmware,
+    test: &BootTest,
+    dir: &Path,
+    cancel: &CancelToken,
+    progress: impl Fn(u64),
+) -> Result<BootTestResult, BootTestError> {
+    let name = firmware.to_string().to_lowercase();
+    let serial_log = dir.join(format!("serial-{}.log", name));
+    let screendump = dir.join(format!("screen-{}.ppm", name));
+    let qemu_log = dir.join(format!("qemu-{}.log", name));
+    let command = qemu_command(device, firmware, dir, &serial_log)?;
+
+    let mut qemu = Qemu(
+        tokio::process::Command::from(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(fs::File::create(&qemu_log)?)
+            .spawn()?,
+    );
+    let mut monitor_in = qemu.0.stdin.take().ok_or_else(|| io::Error::other("no monitor input"))?;
+    let monitor_out = Arc::new(Mutex::new(String::new()));
+    if let Some(mut stdout) = qemu.0.stdout.take() {
+        let monitor_out = monitor_out.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 4096];
+            while let Ok(read) = stdout.read(&mut buffer).await {
+                if read == 0 {
+                    break;
+                }
+                monitor_out.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push_str(&String::from_utf8_lossy(&buffer[..read]));
+            }
+        });
+    }
+
+    let started = Instant::now();
+    let (mut passed, mut error) = (false, None);
+    loop {
+        if cancel.is_cancelled() {
+            break;
+        }
+        if let Some(status) = qemu.0.try_wait()? {
+            let stderr = fs::read_to_string(&qemu_log).unwrap_or_default();
+            error = Some(match stderr.trim() {
+                "" => format!("QEMU exited with {}", status),
+                stderr => stderr.to_string(),
+            });
+            break;
+        }
+
+        let mut screen = fs::read(&serial_log).map(|log| strip_escapes(&String::from_utf8_lossy(&log))).unwrap_or_default();
+        if firmware == Firmware::Bios {
+            monitor_out.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+            monitor_in.write_all(format!("xp /{}hx {:#x}\n", VGA_WORDS, VGA_TEXT_ADDRESS).as_bytes()).await?;
+            tokio::time::sleep(MONITOR_DELAY).await;
+            screen.push_str(&vga_text(&monitor_out.lock().unwrap_or_else(|poisoned| poisoned.into_inner())));
+        }
+        if screen.contains(&test.marker) {
+            passed = true;
+            break;
+        }
+        if started.elapsed() >= test.timeout() {
+            break;
+        }
+        progress(started.elapsed().as_secs());
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    let seconds = started.elapsed().as_secs();
+
+    // Keep a picture of where the run ended up, then stop it
+    let running = qemu.0.try_wait()?.is_none();
+    if running && !cancel.is_cancelled() {
+        monitor_in.write_all(format!("screendump {}\n", screendump.display()).as_bytes()).await?;
+        tokio::time::sleep(MONITOR_DELAY * 2).await;
+    }
+    if running {
+        qemu.stop(&mut monitor_in).await;
+    }
+    if cancel.is_cancelled() {
+        return Err(BootTestError::Cancelled);
+    }
+
+    Ok(BootTestResult {
+        firmware,
+        passed,
+        seconds,
+        error,
+        serial_log,
+        qemu_log,
+        screendump: screendump.is_file().then_some(screendump),
+    })
+}
+
+/// The VGA text screen from a monitor `xp /Nhx` dump of it, one line per row.
+fn vga_text(dump: &str) -> String {
+    let mut characters = Vec::with_capacity(VGA_WORDS);
+    for line in dump.lines() {
+        let Some((address, words)) = line.split_once(": ") else {
+            continue;
+        };
+        let address = address.trim().rsplit(|c: char| !c.is_ascii_hexdigit()).next().unwrap_or_default();
+        if u64::from_str_radix(address, 16).map_or(true, |address| address < VGA_TEXT_ADDRESS) {
+            continue;
+        }
+        for word in words.split_whitespace() {
+            if let Some(word) = word.strip_prefix("0x").and_then(|word| u16::from_str_radix(word, 16).ok()) {
+                let character = (word & 0xff) as u8;
+                characters.push(if character.is_ascii_graphic() { character as char } else { ' ' });
+            }
+        }
+    }
+    characters.chunks(VGA_COLUMNS).map(|row| row.iter().collect::<String>().trim_end().to_string() + "\n").collect()
+}
+
+// Serial consoles carry ANSI escape sequences between the words of a menu.
+fn strip_escapes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            out.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'[') {
+            chars.next();
+            // Parameters run until the final byte, a letter or one of @[\]^_`{|}~
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else if let Some('(' | ')') = chars.next() {
+            // Character set designations name the set in one more byte
+            chars.next();
+        }
+    }
+    out
+}
@@ -0,0 +1,263 @@
+// What this machine can actually do.
+//
+// Probed once when the companion starts and sent to the client in the
+// initial handshake, so the form can disable filesystems and operations the
+// machine cannot perform and say why, instead of failing with mkfs stderr.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::filesystem::Filesystem;
+
+/// Directories searched in addition to PATH; mkfs helpers live in sbin,
+/// which is often missing from a desktop user's PATH.
+#[cfg(unix)]
+const EXTRA_TOOL_DIRS: &[&str] = &["/usr/local/sbin", "/usr/sbin", "/sbin"];
+#[cfg(not(unix))]
+const EXTRA_TOOL_DIRS: &[&str] = &[];
+
+/// Optional tools, with the feature each one enables.
+const OPTIONAL_TOOLS: &[(&str, &[&str], &str)] = &[
+    ("gpgv", &["gpgv"], "detached signature verification"),
+    ("grub", &["grub-mkstandalone", "grub2-mkstandalone"], "EFI loaders for non-hybrid Linux ISOs"),
+    ("qemu", &["qemu-system-x86_64"], "boot tests of written sticks"),
+    ("syslinux", &["syslinux", "extlinux"], "syslinux installation for non-hybrid Linux ISOs"),
+    ("wimlib", &["wimlib-imagex"], "splitting install.wim for FAT32 Windows installers"),
+];
+
+/// Image formats decompressed in-process.
+const DECOMPRESSORS: &[&str] = &["xz", "gzip", "zstd", "bzip2", "zip"];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privilege {
+    /// Running as root.
+    Root,
+    /// sudo works without a password.
+    Sudo,
+    /// sudo exists but wants a password, which the companion cannot supply.
+    SudoPassword,
+    /// No way to run privileged helpers.
+    Unprivileged,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Capability {
+    pub name: String,
+    pub available: bool,
+    /// Tool that provides the capability, when one was found.
+    pub tool: Option<String>,
+    /// Why the capability is unavailable.
+    pub reason: Option<String>,
+}
+
+impl Capability {
+    fn available(name: &str, tool: Option<&Path>) -> Self {
+        Capability {
+            name: name.to_string(),
+            available: true,
+            tool: tool.map(|tool| tool.display().to_string()),
+            reason: None,
+        }
+    }
+
+    fn unavailable(name: &str, reason: String) -> Self {
+        Capability { name: name.to_string(), available: false, tool: None, reason: Some(reason) }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    pub platform: &'static str,
+    pub privilege: Privilege,
+    /// Whether block devices can be opened for writing by this process.
+    pub raw_device_access: bool,
+    pub filesystems: Vec<Capability>,
+    pub operations: Vec<Capability>,
+    pub decompressors: Vec<Capability>,
+    pub tools: Vec<Capability>,
+}
+
+/// Probe the machine. Runs `sudo -n`, so call it off the async runtime.
+pub fn probe() -> Capabilities {
+    let privilege = probe_privilege();
+    let raw_device_access = probe_raw_device_access(privilege);
+
+    let filesystems: Vec<Capability> = Filesystem::all()
+        .iter()
+        .map(|filesystem| probe_filesystem(*filesystem, privilege, raw_device_access))
+        .collect();
+
+    let device_reason = "cannot open block devices; run as root or join the disk group".to_string();
+    let can_format = filesystems.iter().any(|filesystem| filesystem.available);
+    let operations = vec![
+        if raw_device_access {
+            Capability::available("create", None)
+        } else {
+            Capability::unavailable("create", device_reason.clone())
+        },
+        match (raw_device_access, can_format) {
+            (true, true) => Capability::available("restore", None),
+            (false, _) => Capability::unavailable("restore", device_reason.clone()),
+            (true, false) => Capability::unavailable("restore", "no filesystem can be created".to_string()),
+        },
+        if raw_device_access {
+            Capability::available("verify", None)
+        } else {
+            Capability::unavailable("verify", device_reason)
+        },
+    ];
+
+    let decompressors = DECOMPRESSORS.iter().map(|name| Capability::available(name, None)).collect();
+
+    let tools = OPTIONAL_TOOLS
+        .iter()
+        .map(|(name, candidates, feature)| match candidates.iter().find_map(|tool| find_tool(tool)) {
+            Some(path) => Capability::available(name, Some(&path)),
+            None => Capability::unavailable(name, format!("{} not found; needed for {}", candidates.join(" or "), feature)),
+        })
+        .collect();
+
+    Capabilities {
+        platform: std::env::consts::OS,
+        privilege,
+        raw_device_access,
+        filesystems,
+        operations,
+        decompressors,
+        tools,
+    }
+}
+
+/// Locate an executable on PATH or in the sbin directories.
+pub fn find_tool(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(EXTRA_TOOL_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.with_extension("exe").is_file() || path.is_file()
+}
+
+/// Whether `filesystem`'s mkfs helper is installed and can run without a
+/// password prompt.
+#[cfg(target_os = "linux")]
+pub fn mkfs_usable(filesystem: Filesystem) -> bool {
+    find_tool(filesystem.tool()).is_some() && matches!(probe_privilege(), Privilege::Root | Privilege::Sudo)
+}
+
+#[cfg(target_os = "linux")]
+fn probe_filesystem(filesystem: Filesystem, privilege: Privilege, raw_device_access: bool) -> Capability {
+    let name = filesystem.to_string();
+    let reason = match (find_tool(filesystem.tool()), privilege) {
+        (Some(tool), Privilege::Root | Privilege::Sudo) => return Capability::available(&name, Some(&tool)),
+        (Some(_), Privilege::SudoPassword) => format!("{} needs root, and sudo asks for a password", filesystem.tool()),
+        (Some(_), Privilege::Unprivileged) => format!("{} needs root", filesystem.tool()),
+        (None, _) => format!("{} is not installed", filesystem.tool()),
+    };
+
+    // FAT32 falls back to the built-in formatter, which only needs the device
+    if filesystem == Filesystem::Fat32 && raw_device_access {
+        return Capability::available(&name, None);
+    }
+    Capability::unavailable(&name, reason)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_filesystem(filesystem: Filesystem, _privilege: Privilege, _raw_device_access: bool) -> Capability {
+    let name = filesystem.to_string();
+    match filesystem.native_format_name() {
+        Ok(_) => Capability::available(&name, None),
+        Err(reason) => Capability::unavailable(&name, reason),
+    }
+}
+
+#[cfg(unix)]
+fn probe_privilege() -> Privilege {
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        return Privilege::Root;
+    }
+    if find_tool("sudo").is_none() {
+        return Privilege::Unprivileged;
+    }
+
+    let status = Command::new("sudo")
+        .args(["-n", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => Privilege::Sudo,
+        _ => Privilege::SudoPassword,
+    }
+}
+
+// Windows tools elevate through UAC themselves; report what we run as.
+#[cfg(not(unix))]
+fn probe_privilege() -> Privilege {
+    let elevated = Command::new("net")
+        .arg("session")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if elevated {
+        Privilege::Root
+    } else {
+        Privilege::Unprivileged
+    }
+}
+
+// The writer opens devices directly, so it needs root or the group that owns
+// the disk nodes (usually `disk`).
+#[cfg(target_os = "linux")]
+fn probe_raw_device_access(privilege: Privilege) -> bool {
+    if privilege == Privilege::Root {
+        return true;
+    }
+
+    use std::os::unix::fs::MetadataExt;
+    let disk_gid = ["/dev/sda", "/dev/nvme0n1", "/dev/mmcblk0", "/dev/vda"]
+        .iter()
+        .find_map(|node| std::fs::metadata(node).ok())
+        .map(|metadata| metadata.gid());
+    let disk_gid = match disk_gid {
+        Some(gid) => gid,
+        None => return false,
+    };
+
+    // SAFETY: a zero-length call only returns the group count, and the second
+    // call writes at most `count` entries into a buffer of that size.
+    let groups = unsafe {
+        let count = libc::getgroups(0, std::ptr::null_mut());
+        if count < 0 {
+            return false;
+        }
+        let mut groups = vec![0 as libc::gid_t; count as usize];
+        let count = libc::getgroups(count, groups.as_mut_ptr());
+        groups.truncate(count.max(0) as usize);
+        groups
+    };
+    // SAFETY: getegid has no preconditions.
+    let egid = unsafe { libc::getegid() };
+    egid == disk_gid || groups.contains(&disk_gid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_raw_device_access(privilege: Privilege) -> bool {
+    privilege == Privilege::Root
+}
@@ -0,0 +1,537 @@
+// Image checksum and signature verification.
+//
+// A job can name the expected digest directly ("sha256:<hex>", "sha512:<hex>",
+// "blake3:<hex>", or bare hex), point at a SHA256SUMS-style file, or leave both
+// out and let a sums file sitting next to the image be discovered. A detached
+// OpenPGP signature is checked with `gpgv` against an offline keyring, so no
+// key server or user keyring is ever consulted.
+
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::jobs::{self, CancelToken};
+
+const HASH_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Sums files looked for next to an image, with the algorithm they use.
+const SUMS_FILES: &[(&str, HashAlgorithm)] = &[
+    ("SHA256SUMS", HashAlgorithm::Sha256),
+    ("sha256sum.txt", HashAlgorithm::Sha256),
+    ("SHA256SUMS.txt", HashAlgorithm::Sha256),
+    ("CHECKSUM", HashAlgorithm::Sha256),
+    ("SHA512SUMS", HashAlgorithm::Sha512),
+    ("sha512sum.txt", HashAlgorithm::Sha512),
+    ("B3SUMS", HashAlgorithm::Blake3),
+];
+
+const SIGNATURE_SUFFIXES: &[&str] = &["gpg", "sig", "asc"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.to_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            "blake3" | "b3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    // BLAKE3 and SHA-256 digests are both 64 hex digits; bare 64-digit values
+    // are taken as SHA-256 because that is what distributions publish.
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+            HashAlgorithm::Sha512 => 128,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha512 => "SHA-512",
+            HashAlgorithm::Blake3 => "BLAKE3",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedDigest {
+    pub algorithm: HashAlgorithm,
+    pub hex: String,
+    /// Where the digest came from, for messages: "job" or a sums file path.
+    pub origin: String,
+}
+
+#[derive(Debug)]
+pub enum ChecksumError {
+    Io { path: String, source: io::Error },
+    Malformed(String),
+    NoEntry { sums_file: String, image: String },
+    Mismatch { algorithm: HashAlgorithm, expected: String, actual: String },
+    MissingKeyring,
+    SignatureNotFound { image: String },
+    GpgUnavailable(String),
+    BadSignature(String),
+    Cancelled,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumError::Io { path, source } => write!(f, "cannot read {}: {}", path, source),
+            ChecksumError::Malformed(message) => write!(f, "{}", message),
+            ChecksumError::NoEntry { sums_file, image } => write!(f, "{} has no entry for {}", sums_file, image),
+            ChecksumError::Mismatch { algorithm, expected, actual } => {
+                write!(f, "{} mismatch: expected {}, image hashes to {}", algorithm, expected, actual)
+            }
+            ChecksumError::MissingKeyring => write!(f, "a keyring is required to check signatures offline"),
+            ChecksumError::SignatureNotFound { image } => {
+                write!(f, "a keyring was given but no .gpg, .sig or .asc signature was found for {} or its sums file", image)
+            }
+            ChecksumError::GpgUnavailable(message) => write!(f, "cannot run gpgv: {}", message),
+            ChecksumError::BadSignature(message) => write!(f, "signature verification failed: {}", message),
+            ChecksumError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+fn io_error(path: &Path, source: io::Error) -> ChecksumError {
+    ChecksumError::Io { path: path.display().to_string(), source }
+}
+
+/// A detached signature and the file it signs.
+#[derive(Debug, Clone)]
+pub struct SignatureCheck {
+    pub signature: PathBuf,
+    pub signed: PathBuf,
+    pub keyring: PathBuf,
+}
+
+/// What to check about an image before it is written.
+#[derive(Debug, Clone, Default)]
+pub struct CheckPlan {
+    pub expected: Option<ExpectedDigest>,
+    pub signature: Option<SignatureCheck>,
+}
+
+impl CheckPlan {
+    pub fn is_empty(&self) -> bool {
+        self.expected.is_none() && self.signature.is_none()
+    }
+}
+
+/// Work out which checks apply to `image` from the job's fields. An explicit
+/// digest wins over a sums file; without either, a sums file next to the image
+/// is used if one lists it. Signatures are only discovered when a keyring is
+/// given, since there is nothing to check them against otherwise, and a
+/// keyring without any signature to check is an error.
+pub fn plan(
+    image: &Path,
+    checksum: Option<&str>,
+    checksum_file: Option<&str>,
+    signature: Option<&str>,
+    keyring: Option<&str>,
+) -> Result<CheckPlan, ChecksumError> {
+    let sums_file = match checksum_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None if checksum.is_none() => discover_sums_file(image),
+        None => None,
+    };
+
+    let expected = match (checksum, &sums_file) {
+        (Some(value), _) => Some(parse_expected(value)?),
+        (None, Some(path)) => Some(from_sums_file(path, image)?),
+        (None, None) => None,
+    };
+
+    let signature = match (signature, keyring) {
+        (Some(_), None) => return Err(ChecksumError::MissingKeyring),
+        (Some(signature), Some(keyring)) => {
+            let signature = PathBuf::from(signature);
+            let signed = signed_file(&signature, image, sums_file.as_deref());
+            Some(SignatureCheck { signature, signed, keyring: PathBuf::from(keyring) })
+        }
+        (None, Some(keyring)) => sums_file
+            .as_deref()
+            .and_then(|sums| discover_signature(sums).map(|signature| (signature, sums.to_path_buf())))
+            .or_else(|| discover_signature(image).map(|signature| (signature, image.to_path_buf())))
+            .map(|(signature, signed)| SignatureCheck { signature, signed, keyring: PathBuf::from(keyring) })
+            .map(Some)
+            .ok_or_else(|| ChecksumError::SignatureNotFound { image: image.display().to_string() })?,
+        (None, None) => None,
+    };
+
+    Ok(CheckPlan { expected, signature })
+}
+
+// A signature named after the image (`foo.iso.sig`) signs the image itself;
+// any other signature is taken to cover the sums file, which is how
+// distributions publish them.
+fn signed_file(signature: &Path, image: &Path, sums_file: Option<&Path>) -> PathBuf {
+    let signs_image = signature.file_stem().is_some() && signature.file_stem() == image.file_name();
+    match sums_file {
+        Some(sums) if !signs_image => sums.to_path_buf(),
+        _ => image.to_path_buf(),
+    }
+}
+
+/// Parse a digest given in the job, with or without an algorithm prefix.
+pub fn parse_expected(value: &str) -> Result<ExpectedDigest, ChecksumError> {
+    let value = value.trim();
+    let (algorithm, hex) = match value.split_once(':') {
+        Some((prefix, hex)) => {
+            let algorithm = HashAlgorithm::from_prefix(prefix)
+                .ok_or_else(|| ChecksumError::Malformed(format!("unsupported checksum algorithm '{}'", prefix)))?;
+            (algorithm, hex.trim())
+        }
+        None => {
+            let algorithm = HashAlgorithm::from_hex_len(value.len()).ok_or_else(|| {
+                ChecksumError::Malformed(format!("cannot tell the algorithm of a {} digit checksum", value.len()))
+            })?;
+            (algorithm, value)
+        }
+    };
+
+    let hex = normalize_hex(hex, algorithm)?;
+    Ok(ExpectedDigest { algorithm, hex, origin: "job".to_string() })
+}
+
+/// Look up the digest for `image` in a sums file. Both the GNU format
+/// (`<hex>  name` or `<hex> *name`) and the BSD format (`SHA256 (name) = <hex>`)
+/// are understood.
+pub fn from_sums_file(sums_file: &Path, image: &Path) -> Result<ExpectedDigest, ChecksumError> {
+    let contents = fs::read_to_string(sums_file).map_err(|e| io_error(sums_file, e))?;
+    let image_name = image
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_algorithm = sums_file
+        .file_name()
+        .and_then(|name| SUMS_FILES.iter().find(|(known, _)| name.to_string_lossy() == *known))
+        .map(|(_, algorithm)| *algorithm);
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (algorithm, hex, name) = if let Some((head, hex)) = line.rsplit_once(") = ") {
+            let (prefix, name) = match head.split_once(" (") {
+                Some(parts) => parts,
+                None => continue,
+            };
+            (HashAlgorithm::from_prefix(prefix), hex, name)
+        } else {
+            let (hex, name) = match line.split_once(char::is_whitespace) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let name = name.trim_start().trim_start_matches('*');
+            (file_algorithm.or_else(|| HashAlgorithm::from_hex_len(hex.len())), hex, name)
+        };
+
+        // Entries may carry a relative directory, e.g. `./ubuntu.iso`.
+        let entry_name = Path::new(name)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if entry_name != image_name {
+            continue;
+        }
+
+        let algorithm = algorithm
+            .ok_or_else(|| ChecksumError::Malformed(format!("{}: unknown algorithm for {}", sums_file.display(), name)))?;
+        return Ok(ExpectedDigest {
+            algorithm,
+            hex: normalize_hex(hex, algorithm)?,
+            origin: sums_file.display().to_string(),
+        });
+    }
+
+    Err(ChecksumError::NoEntry {
+        sums_file: sums_file.display().to_string(),
+        image: image_name,
+    })
+}
+
+/// Find a sums file next to `image` that lists it.
+pub fn discover_sums_file(image: &Path) -> Option<PathBuf> {
+    let dir = image.parent()?;
+    let mut candidates: Vec<PathBuf> = SUMS_FILES.iter().map(|(name, _)| dir.join(name)).collect();
+    if let Some(name) = image.file_name() {
+        let name = name.to_string_lossy();
+        for suffix in ["sha256", "sha256sum", "sha512", "sha512sum", "b3"] {
+            candidates.push(dir.join(format!("{}.{}", name, suffix)));
+        }
+    }
+
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.is_file() && from_sums_file(candidate, image).is_ok())
+}
+
+/// Find a detached signature for `signed` (`<file>.gpg`, `.sig` or `.asc`).
+pub fn discover_signature(signed: &Path) -> Option<PathBuf> {
+    SIGNATURE_SUFFIXES
+        .iter()
+        .map(|suffix| PathBuf::from(format!("{}.{}", signed.display(), suffix)))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Hash `path`, reporting bytes hashed so far through `progress`.
+pub fn hash_file(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<String, ChecksumError> {
+    let mut file = File::open(path).map_err(|e| io_error(path, e))?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+    let mut done = 0u64;
+
+    loop {
+        if cancel.is_cancelled() {
+            return Err(ChecksumError::Cancelled);
+        }
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(io_error(path, e)),
+        };
+        hasher.update(&buffer[..n]);
+        done += n as u64;
+        progress(done);
+    }
+
+    Ok(hasher.finalize_hex())
+}
+
+/// Compare the digest of `path` with `expected`.
+pub fn verify_file(
+    path: &Path,
+    expected: &ExpectedDigest,
+    cancel: &CancelToken,
+    progress: impl FnMut(u64),
+) -> Result<(), ChecksumError> {
+    let actual = hash_file(path, expected.algorithm, cancel, progress)?;
+    if actual == expected.hex {
+        Ok(())
+    } else {
+        Err(ChecksumError::Mismatch {
+            algorithm: expected.algorithm,
+            expected: expected.hex.clone(),
+            actual,
+        })
+    }
+}
+
+/// Check a detached signature over `signed` using only the keys in `keyring`.
+/// Returns the fingerprint of the signing key.
+pub async fn verify_signature(
+    signature: &Path,
+    signed: &Path,
+    keyring: &Path,
+    cancel: &CancelToken,
+) -> Result<String, ChecksumError> {
+    if !keyring.is_file() {
+        return Err(io_error(keyring, io::Error::new(io::ErrorKind::NotFound, "keyring not found")));
+    }
+
+    let mut command = Command::new("gpgv");
+    command
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg(signature)
+        .arg(signed);
+
+    let output = match jobs::run_cancellable(command, cancel).await {
+        Ok(output) => output,
+        Err(jobs::CommandError::Cancelled) => return Err(ChecksumError::Cancelled),
+        Err(e) => return Err(ChecksumError::GpgUnavailable(e.to_string())),
+    };
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    let fingerprint = status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .next()
+        .map(str::to_string);
+
+    match fingerprint {
+        Some(fingerprint) if output.status.success() => Ok(fingerprint),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().last().unwrap_or("no valid signature from a key in the keyring");
+            Err(ChecksumError::BadSignature(reason.trim().to_string()))
+        }
+    }
+}
+
+fn normalize_hex(hex: &str, algorithm: HashAlgorithm) -> Result<String, ChecksumError> {
+    let hex = hex.trim().to_lowercase();
+    if hex.len() != algorithm.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ChecksumError::Malformed(format!("'{}' is not a valid {} digest", hex, algorithm)));
+    }
+    Ok(hex)
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        let bytes: Vec<u8> = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    const HELLO_SHA512: &str = "e7c22b994c59d9cf2b48e549b1e24666636045930d3da7c1acb299d1c3b7f931f94aae41edda2c2b207a36e10f8bcb8d45223e54878f5b316e7ce3b6bc019629";
+
+    // An image holding "hello\n" in a fresh directory.
+    fn image_dir() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("distro.iso");
+        fs::write(&image, "hello\n").unwrap();
+        (dir, image)
+    }
+
+    #[test]
+    fn parses_expected_digests() {
+        let digest = parse_expected(&format!(" SHA-256:{} ", HELLO_SHA256.to_uppercase())).unwrap();
+        assert_eq!((digest.algorithm, digest.hex.as_str(), digest.origin.as_str()), (HashAlgorithm::Sha256, HELLO_SHA256, "job"));
+        assert_eq!(parse_expected(HELLO_SHA256).unwrap().algorithm, HashAlgorithm::Sha256);
+        assert_eq!(parse_expected(HELLO_SHA512).unwrap().algorithm, HashAlgorithm::Sha512);
+        assert_eq!(parse_expected(&format!("b3:{}", HELLO_SHA256)).unwrap().algorithm, HashAlgorithm::Blake3);
+
+        for bad in ["md5:d41d8cd98f00b204e9800998ecf8427e", "abc123", &format!("sha512:{}", HELLO_SHA256), &format!("sha256:{}g", &HELLO_SHA256[1..])] {
+            assert!(matches!(parse_expected(bad), Err(ChecksumError::Malformed(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn reads_gnu_and_bsd_sums_files() {
+        let (dir, image) = image_dir();
+        let gnu = dir.path().join("SHA256SUMS");
+        fs::write(&gnu, format!("# release sums\n{}  other.iso\n{} *./distro.iso\n", "0".repeat(64), HELLO_SHA256.to_uppercase())).unwrap();
+        let digest = from_sums_file(&gnu, &image).unwrap();
+        assert_eq!((digest.algorithm, digest.hex.as_str()), (HashAlgorithm::Sha256, HELLO_SHA256));
+        assert_eq!(digest.origin, gnu.display().to_string());
+
+        let bsd = dir.path().join("CHECKSUMS.bsd");
+        fs::write(&bsd, format!("SHA256 (other.iso) = {}\nSHA512 (distro.iso) = {}\n", "0".repeat(64), HELLO_SHA512)).unwrap();
+        let digest = from_sums_file(&bsd, &image).unwrap();
+        assert_eq!((digest.algorithm, digest.hex.as_str()), (HashAlgorithm::Sha512, HELLO_SHA512));
+
+        // BLAKE3 sums are only recognisable by their file name
+        let b3 = dir.path().join("B3SUMS");
+        fs::write(&b3, format!("{}  distro.iso\n", HELLO_SHA256)).unwrap();
+        assert_eq!(from_sums_file(&b3, &image).unwrap().algorithm, HashAlgorithm::Blake3);
+
+        fs::write(&gnu, format!("{}  other.iso\n", HELLO_SHA256)).unwrap();
+        assert!(matches!(from_sums_file(&gnu, &image), Err(ChecksumError::NoEntry { .. })));
+    }
+
+    #[test]
+    fn plans_discovered_sums_and_signatures() {
+        let (dir, image) = image_dir();
+        let image_path = image.to_str().unwrap();
+        assert!(plan(&image, None, None, None, None).unwrap().is_empty());
+
+        let sums = dir.path().join("SHA256SUMS");
+        fs::write(&sums, format!("{}  distro.iso\n", HELLO_SHA256)).unwrap();
+        let checks = plan(&image, None, None, None, None).unwrap();
+        assert_eq!(checks.expected.unwrap().hex, HELLO_SHA256);
+        assert!(checks.signature.is_none());
+
+        // A keyring with nothing to check must not fall through to unsigned
+        let keyring = dir.path().join("keyring.gpg").display().to_string();
+        assert!(matches!(plan(&image, None, None, None, Some(&keyring)), Err(ChecksumError::SignatureNotFound { .. })));
+        assert!(matches!(plan(&image, Some(HELLO_SHA256), None, None, Some(&keyring)), Err(ChecksumError::SignatureNotFound { .. })));
+
+        fs::write(dir.path().join("SHA256SUMS.gpg"), "signature").unwrap();
+        let signature = plan(&image, None, None, None, Some(&keyring)).unwrap().signature.unwrap();
+        assert_eq!((signature.signature, signature.signed), (dir.path().join("SHA256SUMS.gpg"), sums.clone()));
+
+        // An image signature signs the image even with a sums file around
+        let image_signature = format!("{}.sig", image_path);
+        let signature = plan(&image, None, None, Some(&image_signature), Some(&keyring)).unwrap().signature.unwrap();
+        assert_eq!(signature.signed, image);
+        assert!(matches!(plan(&image, None, None, Some(&image_signature), None), Err(ChecksumError::MissingKeyring)));
+    }
+
+    #[test]
+    fn verifies_file_digests() {
+        let (_dir, image) = image_dir();
+        let cancel = CancelToken::default();
+        let mut hashed = 0;
+        verify_file(&image, &parse_expected(HELLO_SHA256).unwrap(), &cancel, |done| hashed = done).unwrap();
+        assert_eq!(hashed, 6);
+        verify_file(&image, &parse_expected(HELLO_SHA512).unwrap(), &cancel, |_| {}).unwrap();
+
+        let wrong = parse_expected(&"0".repeat(64)).unwrap();
+        match verify_file(&image, &wrong, &cancel, |_| {}) {
+            Err(ChecksumError::Mismatch { actual, .. }) => assert_eq!(actual, HELLO_SHA256),
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,700 @@
+// Compressed and archived image sources.
+//
+// Board and recovery images ship as `.img.xz`, `.img.gz`, `.zst`, `.bz2` or
+// inside a `.zip`. The format is detected from the leading magic bytes, never
+// from the file name, and the image is decompressed on the fly in front of the
+// writer. Where the container records the uncompressed size it is read from
+// the trailer or central directory so progress can be measured in image bytes;
+// otherwise progress falls back to compressed bytes consumed.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FOOTER_MAGIC: &[u8] = b"YZ";
+const XZ_HEADER_SIZE: u64 = 12;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_METHOD_OFFSET: u64 = 8;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+/// Archive members taken to be disk images when a zip holds more than one file.
+const IMAGE_EXTENSIONS: &[&str] = &["img", "iso", "bin", "raw"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Xz,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Zip,
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Xz => "xz",
+            ImageFormat::Gzip => "gzip",
+            ImageFormat::Zstd => "zstd",
+            ImageFormat::Bzip2 => "bzip2",
+            ImageFormat::Zip => "zip",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// Size of the file on disk.
+    pub compressed_size: u64,
+    /// Size of the image once decompressed, when the container records it.
+    pub size: Option<u64>,
+    /// Name of the image inside a zip archive.
+    pub entry: Option<String>,
+}
+
+impl ImageInfo {
+    /// Total that progress is measured against: image bytes when known,
+    /// compressed bytes otherwise.
+    pub fn progress_total(&self) -> u64 {
+        self.size.unwrap_or(self.compressed_size)
+    }
+}
+
+#[derive(Debug)]
+pub enum DecompressError {
+    Io(io::Error),
+    Zip(String),
+    NoImageInArchive,
+    AmbiguousArchive(Vec<String>),
+    UnsupportedEntry { name: String, reason: String },
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::Io(e) => write!(f, "{}", e),
+            DecompressError::Zip(message) => write!(f, "cannot read zip archive: {}", message),
+            DecompressError::NoImageInArchive => write!(f, "zip archive contains no image"),
+            DecompressError::AmbiguousArchive(names) => {
+                write!(f, "zip archive contains several images ({}); extract the one to write", names.join(", "))
+            }
+            DecompressError::UnsupportedEntry { name, reason } => write!(f, "cannot extract {}: {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+impl From<io::Error> for DecompressError {
+    fn from(e: io::Error) -> Self {
+        DecompressError::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for DecompressError {
+    fn from(e: zip::result::ZipError) -> Self {
+        DecompressError::Zip(e.to_string())
+    }
+}
+
+/// Detect the format of `path` and read the uncompressed size where recorded.
+pub fn probe(path: &Path) -> Result<ImageInfo, DecompressError> {
+    let mut file = File::open(path)?;
+    let compressed_size = file.metadata()?.len();
+    let format = detect(&mut file)?;
+
+    let (size, entry) = match format {
+        ImageFormat::Raw => (Some(compressed_size), None),
+        ImageFormat::Xz => (xz_uncompressed_size(&mut file, compressed_size)?, None),
+        ImageFormat::Zstd => (zstd_content_size(&mut file, compressed_size)?, None),
+        // The gzip trailer only holds the size modulo 4 GiB, which images
+        // routinely exceed, and bzip2 records no size at all.
+        ImageFormat::Gzip | ImageFormat::Bzip2 => (None, None),
+        ImageFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            let index = zip_image_index(&mut archive)?;
+            let entry = archive.by_index_raw(index)?;
+            (Some(entry.size()), Some(entry.name().to_string()))
+        }
+    };
+
+    Ok(ImageInfo { format, compressed_size, size, entry })
+}
+
+/// A decompressing reader over an image file.
+pub struct ImageSource {
+    pub info: ImageInfo,
+    reader: Box<dyn Read + Send>,
+    consumed: Arc<AtomicU64>,
+}
+
+impl ImageSource {
+    pub fn open(path: &Path) -> Result<Self, DecompressError> {
+        Self::open_probed(path, probe(path)?)
+    }
+
+    /// Open `path`, already probed as `info`, without reading its trailers
+    /// or frames again.
+    pub fn open_probed(path: &Path, info: ImageInfo) -> Result<Self, DecompressError> {
+        let consumed = Arc::new(AtomicU64::new(0));
+        let mut file = File::open(path)?;
+
+        let reader: Box<dyn Read + Send> = match info.format {
+            ImageFormat::Raw => Box::new(CountingReader::new(file, &consumed)),
+            ImageFormat::Xz => {
+                let input = BufReader::new(CountingReader::new(file, &consumed));
+                Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input))
+            }
+            ImageFormat::Gzip => {
+                let input = BufReader::new(CountingReader::new(file, &consumed));
+                Box::new(flate2::bufread::MultiGzDecoder::new(input))
+            }
+            ImageFormat::Zstd => Box::new(zstd::stream::read::Decoder::new(CountingReader::new(file, &consumed))?),
+            ImageFormat::Bzip2 => {
+                let input = BufReader::new(CountingReader::new(file, &consumed));
+                Box::new(bzip2::bufread::MultiBzDecoder::new(input))
+            }
+            ImageFormat::Zip => {
+                // Read the entry straight from its offset so the decoder owns
+                // the file instead of borrowing the archive.
+                let (header_start, data_start, compressed_size) = {
+                    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+                    let index = zip_image_index(&mut archive)?;
+                    let entry = archive.by_index_raw(index)?;
+                    if entry.encrypted() {
+                        return Err(DecompressError::UnsupportedEntry {
+                            name: entry.name().to_string(),
+                            reason: "entry is encrypted".to_string(),
+                        });
+                    }
+                    (entry.header_start(), entry.data_start(), entry.compressed_size())
+                };
+
+                // The method comes from the local header: built without its
+                // codecs, the zip crate only knows deflate as "unsupported".
+                let mut method = [0u8; 2];
+                file.seek(SeekFrom::Start(header_start + ZIP_METHOD_OFFSET))?;
+                file.read_exact(&mut method)?;
+
+                file.seek(SeekFrom::Start(data_start))?;
+                let input = CountingReader::new(file, &consumed).take(compressed_size);
+                match u16::from_le_bytes(method) {
+                    ZIP_STORED => Box::new(input),
+                    ZIP_DEFLATED => Box::new(flate2::read::DeflateDecoder::new(input)),
+                    other => {
+                        return Err(DecompressError::UnsupportedEntry {
+                            name: info.entry.clone().unwrap_or_default(),
+                            reason: format!("compression method {} is not supported", other),
+                        });
+                    }
+                }
+            }
+        };
+
+        Ok(ImageSource { info, reader, consumed })
+    }
+
+    /// Counter of compressed bytes read from the file so far.
+    pub fn consumed(&self) -> Arc<AtomicU64> {
+        self.consumed.clone()
+    }
+}
+
+impl Read for ImageSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R, count: &Arc<AtomicU64>) -> Self {
+        CountingReader { inner, count: count.clone() }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+fn detect(file: &mut File) -> io::Result<ImageFormat> {
+    let mut magic = [0u8; 6];
+    let mut filled = 0;
+    while filled < magic.len() {
+        match file.read(&mut magic[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let magic = &magic[..filled];
+    let format = if magic.starts_with(XZ_MAGIC) {
+        ImageFormat::Xz
+    } else if magic.starts_with(GZIP_MAGIC) {
+        ImageFormat::Gzip
+    } else if magic.len() >= 4 && u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]) == ZSTD_MAGIC {
+        ImageFormat::Zstd
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        ImageFormat::Bzip2
+    } else if magic.starts_with(ZIP_MAGIC) {
+        ImageFormat::Zip
+    } else {
+        ImageFormat::Raw
+    };
+    Ok(format)
+}
+
+// Pick the image out of an archive: the only member with an image extension,
+// or failing that the only regular file. macOS resource forks are ignored.
+fn zip_image_index<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Result<usize, DecompressError> {
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index)?;
+        if entry.is_file() && !entry.name().starts_with("__MACOSX/") {
+            files.push((index, entry.name().to_string()));
+        }
+    }
+
+    let images: Vec<&(usize, String)> = files
+        .iter()
+        .filter(|(_, name)| {
+            Path::new(name)
+                .extension()
+                .map(|extension| IMAGE_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    match (images.as_slice(), files.as_slice()) {
+        ([(index, _)], _) => Ok(*index),
+        ([], [(index, _)]) => Ok(*index),
+        ([], []) => Err(DecompressError::NoImageInArchive),
+        ([], files) => Err(DecompressError::AmbiguousArchive(files.iter().map(|(_, name)| name.clone()).collect())),
+        (images, _) => Err(DecompressError::AmbiguousArchive(images.iter().map(|(_, name)| name.clone()).collect())),
+    }
+}
+
+// Sum the uncompressed sizes in the index of every stream, walking backwards
+// from the end of the file. Returns None if the layout is not understood.
+fn xz_uncompressed_size(file: &mut File, len: u64) -> io::Result<Option<u64>> {
+    let mut end = len;
+    let mut total = 0u64;
+
+    while end > 0 {
+        // Stream padding is a multiple of four zero bytes
+        let mut word = [0u8; 4];
+        file.seek(SeekFrom::Start(end.saturating_sub(4)))?;
+        file.read_exact(&mut word)?;
+        if word == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        if end < 2 * XZ_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut footer = [0u8; 12];
+        file.seek(SeekFrom::Start(end - XZ_HEADER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        if &footer[10..] != XZ_FOOTER_MAGIC {
+            return Ok(None);
+        }
+
+        let backward_size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]);
+        let index_size = (backward_size as u64 + 1) * 4;
+        let index_start = match (end - XZ_HEADER_SIZE).checked_sub(index_size) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+
+        let mut index = vec![0u8; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+
+        let (blocks_size, stream_total) = match parse_xz_index(&index) {
+            Some(sizes) => sizes,
+            None => return Ok(None),
+        };
+
+        let stream_start = match index_start.checked_sub(blocks_size + XZ_HEADER_SIZE) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let mut magic = [0u8; 6];
+        file.seek(SeekFrom::Start(stream_start))?;
+        file.read_exact(&mut magic)?;
+        if magic != XZ_MAGIC {
+            return Ok(None);
+        }
+
+        total += stream_total;
+        end = stream_start;
+    }
+
+    Ok(Some(total))
+}
+
+// Returns (size of the padded blocks, total uncompressed size).
+fn parse_xz_index(index: &[u8]) -> Option<(u64, u64)> {
+    if index.first() != Some(&0) {
+        return None;
+    }
+    let mut position = 1;
+    let records = read_varint(index, &mut position)?;
+
+    let mut blocks = 0u64;
+    let mut uncompressed = 0u64;
+    for _ in 0..records {
+        let unpadded = read_varint(index, &mut position)?;
+        uncompressed = uncompressed.checked_add(read_varint(index, &mut position)?)?;
+        blocks = blocks.checked_add(unpadded.div_ceil(4) * 4)?;
+    }
+    Some((blocks, uncompressed))
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in 0..9 {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Sum the content sizes of every zstd frame, skipping from frame to frame
+// over the block headers. Returns None if any frame omits its size.
+fn zstd_content_size(file: &mut File, len: u64) -> io::Result<Option<u64>> {
+    let mut offset = 0u64;
+    let mut total = 0u64;
+
+    while offset < len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut word = [0u8; 4];
+        file.read_exact(&mut word)?;
+        let magic = u32::from_le_bytes(word);
+
+        if magic & 0xffff_fff0 == ZSTD_SKIPPABLE_MAGIC {
+            file.read_exact(&mut word)?;
+            offset += 8 + u32::from_le_bytes(word) as u64;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Ok(None);
+        }
+
+        let mut descriptor = [0u8; 1];
+        file.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dictionary_bytes = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let size_bytes = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        let window_bytes = if single_segment { 0 } else { 1 };
+
+        let mut header = vec![0u8; window_bytes + dictionary_bytes + size_bytes];
+        file.read_exact(&mut header)?;
+        let mut size = [0u8; 8];
+        size[..size_bytes].copy_from_slice(&header[window_bytes + dictionary_bytes..]);
+        let mut content_size = u64::from_le_bytes(size);
+        if size_bytes == 2 {
+            content_size += 256;
+        }
+        total += content_size;
+
+        offset += 5 + header.len() as u64;
+        loop {
+            let mut block = [0u8; 3];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut block)?;
+            let block = u32::from_le_bytes([block[0], block[1], block[2], 0]);
+            let last = block & 1 != 0;
+            let block_size = (block >> 3) as u64;
+            // RLE blocks store a single byte regardless of their size
+            let stored = if (block >> 1) & 3 == 1 { 1 } else { block_size };
+            offset += 3 + stored;
+            if last {
+                break;
+            }
+        }
+        if has_checksum {
+            offset += 4;
+        }
+    }
+
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    // A third zeros (RLE and highly compressible blocks), then noise.
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|index| {
+                if index < len / 3 {
+                    return 0;
+                }
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A zip archive of `(name, data, deflated)` members.
+    fn zip(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data, deflated) in members {
+            let (method, stored) = if *deflated { (ZIP_DEFLATED, deflate(data)) } else { (ZIP_STORED, data.to_vec()) };
+            let mut crc = flate2::Crc::new();
+            crc.update(data);
+            let mut fields = Vec::new();
+            for value in [20u16, 0, method, 0, 0] {
+                fields.extend(value.to_le_bytes());
+            }
+            for value in [crc.sum(), stored.len() as u32, data.len() as u32] {
+                fields.extend(value.to_le_bytes());
+            }
+            fields.extend((name.len() as u16).to_le_bytes());
+            fields.extend(0u16.to_le_bytes());
+
+            directory.extend(0x0201_4b50u32.to_le_bytes());
+            directory.extend(20u16.to_le_bytes());
+            directory.extend(&fields);
+            directory.extend([0u8; 10]);
+            directory.extend((archive.len() as u32).to_le_bytes());
+            directory.extend(name.as_bytes());
+
+            archive.extend(ZIP_MAGIC);
+            archive.extend(&fields);
+            archive.extend(name.as_bytes());
+            archive.extend(&stored);
+        }
+        let directory_start = archive.len() as u32;
+        archive.extend(&directory);
+        archive.extend(0x0605_4b50u32.to_le_bytes());
+        archive.extend([0u8; 4]);
+        archive.extend((members.len() as u16).to_le_bytes());
+        archive.extend((members.len() as u16).to_le_bytes());
+        archive.extend((directory.len() as u32).to_le_bytes());
+        archive.extend(directory_start.to_le_bytes());
+        archive.extend([0u8; 2]);
+        archive
+    }
+
+    fn write(dir: &tempfile::TempDir, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn read_all(path: &Path) -> io::Result<Vec<u8>> {
+        let mut source = ImageSource::open(path).map_err(|e| io::Error::other(e.to_string()))?;
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn assert_round_trip(path: &Path, format: ImageFormat, size: Option<u64>, data: &[u8]) {
+        let info = probe(path).unwrap();
+        assert_eq!((info.format, info.size), (format, size), "{}", path.display());
+        assert_eq!(info.compressed_size, std::fs::metadata(path).unwrap().len());
+
+        let mut source = ImageSource::open(path).unwrap();
+        let consumed = source.consumed();
+        let mut decoded = Vec::new();
+        source.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == data, "{} did not round-trip", path.display());
+        assert!(consumed.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn streams_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = sample(700 * 1024);
+        let len = Some(data.len() as u64);
+
+        assert_round_trip(&write(&dir, "image.img", &data), ImageFormat::Raw, len, &data);
+        assert_round_trip(&write(&dir, "image.img.xz", &xz(&data)), ImageFormat::Xz, len, &data);
+        assert_round_trip(&write(&dir, "image.img.gz", &gzip(&data)), ImageFormat::Gzip, None, &data);
+        assert_round_trip(&write(&dir, "image.img.bz2", &bzip2(&data)), ImageFormat::Bzip2, None, &data);
+        assert_round_trip(&write(&dir, "image.img.zst", &zstd::bulk::compress(&data, 1).unwrap()), ImageFormat::Zstd, len, &data);
+
+        let stored = write(&dir, "stored.zip", &zip(&[("image.img", &data, false)]));
+        assert_round_trip(&stored, ImageFormat::Zip, len, &data);
+        let deflated = write(&dir, "deflated.zip", &zip(&[("image.img", &data, true)]));
+        assert_round_trip(&deflated, ImageFormat::Zip, len, &data);
+        assert_eq!(probe(&deflated).unwrap().entry.as_deref(), Some("image.img"));
+    }
+
+    #[test]
+    fn sums_sizes_across_streams_and_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (sample(300 * 1024), sample(70 * 1024 + 3));
+        let joined = [first.clone(), second.clone()].concat();
+        let len = Some(joined.len() as u64);
+
+        // Concatenated xz streams, with stream padding between them
+        let xz_streams = [xz(&first), vec![0; 8], xz(&second), vec![0; 4]].concat();
+        assert_round_trip(&write(&dir, "multi.xz", &xz_streams), ImageFormat::Xz, len, &joined);
+
+        // zstd frames around a skippable frame
+        let mut skippable = (ZSTD_SKIPPABLE_MAGIC | 7).to_le_bytes().to_vec();
+        skippable.extend(5u32.to_le_bytes());
+        skippable.extend(b"notes");
+        let frames = [zstd::bulk::compress(&first, 3).unwrap(), skippable, zstd::bulk::compress(&second, 3).unwrap()].concat();
+        assert_round_trip(&write(&dir, "multi.zst", &frames), ImageFormat::Zstd, len, &joined);
+
+        // Multi-member gzip and bzip2 streams decode to the whole image
+        assert_round_trip(&write(&dir, "multi.gz", &[gzip(&first), gzip(&second)].concat()), ImageFormat::Gzip, None, &joined);
+        assert_round_trip(&write(&dir, "multi.bz2", &[bzip2(&first), bzip2(&second)].concat()), ImageFormat::Bzip2, None, &joined);
+    }
+
+    #[test]
+    fn zstd_frames_without_a_size_fall_back_to_compressed_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = sample(200 * 1024);
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 1).unwrap();
+        encoder.include_contentsize(false).unwrap();
+        encoder.write_all(&data).unwrap();
+        let path = write(&dir, "nosize.zst", &encoder.finish().unwrap());
+
+        assert_round_trip(&path, ImageFormat::Zstd, None, &data);
+        let info = probe(&path).unwrap();
+        assert_eq!(info.progress_total(), info.compressed_size);
+    }
+
+    #[test]
+    fn picks_the_image_out_of_a_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let image: &[u8] = b"image bytes";
+        let entry = |members: &[(&str, &[u8], bool)]| probe(&write(&dir, "archive.zip", &zip(members))).map(|info| info.entry);
+
+        let with_readme = entry(&[("README.txt", b"read me", false), ("images/", b"", false), ("images/disk.IMG", image, true)]);
+        assert_eq!(with_readme.unwrap().as_deref(), Some("images/disk.IMG"));
+        let with_fork = entry(&[("__MACOSX/._disk.img", b"fork", false), ("disk.img", image, false)]);
+        assert_eq!(with_fork.unwrap().as_deref(), Some("disk.img"));
+        assert_eq!(entry(&[("firmware.dat", image, false)]).unwrap().as_deref(), Some("firmware.dat"));
+
+        assert!(matches!(entry(&[("a.img", image, false), ("b.iso", image, false)]), Err(DecompressError::AmbiguousArchive(names)) if names == ["a.img", "b.iso"]));
+        assert!(matches!(entry(&[("a.txt", image, false), ("b.txt", image, false)]), Err(DecompressError::AmbiguousArchive(_))));
+        assert!(matches!(entry(&[("empty/", b"", false)]), Err(DecompressError::NoImageInArchive)));
+    }
+
+    #[test]
+    fn truncated_and_corrupt_input_fails_to_decode() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = sample(400 * 1024);
+
+        // A cut-off xz file loses its index, so its size is unknown too
+        let mut cut = xz(&data);
+        cut.truncate(cut.len() * 2 / 3);
+        let path = write(&dir, "cut.xz", &cut);
+        assert_eq!(probe(&path).unwrap().size, None);
+        assert!(read_all(&path).is_err());
+
+        let mut cut = gzip(&data);
+        cut.truncate(cut.len() - 100);
+        assert!(read_all(&write(&dir, "cut.gz", &cut)).is_err());
+
+        let mut cut = bzip2(&data);
+        cut.truncate(cut.len() / 2);
+        assert!(read_all(&write(&dir, "cut.bz2", &cut)).is_err());
+
+        let mut cut = zstd::bulk::compress(&data, 1).unwrap();
+        cut.truncate(cut.len() / 2);
+        assert!(probe(&write(&dir, "cut.zst", &cut)).is_err());
+
+        // Noise is stored in raw blocks, so only the frame checksum catches it
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 1).unwrap();
+        encoder.include_checksum(true).unwrap();
+        encoder.write_all(&data).unwrap();
+        let mut corrupt = encoder.finish().unwrap();
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0xff;
+        corrupt[middle + 1] ^= 0xff;
+        assert!(read_all(&write(&dir, "corrupt.zst", &corrupt)).is_err());
+
+        // Garbage in a deflated zip member
+        let mut archive = zip(&[("disk.img", &data, true)]);
+        let start = 30 + "disk.img".len();
+        archive[start..start + 64].fill(0xff);
+        assert!(read_all(&write(&dir, "corrupt.zip", &archive)).is_err());
+
+        // An unknown compression method is refused up front
+        let mut archive = zip(&[("disk.img", &data, false)]);
+        archive[ZIP_METHOD_OFFSET as usize] = 14;
+        assert!(matches!(ImageSource::open(&write(&dir, "lzma.zip", &archive)), Err(DecompressError::UnsupportedEntry { .. })));
+    }
+
+    #[test]
+    fn parses_xz_index_varints() {
+        // Two records: (unpadded 5, uncompressed 300) and (unpadded 130, uncompressed 1)
+        let index = [0x00, 0x02, 0x05, 0xac, 0x02, 0x82, 0x01, 0x01];
+        assert_eq!(parse_xz_index(&index), Some((8 + 132, 301)));
+        assert_eq!(parse_xz_index(&index[..6]), None);
+        assert_eq!(parse_xz_index(&[0x01, 0x00]), None);
+        assert_eq!(read_varint(&[0xff; 9], &mut 0), None);
+    }
+}
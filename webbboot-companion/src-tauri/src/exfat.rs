@@ -0,0 +1,522 @@
+// Mount-free file access for exFAT volumes.
+//
+// Works on volumes made by mkfs.exfat or Windows: entry sets with their
+// checksums and name hashes, the allocation bitmap, the volume's up-case
+// table, and data stored with or without a FAT chain. Files and
+// directories created here always get a FAT chain, which every
+// implementation reads.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::fatfs::{self, DirRef, Entry, FatFs, FatTable, FatTime, VolumeError, SLOT_SIZE};
+
+const FIRST_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0xffff_ffff;
+
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xc0;
+const ENTRY_NAME: u8 = 0xc1;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+const ATTR_ARCHIVE: u16 = 0x20;
+
+/// Stream extension flags.
+const ALLOCATION_POSSIBLE: u8 = 0x01;
+const NO_FAT_CHAIN: u8 = 0x02;
+
+const NAME_UNITS_PER_ENTRY: usize = 15;
+/// Timestamps are written in UTC: offset valid, zero minutes.
+const UTC_OFFSET: u8 = 0x80;
+/// Offset of PercentInUse in the boot sector, outside the boot checksum.
+const PERCENT_IN_USE_OFFSET: u64 = 112;
+const PERCENT_UNKNOWN: u8 = 0xff;
+const BACKUP_BOOT_SECTOR: u64 = 12;
+
+/// An existing exFAT volume opened for mount-free file access.
+pub struct ExfatVolume<D> {
+    disk: D,
+    offset: u64,
+    sector_size: u64,
+    cluster_size: u64,
+    heap_offset: u64,
+    /// FAT entries in use: the cluster count plus the two reserved entries.
+    entries: u32,
+    root_cluster: u32,
+    fat: FatTable,
+    bitmap: Vec<u8>,
+    bitmap_offset: u64,
+    bitmap_dirty: bool,
+    upcase: Vec<u16>,
+    next_free: u32,
+    track_percent: bool,
+}
+
+impl<D: Read + Write + Seek> ExfatVolume<D> {
+    /// Open the volume whose boot sector `boot` was read `offset` bytes into `disk`.
+    pub fn open(mut disk: D, offset: u64, boot: &[u8; 512]) -> Result<Self, VolumeError> {
+        let u32_at = |at: usize| u32::from_le_bytes([boot[at], boot[at + 1], boot[at + 2], boot[at + 3]]);
+
+        let sector_shift = boot[108] as u32;
+        let cluster_shift = boot[109] as u32;
+        if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > 25 {
+            return Err(VolumeError::Corrupt(format!(
+                "sector shift {} and cluster shift {}",
+                sector_shift, cluster_shift
+            )));
+        }
+        let sector_size = 1u64 << sector_shift;
+        let cluster_size = sector_size << cluster_shift;
+        let fat_offset = u32_at(80) as u64;
+        let fat_length = u32_at(84) as u64;
+        let heap_offset = offset + u32_at(88) as u64 * sector_size;
+        let cluster_count = u32_at(92) as u64;
+        let root_cluster = u32_at(96);
+
+        let entries = (cluster_count + 2).min(fat_length * sector_size / 4).min(u32::MAX as u64) as u32;
+        if root_cluster < FIRST_CLUSTER || root_cluster >= entries {
+            return Err(VolumeError::Corrupt(format!("root directory at cluster {}", root_cluster)));
+        }
+
+        // A second FAT only exists for TexFAT; bit 0 of the volume flags picks the live one
+        let active_fat = if boot[110] == 2 { (boot[106] & 1) as u64 } else { 0 };
+        let fat_copy = offset + (fat_offset + active_fat * fat_length) * sector_size;
+        let fat = FatTable::load(&mut disk, vec![fat_copy], entries, sector_size as usize, END_OF_CHAIN, END_OF_CHAIN)?;
+
+        let mut volume = ExfatVolume {
+            disk,
+            offset,
+            sector_size,
+            cluster_size,
+            heap_offset,
+            entries,
+            root_cluster,
+            fat,
+            bitmap: Vec::new(),
+            bitmap_offset: 0,
+            bitmap_dirty: false,
+            upcase: Vec::new(),
+            next_free: FIRST_CLUSTER,
+            track_percent: boot[PERCENT_IN_USE_OFFSET as usize] != PERCENT_UNKNOWN,
+        };
+
+        // The bitmap and up-case table are described in the root directory
+        let root = volume.root();
+        let (_, data) = volume.read_dir(&root)?;
+        let mut bitmap = None;
+        let mut upcase = None;
+        for slot in data.chunks_exact(SLOT_SIZE) {
+            let first = u32::from_le_bytes([slot[20], slot[21], slot[22], slot[23]]);
+            let len = u64::from_le_bytes(slot[24..32].try_into().unwrap());
+            match slot[0] {
+                0x00 => break,
+                // Bit 0 of the bitmap flags says which FAT a bitmap belongs to
+                ENTRY_BITMAP if (slot[1] & 1) as u64 == active_fat => bitmap = Some((first, len)),
+                ENTRY_UPCASE => upcase = Some((first, len)),
+                _ => {}
+            }
+        }
+
+        let (bitmap_cluster, bitmap_len) = bitmap.ok_or_else(|| VolumeError::Corrupt("no allocation bitmap".to_string()))?;
+        if bitmap_len < cluster_count.div_ceil(8) {
+            return Err(VolumeError::Corrupt(format!("allocation bitmap of {} bytes", bitmap_len)));
+        }
+        volume.bitmap_offset = volume.cluster_offset(bitmap_cluster)?;
+        volume.bitmap = vec![0u8; cluster_count.div_ceil(8) as usize];
+        volume.disk.seek(SeekFrom::Start(volume.bitmap_offset))?;
+        volume.disk.read_exact(&mut volume.bitmap)?;
+
+        let (upcase_cluster, upcase_len) = upcase.ok_or_else(|| VolumeError::Corrupt("no up-case table".to_string()))?;
+        let mut table = vec![0u8; (upcase_len as usize).min(2 * 65_536)];
+        volume.disk.seek(SeekFrom::Start(volume.cluster_offset(upcase_cluster)?))?;
+        volume.disk.read_exact(&mut table)?;
+        volume.upcase = expand_upcase(&table);
+
+        Ok(volume)
+    }
+
+    /// Byte offset of `cluster`, which must exist on the volume.
+    fn cluster_offset(&self, cluster: u32) -> Result<u64, VolumeError> {
+        if cluster < FIRST_CLUSTER || cluster >= self.entries {
+            return Err(VolumeError::Corrupt(format!("cluster {} is out of range", cluster)));
+        }
+        Ok(self.heap_offset + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size)
+    }
+
+    /// Clusters holding `len` bytes starting at `first`, following the FAT
+    /// unless the data is one contiguous run.
+    fn clusters_of(&self, first: u32, len: u64, contiguous: bool) -> Result<Vec<u32>, VolumeError> {
+        if !contiguous {
+            return self.fat.chain(first);
+        }
+        let count = len.div_ceil(self.cluster_size);
+        if first < FIRST_CLUSTER || first as u64 + count > self.entries as u64 {
+            return Err(VolumeError::Corrupt(format!("contiguous run of {} clusters at {}", count, first)));
+        }
+        Ok((first..first + count as u32).collect())
+    }
+
+    fn read_dir(&mut self, dir: &DirRef) -> Result<(Vec<u32>, Vec<u8>), VolumeError> {
+        let clusters = match dir.contiguous_len {
+            Some(len) => self.clusters_of(dir.first_cluster, len, true)?,
+            None => self.fat.chain(dir.first_cluster)?,
+        };
+        if clusters.is_empty() {
+            return Err(VolumeError::Corrupt(format!("directory at cluster {} has no clusters", dir.first_cluster)));
+        }
+        let data = fatfs::read_clusters(&mut self.disk, self.heap_offset, self.cluster_size, &clusters)?;
+        Ok((clusters, data))
+    }
+
+    fn is_allocated(&self, cluster: u32) -> bool {
+        let index = (cluster - FIRST_CLUSTER) as usize;
+        self.bitmap[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn mark(&mut self, cluster: u32, allocated: bool) {
+        let index = (cluster - FIRST_CLUSTER) as usize;
+        if allocated {
+            self.bitmap[index / 8] |= 1 << (index % 8);
+        } else {
+            self.bitmap[index / 8] &= !(1 << (index % 8));
+        }
+        self.bitmap_dirty = true;
+    }
+
+    /// Take `count` free clusters from the bitmap and chain them in the FAT.
+    fn allocate(&mut self, count: usize) -> Result<Vec<u32>, VolumeError> {
+        let mut found = Vec::with_capacity(count);
+        let mut cluster = self.next_free;
+        for _ in FIRST_CLUSTER..self.entries {
+            if found.len() == count {
+                break;
+            }
+            if !self.is_allocated(cluster) {
+                found.push(cluster);
+            }
+            cluster = if cluster + 1 >= self.entries { FIRST_CLUSTER } else { cluster + 1 };
+        }
+        if found.len() < count {
+            return Err(VolumeError::NoSpace);
+        }
+        for &cluster in &found {
+            self.mark(cluster, true);
+        }
+        self.fat.link(&found);
+        if let Some(&last) = found.last() {
+            self.next_free = if last + 1 >= self.entries { FIRST_CLUSTER } else { last + 1 };
+        }
+        Ok(found)
+    }
+
+    // FAT entries of contiguous runs are meaningless, so only chained
+    // clusters have theirs cleared.
+    fn release(&mut self, clusters: &[u32], chained: bool) {
+        for &cluster in clusters {
+            self.mark(cluster, false);
+            if chained {
+                self.fat.set(cluster, 0);
+            }
+        }
+    }
+
+    /// Write `set` into the first run of free slots in `dir`, growing the
+    /// directory by a cluster at a time when it is full.
+    fn add_entry_set(&mut self, dir: &DirRef, set: &[u8]) -> Result<usize, VolumeError> {
+        let (mut clusters, mut data) = self.read_dir(dir)?;
+        let count = set.len() / SLOT_SIZE;
+        let mut chained = dir.contiguous_len.is_none();
+
+        let start = loop {
+            if let Some(start) = free_run(&data, count) {
+                break start;
+            }
+            let cluster = self.allocate(1)?[0];
+            let zeros = vec![0u8; self.cluster_size as usize];
+            let written = self.cluster_offset(cluster).and_then(|at| {
+                self.disk.seek(SeekFrom::Start(at))?;
+                self.disk.write_all(&zeros)?;
+                Ok(())
+            });
+            if let Err(e) = written {
+                self.release(&[cluster], true);
+                return Err(e);
+            }
+
+            // A contiguous directory that cannot grow in place gets a FAT chain
+            clusters.push(cluster);
+            self.fat.link(&clusters);
+            chained |= cluster != clusters[clusters.len() - 2] + 1;
+            data.extend_from_slice(&zeros);
+
+            if let Some(owner) = &dir.owner {
+                let (parent, entry) = owner.as_ref();
+                self.update_stream(parent, entry, data.len() as u64, chained)?;
+            }
+        };
+
+        data[start * SLOT_SIZE..(start + count) * SLOT_SIZE].copy_from_slice(set);
+        fatfs::write_slots(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, &data, start..start + count)?;
+        Ok(start)
+    }
+
+    /// Rewrite the size and chain flag of `entry` in `parent` after its
+    /// directory grew.
+    fn update_stream(&mut self, parent: &DirRef, entry: &Entry, len: u64, chained: bool) -> Result<(), VolumeError> {
+        let (clusters, mut data) = self.read_dir(parent)?;
+        let set = &mut data[entry.slot * SLOT_SIZE..(entry.slot + entry.slots) * SLOT_SIZE];
+        let stream = &mut set[SLOT_SIZE..2 * SLOT_SIZE];
+        if chained {
+            stream[1] &= !NO_FAT_CHAIN;
+        }
+        stream[8..16].copy_from_slice(&len.to_le_bytes());
+        stream[24..32].copy_from_slice(&len.to_le_bytes());
+        let checksum = set_checksum(set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+        fatfs::write_slots(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, &data, entry.slot..entry.slot + entry.slots)?;
+        Ok(())
+    }
+
+    /// Build the file, stream extension and name entries for a new entry.
+    fn entry_set(&self, name: &str, attributes: u16, first_cluster: u32, len: u64) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let name_entries = units.len().div_ceil(NAME_UNITS_PER_ENTRY);
+        let mut set = vec![0u8; (2 + name_entries) * SLOT_SIZE];
+
+        let now = FatTime::now();
+        let stamp = (now.date as u32) << 16 | now.time as u32;
+        set[0] = ENTRY_FILE;
+        set[1] = (1 + name_entries) as u8;
+        set[4..6].copy_from_slice(&attributes.to_le_bytes());
+        for at in [8, 12, 16] {
+            set[at..at + 4].copy_from_slice(&stamp.to_le_bytes());
+        }
+        set[20] = now.centiseconds;
+        set[21] = now.centiseconds;
+        set[22..25].fill(UTC_OFFSET);
+
+        let stream = &mut set[SLOT_SIZE..2 * SLOT_SIZE];
+        stream[0] = ENTRY_STREAM;
+        stream[1] = ALLOCATION_POSSIBLE;
+        stream[3] = units.len() as u8;
+        stream[4..6].copy_from_slice(&self.name_hash(&units).to_le_bytes());
+        stream[8..16].copy_from_slice(&len.to_le_bytes());
+        stream[20..24].copy_from_slice(&first_cluster.to_le_bytes());
+        stream[24..32].copy_from_slice(&len.to_le_bytes());
+
+        for (index, chunk) in units.chunks(NAME_UNITS_PER_ENTRY).enumerate() {
+            let slot = &mut set[(2 + index) * SLOT_SIZE..(3 + index) * SLOT_SIZE];
+            slot[0] = ENTRY_NAME;
+            for (unit, bytes) in chunk.iter().zip(slot[2..].chunks_exact_mut(2)) {
+                bytes.copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let checksum = set_checksum(&set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+        set
+    }
+
+    fn name_hash(&self, units: &[u16]) -> u16 {
+        units.iter().fold(0u16, |hash, &unit| {
+            let upper = self.upcase[unit as usize];
+            let hash = hash.rotate_right(1).wrapping_add(upper & 0xff);
+            hash.rotate_right(1).wrapping_add(upper >> 8)
+        })
+    }
+
+    fn create(&mut self, dir: &DirRef, name: &str, attributes: u16, clusters: &[u32], len: u64) -> Result<Entry, VolumeError> {
+        fatfs::check_name(name)?;
+        let first_cluster = clusters.first().copied().unwrap_or(0);
+        let set = self.entry_set(name, attributes, first_cluster, len);
+        let slot = self.add_entry_set(dir, &set)?;
+        Ok(Entry {
+            name: name.to_string(),
+            is_dir: attributes & ATTR_DIRECTORY != 0,
+            first_cluster,
+            size: len,
+            contiguous: false,
+            slot,
+            slots: set.len() / SLOT_SIZE,
+        })
+    }
+}
+
+impl<D: Read + Write + Seek> FatFs for ExfatVolume<D> {
+    fn root(&self) -> DirRef {
+        DirRef { first_cluster: self.root_cluster, contiguous_len: None, owner: None }
+    }
+
+    fn entries(&mut self, dir: &DirRef) -> Result<Vec<Entry>, VolumeError> {
+        let (_, data) = self.read_dir(dir)?;
+        Ok(parse_dir(&data))
+    }
+
+    fn create_dir(&mut self, dir: &DirRef, name: &str) -> Result<Entry, VolumeError> {
+        let clusters = self.allocate(1)?;
+        let zeros = vec![0u8; self.cluster_size as usize];
+        let result = self
+            .cluster_offset(clusters[0])
+            .and_then(|at| {
+                self.disk.seek(SeekFrom::Start(at))?;
+                self.disk.write_all(&zeros)?;
+                Ok(())
+            })
+            .and_then(|()| self.create(dir, name, ATTR_DIRECTORY, &clusters, self.cluster_size));
+        if result.is_err() {
+            self.release(&clusters, true);
+        }
+        result
+    }
+
+    fn create_file(&mut self, dir: &DirRef, name: &str, data: &mut dyn Read, len: u64) -> Result<Entry, VolumeError> {
+        let clusters = self.allocate(len.div_ceil(self.cluster_size) as usize)?;
+        let result = fatfs::write_data(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, data, len)
+            .map_err(VolumeError::from)
+            .and_then(|()| self.create(dir, name, ATTR_ARCHIVE, &clusters, len));
+        if result.is_err() {
+            self.release(&clusters, true);
+        }
+        result
+    }
+
+    fn read(&mut self, entry: &Entry) -> Result<Vec<u8>, VolumeError> {
+        let clusters = if entry.first_cluster >= FIRST_CLUSTER {
+            self.clusters_of(entry.first_cluster, entry.size, entry.contiguous)?
+        } else {
+            Vec::new()
+        };
+        let mut data = fatfs::read_clusters(&mut self.disk, self.heap_offset, self.cluster_size, &clusters)?;
+        if (data.len() as u64) < entry.size {
+            return Err(VolumeError::Corrupt(format!("{} has fewer clusters than its size needs", entry.name)));
+        }
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+
+    fn delete(&mut self, dir: &DirRef, entry: &Entry) -> Result<(), VolumeError> {
+        let (clusters, mut data) = self.read_dir(dir)?;
+        for slot in entry.slot..entry.slot + entry.slots {
+            data[slot * SLOT_SIZE] &= !ENTRY_IN_USE;
+        }
+        fatfs::write_slots(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, &data, entry.slot..entry.slot + entry.slots)?;
+
+        if entry.first_cluster >= FIRST_CLUSTER {
+            let owned = self.clusters_of(entry.first_cluster, entry.size, entry.contiguous)?;
+            self.release(&owned, !entry.contiguous);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VolumeError> {
+        self.fat.flush(&mut self.disk)?;
+
+        if self.bitmap_dirty {
+            self.disk.seek(SeekFrom::Start(self.bitmap_offset))?;
+            self.disk.write_all(&self.bitmap)?;
+            self.bitmap_dirty = false;
+        }
+
+        // PercentInUse sits outside the boot region checksum, so both copies
+        // can be updated in place
+        if self.track_percent {
+            let clusters = (self.entries - FIRST_CLUSTER) as u64;
+            let used: u64 = self.bitmap.iter().map(|byte| byte.count_ones() as u64).sum();
+            let percent = [(used * 100 / clusters.max(1)).min(100) as u8];
+            for sector in [0, BACKUP_BOOT_SECTOR] {
+                self.disk.seek(SeekFrom::Start(self.offset + sector * self.sector_size + PERCENT_IN_USE_OFFSET))?;
+                self.disk.write_all(&percent)?;
+            }
+        }
+
+        self.disk.flush()?;
+        Ok(())
+    }
+}
+
+// File and directory entry sets in a directory's raw slots. Sets that are
+// cut short or lack a stream extension are skipped.
+fn parse_dir(data: &[u8]) -> Vec<Entry> {
+    let slots: Vec<&[u8]> = data.chunks_exact(SLOT_SIZE).collect();
+    let mut found = Vec::new();
+    let mut index = 0;
+
+    while index < slots.len() {
+        let slot = slots[index];
+        if slot[0] == 0x00 {
+            break;
+        }
+        let secondary = slot[1] as usize;
+        if slot[0] != ENTRY_FILE || secondary < 2 || index + secondary >= slots.len() || slots[index + 1][0] != ENTRY_STREAM {
+            index += 1;
+            continue;
+        }
+
+        let stream = slots[index + 1];
+        let name_len = stream[3] as usize;
+        let units: Vec<u16> = slots[index + 2..=index + secondary]
+            .iter()
+            .take_while(|slot| slot[0] == ENTRY_NAME)
+            .flat_map(|slot| slot[2..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])))
+            .take(name_len)
+            .collect();
+
+        found.push(Entry {
+            name: String::from_utf16_lossy(&units),
+            is_dir: u16::from_le_bytes([slot[4], slot[5]]) & ATTR_DIRECTORY != 0,
+            first_cluster: u32::from_le_bytes([stream[20], stream[21], stream[22], stream[23]]),
+            size: u64::from_le_bytes(stream[24..32].try_into().unwrap()),
+            contiguous: stream[1] & NO_FAT_CHAIN != 0,
+            slot: index,
+            slots: secondary + 1,
+        });
+        index += secondary + 1;
+    }
+    found
+}
+
+/// First index of `count` unused slots in a row.
+fn free_run(data: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, slot) in data.chunks_exact(SLOT_SIZE).enumerate() {
+        if slot[0] & ENTRY_IN_USE == 0 {
+            run += 1;
+            if run == count {
+                return Some(index + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Checksum over an entry set, skipping the checksum field itself.
+fn set_checksum(set: &[u8]) -> u16 {
+    set.iter()
+        .enumerate()
+        .filter(|(at, _)| *at != 2 && *at != 3)
+        .fold(0u16, |checksum, (_, &byte)| checksum.rotate_right(1).wrapping_add(byte as u16))
+}
+
+// The on-disk up-case table may be compressed: 0xffff followed by a count
+// means that many characters map to themselves.
+fn expand_upcase(raw: &[u8]) -> Vec<u16> {
+    let mut table: Vec<u16> = (0..=u16::MAX).collect();
+    let mut units = raw.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+    let mut next = 0usize;
+    while let Some(unit) = units.next() {
+        if next >= table.len() {
+            break;
+        }
+        if unit == 0xffff {
+            next += units.next().unwrap_or(0) as usize;
+        } else {
+            table[next] = unit;
+            next += 1;
+        }
+    }
+    table
+}
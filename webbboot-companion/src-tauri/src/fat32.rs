@@ -0,0 +1,1012 @@
+// Userspace FAT32 formatter and file access.
+//
+// Writes the boot sector, FSInfo, their backups, both FATs and an empty root
+// directory for a volume that starts `offset` bytes into a device or image,
+// so a stick can be formatted without mkfs.fat or root. `Fat32Volume` then
+// creates, replaces and deletes files with long names on any FAT32 volume
+// the same way. Everything goes through `Write + Seek`, like the
+// partition-table writer.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::fatfs::{self, DirRef, Entry, FatFs, FatTable, FatTime, VolumeError, SLOT_SIZE};
+use crate::jobs::CancelToken;
+
+const RESERVED_SECTORS: u32 = 32;
+const FAT_COUNT: u32 = 2;
+const ROOT_CLUSTER: u32 = 2;
+const FSINFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const MEDIA_FIXED: u8 = 0xf8;
+const OEM_NAME: &[u8; 8] = b"MSWIN4.1";
+const NO_LABEL: &[u8; 11] = b"NO NAME    ";
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_LONG_NAME_MASK: u8 = 0x3f;
+
+/// Cluster counts FAT32 can describe; fewer clusters would be read as FAT16.
+const MIN_CLUSTERS: u64 = 65_525;
+const MAX_CLUSTERS: u64 = 0x0fff_fff5;
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+
+const FAT_END_OF_CHAIN: u32 = 0x0fff_ffff;
+/// FAT32 entries are 28 bits; the top four are reserved and preserved.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xaa55_0000;
+
+/// Boot code for the volume boot record: ask the BIOS to try the next boot
+/// device, then halt. Partitions formatted here are data volumes.
+const BOOT_CODE: [u8; 5] = [0xcd, 0x18, 0xf4, 0xeb, 0xfd];
+
+/// Size of the zero-filled buffer used for the FATs and full formats.
+const ZERO_CHUNK: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct Fat32Options {
+    /// Volume label, already checked by `Filesystem::normalize_label`.
+    pub label: Option<String>,
+    /// Cluster size in bytes; None picks one from the volume size.
+    pub cluster_size: Option<u32>,
+    pub volume_id: u32,
+    /// Zero the whole data area instead of only the root directory.
+    pub full: bool,
+}
+
+/// Where a FAT32 volume keeps its structures, in bytes from the device start.
+#[derive(Debug, Clone, Copy)]
+pub struct Fat32Layout {
+    pub offset: u64,
+    pub sector_size: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_sectors: u32,
+    pub total_sectors: u32,
+    pub clusters: u32,
+}
+
+impl Fat32Layout {
+    pub fn cluster_size(&self) -> u64 {
+        self.sector_size as u64 * self.sectors_per_cluster as u64
+    }
+
+    /// Byte offset of FAT number `index`.
+    pub fn fat_offset(&self, index: u32) -> u64 {
+        self.offset + (self.reserved_sectors as u64 + index as u64 * self.fat_sectors as u64) * self.sector_size as u64
+    }
+
+    pub fn data_offset(&self) -> u64 {
+        self.fat_offset(FAT_COUNT)
+    }
+
+    /// Byte offset of data cluster `cluster` (numbered from 2).
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset() + (cluster - ROOT_CLUSTER) as u64 * self.cluster_size()
+    }
+}
+
+#[derive(Debug)]
+pub enum Fat32Error {
+    Io(io::Error),
+    InvalidSectorSize(u64),
+    InvalidClusterSize(u32),
+    VolumeTooSmall { clusters: u64, cluster_size: u32 },
+    VolumeTooLarge { size: u64 },
+    Cancelled,
+}
+
+impl fmt::Display for Fat32Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fat32Error::Io(e) => write!(f, "{}", e),
+            Fat32Error::InvalidSectorSize(size) => write!(f, "unsupported sector size {}", size),
+            Fat32Error::InvalidClusterSize(size) => {
+                write!(f, "cluster size {} is not a power-of-two multiple of the sector size up to 64 KiB", size)
+            }
+            Fat32Error::VolumeTooSmall { clusters, cluster_size } => write!(
+                f,
+                "volume only holds {} clusters of {} bytes; FAT32 needs at least {}",
+                clusters, cluster_size, MIN_CLUSTERS
+            ),
+            Fat32Error::VolumeTooLarge { size } => write!(f, "{} bytes is too large for FAT32", size),
+            Fat32Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Fat32Error {}
+
+impl From<io::Error> for Fat32Error {
+    fn from(e: io::Error) -> Self {
+        Fat32Error::Io(e)
+    }
+}
+
+/// Default cluster size for a volume, following Microsoft's FAT32 table.
+pub fn default_cluster_size(size: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    match size {
+        s if s <= 260 * MIB => 512,
+        s if s <= 8 * 1024 * MIB => 4 * 1024,
+        s if s <= 16 * 1024 * MIB => 8 * 1024,
+        s if s <= 32 * 1024 * MIB => 16 * 1024,
+        _ => 32 * 1024,
+    }
+}
+
+/// Work out the layout of a volume of `size` bytes without writing.
+pub fn plan(offset: u64, size: u64, sector_size: u64, cluster_size: Option<u32>) -> Result<Fat32Layout, Fat32Error> {
+    if !matches!(sector_size, 512 | 1024 | 2048 | 4096) {
+        return Err(Fat32Error::InvalidSectorSize(sector_size));
+    }
+    let sector_size = sector_size as u32;
+
+    // Small volumes use the sector size itself when the table asks for less
+    let cluster_size = cluster_size.unwrap_or_else(|| default_cluster_size(size).max(sector_size));
+    if !cluster_size.is_power_of_two() || cluster_size < sector_size || cluster_size > MAX_CLUSTER_SIZE {
+        return Err(Fat32Error::InvalidClusterSize(cluster_size));
+    }
+    let sectors_per_cluster = cluster_size / sector_size;
+
+    let total_sectors = size / sector_size as u64;
+    if total_sectors > u32::MAX as u64 {
+        return Err(Fat32Error::VolumeTooLarge { size });
+    }
+    let total_sectors = total_sectors as u32;
+    let too_small = |clusters: u64| Fat32Error::VolumeTooSmall { clusters, cluster_size };
+    if total_sectors <= RESERVED_SECTORS {
+        return Err(too_small(0));
+    }
+
+    // Size the FATs for every cluster the volume could hold if they took no
+    // space, which overestimates slightly, then pad the reserved area so the
+    // data area starts on a cluster boundary.
+    let entries_per_sector = sector_size as u64 / 4;
+    let upper_clusters = (total_sectors - RESERVED_SECTORS) as u64 / sectors_per_cluster as u64;
+    let fat_sectors = (upper_clusters + 2).div_ceil(entries_per_sector);
+    let fat_region = FAT_COUNT as u64 * fat_sectors;
+    let unaligned_data = RESERVED_SECTORS as u64 + fat_region;
+    let padding = unaligned_data.next_multiple_of(sectors_per_cluster as u64) - unaligned_data;
+    let reserved_sectors = RESERVED_SECTORS as u64 + padding;
+
+    let data_start = reserved_sectors + fat_region;
+    if data_start >= total_sectors as u64 {
+        return Err(too_small(0));
+    }
+    let clusters = (total_sectors as u64 - data_start) / sectors_per_cluster as u64;
+    if clusters < MIN_CLUSTERS {
+        return Err(too_small(clusters));
+    }
+    if clusters > MAX_CLUSTERS {
+        return Err(Fat32Error::VolumeTooLarge { size });
+    }
+
+    Ok(Fat32Layout {
+        offset,
+        sector_size,
+        sectors_per_cluster,
+        reserved_sectors: reserved_sectors as u32,
+        fat_sectors: fat_sectors as u32,
+        total_sectors,
+        clusters: clusters as u32,
+    })
+}
+
+/// Format the `size` bytes at `offset` in `disk` as FAT32. `progress` gets
+/// the number of bytes written so far; `cancel` is checked between chunks.
+pub fn format<D: Write + Seek>(
+    disk: &mut D,
+    offset: u64,
+    size: u64,
+    sector_size: u64,
+    options: &Fat32Options,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<Fat32Layout, Fat32Error> {
+    let layout = plan(offset, size, sector_size, options.cluster_size)?;
+    let sector = layout.sector_size as u64;
+    let mut written = 0u64;
+
+    // Reserved area first, so a half-formatted volume has no valid boot sector
+    let reserved_bytes = layout.reserved_sectors as u64 * sector;
+    write_zeros(disk, offset, reserved_bytes, cancel, &mut written, &mut progress)?;
+
+    // FATs, then the data area or just the root directory
+    let fat_bytes = FAT_COUNT as u64 * layout.fat_sectors as u64 * sector;
+    write_zeros(disk, layout.fat_offset(0), fat_bytes, cancel, &mut written, &mut progress)?;
+    let data_bytes = if options.full {
+        layout.clusters as u64 * layout.cluster_size()
+    } else {
+        layout.cluster_size()
+    };
+    write_zeros(disk, layout.data_offset(), data_bytes, cancel, &mut written, &mut progress)?;
+
+    let mut fat_start = [0u8; 12];
+    fat_start[0..4].copy_from_slice(&(0x0fff_ff00 | MEDIA_FIXED as u32).to_le_bytes());
+    fat_start[4..8].copy_from_slice(&FAT_END_OF_CHAIN.to_le_bytes());
+    fat_start[8..12].copy_from_slice(&FAT_END_OF_CHAIN.to_le_bytes());
+    for index in 0..FAT_COUNT {
+        write_at(disk, layout.fat_offset(index), &fat_start)?;
+    }
+
+    let label = options.label.as_deref().map(padded_label);
+    if let Some(label) = &label {
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(label);
+        entry[11] = ATTR_VOLUME_ID;
+        write_at(disk, layout.cluster_offset(ROOT_CLUSTER), &entry)?;
+    }
+
+    // Boot sector and FSInfo, each with a backup copy
+    let boot = boot_sector(&layout, options.volume_id, label.as_ref().unwrap_or(NO_LABEL));
+    let fsinfo = fsinfo_sector(&layout);
+    for base in [0, BACKUP_BOOT_SECTOR as u64] {
+        write_at(disk, offset + base * sector, &boot)?;
+        write_at(disk, offset + (base + FSINFO_SECTOR as u64) * sector, &fsinfo)?;
+    }
+
+    disk.flush()?;
+    Ok(layout)
+}
+
+/// Bytes `format` will write for a volume, for progress planning.
+pub fn format_bytes(layout: &Fat32Layout, full: bool) -> u64 {
+    let sector = layout.sector_size as u64;
+    let data = if full { layout.clusters as u64 } else { 1 } * layout.cluster_size();
+    (layout.reserved_sectors as u64 + FAT_COUNT as u64 * layout.fat_sectors as u64) * sector + data
+}
+
+fn boot_sector(layout: &Fat32Layout, volume_id: u32, label: &[u8; 11]) -> Vec<u8> {
+    let mut sector = vec![0u8; layout.sector_size as usize];
+    sector[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(OEM_NAME);
+    sector[11..13].copy_from_slice(&(layout.sector_size as u16).to_le_bytes());
+    sector[13] = layout.sectors_per_cluster as u8;
+    sector[14..16].copy_from_slice(&(layout.reserved_sectors as u16).to_le_bytes());
+    sector[16] = FAT_COUNT as u8;
+    // Root entry count and 16-bit sector counts stay zero on FAT32
+    sector[21] = MEDIA_FIXED;
+    sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+    let hidden_sectors = (layout.offset / layout.sector_size as u64).min(u32::MAX as u64) as u32;
+    sector[28..32].copy_from_slice(&hidden_sectors.to_le_bytes());
+    sector[32..36].copy_from_slice(&layout.total_sectors.to_le_bytes());
+    sector[36..40].copy_from_slice(&layout.fat_sectors.to_le_bytes());
+    sector[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    sector[48..50].copy_from_slice(&FSINFO_SECTOR.to_le_bytes());
+    sector[50..52].copy_from_slice(&BACKUP_BOOT_SECTOR.to_le_bytes());
+    sector[64] = 0x80;
+    sector[66] = 0x29;
+    sector[67..71].copy_from_slice(&volume_id.to_le_bytes());
+    sector[71..82].copy_from_slice(label);
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[90..90 + BOOT_CODE.len()].copy_from_slice(&BOOT_CODE);
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    sector
+}
+
+fn fsinfo_sector(layout: &Fat32Layout) -> Vec<u8> {
+    let mut sector = vec![0u8; layout.sector_size as usize];
+    sector[0..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+    sector[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+    // The root directory holds the first cluster
+    sector[488..492].copy_from_slice(&(layout.clusters - 1).to_le_bytes());
+    sector[492..496].copy_from_slice(&(ROOT_CLUSTER + 1).to_le_bytes());
+    sector[508..512].copy_from_slice(&FSINFO_TRAIL_SIGNATURE.to_le_bytes());
+    sector
+}
+
+/// Space-pad a label to the 11 bytes FAT directory entries use.
+fn padded_label(label: &str) -> [u8; 11] {
+    let mut padded = *b"           ";
+    for (slot, byte) in padded.iter_mut().zip(label.bytes()) {
+        *slot = byte;
+    }
+    padded
+}
+
+fn write_at<D: Write + Seek>(disk: &mut D, offset: u64, data: &[u8]) -> io::Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.write_all(data)
+}
+
+fn write_zeros<D: Write + Seek>(
+    disk: &mut D,
+    offset: u64,
+    len: u64,
+    cancel: &CancelToken,
+    written: &mut u64,
+    progress: &mut impl FnMut(u64),
+) -> Result<(), Fat32Error> {
+    let zeros = vec![0u8; ZERO_CHUNK.min(len) as usize];
+    disk.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        if cancel.is_cancelled() {
+            return Err(Fat32Error::Cancelled);
+        }
+        let chunk = remaining.min(ZERO_CHUNK);
+        disk.write_all(&zeros[..chunk as usize])?;
+        remaining -= chunk;
+        *written += chunk;
+        progress(*written);
+    }
+    Ok(())
+}
+
+/// Directory entry marker for a deleted entry.
+const DELETED: u8 = 0xe5;
+/// Stands in for a leading 0xe5 byte in a short name.
+const KANJI_LEAD: u8 = 0x05;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_UNITS: usize = 13;
+/// Byte offsets of the 13 UTF-16 units in a long-name slot.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Case flags in byte 12 of a short entry: base and extension stored lower-case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// Punctuation allowed in short names besides letters and digits.
+const SHORT_NAME_EXTRA: &str = "!#$%&'()-@^_`{}~";
+/// Directories are limited to 65,536 slots.
+const MAX_DIR_SLOTS: usize = 65_536;
+
+/// An existing FAT32 volume opened for mount-free file access.
+pub struct Fat32Volume<D> {
+    disk: D,
+    fat: FatTable,
+    cluster_size: u64,
+    heap_offset: u64,
+    /// FAT entries in use: the cluster count plus the two reserved entries.
+    entries: u32,
+    root_cluster: u32,
+    fsinfo_offset: Option<u64>,
+    /// Where the next allocation starts looking, as FSInfo suggests.
+    next_free: u32,
+}
+
+// A parsed directory entry along with its 8.3 name, which long-named
+// entries keep too and new aliases must not collide with.
+struct RawEntry {
+    entry: Entry,
+    short_name: [u8; 11],
+}
+
+impl<D: Read + Write + Seek> Fat32Volume<D> {
+    /// Open the volume whose boot sector `boot` was read `offset` bytes into `disk`.
+    pub fn open(mut disk: D, offset: u64, boot: &[u8; 512]) -> Result<Self, VolumeError> {
+        let u16_at = |at: usize| u16::from_le_bytes([boot[at], boot[at + 1]]) as u64;
+        let u32_at = |at: usize| u32::from_le_bytes([boot[at], boot[at + 1], boot[at + 2], boot[at + 3]]) as u64;
+
+        let sector_size = u16_at(11);
+        let sectors_per_cluster = boot[13] as u64;
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096) || !sectors_per_cluster.is_power_of_two() {
+            return Err(VolumeError::Corrupt(format!(
+                "{} bytes per sector and {} sectors per cluster",
+                sector_size, sectors_per_cluster
+            )));
+        }
+        let reserved_sectors = u16_at(14);
+        let fat_count = boot[16] as u64;
+        let total_sectors = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_sectors = u32_at(36);
+        let root_cluster = u32_at(44) as u32;
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        if reserved_sectors == 0 || fat_count == 0 || fat_sectors == 0 || data_start >= total_sectors {
+            return Err(VolumeError::Corrupt("boot sector describes no data area".to_string()));
+        }
+
+        // Trust neither the cluster count nor the FAT size alone
+        let clusters = ((total_sectors - data_start) / sectors_per_cluster).min(MAX_CLUSTERS);
+        let entries = (clusters + 2).min(fat_sectors * sector_size / 4) as u32;
+        if root_cluster < ROOT_CLUSTER || root_cluster >= entries {
+            return Err(VolumeError::Corrupt(format!("root directory at cluster {}", root_cluster)));
+        }
+
+        // Bit 7 of the extended flags turns mirroring off; only the active FAT is then live
+        let ext_flags = u16_at(40);
+        let fat_offset = |index: u64| offset + (reserved_sectors + index * fat_sectors) * sector_size;
+        let copies = if ext_flags & 0x80 != 0 {
+            vec![fat_offset((ext_flags & 0x0f).min(fat_count - 1))]
+        } else {
+            (0..fat_count).map(fat_offset).collect()
+        };
+        let fat = FatTable::load(&mut disk, copies, entries, sector_size as usize, FAT_ENTRY_MASK, FAT_END_OF_CHAIN)?;
+
+        let fsinfo_sector = u16_at(48);
+        let mut fsinfo_offset = None;
+        let mut next_free = ROOT_CLUSTER;
+        if fsinfo_sector > 0 && fsinfo_sector < reserved_sectors {
+            let at = offset + fsinfo_sector * sector_size;
+            let mut fsinfo = [0u8; 512];
+            disk.seek(SeekFrom::Start(at))?;
+            disk.read_exact(&mut fsinfo)?;
+            let field = |at: usize| u32::from_le_bytes([fsinfo[at], fsinfo[at + 1], fsinfo[at + 2], fsinfo[at + 3]]);
+            if field(0) == FSINFO_LEAD_SIGNATURE && field(484) == FSINFO_STRUCT_SIGNATURE {
+                fsinfo_offset = Some(at);
+                if (ROOT_CLUSTER..entries).contains(&field(492)) {
+                    next_free = field(492);
+                }
+            }
+        }
+
+        Ok(Fat32Volume {
+            disk,
+            fat,
+            cluster_size: sector_size * sectors_per_cluster,
+            heap_offset: fat_offset(fat_count),
+            entries,
+            root_cluster,
+            fsinfo_offset,
+            next_free,
+        })
+    }
+
+    fn read_dir(&mut self, dir: &DirRef) -> Result<(Vec<u32>, Vec<u8>), VolumeError> {
+        let clusters = self.fat.chain(dir.first_cluster)?;
+        if clusters.is_empty() {
+            return Err(VolumeError::Corrupt(format!("directory at cluster {} has no clusters", dir.first_cluster)));
+        }
+        let data = fatfs::read_clusters(&mut self.disk, self.heap_offset, self.cluster_size, &clusters)?;
+        Ok((clusters, data))
+    }
+
+    /// Take `count` free clusters, linked into one chain, preferring the
+    /// ones after the last allocation so files come out contiguous.
+    fn allocate(&mut self, count: usize) -> Result<Vec<u32>, VolumeError> {
+        let mut found = Vec::with_capacity(count);
+        let mut cluster = self.next_free;
+        for _ in ROOT_CLUSTER..self.entries {
+            if found.len() == count {
+                break;
+            }
+            if self.fat.is_free(cluster) {
+                found.push(cluster);
+            }
+            cluster = if cluster + 1 >= self.entries { ROOT_CLUSTER } else { cluster + 1 };
+        }
+        if found.len() < count {
+            return Err(VolumeError::NoSpace);
+        }
+        self.fat.link(&found);
+        if let Some(&last) = found.last() {
+            self.next_free = if last + 1 >= self.entries { ROOT_CLUSTER } else { last + 1 };
+        }
+        Ok(found)
+    }
+
+    fn release(&mut self, clusters: &[u32]) {
+        for &cluster in clusters {
+            self.fat.set(cluster, 0);
+        }
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.heap_offset + (cluster - ROOT_CLUSTER) as u64 * self.cluster_size
+    }
+
+    /// Add an entry named `name` to `dir`, with long-name slots when the
+    /// name is not a plain 8.3 name. Grows the directory when it is full.
+    fn add_entry(&mut self, dir: &DirRef, name: &str, attributes: u8, first_cluster: u32, size: u32) -> Result<Entry, VolumeError> {
+        fatfs::check_name(name)?;
+        let (mut clusters, mut data) = self.read_dir(dir)?;
+        let taken: Vec<[u8; 11]> = parse_dir(&data).into_iter().map(|raw| raw.short_name).collect();
+        let short = short_name(name, &taken)?;
+
+        let mut slots = match short.case {
+            Some(_) => Vec::new(),
+            None => {
+                let units: Vec<u16> = name.encode_utf16().collect();
+                long_name_slots(&units, lfn_checksum(&short.name))
+            }
+        };
+        slots.push(short_entry(&short.name, short.case.unwrap_or(0), attributes, first_cluster, size));
+
+        let start = loop {
+            if let Some(start) = free_run(&data, slots.len()) {
+                break start;
+            }
+            if data.len() / SLOT_SIZE >= MAX_DIR_SLOTS {
+                return Err(VolumeError::NoSpace);
+            }
+            let cluster = self.allocate(1)?[0];
+            let zeros = vec![0u8; self.cluster_size as usize];
+            let at = self.cluster_offset(cluster);
+            if let Err(e) = write_at(&mut self.disk, at, &zeros) {
+                self.release(&[cluster]);
+                return Err(e.into());
+            }
+            self.fat.set(*clusters.last().unwrap(), cluster);
+            clusters.push(cluster);
+            data.extend_from_slice(&zeros);
+        };
+
+        for (index, slot) in slots.iter().enumerate() {
+            let at = (start + index) * SLOT_SIZE;
+            data[at..at + SLOT_SIZE].copy_from_slice(slot);
+        }
+        fatfs::write_slots(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, &data, start..start + slots.len())?;
+
+        Ok(Entry {
+            name: name.to_string(),
+            is_dir: attributes & ATTR_DIRECTORY != 0,
+            first_cluster,
+            size: size as u64,
+            contiguous: false,
+            slot: start,
+            slots: slots.len(),
+        })
+    }
+}
+
+impl<D: Read + Write + Seek> FatFs for Fat32Volume<D> {
+    fn root(&self) -> DirRef {
+        DirRef { first_cluster: self.root_cluster, contiguous_len: None, owner: None }
+    }
+
+    fn entries(&mut self, dir: &DirRef) -> Result<Vec<Entry>, VolumeError> {
+        let (_, data) = self.read_dir(dir)?;
+        Ok(parse_dir(&data).into_iter().map(|raw| raw.entry).collect())
+    }
+
+    fn create_dir(&mut self, dir: &DirRef, name: &str) -> Result<Entry, VolumeError> {
+        let cluster = self.allocate(1)?[0];
+
+        // "." and ".." lead every directory but the root, which ".." names as cluster 0
+        let parent = if dir.first_cluster == self.root_cluster { 0 } else { dir.first_cluster };
+        let mut block = vec![0u8; self.cluster_size as usize];
+        block[..SLOT_SIZE].copy_from_slice(&short_entry(b".          ", 0, ATTR_DIRECTORY, cluster, 0));
+        block[SLOT_SIZE..2 * SLOT_SIZE].copy_from_slice(&short_entry(b"..         ", 0, ATTR_DIRECTORY, parent, 0));
+
+        let at = self.cluster_offset(cluster);
+        let result = write_at(&mut self.disk, at, &block)
+            .map_err(VolumeError::from)
+            .and_then(|()| self.add_entry(dir, name, ATTR_DIRECTORY, cluster, 0));
+        if result.is_err() {
+            self.release(&[cluster]);
+        }
+        result
+    }
+
+    fn create_file(&mut self, dir: &DirRef, name: &str, data: &mut dyn Read, len: u64) -> Result<Entry, VolumeError> {
+        let size = u32::try_from(len).map_err(|_| VolumeError::FileTooLarge { path: name.to_string(), size: len })?;
+        let clusters = self.allocate(len.div_ceil(self.cluster_size) as usize)?;
+        let first_cluster = clusters.first().copied().unwrap_or(0);
+
+        let result = fatfs::write_data(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, data, len)
+            .map_err(VolumeError::from)
+            .and_then(|()| self.add_entry(dir, name, ATTR_ARCHIVE, first_cluster, size));
+        if result.is_err() {
+            self.release(&clusters);
+        }
+        result
+    }
+
+    fn read(&mut self, entry: &Entry) -> Result<Vec<u8>, VolumeError> {
+        let clusters = if entry.first_cluster >= ROOT_CLUSTER { self.fat.chain(entry.first_cluster)? } else { Vec::new() };
+        let mut data = fatfs::read_clusters(&mut self.disk, self.heap_offset, self.cluster_size, &clusters)?;
+        if (data.len() as u64) < entry.size {
+            return Err(VolumeError::Corrupt(format!("{} has fewer clusters than its size needs", entry.name)));
+        }
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+
+    fn delete(&mut self, dir: &DirRef, entry: &Entry) -> Result<(), VolumeError> {
+        let (clusters, mut data) = self.read_dir(dir)?;
+        for slot in entry.slot..entry.slot + entry.slots {
+            data[slot * SLOT_SIZE] = DELETED;
+        }
+        fatfs::write_slots(&mut self.disk, self.heap_offset, self.cluster_size, &clusters, &data, entry.slot..entry.slot + entry.slots)?;
+
+        if entry.first_cluster >= ROOT_CLUSTER {
+            let chain = self.fat.chain(entry.first_cluster)?;
+            self.release(&chain);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VolumeError> {
+        self.fat.flush(&mut self.disk)?;
+
+        if let Some(at) = self.fsinfo_offset {
+            let free = (ROOT_CLUSTER..self.entries).filter(|&cluster| self.fat.is_free(cluster)).count() as u32;
+            let mut fields = [0u8; 8];
+            fields[0..4].copy_from_slice(&free.to_le_bytes());
+            fields[4..8].copy_from_slice(&self.next_free.to_le_bytes());
+            write_at(&mut self.disk, at + 488, &fields)?;
+        }
+
+        self.disk.flush()?;
+        Ok(())
+    }
+}
+
+// Live entries of a directory's raw slots, skipping "." and "..", volume
+// labels, and long names whose checksum does not match their short entry.
+fn parse_dir(data: &[u8]) -> Vec<RawEntry> {
+    let mut found = Vec::new();
+    // Long name being collected: first slot, next ordinal expected, checksum, units
+    let mut long: Option<(usize, u8, u8, Vec<u16>)> = None;
+
+    for (index, slot) in data.chunks_exact(SLOT_SIZE).enumerate() {
+        match slot[0] {
+            0x00 => break,
+            DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        if slot[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let ordinal = slot[0] & 0x1f;
+            if slot[0] & LAST_LONG_ENTRY != 0 && ordinal > 0 {
+                long = Some((index, ordinal, slot[13], vec![0; ordinal as usize * LONG_NAME_UNITS]));
+            }
+            long = match long.take() {
+                Some((start, expected, checksum, mut units)) if expected == ordinal && checksum == slot[13] => {
+                    let base = (ordinal as usize - 1) * LONG_NAME_UNITS;
+                    for (unit, &at) in units[base..base + LONG_NAME_UNITS].iter_mut().zip(&LONG_NAME_OFFSETS) {
+                        *unit = u16::from_le_bytes([slot[at], slot[at + 1]]);
+                    }
+                    Some((start, expected - 1, checksum, units))
+                }
+                _ => None,
+            };
+            continue;
+        }
+
+        let long_name = long.take();
+        if slot[11] & ATTR_VOLUME_ID != 0 || slot[0] == b'.' {
+            continue;
+        }
+
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&slot[0..11]);
+        if short_name[0] == KANJI_LEAD {
+            short_name[0] = DELETED;
+        }
+
+        let (name, first_slot) = match long_name {
+            Some((start, 0, checksum, units)) if checksum == lfn_checksum(&slot[0..11]) => {
+                let end = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+                (String::from_utf16_lossy(&units[..end]), start)
+            }
+            _ => (display_short_name(&short_name, slot[12]), index),
+        };
+
+        let first_cluster = (u16::from_le_bytes([slot[20], slot[21]]) as u32) << 16 | u16::from_le_bytes([slot[26], slot[27]]) as u32;
+        found.push(RawEntry {
+            entry: Entry {
+                name,
+                is_dir: slot[11] & ATTR_DIRECTORY != 0,
+                first_cluster,
+                size: u32::from_le_bytes([slot[28], slot[29], slot[30], slot[31]]) as u64,
+                contiguous: false,
+                slot: first_slot,
+                slots: index - first_slot + 1,
+            },
+            short_name,
+        });
+    }
+    found
+}
+
+fn display_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let text: String = bytes.iter().map(|&byte| byte as char).collect::<String>().trim_end().to_string();
+        if lower { text.to_lowercase() } else { text }
+    };
+    let base = part(&short_name[0..8], case & CASE_LOWER_BASE != 0);
+    let ext = part(&short_name[8..11], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+}
+
+/// First index of `count` free slots in a row, or None when the directory
+/// needs another cluster.
+fn free_run(data: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, slot) in data.chunks_exact(SLOT_SIZE).enumerate() {
+        if slot[0] == 0x00 || slot[0] == DELETED {
+            run += 1;
+            if run == count {
+                return Some(index + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+struct ShortName {
+    name: [u8; 11],
+    /// Case flags when the short entry alone can carry the name; None when
+    /// long-name slots are needed.
+    case: Option<u8>,
+}
+
+// Pick the 8.3 name stored for `name`. A name that already is 8.3, with each
+// part in a single case, is stored as-is with the case flags Windows uses;
+// anything else gets a numbered alias like `LONGFI~1.TXT` next to its long name.
+fn short_name(name: &str, taken: &[[u8; 11]]) -> Result<ShortName, VolumeError> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let plain = |part: &str| part.chars().all(|c| c.is_ascii_alphanumeric() || SHORT_NAME_EXTRA.contains(c));
+    let case_flag = |part: &str, flag: u8| {
+        if !part.chars().any(|c| c.is_ascii_lowercase()) {
+            Some(0)
+        } else if !part.chars().any(|c| c.is_ascii_uppercase()) {
+            Some(flag)
+        } else {
+            None
+        }
+    };
+
+    if (1..=8).contains(&base.len()) && ext.len() <= 3 && plain(base) && plain(ext) {
+        if let (Some(base_case), Some(ext_case)) = (case_flag(base, CASE_LOWER_BASE), case_flag(ext, CASE_LOWER_EXT)) {
+            let short = pack_short_name(&base.to_ascii_uppercase(), &ext.to_ascii_uppercase());
+            if !taken.contains(&short) {
+                return Ok(ShortName { name: short, case: Some(base_case | ext_case) });
+            }
+        }
+    }
+
+    // Basis name: leading dots and spaces dropped, the rest upper-cased with
+    // anything a short name cannot hold turned into '_'
+    let basis = |part: &str, len: usize| -> String {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii_alphanumeric() || SHORT_NAME_EXTRA.contains(c) { c } else { '_' }
+            })
+            .take(len)
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (basis(&trimmed[..dot], 8), basis(&trimmed[dot + 1..], 3)),
+        None => (basis(trimmed, 8), String::new()),
+    };
+    let base = if base.is_empty() { "_".to_string() } else { base };
+
+    for number in 1..1_000_000u32 {
+        let tail = format!("~{}", number);
+        let keep = base.len().min(8 - tail.len());
+        let short = pack_short_name(&format!("{}{}", &base[..keep], tail), &ext);
+        if !taken.contains(&short) {
+            return Ok(ShortName { name: short, case: None });
+        }
+    }
+    Err(VolumeError::NoSpace)
+}
+
+fn pack_short_name(base: &str, ext: &str) -> [u8; 11] {
+    let mut short = *b"           ";
+    for (slot, byte) in short[..8].iter_mut().zip(base.bytes()) {
+        *slot = byte;
+    }
+    for (slot, byte) in short[8..].iter_mut().zip(ext.bytes()) {
+        *slot = byte;
+    }
+    short
+}
+
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+// Long-name slots in on-disk order: the last part of the name comes first,
+// flagged with LAST_LONG_ENTRY. The name is NUL-terminated unless it fills
+// the final slot exactly, and padded with 0xffff after that.
+fn long_name_slots(units: &[u16], checksum: u8) -> Vec<[u8; SLOT_SIZE]> {
+    let count = units.len().div_ceil(LONG_NAME_UNITS);
+    let mut padded = units.to_vec();
+    if padded.len() < count * LONG_NAME_UNITS {
+        padded.push(0);
+    }
+    padded.resize(count * LONG_NAME_UNITS, 0xffff);
+
+    (0..count)
+        .rev()
+        .map(|part| {
+            let mut slot = [0u8; SLOT_SIZE];
+            slot[0] = (part + 1) as u8 | if part + 1 == count { LAST_LONG_ENTRY } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            let units = &padded[part * LONG_NAME_UNITS..(part + 1) * LONG_NAME_UNITS];
+            for (unit, &at) in units.iter().zip(&LONG_NAME_OFFSETS) {
+                slot[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+fn short_entry(short_name: &[u8; 11], case: u8, attributes: u8, first_cluster: u32, size: u32) -> [u8; SLOT_SIZE] {
+    let now = FatTime::now();
+    let mut slot = [0u8; SLOT_SIZE];
+    slot[0..11].copy_from_slice(short_name);
+    if slot[0] == DELETED {
+        slot[0] = KANJI_LEAD;
+    }
+    slot[11] = attributes;
+    slot[12] = case;
+    slot[13] = now.centiseconds;
+    slot[14..16].copy_from_slice(&now.time.to_le_bytes());
+    slot[16..18].copy_from_slice(&now.date.to_le_bytes());
+    slot[18..20].copy_from_slice(&now.date.to_le_bytes());
+    slot[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    slot[22..24].copy_from_slice(&now.time.to_le_bytes());
+    slot[24..26].copy_from_slice(&now.date.to_le_bytes());
+    slot[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::Command;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Run `fsck.fat -n` on the volume image at `path` and return whether it
+    /// was clean along with its report. Panics when no FAT checker is installed.
+    fn fsck(path: &Path) -> (bool, String) {
+        for tool in ["fsck.fat", "fsck.vfat", "dosfsck"] {
+            if let Ok(output) = Command::new(tool).arg("-n").arg(path).output() {
+                let report = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+                return (output.status.success(), report);
+            }
+        }
+        panic!("no fsck.fat, fsck.vfat or dosfsck found; install dosfstools to run this test");
+    }
+
+    fn read_at(file: &mut File, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut data).unwrap();
+        data
+    }
+
+    /// Format `size` bytes at `offset` of a sparse image and check its
+    /// structures. The volume is also copied on its own to `volume.img` in
+    /// the returned directory.
+    fn format_volume(offset: u64, size: u64, sector_size: u64, cluster_size: Option<u32>) -> (tempfile::TempDir, Fat32Layout) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let mut disk = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        disk.set_len(offset + size + MIB).unwrap();
+        let options = Fat32Options { label: Some("WEBBOOT".to_string()), cluster_size, volume_id: 0x1234_abcd, full: false };
+
+        let mut reported = 0;
+        let layout = format(&mut disk, offset, size, sector_size, &options, &CancelToken::default(), |done| reported = done).unwrap();
+        assert_eq!(reported, format_bytes(&layout, false));
+        assert!(layout.clusters as u64 >= MIN_CLUSTERS);
+        assert_eq!((layout.data_offset() - offset) % layout.cluster_size(), 0);
+        assert!(layout.data_offset() + layout.clusters as u64 * layout.cluster_size() <= offset + size);
+
+        let sector = sector_size as usize;
+        let boot = read_at(&mut disk, offset, sector);
+        assert_eq!(boot[510..512], [0x55, 0xaa]);
+        assert_eq!(u16::from_le_bytes([boot[11], boot[12]]) as u64, sector_size);
+        assert_eq!(&boot[71..82], b"WEBBOOT    ");
+        assert_eq!(read_at(&mut disk, offset + BACKUP_BOOT_SECTOR as u64 * sector_size, sector), boot);
+        let fsinfo = read_at(&mut disk, offset + FSINFO_SECTOR as u64 * sector_size, sector);
+        assert_eq!(u32::from_le_bytes(fsinfo[488..492].try_into().unwrap()), layout.clusters - 1);
+        for index in 0..FAT_COUNT {
+            let fat = read_at(&mut disk, layout.fat_offset(index), 12);
+            assert_eq!(u32::from_le_bytes(fat[8..12].try_into().unwrap()), FAT_END_OF_CHAIN);
+        }
+        let root = read_at(&mut disk, layout.cluster_offset(ROOT_CLUSTER), 32);
+        assert_eq!((&root[0..11], root[11]), (&b"WEBBOOT    "[..], ATTR_VOLUME_ID));
+
+        // fsck.fat has no offset option, so it gets the volume on its own
+        let volume_path = dir.path().join("volume.img");
+        let mut volume = File::create(&volume_path).unwrap();
+        volume.set_len(size).unwrap();
+        let metadata_end = layout.cluster_offset(ROOT_CLUSTER) + layout.cluster_size() - offset;
+        volume.write_all(&read_at(&mut disk, offset, metadata_end as usize)).unwrap();
+        drop(volume);
+        drop(disk);
+        (dir, layout)
+    }
+
+    fn format_and_check(offset: u64, size: u64, sector_size: u64, cluster_size: Option<u32>) -> Fat32Layout {
+        format_volume(offset, size, sector_size, cluster_size).1
+    }
+
+    #[test]
+    fn formats_volumes() {
+        // Just over the smallest FAT32 volume with 512-byte clusters
+        let layout = format_and_check(0, 34 * MIB, 512, None);
+        assert_eq!(layout.cluster_size(), 512);
+        let layout = format_and_check(MIB, 300 * MIB, 512, None);
+        assert_eq!(layout.cluster_size(), 4096);
+        let layout = format_and_check(MIB, 600 * MIB, 512, Some(8192));
+        assert_eq!(layout.sectors_per_cluster, 16);
+        // 64 KiB clusters need a volume of 4 GiB or more
+        let layout = format_and_check(32 * MIB, 5 * 1024 * MIB, 512, Some(64 * 1024));
+        assert_eq!(layout.sectors_per_cluster, 128);
+    }
+
+    #[test]
+    fn formats_volumes_with_4k_sectors() {
+        let layout = format_and_check(MIB, 512 * MIB, 4096, None);
+        assert_eq!((layout.sector_size, layout.sectors_per_cluster), (4096, 1));
+        let layout = format_and_check(0, 2 * 1024 * MIB, 4096, Some(16 * 1024));
+        assert_eq!(layout.sectors_per_cluster, 4);
+    }
+
+    #[test]
+    #[ignore = "needs fsck.fat from dosfstools"]
+    fn formatted_volumes_pass_fsck() {
+        let cases = [
+            (0, 34 * MIB, 512, None),
+            (MIB, 300 * MIB, 512, None),
+            (MIB, 600 * MIB, 512, Some(8192)),
+            (32 * MIB, 5 * 1024 * MIB, 512, Some(64 * 1024)),
+            (MIB, 512 * MIB, 4096, None),
+            (0, 2 * 1024 * MIB, 4096, Some(16 * 1024)),
+        ];
+        for (offset, size, sector_size, cluster_size) in cases {
+            let (dir, _) = format_volume(offset, size, sector_size, cluster_size);
+            let (clean, report) = fsck(&dir.path().join("volume.img"));
+            assert!(clean, "fsck.fat -n failed for {} bytes at {}:\n{}", size, offset, report);
+        }
+    }
+
+    #[test]
+    fn plan_finds_the_smallest_volume() {
+        // Step up a sector at a time from just under the size that makes
+        // the minimum cluster count
+        let mut size = MIN_CLUSTERS * 512;
+        let smallest = loop {
+            match plan(0, size, 512, Some(512)) {
+                Ok(layout) => break layout,
+                Err(Fat32Error::VolumeTooSmall { clusters, cluster_size }) => {
+                    assert!(clusters < MIN_CLUSTERS);
+                    assert_eq!(cluster_size, 512);
+                }
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+            size += 512;
+        };
+        assert_eq!(smallest.clusters as u64, MIN_CLUSTERS);
+        assert!(matches!(plan(0, size - 512, 512, Some(512)), Err(Fat32Error::VolumeTooSmall { .. })));
+        assert!(matches!(plan(0, 16 * 512, 512, None), Err(Fat32Error::VolumeTooSmall { clusters: 0, .. })));
+    }
+
+    #[test]
+    fn plan_rejects_bad_geometry() {
+        assert!(matches!(plan(0, 64 * MIB, 520, None), Err(Fat32Error::InvalidSectorSize(520))));
+        assert!(matches!(plan(0, 64 * MIB, 512, Some(3072)), Err(Fat32Error::InvalidClusterSize(3072))));
+        assert!(matches!(plan(0, 64 * MIB, 512, Some(128 * 1024)), Err(Fat32Error::InvalidClusterSize(_))));
+        assert!(matches!(plan(0, 1024 * MIB, 4096, Some(2048)), Err(Fat32Error::InvalidClusterSize(2048))));
+        // More than 32-bit sector counts, then more clusters than FAT32 numbers
+        assert!(matches!(plan(0, 3 * 1024 * 1024 * MIB, 512, None), Err(Fat32Error::VolumeTooLarge { .. })));
+        assert!(matches!(plan(0, 200 * 1024 * MIB, 512, Some(512)), Err(Fat32Error::VolumeTooLarge { .. })));
+        assert!(plan(0, 200 * 1024 * MIB, 512, None).is_ok());
+    }
+
+    #[test]
+    fn cancelled_format_stops() {
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let mut disk = std::io::Cursor::new(vec![0u8; 40 * MIB as usize]);
+        let result = format(&mut disk, 0, 40 * MIB, 512, &Fat32Options::default(), &cancel, |_| {});
+        assert!(matches!(result, Err(Fat32Error::Cancelled)));
+        assert!(disk.get_ref()[510..512].iter().all(|byte| *byte == 0));
+    }
+}
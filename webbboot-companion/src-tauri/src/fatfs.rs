@@ -0,0 +1,633 @@
+// Mount-free file access for FAT32 and exFAT volumes.
+//
+// Reads the volume's own structures through `Read + Write + Seek` at the
+// partition's offset, so boot media can be laid out without root, mounting,
+// or racing the desktop's auto-mounter for the new partition. Both
+// filesystems expose the same few primitives through `FatFs`; path walking,
+// overwriting and recursive removal are written once on top of them.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::exfat::ExfatVolume;
+use crate::fat32::Fat32Volume;
+use crate::iso9660::IsoFile;
+use crate::jobs::CancelToken;
+
+/// Size of one directory entry slot on both filesystems.
+pub const SLOT_SIZE: usize = 32;
+
+/// Characters neither filesystem allows in a name.
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+const MAX_NAME_UNITS: usize = 255;
+
+/// Largest run of clusters read or written in one call.
+pub const IO_CHUNK: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum VolumeError {
+    Io(io::Error),
+    Unsupported(String),
+    Corrupt(String),
+    NotFound(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    InvalidName(String),
+    FileTooLarge { path: String, size: u64 },
+    NoSpace,
+    Cancelled,
+}
+
+impl fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeError::Io(e) => write!(f, "{}", e),
+            VolumeError::Unsupported(what) => write!(f, "unsupported volume: {}", what),
+            VolumeError::Corrupt(what) => write!(f, "corrupt volume: {}", what),
+            VolumeError::NotFound(path) => write!(f, "{} does not exist", path),
+            VolumeError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+            VolumeError::IsADirectory(path) => write!(f, "{} is a directory", path),
+            VolumeError::InvalidName(name) => write!(f, "'{}' is not a valid file name", name),
+            VolumeError::FileTooLarge { path, size } => {
+                write!(f, "{} is {} bytes, more than the filesystem allows in one file", path, size)
+            }
+            VolumeError::NoSpace => write!(f, "no space left on the volume"),
+            VolumeError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {}
+
+impl From<io::Error> for VolumeError {
+    fn from(e: io::Error) -> Self {
+        VolumeError::Io(e)
+    }
+}
+
+/// A directory as the primitives see it. `contiguous_len` is set for exFAT
+/// directories stored without a FAT chain; `owner` is the entry that
+/// describes the directory in its parent, absent for the root.
+#[derive(Debug, Clone)]
+pub struct DirRef {
+    pub first_cluster: u32,
+    pub contiguous_len: Option<u64>,
+    pub owner: Option<Box<(DirRef, Entry)>>,
+}
+
+/// A file or directory found in a directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub first_cluster: u32,
+    pub size: u64,
+    /// Data is one run of clusters with no FAT chain (exFAT only).
+    pub contiguous: bool,
+    /// First slot of the entry (including long-name slots) in its directory.
+    pub slot: usize,
+    pub slots: usize,
+}
+
+impl Entry {
+    fn open(&self, parent: &DirRef) -> DirRef {
+        DirRef {
+            first_cluster: self.first_cluster,
+            contiguous_len: self.contiguous.then_some(self.size),
+            owner: Some(Box::new((parent.clone(), self.clone()))),
+        }
+    }
+}
+
+/// What each filesystem implements; everything else is built on these.
+pub trait FatFs {
+    fn root(&self) -> DirRef;
+    /// Live entries of `dir`, without `.` and `..`.
+    fn entries(&mut self, dir: &DirRef) -> Result<Vec<Entry>, VolumeError>;
+    fn create_dir(&mut self, dir: &DirRef, name: &str) -> Result<Entry, VolumeError>;
+    /// Create `name` holding the next `len` bytes of `data`. `name` must not exist.
+    fn create_file(&mut self, dir: &DirRef, name: &str, data: &mut dyn Read, len: u64) -> Result<Entry, VolumeError>;
+    /// The contents of the file `entry`, whole.
+    fn read(&mut self, entry: &Entry) -> Result<Vec<u8>, VolumeError>;
+    /// Remove `entry` and free its clusters; directories must already be empty.
+    fn delete(&mut self, dir: &DirRef, entry: &Entry) -> Result<(), VolumeError>;
+    fn flush(&mut self) -> Result<(), VolumeError>;
+}
+
+/// A FAT32 or exFAT volume opened for writing.
+pub enum Volume<D> {
+    Fat32(Fat32Volume<D>),
+    Exfat(ExfatVolume<D>),
+}
+
+impl<D: Read + Write + Seek> Volume<D> {
+    /// Open the volume that starts `offset` bytes into `disk`.
+    pub fn open(mut disk: D, offset: u64) -> Result<Self, VolumeError> {
+        let mut boot = [0u8; 512];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut boot)?;
+
+        if &boot[3..11] == b"EXFAT   " {
+            Ok(Volume::Exfat(ExfatVolume::open(disk, offset, &boot)?))
+        } else if &boot[82..90] == b"FAT32   " {
+            Ok(Volume::Fat32(Fat32Volume::open(disk, offset, &boot)?))
+        } else {
+            Err(VolumeError::Unsupported("no FAT32 or exFAT boot sector".to_string()))
+        }
+    }
+
+    fn fs(&mut self) -> &mut dyn FatFs {
+        match self {
+            Volume::Fat32(volume) => volume,
+            Volume::Exfat(volume) => volume,
+        }
+    }
+
+    /// Create `path` and any missing parents.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), VolumeError> {
+        let components = split_path(path)?;
+        resolve_dir(self.fs(), &components, true).map(|_| ())
+    }
+
+    /// Write `len` bytes from `data` to `path`, replacing any existing file
+    /// and creating missing parent directories.
+    pub fn write_file(&mut self, path: &str, data: &mut dyn Read, len: u64) -> Result<(), VolumeError> {
+        let components = split_path(path)?;
+        let (name, parents) = components.split_last().ok_or_else(|| VolumeError::InvalidName(path.to_string()))?;
+        let fs = self.fs();
+        let dir = resolve_dir(fs, parents, true)?;
+
+        if let Some(existing) = find(fs, &dir, name)? {
+            if existing.is_dir {
+                return Err(VolumeError::IsADirectory(path.to_string()));
+            }
+            fs.delete(&dir, &existing)?;
+        }
+        fs.create_file(&dir, name, data, len).map(|_| ())
+    }
+
+    /// Entries of the directory at `path`.
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<Entry>, VolumeError> {
+        let components = split_path(path)?;
+        let fs = self.fs();
+        let dir = resolve_dir(fs, &components, false)?;
+        fs.entries(&dir)
+    }
+
+    /// Read the file at `path` into memory; meant for configs, not images.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, VolumeError> {
+        let components = split_path(path)?;
+        let (name, parents) = components.split_last().ok_or_else(|| VolumeError::InvalidName(path.to_string()))?;
+        let fs = self.fs();
+        let dir = resolve_dir(fs, parents, false)?;
+        let entry = find(fs, &dir, name)?.ok_or_else(|| VolumeError::NotFound(path.to_string()))?;
+        if entry.is_dir {
+            return Err(VolumeError::IsADirectory(path.to_string()));
+        }
+        fs.read(&entry)
+    }
+
+    /// Remove a file, or a directory and everything under it.
+    pub fn remove(&mut self, path: &str) -> Result<(), VolumeError> {
+        let components = split_path(path)?;
+        let (name, parents) = components.split_last().ok_or_else(|| VolumeError::InvalidName(path.to_string()))?;
+        let fs = self.fs();
+        let dir = resolve_dir(fs, parents, false)?;
+        let entry = find(fs, &dir, name)?.ok_or_else(|| VolumeError::NotFound(path.to_string()))?;
+        remove_entry(fs, &dir, &entry)
+    }
+
+    /// Write cached FAT, bitmap and free-space information back to the disk.
+    pub fn flush(&mut self) -> Result<(), VolumeError> {
+        self.fs().flush()
+    }
+}
+
+/// Copy the contents of `source` into `target` on the volume, replacing
+/// files and directories that are in the way. `progress` gets the number of
+/// bytes copied so far. Returns the total copied.
+pub fn copy_tree<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    source: &Path,
+    target: &str,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, VolumeError> {
+    let mut copied = 0;
+    volume.create_dir_all(target)?;
+    copy_dir(volume, source, target, cancel, &mut copied, &mut progress)?;
+    volume.flush()?;
+    Ok(copied)
+}
+
+/// Bytes `copy_tree` would copy from `source`.
+pub fn tree_size(source: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let metadata = std::fs::metadata(entry.path())?;
+        total += if metadata.is_dir() { tree_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(total)
+}
+
+/// Copy the local file `source` to `target` on the volume, replacing any
+/// file already there. `progress` gets the number of bytes copied so far.
+pub fn copy_file_to<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    source: &Path,
+    target: &str,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, VolumeError> {
+    let len = std::fs::metadata(source)?.len();
+    let mut copied = 0;
+    copy_file(volume, source, target, len, cancel, &mut copied, &mut progress)?;
+    volume.flush()?;
+    Ok(copied)
+}
+
+fn copy_dir<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    source: &Path,
+    target: &str,
+    cancel: &CancelToken,
+    copied: &mut u64,
+    progress: &mut impl FnMut(u64),
+) -> Result<(), VolumeError> {
+    let mut children: Vec<_> = std::fs::read_dir(source)?.collect::<io::Result<_>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        if cancel.is_cancelled() {
+            return Err(VolumeError::Cancelled);
+        }
+        let name = child.file_name();
+        let name = name.to_str().ok_or_else(|| VolumeError::InvalidName(name.to_string_lossy().into_owned()))?;
+        let path = if target.trim_matches('/').is_empty() { name.to_string() } else { format!("{}/{}", target, name) };
+        // Follow symlinks; the stick cannot hold them anyway
+        let metadata = std::fs::metadata(child.path())?;
+
+        if metadata.is_dir() {
+            match volume.create_dir_all(&path) {
+                Err(VolumeError::NotADirectory(_)) => {
+                    volume.remove(&path)?;
+                    volume.create_dir_all(&path)?;
+                }
+                result => result?,
+            }
+            copy_dir(volume, &child.path(), &path, cancel, copied, progress)?;
+        } else {
+            match copy_file(volume, &child.path(), &path, metadata.len(), cancel, copied, progress) {
+                Err(VolumeError::IsADirectory(_)) => {
+                    volume.remove(&path)?;
+                    copy_file(volume, &child.path(), &path, metadata.len(), cancel, copied, progress)?;
+                }
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_file<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    source: &Path,
+    target: &str,
+    len: u64,
+    cancel: &CancelToken,
+    copied: &mut u64,
+    progress: &mut impl FnMut(u64),
+) -> Result<(), VolumeError> {
+    copy_stream(volume, File::open(source)?, target, len, cancel, copied, progress)
+}
+
+fn copy_stream<D: Read + Write + Seek, R: Read>(
+    volume: &mut Volume<D>,
+    source: R,
+    target: &str,
+    len: u64,
+    cancel: &CancelToken,
+    copied: &mut u64,
+    progress: &mut impl FnMut(u64),
+) -> Result<(), VolumeError> {
+    let mut reader = ProgressReader { inner: source, cancel, copied, progress };
+    match volume.write_file(target, &mut reader, len) {
+        Err(VolumeError::Io(_)) if cancel.is_cancelled() => Err(VolumeError::Cancelled),
+        result => result,
+    }
+}
+
+/// Copy `files`, as listed from an ISO image, into `target` on the volume.
+/// Directories are created as listed, so an empty one survives the copy.
+/// `progress` gets the number of bytes copied so far. Returns the total
+/// copied.
+pub fn copy_image_tree<D: Read + Write + Seek, R: Read + Seek>(
+    volume: &mut Volume<D>,
+    image: &mut R,
+    files: &[IsoFile],
+    target: &str,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, VolumeError> {
+    let mut copied = 0;
+    volume.create_dir_all(target)?;
+    for file in files {
+        if cancel.is_cancelled() {
+            return Err(VolumeError::Cancelled);
+        }
+        let path = if target.trim_matches('/').is_empty() {
+            file.path.clone()
+        } else {
+            format!("{}/{}", target.trim_end_matches('/'), file.path)
+        };
+        if file.is_dir {
+            volume.create_dir_all(&path)?;
+        } else {
+            copy_stream(volume, file.reader(image), &path, file.size, cancel, &mut copied, &mut progress)?;
+        }
+    }
+    volume.flush()?;
+    Ok(copied)
+}
+
+// Counts bytes as the volume pulls them and stops the copy once cancelled.
+struct ProgressReader<'a, R: Read, F: FnMut(u64)> {
+    inner: R,
+    cancel: &'a CancelToken,
+    copied: &'a mut u64,
+    progress: &'a mut F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<'_, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::other("cancelled"));
+        }
+        let n = self.inner.read(buf)?;
+        *self.copied += n as u64;
+        (self.progress)(*self.copied);
+        Ok(n)
+    }
+}
+
+fn find(fs: &mut dyn FatFs, dir: &DirRef, name: &str) -> Result<Option<Entry>, VolumeError> {
+    Ok(fs.entries(dir)?.into_iter().find(|entry| names_equal(&entry.name, name)))
+}
+
+fn resolve_dir(fs: &mut dyn FatFs, components: &[&str], create: bool) -> Result<DirRef, VolumeError> {
+    let mut dir = fs.root();
+    for (depth, name) in components.iter().enumerate() {
+        let entry = match find(fs, &dir, name)? {
+            Some(entry) => entry,
+            None if create => fs.create_dir(&dir, name)?,
+            None => return Err(VolumeError::NotFound(components[..=depth].join("/"))),
+        };
+        if !entry.is_dir {
+            return Err(VolumeError::NotADirectory(components[..=depth].join("/")));
+        }
+        dir = entry.open(&dir);
+    }
+    Ok(dir)
+}
+
+fn remove_entry(fs: &mut dyn FatFs, dir: &DirRef, entry: &Entry) -> Result<(), VolumeError> {
+    if entry.is_dir {
+        let inner = entry.open(dir);
+        for child in fs.entries(&inner)? {
+            remove_entry(fs, &inner, &child)?;
+        }
+    }
+    fs.delete(dir, entry)
+}
+
+fn split_path(path: &str) -> Result<Vec<&str>, VolumeError> {
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    for component in &components {
+        check_name(component)?;
+    }
+    Ok(components)
+}
+
+/// Reject names neither filesystem can store.
+pub fn check_name(name: &str) -> Result<(), VolumeError> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with(['.', ' '])
+        || name.chars().any(|c| c.is_control() || FORBIDDEN.contains(c))
+        || name.encode_utf16().count() > MAX_NAME_UNITS;
+    if invalid {
+        return Err(VolumeError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+// Both filesystems compare names case-insensitively.
+pub fn names_equal(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// A timestamp in the packed date/time form both filesystems use.
+#[derive(Debug, Clone, Copy)]
+pub struct FatTime {
+    pub date: u16,
+    pub time: u16,
+    /// Hundredths of a second past `time`, which only has two-second steps.
+    pub centiseconds: u8,
+}
+
+impl FatTime {
+    /// The current time in UTC; dates before 1980 clamp to the FAT epoch.
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        if year < 1980 {
+            return FatTime { date: (1 << 5) | 1, time: 0, centiseconds: 0 };
+        }
+
+        let of_day = seconds % 86_400;
+        let (hour, minute, second) = (of_day / 3600, of_day % 3600 / 60, of_day % 60);
+        FatTime {
+            date: (((year - 1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16,
+            time: ((hour as u16) << 11) | ((minute as u16) << 5) | (second / 2) as u16,
+            centiseconds: ((second % 2) * 100 + since_epoch.subsec_millis() as u64 / 10) as u8,
+        }
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian
+// calendar (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// An in-memory copy of a file allocation table, written back sector by
+/// sector to every copy on flush.
+pub struct FatTable {
+    raw: Vec<u8>,
+    copies: Vec<u64>,
+    sector_size: usize,
+    /// Values are masked with this on read; FAT32 keeps the top four bits.
+    mask: u32,
+    end_of_chain: u32,
+    entries: u32,
+    dirty: BTreeSet<usize>,
+}
+
+impl FatTable {
+    /// Load `entries` entries from the first of `copies` (byte offsets).
+    pub fn load<D: Read + Seek>(
+        disk: &mut D,
+        copies: Vec<u64>,
+        entries: u32,
+        sector_size: usize,
+        mask: u32,
+        end_of_chain: u32,
+    ) -> io::Result<Self> {
+        let len = (entries as usize * 4).next_multiple_of(sector_size);
+        let mut raw = vec![0u8; len];
+        disk.seek(SeekFrom::Start(copies[0]))?;
+        disk.read_exact(&mut raw)?;
+        Ok(FatTable { raw, copies, sector_size, mask, end_of_chain, entries, dirty: BTreeSet::new() })
+    }
+
+    pub fn get(&self, cluster: u32) -> u32 {
+        let at = cluster as usize * 4;
+        u32::from_le_bytes(self.raw[at..at + 4].try_into().unwrap()) & self.mask
+    }
+
+    pub fn set(&mut self, cluster: u32, value: u32) {
+        let at = cluster as usize * 4;
+        let kept = u32::from_le_bytes(self.raw[at..at + 4].try_into().unwrap()) & !self.mask;
+        self.raw[at..at + 4].copy_from_slice(&(kept | (value & self.mask)).to_le_bytes());
+        self.dirty.insert(at / self.sector_size);
+    }
+
+    pub fn is_free(&self, cluster: u32) -> bool {
+        self.get(cluster) == 0
+    }
+
+    /// Clusters of the chain starting at `first`.
+    pub fn chain(&self, first: u32) -> Result<Vec<u32>, VolumeError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster >= 2 && cluster < self.entries {
+            chain.push(cluster);
+            if chain.len() > self.entries as usize {
+                return Err(VolumeError::Corrupt(format!("cluster chain from {} loops", first)));
+            }
+            cluster = self.get(cluster);
+        }
+        // Anything but an end-of-chain marker is a broken link
+        if first >= 2 && cluster < self.end_of_chain - 7 {
+            return Err(VolumeError::Corrupt(format!("cluster chain from {} is broken at {}", first, cluster)));
+        }
+        Ok(chain)
+    }
+
+    /// Link `clusters` into one chain, ending it after the last.
+    pub fn link(&mut self, clusters: &[u32]) {
+        for pair in clusters.windows(2) {
+            self.set(pair[0], pair[1]);
+        }
+        if let Some(&last) = clusters.last() {
+            self.set(last, self.end_of_chain);
+        }
+    }
+
+    pub fn flush<D: Write + Seek>(&mut self, disk: &mut D) -> io::Result<()> {
+        for &sector in &self.dirty {
+            let at = sector * self.sector_size;
+            for copy in &self.copies {
+                disk.seek(SeekFrom::Start(copy + at as u64))?;
+                disk.write_all(&self.raw[at..at + self.sector_size])?;
+            }
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+/// Read all of `clusters` starting at `heap_offset` (the byte offset of
+/// cluster 2) into one buffer.
+pub fn read_clusters<D: Read + Seek>(disk: &mut D, heap_offset: u64, cluster_size: u64, clusters: &[u32]) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; clusters.len() * cluster_size as usize];
+    for (chunk, &cluster) in data.chunks_exact_mut(cluster_size as usize).zip(clusters) {
+        disk.seek(SeekFrom::Start(heap_offset + (cluster as u64 - 2) * cluster_size))?;
+        disk.read_exact(chunk)?;
+    }
+    Ok(data)
+}
+
+/// Write the slots `slots` of a directory stream held in `data` back to the
+/// clusters it came from.
+pub fn write_slots<D: Write + Seek>(
+    disk: &mut D,
+    heap_offset: u64,
+    cluster_size: u64,
+    clusters: &[u32],
+    data: &[u8],
+    slots: std::ops::Range<usize>,
+) -> io::Result<()> {
+    let per_cluster = cluster_size as usize / SLOT_SIZE;
+    let mut slot = slots.start;
+    while slot < slots.end {
+        let index = slot / per_cluster;
+        let end = slots.end.min((index + 1) * per_cluster);
+        let within = (slot % per_cluster * SLOT_SIZE) as u64;
+        disk.seek(SeekFrom::Start(heap_offset + (clusters[index] as u64 - 2) * cluster_size + within))?;
+        disk.write_all(&data[slot * SLOT_SIZE..end * SLOT_SIZE])?;
+        slot = end;
+    }
+    Ok(())
+}
+
+/// Write `len` bytes from `data` across `clusters`, zero-filling the tail of
+/// the last cluster. Fails if `data` runs out early.
+pub fn write_data<D: Write + Seek>(
+    disk: &mut D,
+    heap_offset: u64,
+    cluster_size: u64,
+    clusters: &[u32],
+    data: &mut dyn Read,
+    len: u64,
+) -> io::Result<()> {
+    let max_run = (IO_CHUNK / cluster_size).max(1) as usize;
+    let mut buffer = vec![0u8; (max_run as u64 * cluster_size) as usize];
+    let mut remaining = len;
+    let mut start = 0;
+
+    while start < clusters.len() {
+        // Extend the run while clusters are adjacent on disk
+        let mut end = start + 1;
+        while end < clusters.len() && end - start < max_run && clusters[end] == clusters[end - 1] + 1 {
+            end += 1;
+        }
+
+        let run_bytes = (end - start) as u64 * cluster_size;
+        let take = remaining.min(run_bytes) as usize;
+        data.read_exact(&mut buffer[..take])?;
+        buffer[take..run_bytes as usize].fill(0);
+        disk.seek(SeekFrom::Start(heap_offset + (clusters[start] as u64 - 2) * cluster_size))?;
+        disk.write_all(&buffer[..run_bytes as usize])?;
+
+        remaining -= take as u64;
+        start = end;
+    }
+    Ok(())
+}
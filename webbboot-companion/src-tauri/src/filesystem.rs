@@ -0,0 +1,336 @@
+// Filesystems the companion can create, and how to create them.
+//
+// Each filesystem knows its mkfs tool, how that tool spells label, cluster
+// size and full-format options, and what makes a label legal. Options are
+// checked up front so a bad label fails validation instead of surfacing as
+// mkfs stderr after the disk has been repartitioned.
+
+use std::fmt;
+use std::process::Command;
+
+use crate::partition::PartitionType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    Fat32,
+    Exfat,
+    Ntfs,
+    Ext4,
+    Btrfs,
+}
+
+/// Options for creating a filesystem.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub label: Option<String>,
+    /// Cluster (allocation unit) size in bytes; None leaves it to the tool.
+    pub cluster_size: Option<u32>,
+    /// Skip the slow pass over the whole device (bad-block scan or zeroing).
+    pub quick: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { label: None, cluster_size: None, quick: true }
+    }
+}
+
+// Characters FAT and exFAT refuse in volume labels.
+const FAT_FORBIDDEN: &str = "\"*/:<>?\\|";
+const FAT_ONLY_FORBIDDEN: &str = "+,.;=[]";
+
+impl Filesystem {
+    pub fn all() -> &'static [Filesystem] {
+        &[Filesystem::Fat32, Filesystem::Exfat, Filesystem::Ntfs, Filesystem::Ext4, Filesystem::Btrfs]
+    }
+
+    /// Parse the job's `filesystem` field.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "fat32" | "vfat" | "fat" => Ok(Filesystem::Fat32),
+            "exfat" => Ok(Filesystem::Exfat),
+            "ntfs" => Ok(Filesystem::Ntfs),
+            "ext4" => Ok(Filesystem::Ext4),
+            "btrfs" => Ok(Filesystem::Btrfs),
+            _ => Err(format!("Unsupported filesystem '{}'", value)),
+        }
+    }
+
+    /// The mkfs helper that creates this filesystem.
+    pub fn tool(&self) -> &'static str {
+        match self {
+            Filesystem::Fat32 => "mkfs.fat",
+            Filesystem::Exfat => "mkfs.exfat",
+            Filesystem::Ntfs => "mkfs.ntfs",
+            Filesystem::Ext4 => "mkfs.ext4",
+            Filesystem::Btrfs => "mkfs.btrfs",
+        }
+    }
+
+    /// Name of the filesystem as the platform's format tool spells it.
+    #[cfg(target_os = "windows")]
+    pub fn native_format_name(&self) -> Result<&'static str, String> {
+        match self {
+            Filesystem::Fat32 => Ok("FAT32"),
+            Filesystem::Exfat => Ok("exFAT"),
+            Filesystem::Ntfs => Ok("NTFS"),
+            other => Err(format!("{} cannot be created on Windows", other)),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn native_format_name(&self) -> Result<&'static str, String> {
+        match self {
+            Filesystem::Fat32 => Ok("FAT32"),
+            Filesystem::Exfat => Ok("ExFAT"),
+            other => Err(format!("{} cannot be created on macOS", other)),
+        }
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        match self {
+            Filesystem::Fat32 => PartitionType::FAT32,
+            Filesystem::Exfat | Filesystem::Ntfs => PartitionType::NTFS,
+            Filesystem::Ext4 | Filesystem::Btrfs => PartitionType::LINUX,
+        }
+    }
+
+    /// Check a volume label against this filesystem's rules and return it in
+    /// the form it will be written (FAT labels are stored upper-case).
+    pub fn normalize_label(&self, label: &str) -> Result<String, String> {
+        if label.chars().any(char::is_control) {
+            return Err("Volume label contains control characters".to_string());
+        }
+
+        match self {
+            Filesystem::Fat32 => {
+                if !label.is_ascii() {
+                    return Err("FAT32 labels may only contain ASCII characters".to_string());
+                }
+                if let Some(c) = label.chars().find(|c| FAT_FORBIDDEN.contains(*c) || FAT_ONLY_FORBIDDEN.contains(*c)) {
+                    return Err(format!("FAT32 labels may not contain '{}'", c));
+                }
+                check_length(label.len(), 11, "FAT32", "characters")?;
+                Ok(label.to_uppercase())
+            }
+            Filesystem::Exfat => {
+                if let Some(c) = label.chars().find(|c| FAT_FORBIDDEN.contains(*c)) {
+                    return Err(format!("exFAT labels may not contain '{}'", c));
+                }
+                check_length(label.encode_utf16().count(), 11, "exFAT", "characters")?;
+                Ok(label.to_string())
+            }
+            Filesystem::Ntfs => {
+                check_length(label.encode_utf16().count(), 128, "NTFS", "characters")?;
+                Ok(label.to_string())
+            }
+            Filesystem::Ext4 => {
+                check_length(label.len(), 16, "ext4", "bytes")?;
+                Ok(label.to_string())
+            }
+            Filesystem::Btrfs => {
+                check_length(label.len(), 255, "btrfs", "bytes")?;
+                Ok(label.to_string())
+            }
+        }
+    }
+
+    /// Reject cluster sizes the tool would refuse or the filesystem cannot use.
+    pub fn check_cluster_size(&self, size: u32) -> Result<(), String> {
+        let (min, max) = match self {
+            Filesystem::Fat32 => (512, 64 * 1024),
+            Filesystem::Exfat => (4 * 1024, 32 * 1024 * 1024),
+            Filesystem::Ntfs => (512, 2 * 1024 * 1024),
+            Filesystem::Ext4 => (1024, 64 * 1024),
+            Filesystem::Btrfs => return Err("btrfs does not take a cluster size".to_string()),
+        };
+        if !size.is_power_of_two() || size < min || size > max {
+            return Err(format!(
+                "{} cluster size must be a power of two between {} and {} bytes",
+                self, min, max
+            ));
+        }
+        Ok(())
+    }
+
+    /// Validate `options` for this filesystem, normalizing the label.
+    pub fn check_options(&self, options: &FormatOptions) -> Result<FormatOptions, String> {
+        let label = options.label.as_deref().map(|label| self.normalize_label(label)).transpose()?;
+        if let Some(size) = options.cluster_size {
+            self.check_cluster_size(size)?;
+        }
+        Ok(FormatOptions { label, ..options.clone() })
+    }
+
+    /// The mkfs command line that formats `target`, whose logical sectors are
+    /// `sector_size` bytes, with `options`. Options must already have passed
+    /// `check_options`.
+    pub fn mkfs_command(&self, target: &str, sector_size: u64, options: &FormatOptions) -> Command {
+        let mut command = Command::new("sudo");
+        command.arg(self.tool());
+
+        match self {
+            Filesystem::Fat32 => {
+                command.args(["-F", "32"]);
+                if let Some(label) = &options.label {
+                    command.args(["-n", label]);
+                }
+                if let Some(size) = options.cluster_size {
+                    // mkfs.fat counts clusters in the device's logical sectors
+                    command.args(["-s", &(u64::from(size) / sector_size.max(1)).max(1).to_string()]);
+                }
+                if !options.quick {
+                    command.arg("-c");
+                }
+            }
+            Filesystem::Exfat => {
+                if let Some(label) = &options.label {
+                    command.args(["-L", label]);
+                }
+                if let Some(size) = options.cluster_size {
+                    command.args(["-c", &size.to_string()]);
+                }
+                if !options.quick {
+                    command.arg("--full-format");
+                }
+            }
+            Filesystem::Ntfs => {
+                if let Some(label) = &options.label {
+                    command.args(["-L", label]);
+                }
+                if let Some(size) = options.cluster_size {
+                    command.args(["-c", &size.to_string()]);
+                }
+                // Without -Q mkfs.ntfs zeroes every sector first
+                if options.quick {
+                    command.arg("-Q");
+                }
+            }
+            Filesystem::Ext4 => {
+                command.arg("-F");
+                if let Some(label) = &options.label {
+                    command.args(["-L", label]);
+                }
+                if let Some(size) = options.cluster_size {
+                    command.args(["-b", &size.to_string()]);
+                }
+                if !options.quick {
+                    command.arg("-c");
+                }
+            }
+            Filesystem::Btrfs => {
+                // btrfs has no full-format pass; quick and full are the same
+                command.arg("-f");
+                if let Some(label) = &options.label {
+                    command.args(["-L", label]);
+                }
+            }
+        }
+
+        command.arg(target);
+        command
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Filesystem::Fat32 => "FAT32",
+            Filesystem::Exfat => "exFAT",
+            Filesystem::Ntfs => "NTFS",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Btrfs => "btrfs",
+        };
+        f.write_str(name)
+    }
+}
+
+fn check_length(len: usize, max: usize, filesystem: &str, unit: &str) -> Result<(), String> {
+    if len > max {
+        return Err(format!("{} labels are limited to {} {}", filesystem, max, unit));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(label: Option<&str>, cluster_size: Option<u32>) -> FormatOptions {
+        FormatOptions { label: label.map(str::to_string), cluster_size, quick: true }
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn parses_supported_filesystems_only() {
+        assert_eq!(Filesystem::parse("VFAT"), Ok(Filesystem::Fat32));
+        assert_eq!(Filesystem::parse("exFAT"), Ok(Filesystem::Exfat));
+        for name in ["", "fat16", "hfs+", "zfs", "ext3", "ntfs3"] {
+            assert_eq!(Filesystem::parse(name), Err(format!("Unsupported filesystem '{}'", name)));
+        }
+    }
+
+    #[test]
+    fn checks_fat_labels() {
+        let fat = Filesystem::Fat32;
+        assert_eq!(fat.normalize_label("webbboot"), Ok("WEBBBOOT".to_string()));
+        assert_eq!(fat.normalize_label("ELEVENCHARS"), Ok("ELEVENCHARS".to_string()));
+        assert!(fat.normalize_label("TWELVE CHARS").is_err());
+        assert!(fat.normalize_label("STICK\n").is_err());
+        assert!(fat.normalize_label("CAFÉ").is_err());
+        for c in FAT_FORBIDDEN.chars().chain(FAT_ONLY_FORBIDDEN.chars()) {
+            assert_eq!(fat.normalize_label(&format!("A{}B", c)), Err(format!("FAT32 labels may not contain '{}'", c)));
+        }
+
+        // exFAT keeps case and allows the FAT-only characters
+        assert_eq!(Filesystem::Exfat.normalize_label("Data+1.0"), Ok("Data+1.0".to_string()));
+        assert!(Filesystem::Exfat.normalize_label("a:b").is_err());
+        assert!(Filesystem::Exfat.normalize_label("twelve chars").is_err());
+    }
+
+    #[test]
+    fn checks_label_lengths() {
+        assert!(Filesystem::Ext4.normalize_label(&"x".repeat(16)).is_ok());
+        assert!(Filesystem::Ext4.normalize_label(&"x".repeat(17)).is_err());
+        // ext4 counts bytes, NTFS UTF-16 units
+        assert!(Filesystem::Ext4.normalize_label(&"é".repeat(9)).is_err());
+        assert!(Filesystem::Ntfs.normalize_label(&"é".repeat(128)).is_ok());
+        assert!(Filesystem::Ntfs.normalize_label(&"é".repeat(129)).is_err());
+        assert!(Filesystem::Btrfs.normalize_label(&"x".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn checks_cluster_sizes() {
+        assert!(Filesystem::Fat32.check_cluster_size(4096).is_ok());
+        for size in [0, 256, 3000, 128 * 1024] {
+            assert!(Filesystem::Fat32.check_cluster_size(size).is_err(), "{}", size);
+        }
+        assert!(Filesystem::Exfat.check_cluster_size(2048).is_err());
+        assert!(Filesystem::Exfat.check_cluster_size(32 * 1024 * 1024).is_ok());
+        assert!(Filesystem::Ext4.check_cluster_size(512).is_err());
+        assert!(Filesystem::Btrfs.check_cluster_size(4096).is_err());
+
+        assert!(Filesystem::Fat32.check_options(&options(Some("ok"), Some(1000))).is_err());
+        assert!(Filesystem::Fat32.check_options(&options(Some("a*b"), None)).is_err());
+        let checked = Filesystem::Fat32.check_options(&options(Some("stick"), Some(8192))).unwrap();
+        assert_eq!((checked.label.as_deref(), checked.cluster_size), (Some("STICK"), Some(8192)));
+    }
+
+    #[test]
+    fn counts_fat_clusters_in_logical_sectors() {
+        let options = options(Some("STICK"), Some(32 * 1024));
+        let command = Filesystem::Fat32.mkfs_command("/dev/sdz1", 512, &options);
+        assert_eq!(command.get_program(), "sudo");
+        assert_eq!(args(&command), ["mkfs.fat", "-F", "32", "-n", "STICK", "-s", "64", "/dev/sdz1"]);
+
+        let command = Filesystem::Fat32.mkfs_command("/dev/sdz1", 4096, &options);
+        assert_eq!(args(&command)[5..7], ["-s", "8"]);
+
+        // Clusters smaller than a sector become one sector
+        let small = FormatOptions { cluster_size: Some(512), ..options };
+        assert_eq!(args(&Filesystem::Fat32.mkfs_command("/dev/sdz1", 4096, &small))[5..7], ["-s", "1"]);
+    }
+}
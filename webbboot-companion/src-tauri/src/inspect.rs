@@ -0,0 +1,244 @@
+// Pre-write ISO inspection.
+//
+// Summarizes what an installer image is before anything touches the device:
+// label and size, the El Torito boot entries, EFI loaders in the file tree,
+// whether an MBR or GPT is embedded in the system area (isohybrid), and the
+// OS family its layout points to. The result decides how an image can be
+// written, so it is also what the user sees when a job is refused.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::decompress::{self, DecompressError, ImageFormat, ImageInfo};
+use crate::iso9660::{self, BootEntry, BootPlatform, Descriptors, IsoError, IsoFile};
+use crate::udf::UdfVolume;
+
+/// Largest file FAT32 can store.
+pub const FAT32_MAX_FILE_SIZE: u64 = 0xffff_ffff;
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OsFamily {
+    Windows,
+    Linux,
+    Bsd,
+    Unknown,
+}
+
+/// Paths whose presence identifies an OS, checked in order. A marker matches
+/// the path itself or anything below it, ignoring case.
+const OS_MARKERS: &[(&str, OsFamily, Option<&str>)] = &[
+    ("sources/install.wim", OsFamily::Windows, None),
+    ("sources/install.esd", OsFamily::Windows, None),
+    ("sources/boot.wim", OsFamily::Windows, None),
+    ("bootmgr", OsFamily::Windows, None),
+    ("casper", OsFamily::Linux, Some("ubuntu")),
+    ("live/filesystem.squashfs", OsFamily::Linux, Some("debian")),
+    ("install.amd", OsFamily::Linux, Some("debian")),
+    ("arch/boot", OsFamily::Linux, Some("arch")),
+    ("images/pxeboot", OsFamily::Linux, Some("redhat")),
+    ("liveos/squashfs.img", OsFamily::Linux, Some("redhat")),
+    ("boot/x86_64/loader/linux", OsFamily::Linux, Some("opensuse")),
+    ("boot/kernel/kernel", OsFamily::Bsd, Some("freebsd")),
+    ("isolinux", OsFamily::Linux, None),
+    ("syslinux", OsFamily::Linux, None),
+    ("boot/grub", OsFamily::Linux, None),
+];
+
+/// A file too large for FAT32.
+#[derive(Serialize, Debug, Clone)]
+pub struct LargeFile {
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IsoReport {
+    pub label: Option<String>,
+    /// Size of the image file.
+    pub size: u64,
+    /// Bytes in the image's file tree, which is what a file-by-file copy
+    /// writes.
+    pub content_size: u64,
+    pub joliet: bool,
+    pub rock_ridge: bool,
+    pub udf: bool,
+    /// A bootable BIOS entry exists in the El Torito catalog.
+    pub bios_boot: bool,
+    /// An EFI El Torito entry or a removable-media EFI loader exists.
+    pub efi_boot: bool,
+    pub boot_entries: Vec<BootEntry>,
+    /// `EFI/BOOT/BOOT*.EFI` loaders found in the file tree.
+    pub efi_loaders: Vec<String>,
+    /// The system area holds a partition table, so the image boots from a
+    /// stick when written as-is.
+    pub isohybrid: bool,
+    /// "gpt" or "mbr" for isohybrid images.
+    pub partition_table: Option<String>,
+    pub os_family: OsFamily,
+    pub distribution: Option<String>,
+    /// Files too large for FAT32.
+    pub large_files: Vec<LargeFile>,
+}
+
+#[derive(Debug)]
+pub enum InspectError {
+    Io(io::Error),
+    Probe(DecompressError),
+    Compressed(ImageFormat),
+    Image(IsoError),
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InspectError::Io(e) => write!(f, "{}", e),
+            InspectError::Probe(e) => write!(f, "{}", e),
+            InspectError::Compressed(format) => write!(f, "{} images must be decompressed before they can be inspected", format),
+            InspectError::Image(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InspectError {}
+
+impl From<io::Error> for InspectError {
+    fn from(e: io::Error) -> Self {
+        InspectError::Io(e)
+    }
+}
+
+impl From<IsoError> for InspectError {
+    fn from(e: IsoError) -> Self {
+        InspectError::Image(e)
+    }
+}
+
+impl From<DecompressError> for InspectError {
+    fn from(e: DecompressError) -> Self {
+        InspectError::Probe(e)
+    }
+}
+
+/// Inspect the ISO at `path`.
+pub fn inspect(path: &Path) -> Result<IsoReport, InspectError> {
+    inspect_probed(path, &decompress::probe(path)?)
+}
+
+/// Inspect the ISO at `path`, already probed as `info`.
+pub fn inspect_probed(path: &Path, info: &ImageInfo) -> Result<IsoReport, InspectError> {
+    if info.format != ImageFormat::Raw {
+        return Err(InspectError::Compressed(info.format));
+    }
+
+    let mut image = BufReader::new(File::open(path)?);
+    inspect_image(&mut image, info.compressed_size)
+}
+
+pub fn inspect_image<R: Read + Seek>(image: &mut R, size: u64) -> Result<IsoReport, InspectError> {
+    let descriptors = iso9660::read_descriptors(image)?;
+    let partition_table = embedded_partition_table(image)?;
+    let rock_ridge = iso9660::has_rock_ridge(image, &descriptors)?;
+
+    let boot_entries = match descriptors.boot_catalog {
+        Some(catalog) => iso9660::read_boot_catalog(image, catalog)?,
+        None => Vec::new(),
+    };
+
+    let (udf_label, files) = read_tree(image, &descriptors)?;
+    let lowercase: Vec<String> = files.iter().map(|file| file.path.to_lowercase()).collect();
+
+    let efi_loaders: Vec<String> = files
+        .iter()
+        .zip(&lowercase)
+        .filter(|(file, path)| !file.is_dir && is_efi_loader(path))
+        .map(|(file, _)| file.path.clone())
+        .collect();
+
+    let (os_family, distribution) = OS_MARKERS
+        .iter()
+        .find(|(marker, _, _)| lowercase.iter().any(|path| is_at_or_below(path, marker)))
+        .map(|(_, family, distribution)| (*family, distribution.map(str::to_string)))
+        .unwrap_or((OsFamily::Unknown, None));
+
+    let content_size = files.iter().filter(|file| !file.is_dir).map(|file| file.size).sum();
+    let large_files = files
+        .iter()
+        .filter(|file| !file.is_dir && file.size > FAT32_MAX_FILE_SIZE)
+        .map(|file| LargeFile { path: file.path.clone(), size: file.size })
+        .collect();
+
+    // Joliet and UDF labels are not limited to upper-case d-characters, so
+    // prefer them when present
+    let label = [udf_label, descriptors.joliet.as_ref().map(|joliet| joliet.label.clone())]
+        .into_iter()
+        .flatten()
+        .chain(descriptors.primary.as_ref().map(|primary| primary.label.clone()))
+        .find(|label| !label.is_empty());
+
+    Ok(IsoReport {
+        label,
+        size,
+        content_size,
+        joliet: descriptors.joliet.is_some(),
+        rock_ridge,
+        udf: descriptors.udf,
+        bios_boot: boot_entries.iter().any(|entry| entry.platform == BootPlatform::Bios && entry.bootable),
+        efi_boot: boot_entries.iter().any(|entry| entry.platform == BootPlatform::Efi) || !efi_loaders.is_empty(),
+        boot_entries,
+        efi_loaders,
+        isohybrid: partition_table.is_some(),
+        partition_table: partition_table.map(str::to_string),
+        os_family,
+        distribution,
+        large_files,
+    })
+}
+
+/// List the image's files, through UDF when the image has it (the ISO9660
+/// side of a Windows ISO is only a README), otherwise through ISO9660.
+/// Returns the UDF volume label alongside.
+pub fn read_tree<R: Read + Seek>(image: &mut R, descriptors: &Descriptors) -> Result<(Option<String>, Vec<IsoFile>), IsoError> {
+    if descriptors.udf {
+        match UdfVolume::open(image).and_then(|volume| volume.files(image).map(|files| (volume.label, files))) {
+            Ok((label, files)) => return Ok((Some(label), files)),
+            // Hybrid UDF/ISO9660 images stay readable through ISO9660
+            Err(IsoError::Unsupported(_)) if descriptors.primary.is_some() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((None, iso9660::files(image, descriptors)?))
+}
+
+// An isohybrid image carries a GPT header at LBA 1 or an MBR with at least
+// one partition entry in its system area.
+fn embedded_partition_table<R: Read + Seek>(image: &mut R) -> Result<Option<&'static str>, IsoError> {
+    let mut head = [0u8; 1024];
+    image.seek(SeekFrom::Start(0))?;
+    image.read_exact(&mut head)?;
+
+    if head[512..512 + GPT_SIGNATURE.len()] == *GPT_SIGNATURE {
+        return Ok(Some("gpt"));
+    }
+    let has_partition = (0..4).any(|index| head[MBR_ENTRIES_OFFSET + index * 16 + 4] != 0);
+    if head[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] == [0x55, 0xaa] && has_partition {
+        return Ok(Some("mbr"));
+    }
+    Ok(None)
+}
+
+fn is_efi_loader(path: &str) -> bool {
+    path.strip_prefix("efi/boot/boot")
+        .is_some_and(|rest| rest.ends_with(".efi") && !rest.contains('/'))
+}
+
+fn is_at_or_below(path: &str, marker: &str) -> bool {
+    path.strip_prefix(marker).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
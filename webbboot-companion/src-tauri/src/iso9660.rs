@@ -0,0 +1,592 @@
+// ISO9660 image reader.
+//
+// Parses the volume descriptor set (primary, Joliet supplementary, El Torito
+// boot record and the UDF recognition sequence that follows it), the El Torito
+// boot catalog, and the directory tree with Rock Ridge or Joliet names. Files
+// are returned as lists of extents in the image, so installer media can be
+// inspected and unpacked straight from the ISO without loop-mounting it.
+
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+/// First sector of the volume descriptor set; the 32 KiB before it is the
+/// system area, where isohybrid images keep their MBR and GPT.
+const DESCRIPTORS_START: u64 = 16;
+/// Descriptors scanned before giving up on finding a terminator.
+const MAX_DESCRIPTORS: u64 = 64;
+
+const STANDARD_ID: &[u8] = b"CD001";
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+const DESCRIPTOR_BOOT: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Nesting beyond this is treated as a corrupt or looping directory tree.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub enum IsoError {
+    Io(io::Error),
+    NotIso,
+    Corrupt(String),
+    Unsupported(String),
+}
+
+impl fmt::Display for IsoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsoError::Io(e) => write!(f, "{}", e),
+            IsoError::NotIso => write!(f, "not an ISO9660 or UDF image"),
+            IsoError::Corrupt(message) => write!(f, "corrupt image: {}", message),
+            IsoError::Unsupported(message) => write!(f, "unsupported image: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for IsoError {}
+
+impl From<io::Error> for IsoError {
+    fn from(e: io::Error) -> Self {
+        IsoError::Io(e)
+    }
+}
+
+/// A run of file data in the image. Unrecorded runs (`offset` of `None`) read
+/// as zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: Option<u64>,
+    pub len: u64,
+}
+
+/// A file or directory found in an image, with a '/'-separated path relative
+/// to the root.
+#[derive(Debug, Clone)]
+pub struct IsoFile {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub extents: Vec<Extent>,
+}
+
+impl IsoFile {
+    /// Stream the file's contents out of `image`.
+    pub fn reader<'a, R: Read + Seek>(&'a self, image: &'a mut R) -> ExtentReader<'a, R> {
+        ExtentReader {
+            image,
+            extents: &self.extents,
+            index: 0,
+            within: 0,
+            remaining: self.size,
+        }
+    }
+}
+
+pub struct ExtentReader<'a, R> {
+    image: &'a mut R,
+    extents: &'a [Extent],
+    index: usize,
+    within: u64,
+    remaining: u64,
+}
+
+impl<R: Read + Seek> Read for ExtentReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.remaining == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            let Some(extent) = self.extents.get(self.index) else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file extends past its extents"));
+            };
+            if self.within >= extent.len {
+                self.index += 1;
+                self.within = 0;
+                continue;
+            }
+
+            let len = (buf.len() as u64).min(extent.len - self.within).min(self.remaining) as usize;
+            let n = match extent.offset {
+                Some(offset) => {
+                    self.image.seek(SeekFrom::Start(offset + self.within))?;
+                    let n = self.image.read(&mut buf[..len])?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "extent runs past the end of the image"));
+                    }
+                    n
+                }
+                None => {
+                    buf[..len].fill(0);
+                    len
+                }
+            };
+            self.within += n as u64;
+            self.remaining -= n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DirLocation {
+    extent: u32,
+    len: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeDescriptor {
+    pub label: String,
+    root: DirLocation,
+}
+
+/// What the volume descriptor area of an image contains.
+#[derive(Debug, Clone, Default)]
+pub struct Descriptors {
+    pub primary: Option<VolumeDescriptor>,
+    pub joliet: Option<VolumeDescriptor>,
+    /// Sector of the El Torito boot catalog.
+    pub boot_catalog: Option<u32>,
+    /// An NSR02/NSR03 descriptor announces a UDF file system.
+    pub udf: bool,
+}
+
+/// Read the volume descriptor set and the UDF volume recognition sequence
+/// that may follow it. Fails with `NotIso` when neither is present.
+pub fn read_descriptors<R: Read + Seek>(image: &mut R) -> Result<Descriptors, IsoError> {
+    let mut descriptors = Descriptors::default();
+    let mut recognised = false;
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+
+    for index in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+        if read_sector(image, index, &mut sector).is_err() {
+            break;
+        }
+        let kind = sector[0];
+        match &sector[1..6] {
+            STANDARD_ID => {
+                recognised = true;
+                match kind {
+                    DESCRIPTOR_PRIMARY if descriptors.primary.is_none() => {
+                        descriptors.primary = Some(volume_descriptor(&sector, false));
+                    }
+                    DESCRIPTOR_SUPPLEMENTARY if descriptors.joliet.is_none() && is_joliet(&sector) => {
+                        descriptors.joliet = Some(volume_descriptor(&sector, true));
+                    }
+                    DESCRIPTOR_BOOT if sector[7..7 + EL_TORITO_ID.len()] == *EL_TORITO_ID => {
+                        descriptors.boot_catalog = Some(le32(&sector[71..75]));
+                    }
+                    // Keep going past the terminator: the UDF recognition
+                    // sequence follows it
+                    _ => {}
+                }
+            }
+            b"BEA01" | b"TEA01" | b"BOOT2" => recognised = true,
+            b"NSR02" | b"NSR03" => {
+                recognised = true;
+                descriptors.udf = true;
+            }
+            _ => break,
+        }
+    }
+
+    if !recognised {
+        return Err(IsoError::NotIso);
+    }
+    Ok(descriptors)
+}
+
+fn volume_descriptor(sector: &[u8], joliet: bool) -> VolumeDescriptor {
+    let label = if joliet {
+        ucs2_name(&sector[40..72])
+    } else {
+        String::from_utf8_lossy(&sector[40..72]).into_owned()
+    };
+    let root = &sector[156..190];
+    VolumeDescriptor {
+        label: label.trim_end_matches([' ', '\0']).to_string(),
+        root: DirLocation { extent: le32(&root[2..6]), len: le32(&root[10..14]) },
+    }
+}
+
+// Joliet is a supplementary descriptor whose escape sequences select UCS-2
+// level 1, 2 or 3.
+fn is_joliet(sector: &[u8]) -> bool {
+    sector[88..90] == *b"%/" && matches!(sector[90], b'@' | b'C' | b'E')
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootPlatform {
+    Bios,
+    Efi,
+    Other,
+}
+
+impl BootPlatform {
+    fn from_id(id: u8) -> Self {
+        match id {
+            0x00 => BootPlatform::Bios,
+            0xef => BootPlatform::Efi,
+            _ => BootPlatform::Other,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootMedia {
+    NoEmulation,
+    Floppy,
+    HardDisk,
+}
+
+/// One initial or section entry from the El Torito boot catalog.
+#[derive(Serialize, Debug, Clone)]
+pub struct BootEntry {
+    pub platform: BootPlatform,
+    pub bootable: bool,
+    pub media: BootMedia,
+    /// Sector of the boot image.
+    pub load_rba: u32,
+    /// Size of the boot image in 512-byte sectors; 0 means "to the end of
+    /// the image", which EFI entries often rely on.
+    pub sectors: u16,
+}
+
+/// Parse the El Torito boot catalog at `catalog`.
+pub fn read_boot_catalog<R: Read + Seek>(image: &mut R, catalog: u32) -> Result<Vec<BootEntry>, IsoError> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    read_sector(image, catalog as u64, &mut sector)?;
+
+    let validation = &sector[..32];
+    if validation[0] != 0x01 || validation[30..32] != [0x55, 0xaa] {
+        return Err(IsoError::Corrupt("boot catalog has no validation entry".to_string()));
+    }
+    let checksum = validation
+        .chunks(2)
+        .fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+    if checksum != 0 {
+        return Err(IsoError::Corrupt("boot catalog validation entry checksum mismatch".to_string()));
+    }
+
+    let mut entries = vec![boot_entry(&sector[32..64], BootPlatform::from_id(validation[1]))];
+
+    // Section headers follow the initial entry; 0x90 means another header
+    // comes after this section, 0x91 marks the last one.
+    let mut offset = 64;
+    while offset + 32 <= sector.len() {
+        let header = &sector[offset..offset + 32];
+        if header[0] != 0x90 && header[0] != 0x91 {
+            break;
+        }
+        let platform = BootPlatform::from_id(header[1]);
+        let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+        offset += 32;
+
+        let mut seen = 0;
+        while seen < count && offset + 32 <= sector.len() {
+            let entry = &sector[offset..offset + 32];
+            offset += 32;
+            // Extension entries carry extra selection criteria only
+            if entry[0] == 0x44 {
+                continue;
+            }
+            entries.push(boot_entry(entry, platform));
+            seen += 1;
+        }
+
+        if header[0] == 0x91 {
+            break;
+        }
+    }
+
+    Ok(entries)
+}
+
+fn boot_entry(entry: &[u8], platform: BootPlatform) -> BootEntry {
+    let media = match entry[1] & 0x0f {
+        0 => BootMedia::NoEmulation,
+        1..=3 => BootMedia::Floppy,
+        _ => BootMedia::HardDisk,
+    };
+    BootEntry {
+        platform,
+        bootable: entry[0] == 0x88,
+        media,
+        load_rba: le32(&entry[8..12]),
+        sectors: u16::from_le_bytes([entry[6], entry[7]]),
+    }
+}
+
+/// Whether the primary tree carries Rock Ridge (SUSP "SP" in the root's "."
+/// record) names.
+pub fn has_rock_ridge<R: Read + Seek>(image: &mut R, descriptors: &Descriptors) -> Result<bool, IsoError> {
+    let Some(primary) = &descriptors.primary else {
+        return Ok(false);
+    };
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    read_sector(image, primary.root.extent as u64, &mut sector)?;
+
+    let len = sector[0] as usize;
+    if len < 34 {
+        return Ok(false);
+    }
+    let system_use = &sector[system_use_start(&sector[..len])..len];
+    Ok(system_use.len() >= 7 && system_use[..2] == *b"SP" && system_use[4..6] == [0xbe, 0xef])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    RockRidge,
+    Joliet,
+    Plain,
+}
+
+/// List every file and directory on the ISO9660 side of the image, using
+/// Rock Ridge names where present, then Joliet, then plain ISO9660 names.
+pub fn files<R: Read + Seek>(image: &mut R, descriptors: &Descriptors) -> Result<Vec<IsoFile>, IsoError> {
+    let (root, names) = match (&descriptors.primary, &descriptors.joliet) {
+        (Some(primary), _) if has_rock_ridge(image, descriptors)? => (primary.root, Names::RockRidge),
+        (_, Some(joliet)) => (joliet.root, Names::Joliet),
+        (Some(primary), None) => (primary.root, Names::Plain),
+        (None, None) => return Err(IsoError::NotIso),
+    };
+
+    let mut walker = Walker { image, names, visited: HashSet::new(), out: Vec::new() };
+    walker.walk(root, "", 0)?;
+    Ok(walker.out)
+}
+
+struct Walker<'a, R> {
+    image: &'a mut R,
+    names: Names,
+    visited: HashSet<u32>,
+    out: Vec<IsoFile>,
+}
+
+/// What the system use area of a record says about it.
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    /// Directory relocated elsewhere by the child link at this sector.
+    child_link: Option<u32>,
+    /// This is the relocated copy; it is listed through its child link.
+    relocated: bool,
+}
+
+impl<R: Read + Seek> Walker<'_, R> {
+    fn walk(&mut self, dir: DirLocation, prefix: &str, depth: usize) -> Result<(), IsoError> {
+        if depth > MAX_DEPTH || !self.visited.insert(dir.extent) {
+            return Err(IsoError::Corrupt(format!("directory loop at sector {}", dir.extent)));
+        }
+
+        let mut data = vec![0u8; dir.len as usize];
+        self.image.seek(SeekFrom::Start(dir.extent as u64 * SECTOR_SIZE))?;
+        self.image.read_exact(&mut data)?;
+
+        let mut pending: Vec<Extent> = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = data[offset] as usize;
+            // Records never straddle sectors; a zero length pads to the next
+            if len == 0 {
+                offset = (offset / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            if len < 34 || offset + len > data.len() {
+                return Err(IsoError::Corrupt(format!("bad directory record in sector {}", dir.extent)));
+            }
+            let record = &data[offset..offset + len];
+            offset += len;
+
+            let name_len = record[32] as usize;
+            if 33 + name_len > record.len() {
+                return Err(IsoError::Corrupt(format!("bad directory record in sector {}", dir.extent)));
+            }
+            let raw_name = &record[33..33 + name_len];
+            if name_len == 1 && (raw_name[0] == 0 || raw_name[0] == 1) {
+                continue;
+            }
+
+            let flags = record[25];
+            let extent = le32(&record[2..6]);
+            let size = le32(&record[10..14]);
+            pending.push(Extent { offset: Some(extent as u64 * SECTOR_SIZE), len: size as u64 });
+            if flags & FLAG_MULTI_EXTENT != 0 {
+                continue;
+            }
+            let extents = std::mem::take(&mut pending);
+
+            let rock_ridge = match self.names {
+                Names::RockRidge => self.rock_ridge(record)?,
+                _ => RockRidge::default(),
+            };
+            if rock_ridge.relocated {
+                continue;
+            }
+            let name = match (self.names, rock_ridge.name) {
+                (_, Some(name)) => name,
+                (Names::Joliet, None) => strip_version(&ucs2_name(raw_name)),
+                _ => strip_version(&String::from_utf8_lossy(raw_name)),
+            };
+            let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+
+            if let Some(target) = rock_ridge.child_link {
+                let target = self.relocated_dir(target)?;
+                self.out.push(IsoFile { path: path.clone(), is_dir: true, size: 0, extents: Vec::new() });
+                self.walk(target, &path, depth + 1)?;
+            } else if flags & FLAG_DIRECTORY != 0 {
+                self.out.push(IsoFile { path: path.clone(), is_dir: true, size: 0, extents: Vec::new() });
+                self.walk(DirLocation { extent, len: size }, &path, depth + 1)?;
+            } else {
+                let size = extents.iter().map(|extent| extent.len).sum();
+                self.out.push(IsoFile { path, is_dir: false, size, extents });
+            }
+        }
+        Ok(())
+    }
+
+    // A relocated directory's size is only recorded in its own "." entry.
+    fn relocated_dir(&mut self, extent: u32) -> Result<DirLocation, IsoError> {
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        read_sector(self.image, extent as u64, &mut sector)?;
+        Ok(DirLocation { extent, len: le32(&sector[10..14]) })
+    }
+
+    fn rock_ridge(&mut self, record: &[u8]) -> Result<RockRidge, IsoError> {
+        let mut result = RockRidge::default();
+        let mut name = String::new();
+        let mut has_name = false;
+
+        let mut area = record[system_use_start(record)..].to_vec();
+        // Continuation areas are followed at most a few times; real images
+        // need one at most, and a cycle must not hang the walk.
+        for _ in 0..8 {
+            let mut continuation = None;
+            let mut pos = 0;
+            while pos + 4 <= area.len() {
+                let len = area[pos + 2] as usize;
+                if len < 4 || pos + len > area.len() {
+                    break;
+                }
+                let entry = &area[pos..pos + len];
+                match &entry[..2] {
+                    // CURRENT and PARENT names only occur on "." and ".."
+                    b"NM" if len >= 5 && entry[4] & 0x06 == 0 => {
+                        name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                        has_name = true;
+                    }
+                    b"CL" if len >= 12 => result.child_link = Some(le32(&entry[4..8])),
+                    b"RE" => result.relocated = true,
+                    b"CE" if len >= 28 => {
+                        continuation = Some((le32(&entry[4..8]), le32(&entry[12..16]), le32(&entry[20..24])));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+                pos += len;
+            }
+
+            let Some((block, offset, len)) = continuation else {
+                break;
+            };
+            area = vec![0u8; len as usize];
+            self.image.seek(SeekFrom::Start(block as u64 * SECTOR_SIZE + offset as u64))?;
+            self.image.read_exact(&mut area)?;
+        }
+
+        if has_name {
+            result.name = Some(name);
+        }
+        Ok(result)
+    }
+}
+
+/// Byte offsets of every directory record, in the primary and Joliet trees
+/// alike, whose data starts at byte `offset` of the image. A file's size
+/// lives in each of them, so changing it in place means rewriting them all.
+pub fn records_at<R: Read + Seek>(image: &mut R, descriptors: &Descriptors, offset: u64) -> Result<Vec<u64>, IsoError> {
+    let mut found = Vec::new();
+    for descriptor in [&descriptors.primary, &descriptors.joliet].into_iter().flatten() {
+        let mut visited = HashSet::new();
+        find_records(image, descriptor.root, offset, &mut visited, 0, &mut found)?;
+    }
+    Ok(found)
+}
+
+fn find_records<R: Read + Seek>(
+    image: &mut R,
+    dir: DirLocation,
+    offset: u64,
+    visited: &mut HashSet<u32>,
+    depth: usize,
+    found: &mut Vec<u64>,
+) -> Result<(), IsoError> {
+    if depth > MAX_DEPTH || !visited.insert(dir.extent) {
+        return Err(IsoError::Corrupt(format!("directory loop at sector {}", dir.extent)));
+    }
+
+    let mut data = vec![0u8; dir.len as usize];
+    image.seek(SeekFrom::Start(dir.extent as u64 * SECTOR_SIZE))?;
+    image.read_exact(&mut data)?;
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        if len == 0 {
+            pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+            continue;
+        }
+        if len < 34 || pos + len > data.len() || 33 + data[pos + 32] as usize > len {
+            return Err(IsoError::Corrupt(format!("bad directory record in sector {}", dir.extent)));
+        }
+        let record = &data[pos..pos + len];
+        let name = &record[33..33 + record[32] as usize];
+        let extent = le32(&record[2..6]);
+        if !(name.len() == 1 && (name[0] == 0 || name[0] == 1)) {
+            if record[25] & FLAG_DIRECTORY != 0 {
+                find_records(image, DirLocation { extent, len: le32(&record[10..14]) }, offset, visited, depth + 1, found)?;
+            } else if extent as u64 * SECTOR_SIZE == offset {
+                found.push(dir.extent as u64 * SECTOR_SIZE + pos as u64);
+            }
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+// The system use area starts after the name and its padding byte, which is
+// present when the name length is even.
+fn system_use_start(record: &[u8]) -> usize {
+    let name_len = record[32] as usize;
+    (33 + name_len + (1 - name_len % 2)).min(record.len())
+}
+
+// Plain and Joliet names carry a ";1" version and, without an extension, a
+// trailing dot.
+fn strip_version(name: &str) -> String {
+    let name = name.split(';').next().unwrap_or(name);
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+fn ucs2_name(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn read_sector<R: Read + Seek>(image: &mut R, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+    image.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    image.read_exact(buf)
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -0,0 +1,236 @@
+// Running-job registry and cancellation.
+//
+// Every job gets an id and a cancel token. The WebSocket read loop and the
+// Tauri `cancel_job` command look the token up by id; the writer and child
+// process helpers poll it and stop at the next chunk or poll interval.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time a cancelled command gets to exit after SIGTERM before SIGKILL.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once the token is cancelled.
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn registry() -> &'static Mutex<HashMap<String, CancelToken>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, CancelToken>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn next_job_id() -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    format!("job-{}", NEXT_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Whether a client-supplied job id is safe to log, echo and key jobs by.
+pub fn valid_job_id(job_id: &str) -> bool {
+    !job_id.is_empty() && job_id.len() <= 64 && job_id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Create a scratch directory for files a job stages outside the device; the
+/// caller removes it. Privileged tools read from and write into it, so the
+/// name is random and never derived from the job, and the directory is made
+/// fresh and private rather than reused.
+pub fn create_work_dir() -> std::io::Result<PathBuf> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    loop {
+        let dir = std::env::temp_dir().join(format!("webbboot-{:016x}", rand::random::<u64>()));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Track a job for the lifetime of the returned guard. Returns None while
+/// another job holds the id, so neither can take over the other's token.
+pub fn register(job_id: &str) -> Option<RegisteredJob> {
+    let mut registry = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match registry.entry(job_id.to_string()) {
+        Entry::Occupied(_) => None,
+        Entry::Vacant(entry) => {
+            let token = CancelToken::default();
+            entry.insert(token.clone());
+            Some(RegisteredJob { job_id: job_id.to_string(), token })
+        }
+    }
+}
+
+/// Track a job that came without an id under a fresh one, skipping any a
+/// client already took.
+pub fn register_new() -> RegisteredJob {
+    loop {
+        if let Some(registration) = register(&next_job_id()) {
+            return registration;
+        }
+    }
+}
+
+/// Request cancellation of a running job. Returns false for unknown ids.
+pub fn cancel(job_id: &str) -> bool {
+    let registry = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match registry.get(job_id) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+pub struct RegisteredJob {
+    job_id: String,
+    token: CancelToken,
+}
+
+impl RegisteredJob {
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for RegisteredJob {
+    fn drop(&mut self) {
+        registry()
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.job_id);
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn(std::io::Error),
+    Wait(std::io::Error),
+    Cancelled,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Spawn(e) => write!(f, "failed to start: {}", e),
+            CommandError::Wait(e) => write!(f, "failed while running: {}", e),
+            CommandError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Run a command to completion, stopping it if `token` is cancelled first.
+/// Returns only once the child has exited, so the caller may touch the
+/// device the command was working on.
+pub async fn run_cancellable(command: std::process::Command, token: &CancelToken) -> Result<Output, CommandError> {
+    let mut command = tokio::process::Command::from(command);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+
+    let mut child = command.spawn().map_err(CommandError::Spawn)?;
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+
+    // Drain both pipes concurrently so a chatty tool cannot block on a full pipe.
+    let stdout_task = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        if let Some(stdout) = stdout.as_mut() {
+            let _ = stdout.read_to_end(&mut buffer).await;
+        }
+        buffer
+    });
+    let stderr_task = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        if let Some(stderr) = stderr.as_mut() {
+            let _ = stderr.read_to_end(&mut buffer).await;
+        }
+        buffer
+    });
+
+    let status = tokio::select! {
+        status = child.wait() => status.map_err(CommandError::Wait)?,
+        _ = token.cancelled() => {
+            stop(&mut child).await;
+            return Err(CommandError::Cancelled);
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout_task.await.unwrap_or_default(),
+        stderr: stderr_task.await.unwrap_or_default(),
+    })
+}
+
+// Commands usually run under sudo, which relays SIGTERM to the tool but
+// cannot relay SIGKILL, so terminate first and only kill what ignores it.
+async fn stop(child: &mut tokio::process::Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        if tokio::time::timeout(TERMINATE_TIMEOUT, child.wait()).await.is_ok() {
+            return;
+        }
+    }
+    let _ = child.kill().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_commands_get_sigterm_and_are_reaped() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("terminated");
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("trap 'kill $!; echo term > \"$0\"; exit 0' TERM; sleep 30 & wait").arg(&marker);
+
+        let token = CancelToken::default();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel.cancel();
+        });
+        let started = std::time::Instant::now();
+        assert!(matches!(run_cancellable(command, &token).await, Err(CommandError::Cancelled)));
+        assert!(started.elapsed() < TERMINATE_TIMEOUT);
+        // The trap ran before run_cancellable returned
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "term\n");
+    }
+
+    #[tokio::test]
+    async fn finished_commands_return_their_output() {
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2; exit 3");
+        let output = run_cancellable(command, &CancelToken::default()).await.unwrap();
+        assert_eq!((output.status.code(), &output.stdout[..], &output.stderr[..]), (Some(3), &b"out\n"[..], &b"err\n"[..]));
+    }
+}
@@ -0,0 +1,142 @@
+// Extracted Linux media.
+//
+// An ISO without a partition table in its system area only boots from
+// optical media; written raw to a stick, firmware finds nothing to start.
+// Such images are copied file by file onto a FAT32 partition instead, with a
+// boot menu built from the ISO's own menus. syslinux boots it on legacy
+// BIOS, and UEFI starts the ISO's EFI/BOOT loader or, when it has none, a
+// standalone GRUB carrying the same menu. The FAT label cannot always match
+// the ISO's, so boot configs naming the old label are rewritten.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Seek};
+
+use crate::bootloader::EFI_LOADER;
+use crate::bootmenu::{self, MenuEntry};
+use crate::bootpatch;
+use crate::iso9660::IsoFile;
+
+/// syslinux modules the generated menu needs.
+pub const SYSLINUX_MODULES: &[&str] = &["menu.c32", "libutil.c32", "libcom32.c32"];
+
+/// Top-level directories whose config files may name the volume label.
+const CONFIG_DIRS: &[&str] = &["boot", "efi", "isolinux", "syslinux", "loader"];
+const CONFIG_EXTENSIONS: &[&str] = &[".cfg", ".conf"];
+const SYSLINUX_DIRS: &[&str] = &["isolinux", "syslinux", "boot/isolinux", "boot/syslinux"];
+
+/// Anything larger is not a boot config.
+const MAX_CONFIG_SIZE: u64 = 1024 * 1024;
+
+/// What extracting an image involves beyond copying its files.
+pub struct ExtractPlan {
+    pub entries: Vec<MenuEntry>,
+    /// Boot configs rewritten for the FAT label, by path.
+    pub patched: Vec<(String, String)>,
+    /// The image brings its own removable-media EFI loader.
+    pub has_efi_loader: bool,
+}
+
+/// A FAT32 label standing in for the ISO volume label `iso_label`.
+pub fn fat_label(iso_label: &str) -> Option<String> {
+    let label: String = iso_label
+        .chars()
+        .filter(char::is_ascii)
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c.to_ascii_uppercase() } else { '_' })
+        .take(11)
+        .collect();
+    let label = label.trim_matches('_');
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Work out the boot menu for `files` and the configs to rewrite. `labels`
+/// are the ISO's volume labels, which become `fat_label`. Every kernel
+/// command line gets `parameter`, when there is one.
+pub fn plan<R: Read + Seek>(
+    image: &mut R,
+    files: &[IsoFile],
+    labels: &[String],
+    fat_label: &str,
+    distribution: Option<&str>,
+    parameter: Option<&str>,
+) -> io::Result<ExtractPlan> {
+    // Longer labels first, so one that contains another is replaced whole
+    let mut labels = labels.to_vec();
+    labels.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    labels.dedup();
+
+    let mut entries = Vec::new();
+    let mut patched = Vec::new();
+    for file in files.iter().filter(|file| is_config(file)) {
+        let mut data = Vec::new();
+        file.reader(image).read_to_end(&mut data)?;
+        let Ok(text) = String::from_utf8(data) else {
+            continue;
+        };
+
+        let dir = bootmenu::parent(&file.path);
+        let syslinux = SYSLINUX_DIRS.iter().any(|syslinux| dir.eq_ignore_ascii_case(syslinux));
+        if bootmenu::file_name(&file.path).eq_ignore_ascii_case("grub.cfg") {
+            entries.extend(bootmenu::parse_grub(&text));
+        } else if syslinux {
+            entries.extend(bootmenu::parse_syslinux(&text, dir));
+        }
+
+        let mut rewritten = relabel(&text, &labels, fat_label);
+        if let Some(parameter) = parameter {
+            rewritten = bootpatch::add_parameter(&rewritten, &[], parameter, syslinux, usize::MAX).0;
+        }
+        if rewritten != text {
+            patched.push((file.path.clone(), rewritten));
+        }
+    }
+
+    // Only entries whose files made it into the listing can boot
+    let present: HashSet<String> = files.iter().map(|file| format!("/{}", file.path.to_lowercase())).collect();
+    let mut seen = HashSet::new();
+    entries.retain(|entry| {
+        let complete = std::iter::once(&entry.kernel).chain(&entry.initrds).all(|path| present.contains(&path.to_lowercase()));
+        complete && seen.insert((entry.kernel.to_lowercase(), entry.initrds.join(",").to_lowercase(), entry.args.clone()))
+    });
+    for entry in &mut entries {
+        entry.args = relabel(&entry.args, &labels, fat_label);
+    }
+    if entries.is_empty() {
+        entries = bootmenu::discover(files, |dir| default_args(distribution, dir, fat_label));
+    }
+    if let Some(parameter) = parameter {
+        for entry in &mut entries {
+            entry.args = bootpatch::insert_parameter(&entry.args, parameter);
+        }
+    }
+
+    Ok(ExtractPlan {
+        entries,
+        patched,
+        has_efi_loader: files.iter().any(|file| !file.is_dir && file.path.eq_ignore_ascii_case(EFI_LOADER)),
+    })
+}
+
+fn is_config(file: &IsoFile) -> bool {
+    let path = file.path.to_lowercase();
+    !file.is_dir
+        && file.size <= MAX_CONFIG_SIZE
+        && CONFIG_EXTENSIONS.iter().any(|extension| path.ends_with(extension))
+        && CONFIG_DIRS.iter().any(|dir| path.starts_with(&format!("{}/", dir)))
+}
+
+fn relabel(text: &str, labels: &[String], fat_label: &str) -> String {
+    labels.iter().fold(text.to_string(), |text, label| bootmenu::replace_label(&text, label, fat_label))
+}
+
+// The command line a distribution's live or installer kernel needs to find
+// its root when no menu says so.
+fn default_args(distribution: Option<&str>, kernel_dir: &str, label: &str) -> String {
+    match distribution {
+        Some("ubuntu") => "boot=casper".to_string(),
+        Some("debian") if kernel_dir.eq_ignore_ascii_case("live") => "boot=live components".to_string(),
+        Some("arch") => format!("archisobasedir=arch archisolabel={}", label),
+        Some("redhat") if kernel_dir.eq_ignore_ascii_case("images/pxeboot") => format!("inst.stage2=hd:LABEL={}", label),
+        Some("redhat") => format!("root=live:LABEL={} rd.live.image", label),
+        _ => String::new(),
+    }
+}
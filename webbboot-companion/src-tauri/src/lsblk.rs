@@ -0,0 +1,186 @@
+// Parse `lsblk --json --bytes` into a block device tree.
+//
+// Older util-linux releases only know the singular MOUNTPOINT column and print
+// sizes as strings, newer ones add MOUNTPOINTS (an array) and print numbers,
+// so the parser accepts both shapes.
+
+use serde_json::Value;
+use std::process::Command;
+
+const COLUMNS: &str = "NAME,PATH,TYPE,SIZE,FSTYPE,LABEL,UUID,MOUNTPOINTS";
+const LEGACY_COLUMNS: &str = "NAME,PATH,TYPE,SIZE,FSTYPE,LABEL,UUID,MOUNTPOINT";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockNode {
+    pub name: String,
+    pub path: String,
+    pub kind: String,
+    pub size: u64,
+    pub fstype: Option<String>,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    pub mount_points: Vec<String>,
+    pub children: Vec<BlockNode>,
+}
+
+impl BlockNode {
+    /// Mount points of this node and everything stacked on it, such as an
+    /// unlocked LUKS volume or an LVM logical volume inside a partition.
+    pub fn all_mount_points(&self) -> Vec<String> {
+        let mut mount_points = self.mount_points.clone();
+        for child in &self.children {
+            mount_points.extend(child.all_mount_points());
+        }
+        mount_points
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        !self.all_mount_points().is_empty()
+    }
+}
+
+/// Run lsblk for a single device and return its tree.
+pub fn query(device_path: &str) -> Result<BlockNode, String> {
+    let output = Command::new("lsblk")
+        .args(["--json", "--bytes", "-o", COLUMNS, device_path])
+        .output()
+        .map_err(|e| format!("Failed to run lsblk: {}", e))?;
+
+    let output = if output.status.success() {
+        output
+    } else {
+        Command::new("lsblk")
+            .args(["--json", "--bytes", "-o", LEGACY_COLUMNS, device_path])
+            .output()
+            .map_err(|e| format!("Failed to run lsblk: {}", e))?
+    };
+
+    if !output.status.success() {
+        return Err(format!("lsblk failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    parse(&String::from_utf8_lossy(&output.stdout))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("lsblk returned no information for {}", device_path))
+}
+
+pub fn parse(json: &str) -> Result<Vec<BlockNode>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("Invalid lsblk output: {}", e))?;
+    let devices = value
+        .get("blockdevices")
+        .and_then(Value::as_array)
+        .ok_or("lsblk output has no blockdevices list")?;
+
+    devices.iter().map(parse_node).collect()
+}
+
+fn parse_node(value: &Value) -> Result<BlockNode, String> {
+    let name = string_field(value, "name").ok_or("lsblk entry without a name")?;
+    let path = string_field(value, "path").unwrap_or_else(|| format!("/dev/{}", name));
+
+    let size = match value.get("size") {
+        Some(Value::Number(n)) => n.as_u64().unwrap_or(0),
+        Some(Value::String(s)) => s.trim().parse().unwrap_or(0),
+        _ => 0,
+    };
+
+    let mut mount_points: Vec<String> = match value.get("mountpoints") {
+        Some(Value::Array(points)) => points.iter().filter_map(|p| p.as_str().map(str::to_string)).collect(),
+        _ => vec![],
+    };
+    if let Some(point) = string_field(value, "mountpoint") {
+        if !mount_points.contains(&point) {
+            mount_points.push(point);
+        }
+    }
+
+    let children = match value.get("children") {
+        Some(Value::Array(children)) => children.iter().map(parse_node).collect::<Result<Vec<_>, _>>()?,
+        _ => vec![],
+    };
+
+    Ok(BlockNode {
+        name,
+        path,
+        kind: string_field(value, "type").unwrap_or_default(),
+        size,
+        fstype: string_field(value, "fstype"),
+        label: string_field(value, "label"),
+        uuid: string_field(value, "uuid"),
+        mount_points,
+        children,
+    })
+}
+
+// lsblk emits `null` for empty columns; treat empty strings the same way.
+fn string_field(value: &Value, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // util-linux 2.37+: MOUNTPOINTS is an array (with null for unmounted), sizes are numbers
+    const CURRENT: &str = r#"{
+        "blockdevices": [
+            {"name": "sdb", "path": "/dev/sdb", "type": "disk", "size": 31914983424,
+             "fstype": null, "label": null, "uuid": null, "mountpoints": [null],
+             "children": [
+                {"name": "sdb1", "path": "/dev/sdb1", "type": "part", "size": 536870912,
+                 "fstype": "vfat", "label": "EFI", "uuid": "1234-ABCD", "mountpoints": [null]},
+                {"name": "sdb2", "path": "/dev/sdb2", "type": "part", "size": 31376015360,
+                 "fstype": "ext4", "label": "", "uuid": "0f3c",
+                 "mountpoints": ["/media/usb", "/mnt/bind"]}
+             ]}
+        ]
+    }"#;
+
+    // Older util-linux: singular MOUNTPOINT, sizes as strings, no PATH column
+    const LEGACY: &str = r#"{
+        "blockdevices": [
+            {"name": "sdc", "type": "disk", "size": "15931539456",
+             "fstype": null, "label": null, "uuid": null, "mountpoint": null,
+             "children": [
+                {"name": "sdc1", "type": "part", "size": "15930490880",
+                 "fstype": "vfat", "label": "STICK", "uuid": "AB12-34CD", "mountpoint": "/run/media/user/STICK"}
+             ]}
+        ]
+    }"#;
+
+    #[test]
+    fn finds_a_mounted_partition_in_mountpoints_arrays() {
+        let disk = parse(CURRENT).unwrap().remove(0);
+        assert_eq!((disk.path.as_str(), disk.size), ("/dev/sdb", 31_914_983_424));
+        assert!(disk.mount_points.is_empty());
+        assert!(disk.children[0].mount_points.is_empty());
+        assert_eq!(disk.children[1].mount_points, ["/media/usb", "/mnt/bind"]);
+        assert_eq!(disk.children[1].label, None);
+        assert!(disk.is_mounted());
+        assert_eq!(disk.all_mount_points(), ["/media/usb", "/mnt/bind"]);
+    }
+
+    #[test]
+    fn finds_a_mounted_partition_in_legacy_output() {
+        let disk = parse(LEGACY).unwrap().remove(0);
+        assert_eq!((disk.path.as_str(), disk.size), ("/dev/sdc", 15_931_539_456));
+        assert!(disk.mount_points.is_empty());
+        let partition = &disk.children[0];
+        assert_eq!((partition.path.as_str(), partition.label.as_deref()), ("/dev/sdc1", Some("STICK")));
+        assert!(disk.is_mounted());
+        assert_eq!(disk.all_mount_points(), ["/run/media/user/STICK"]);
+    }
+
+    #[test]
+    fn unmounted_disks_are_not_mounted() {
+        let json = CURRENT.replace(r#"["/media/usb", "/mnt/bind"]"#, "[null]");
+        assert!(!parse(&json).unwrap()[0].is_mounted());
+        assert!(parse("{}").is_err());
+        assert!(parse(r#"{"blockdevices": [{"path": "/dev/sdd"}]}"#).is_err());
+    }
+}
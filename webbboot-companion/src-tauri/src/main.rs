@@ -11,13 +11,107 @@ use tokio_tungstenite::accept_async;
 use futures_util::{SinkExt, StreamExt};
 use std::fs;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod sysfs;
+#[cfg(target_os = "linux")]
+mod protect;
+#[cfg(target_os = "linux")]
+mod lsblk;
+#[cfg(target_os = "linux")]
+mod writer;
+#[cfg(target_os = "linux")]
+mod verify;
+mod capabilities;
+mod checksum;
+mod filesystem;
+mod iso9660;
+mod udf;
+mod inspect;
+mod advisor;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod fat32;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod exfat;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod fatfs;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod decompress;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod partition;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod bootloader;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod wininstall;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod bootmenu;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod linuxinstall;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod multiboot;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod bootpatch;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod persistence;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod provision;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod raspberrypi;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod boottest;
+mod progress;
+mod jobs;
+
+use progress::{Phase, ProgressTracker};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Job {
+    id: Option<String>,
     action: String,
     iso: Option<String>,
     filesystem: String,
     scheme: String,
     device: String,
+    verify: Option<String>,
+    /// Expected image digest: "sha256:<hex>", "sha512:<hex>", "blake3:<hex>" or bare hex.
+    checksum: Option<String>,
+    /// SHA256SUMS-style file listing the image.
+    checksum_file: Option<String>,
+    /// Detached OpenPGP signature over the sums file or the image.
+    signature: Option<String>,
+    /// Offline keyring the signature must verify against.
+    keyring: Option<String>,
+    /// Volume label for the formatted partition.
+    label: Option<String>,
+    /// Cluster size in bytes; the mkfs default when absent.
+    cluster_size: Option<u32>,
+    /// Quick format (the default) or a full pass over the device.
+    quick: Option<bool>,
+    /// Local directory whose contents are copied onto the restored partition.
+    files: Option<String>,
+    /// How a create job writes the image: "raw", "windows" or "extract".
+    /// Chosen from the inspected image when absent.
+    mode: Option<String>,
+    /// What a multiboot job does: "prepare", "add" or "remove".
+    operation: Option<String>,
+    /// ISOs a multiboot job puts on the stick, or for "remove" the names of
+    /// those to delete.
+    images: Option<Vec<String>>,
+    /// Add a partition that a live image written as-is keeps its changes on.
+    persistence: Option<bool>,
+    /// Unattended-install data placed on the media after writing:
+    /// "autoinstall", "nocloud", "kickstart", "preseed" or "autounattend".
+    provision: Option<String>,
+    /// Local file holding that data.
+    provision_file: Option<String>,
+    /// First-boot settings for a Raspberry Pi OS image written as-is.
+    customize: Option<raspberrypi::Customization>,
+    /// Move the backup GPT of an image written as-is to the end of the
+    /// device. On unless turned off.
+    fix_gpt: Option<bool>,
+    /// Give that GPT fresh disk and partition GUIDs.
+    new_guids: Option<bool>,
+    /// Boot the finished stick in QEMU and wait for a marker to show up.
+    boot_test: Option<boottest::BootTest>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,21 +122,50 @@ struct UsbDevice {
     product_id: u16,
     size: Option<u64>,
     mount_point: Option<String>,
+    device_path: Option<String>,
+    partitions: Vec<String>,
+    sysfs_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct ProgressUpdate {
-    status: String,
-    progress: u8,
-    current_operation: String,
+struct DeviceInfo {
+    path: String,
+    size: u64,
+    filesystem: Option<String>,
+    is_mounted: bool,
+    mount_points: Vec<String>,
+    partitions: Vec<PartitionInfo>,
+}
+
+/// Why `verify_device` turned a device down.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum DeviceError {
+    NotFound,
+    /// The running system depends on the device.
+    #[cfg(target_os = "linux")]
+    Protected(protect::ProtectionError),
+    Unavailable,
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::NotFound => write!(f, "Device does not exist"),
+            #[cfg(target_os = "linux")]
+            DeviceError::Protected(e) => write!(f, "{}", e),
+            DeviceError::Unavailable => write!(f, "Unable to get device information"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct DeviceInfo {
+struct PartitionInfo {
     path: String,
     size: u64,
     filesystem: Option<String>,
-    is_mounted: bool,
+    label: Option<String>,
+    uuid: Option<String>,
     mount_points: Vec<String>,
 }
 
@@ -51,7 +174,7 @@ fn list_usb_devices() -> Vec<UsbDevice> {
     match devices() {
         Ok(dev_list) => dev_list
             .iter()
-            .filter_map(|dev| {
+            .flat_map(|dev| {
                 match dev.device_descriptor() {
                     Ok(desc) => {
                         match dev.open() {
@@ -65,31 +188,52 @@ fn list_usb_devices() -> Vec<UsbDevice> {
                                         if is_mass_storage {
                                             let device_id = format!("USB {:04x}:{:04x}", desc.vendor_id(), desc.product_id());
                                             let device_name = get_device_name(&desc, &handle);
-                                            let device_path = find_device_path(desc.vendor_id(), desc.product_id());
-                                            let device_size = get_device_size(&device_path);
-                                            
-                                            Some(UsbDevice {
-                                                id: device_path.unwrap_or(device_id.clone()),
-                                                name: format!("{} ({})", device_name, device_id),
-                                                vendor_id: desc.vendor_id(),
-                                                product_id: desc.product_id(),
-                                                size: device_size,
-                                                mount_point: None,
-                                            })
+                                            let block_devices = find_block_devices(&dev, &desc, &handle);
+
+                                            if block_devices.is_empty() {
+                                                // Keep the stick visible so the user sees it was detected,
+                                                // but without a device path no job can target it.
+                                                return vec![UsbDevice {
+                                                    id: device_id.clone(),
+                                                    name: format!("{} ({})", device_name, device_id),
+                                                    vendor_id: desc.vendor_id(),
+                                                    product_id: desc.product_id(),
+                                                    size: None,
+                                                    mount_point: None,
+                                                    device_path: None,
+                                                    partitions: vec![],
+                                                    sysfs_path: None,
+                                                }];
+                                            }
+
+                                            block_devices
+                                                .into_iter()
+                                                .map(|block| UsbDevice {
+                                                    id: block.dev_path.clone(),
+                                                    name: format!("{} ({})", device_name, device_id),
+                                                    vendor_id: desc.vendor_id(),
+                                                    product_id: desc.product_id(),
+                                                    size: block.size.or_else(|| get_device_size(&Some(block.dev_path.clone()))),
+                                                    mount_point: None,
+                                                    device_path: Some(block.dev_path),
+                                                    partitions: block.partitions,
+                                                    sysfs_path: Some(block.sysfs_path),
+                                                })
+                                                .collect()
                                         } else {
-                                            None
+                                            vec![]
                                         }
                                     } else {
-                                        None
+                                        vec![]
                                     }
                                 } else {
-                                    None
+                                    vec![]
                                 }
                             }
-                            Err(_) => None,
+                            Err(_) => vec![],
                         }
                     }
-                    Err(_) => None,
+                    Err(_) => vec![],
                 }
             })
             .collect(),
@@ -127,29 +271,30 @@ fn get_device_name(desc: &rusb::DeviceDescriptor, handle: &rusb::DeviceHandle<ru
 }
 
 #[cfg(target_os = "linux")]
-fn find_device_path(_vendor_id: u16, _product_id: u16) -> Option<String> {
-    // Try to find the actual device path in /dev
-    if let Ok(entries) = fs::read_dir("/dev") {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if let Some(name) = path.file_name() {
-                if let Some(name_str) = name.to_str() {
-                    if name_str.starts_with("sd") && name_str.len() == 3 {
-                        // This is a basic heuristic - in a real implementation,
-                        // you'd want to match the USB device to the block device
-                        // through sysfs or similar mechanisms
-                        return Some(path.to_string_lossy().into_owned());
-                    }
-                }
-            }
+fn find_block_devices(
+    dev: &rusb::Device<rusb::GlobalContext>,
+    desc: &rusb::DeviceDescriptor,
+    handle: &rusb::DeviceHandle<rusb::GlobalContext>,
+) -> Vec<sysfs::ResolvedBlockDevice> {
+    let port_path = match dev.port_numbers() {
+        Ok(ports) => ports,
+        Err(e) => {
+            warn!("Failed to read port path for USB device on bus {}: {}", dev.bus_number(), e);
+            return vec![];
         }
-    }
-    None
+    };
+    let serial = handle.read_serial_number_string_ascii(desc).ok();
+
+    sysfs::SysfsResolver::default().resolve(dev.bus_number(), &port_path, serial.as_deref())
 }
 
 #[cfg(not(target_os = "linux"))]
-fn find_device_path(_vendor_id: u16, _product_id: u16) -> Option<String> {
-    None
+fn find_block_devices(
+    _dev: &rusb::Device<rusb::GlobalContext>,
+    _desc: &rusb::DeviceDescriptor,
+    _handle: &rusb::DeviceHandle<rusb::GlobalContext>,
+) -> Vec<sysfs::ResolvedBlockDevice> {
+    vec![]
 }
 
 fn get_device_size(device_path: &Option<String>) -> Option<u64> {
@@ -173,262 +318,2110 @@ fn get_device_size(device_path: &Option<String>) -> Option<u64> {
 
 async fn execute_job(job: Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) {
     info!("Received job: {:?}", job);
-    
+
+    if let Some(id) = job.id.as_deref().filter(|id| !jobs::valid_job_id(id)) {
+        warn!("Rejecting job with invalid id {:?}", id);
+        let mut progress = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT);
+        progress.begin(Phase::Validate, 0);
+        send_error(write, &mut progress, "Error: Job ids may only contain letters, digits, '-' and '_'").await;
+        return;
+    }
+    let registration = match &job.id {
+        Some(id) => match jobs::register(id) {
+            Some(registration) => registration,
+            None => {
+                warn!("Rejecting job {}: a job with that id is already running", id);
+                let mut progress = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT).for_job(id);
+                progress.begin(Phase::Validate, 0);
+                send_error(write, &mut progress, &format!("Error: A job with id {} is already running", id)).await;
+                return;
+            }
+        },
+        None => jobs::register_new(),
+    };
+    let job_id = registration.job_id().to_string();
+    let cancel = registration.token().clone();
+
+    if job.action == "multiboot" {
+        #[cfg(target_os = "linux")]
+        execute_multiboot(&job, &job_id, write, &cancel).await;
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut progress = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT).for_job(&job_id);
+            progress.begin(Phase::Validate, 0);
+            send_error(write, &mut progress, "Error: Multiboot sticks can only be made on Linux").await;
+        }
+        return;
+    }
+
+    let iso_size = job.iso.as_ref().and_then(|iso| fs::metadata(iso).ok()).map(|metadata| metadata.len()).unwrap_or(0);
+    let image_info = match (&job.iso, job.action.as_str()) {
+        (Some(iso), "create") => Some(decompress::probe(Path::new(iso))),
+        _ => None,
+    };
+    let image_checks = match (&job.iso, job.action.as_str()) {
+        (Some(iso), "create") => checksum::plan(
+            Path::new(iso),
+            job.checksum.as_deref(),
+            job.checksum_file.as_deref(),
+            job.signature.as_deref(),
+            job.keyring.as_deref(),
+        ),
+        _ => Ok(checksum::CheckPlan::default()),
+    };
+    let hashes_image = image_checks.as_ref().map(|checks| checks.expected.is_some()).unwrap_or(false);
+    let files_size = job.files.as_ref().and_then(|files| fatfs::tree_size(Path::new(files)).ok()).unwrap_or(0);
+
+    // What the image is decides how it gets written, and so the whole plan
+    let assessment_job = job.clone();
+    let assessment_info = image_info.as_ref().and_then(|info| info.as_ref().ok()).cloned();
+    let assessment = tokio::task::spawn_blocking(move || assess_probed_job(&assessment_job, assessment_info.as_ref()))
+        .await
+        .unwrap_or_else(|e| Err(format!("Cannot check job: {}", e)));
+    let mode = assessment.as_ref().map(|assessment| assessment.mode).unwrap_or(advisor::WriteMode::Raw);
+    let image_report = assessment.as_ref().ok().and_then(|assessment| assessment.image.clone());
+    let copy_size = match (mode, &image_report) {
+        (advisor::WriteMode::Windows, Some(image)) => wininstall::copy_size(image),
+        (advisor::WriteMode::Extract, Some(image)) => image.content_size,
+        _ => files_size,
+    };
+    let mut progress = plan_job(&job, image_info.as_ref().and_then(|info| info.as_ref().ok()), hashes_image, mode, copy_size).for_job(&job_id);
+
     // Validate inputs
+    progress.begin(Phase::Validate, 0);
     if job.device.is_empty() {
-        send_progress_update(write, "Error: No device selected", 0, "validation").await;
+        send_error(write, &mut progress, "Error: No device selected").await;
         return;
     }
     
     if job.action == "create" && job.iso.is_none() {
-        send_progress_update(write, "Error: No ISO file specified", 0, "validation").await;
+        send_error(write, &mut progress, "Error: No ISO file specified").await;
         return;
     }
 
-    // Check if device exists and is accessible
-    if !Path::new(&job.device).exists() {
-        send_progress_update(write, &format!("Error: Device {} not found", job.device), 0, "validation").await;
-        return;
+    let filesystem = match filesystem::Filesystem::parse(&job.filesystem) {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
+        }
+    };
+    // A raw write lays down the image's own partitions and filesystems, so
+    // nothing is partitioned or formatted first
+    let raw_write = job.action == "create" && mode == advisor::WriteMode::Raw;
+    // File-copied media is FAT32 whatever was asked for; the advisor warns
+    // when that overrides the form
+    let filesystem = match mode {
+        advisor::WriteMode::Windows | advisor::WriteMode::Extract => filesystem::Filesystem::Fat32,
+        advisor::WriteMode::Raw => filesystem,
+    };
+    let mut requested_options = format_options(&job);
+    // Extracted media keeps as much of the ISO label as FAT allows, since
+    // boot configs find their files by label
+    if mode == advisor::WriteMode::Extract && job.label.is_none() {
+        if let Some(label) = image_report.as_ref().and_then(|image| image.label.as_deref()).and_then(linuxinstall::fat_label) {
+            requested_options.label = Some(label);
+        }
     }
+    let format_options = match filesystem.check_options(&requested_options) {
+        Ok(options) => options,
+        Err(_) if raw_write => requested_options,
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
+        }
+    };
 
-    // Verify device before starting
-    send_progress_update(write, "Verifying device...", 5, "device verification").await;
-    match verify_device(job.device.clone()) {
-        Ok(device_info) => {
-            if device_info.is_mounted {
-                send_progress_update(write, "Error: Device is currently mounted. Please unmount first.", 0, "verification").await;
-                return;
-            }
-            info!("Device verified: {} ({} bytes)", device_info.path, device_info.size);
+    #[cfg(not(target_os = "linux"))]
+    if !raw_write {
+        if let Err(e) = filesystem.native_format_name() {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
         }
-        Err(e) => {
-            send_progress_update(write, &format!("Device verification failed: {}", e), 0, "verification").await;
+    }
+
+    if let Err(e) = partition::PartitionScheme::parse(&job.scheme) {
+        send_error(write, &mut progress, &format!("Error: {}", e)).await;
+        return;
+    }
+
+    if let Some(files) = &job.files {
+        if let Err(e) = check_files(&job, filesystem, files) {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
             return;
         }
     }
 
-    // Format the device
-    send_progress_update(write, "Formatting device...", 10, "formatting").await;
-    if !format_device(&job, write).await {
+    #[cfg(target_os = "linux")]
+    if let Err(e) = verify::VerifyMode::parse(job.verify.as_deref()) {
+        send_error(write, &mut progress, &format!("Error: {}", e)).await;
         return;
     }
 
-    // If creating bootable USB, write the ISO
-    if job.action == "create" {
-        send_progress_update(write, "Writing ISO to device...", 50, "iso writing").await;
-        if !write_iso(&job, write).await {
+    let image_info = match image_info.transpose() {
+        Ok(info) => info,
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: Cannot read image: {}", e)).await;
             return;
         }
-        
-        send_progress_update(write, "Verifying write operation...", 95, "verification").await;
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    if let Some(info) = image_info.as_ref().filter(|info| info.format != decompress::ImageFormat::Raw) {
+        send_error(write, &mut progress, &format!("Error: {} compressed images can only be written on Linux", info.format)).await;
+        return;
     }
 
-    send_progress_update(write, "Operation completed successfully!", 100, "complete").await;
-}
+    #[cfg(not(target_os = "linux"))]
+    if mode != advisor::WriteMode::Raw {
+        send_error(write, &mut progress, &format!("Error: {} mode is only supported on Linux", mode)).await;
+        return;
+    }
 
-async fn send_status(write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, status: &str, progress: u8) {
-    let msg = serde_json::json!({
-        "status": status,
-        "progress": progress
-    });
-    if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await {
-        error!("Failed to send WebSocket message: {}", e);
+    #[cfg(not(target_os = "linux"))]
+    if job.persistence.unwrap_or(false) {
+        send_error(write, &mut progress, "Error: Persistence partitions can only be added on Linux").await;
+        return;
     }
-}
 
-async fn send_progress_update(
-    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
-    status: &str,
-    progress: u8,
-    operation: &str
-) {
-    let update = ProgressUpdate {
-        status: status.to_string(),
-        progress,
-        current_operation: operation.to_string(),
-    };
-    
-    let msg = serde_json::to_string(&update).unwrap_or_else(|_| {
-        serde_json::json!({
-            "status": status,
-            "progress": progress,
-            "current_operation": operation
-        }).to_string()
-    });
-    
-    if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(msg)).await {
-        error!("Failed to send progress update: {}", e);
+    #[cfg(not(target_os = "linux"))]
+    if job.provision.is_some() {
+        send_error(write, &mut progress, "Error: Unattended-install data can only be placed on Linux").await;
+        return;
     }
-}
 
-async fn format_device(job: &Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) -> bool {
-    #[cfg(target_os = "linux")]
-    {
-        let format_result = Command::new("sudo")
-            .args(["mkfs", &format!("-t{}", job.filesystem.to_lowercase()), &job.device])
-            .output();
+    #[cfg(not(target_os = "linux"))]
+    if job.new_guids.unwrap_or(false) {
+        send_error(write, &mut progress, "Error: Partition GUIDs can only be regenerated on Linux").await;
+        return;
+    }
 
-        match format_result {
-            Ok(output) if output.status.success() => {
-                info!("Successfully formatted {} with {}", job.device, job.filesystem);
-                true
-            }
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                error!("Format failed: {}", stderr);
-                send_status(write, &format!("Format failed: {}", stderr), 0).await;
-                false
-            }
-            Err(e) => {
-                error!("Failed to execute format command: {}", e);
-                send_status(write, "Format failed: Unable to execute format command", 0).await;
-                false
-            }
+    #[cfg(not(target_os = "linux"))]
+    if job.customize.is_some() {
+        send_error(write, &mut progress, "Error: Raspberry Pi images can only be customized on Linux").await;
+        return;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if job.boot_test.is_some() {
+        send_error(write, &mut progress, "Error: Boot tests can only be run on Linux").await;
+        return;
+    }
+
+    let image_checks = match image_checks {
+        Ok(checks) => checks,
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
         }
+    };
+
+    if !check_device(&job, write, &mut progress).await {
+        return;
     }
 
-    #[cfg(target_os = "windows")]
-    {
-        // Windows formatting logic
-        let format_result = Command::new("format")
-            .args([&job.device, "/FS:FAT32", "/Q", "/Y"])
-            .output();
+    // Refuse combinations that cannot work, and pass on the rest of the advice
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    let (advice, persistence_plan, provision_plan) = match assessment {
+        Ok(assessment) => (assessment.advice, assessment.persistence, assessment.provision),
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
+        }
+    };
+    if !advice.is_empty() || mode != advisor::WriteMode::Raw || persistence_plan.is_some() || provision_plan.is_some() {
+        for item in &advice {
+            warn!("{:?} {}: {}", item.severity, item.code, item.message);
+        }
+        let msg = serde_json::json!({"job_id": job_id, "mode": mode, "persistence": persistence_plan, "provision": provision_plan, "advice": advice});
+        let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+    }
+    if let Some(blocking) = advice.iter().find(|item| item.severity == advisor::Severity::Error) {
+        send_error(write, &mut progress, &format!("Error: {}. {}", blocking.message, blocking.suggestion)).await;
+        return;
+    }
 
-        match format_result {
-            Ok(output) if output.status.success() => {
-                info!("Successfully formatted {} with {}", job.device, job.filesystem);
-                true
-            }
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                error!("Format failed: {}", stderr);
-                send_status(write, &format!("Format failed: {}", stderr), 0).await;
-                false
+    if cancel.is_cancelled() {
+        report_cancelled(&job, write, &mut progress, false).await;
+        return;
+    }
+
+    // Reject a corrupt or tampered image before the device is touched
+    if !image_checks.is_empty() && !check_image(&job, &image_checks, iso_size, write, &mut progress, &cancel).await {
+        if cancel.is_cancelled() {
+            report_cancelled(&job, write, &mut progress, false).await;
+        }
+        return;
+    }
+
+    // Lay down a fresh partition table and format its partition, then fill it
+    if !raw_write {
+        #[cfg(target_os = "linux")]
+        let format_target = {
+            progress.begin(Phase::Partition, 0);
+            // Some BIOSes skip USB sticks that have no active partition
+            let spec = partition::PartitionSpec::new(filesystem.partition_type(), None, DEFAULT_LABEL).bootable();
+            match partition_device(&job, vec![spec], write, &mut progress, &cancel).await {
+                Some(mut partitions) => partitions.remove(0),
+                None => {
+                    if cancel.is_cancelled() {
+                        report_cancelled(&job, write, &mut progress, true).await;
+                    }
+                    return;
+                }
             }
-            Err(e) => {
-                error!("Failed to execute format command: {}", e);
-                send_status(write, "Format failed: Unable to execute format command", 0).await;
-                false
+        };
+        #[cfg(not(target_os = "linux"))]
+        let format_target = job.device.clone();
+
+        #[cfg(target_os = "linux")]
+        let formatted = format_partition(&job, filesystem, &format_options, &format_target, write, &mut progress, &cancel).await;
+        #[cfg(not(target_os = "linux"))]
+        let formatted = {
+            progress.begin(Phase::Format, 0);
+            send_progress_update(write, &progress, "Formatting device...").await;
+            format_device(&job, filesystem, &format_options, &format_target, write, &mut progress, &cancel).await
+        };
+        if !formatted {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, true).await;
             }
+            return;
         }
-    }
 
-    #[cfg(target_os = "macos")]
-    {
-        // macOS formatting logic
-        let format_result = Command::new("diskutil")
-            .args(["eraseDisk", "FAT32", "WEBBOOT", &job.device])
-            .output();
+        #[cfg(target_os = "linux")]
+        if let Some(files) = &job.files {
+            if !copy_files(&job, files, &format_target, files_size, write, &mut progress, &cancel).await {
+                if cancel.is_cancelled() {
+                    report_cancelled(&job, write, &mut progress, true).await;
+                }
+                return;
+            }
+        }
 
-        match format_result {
-            Ok(output) if output.status.success() => {
-                info!("Successfully formatted {} with {}", job.device, job.filesystem);
-                true
+        #[cfg(target_os = "linux")]
+        if mode == advisor::WriteMode::Windows && !write_windows_media(&job, &format_target, write, &mut progress, &cancel).await {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, true).await;
             }
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                error!("Format failed: {}", stderr);
-                send_status(write, &format!("Format failed: {}", stderr), 0).await;
-                false
+            return;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let (advisor::WriteMode::Extract, Some(image)) = (mode, &image_report) {
+            let label = format_options.label.as_deref().unwrap_or(DEFAULT_LABEL);
+            let parameter = provision_plan.as_ref().and_then(|plan| plan.parameter(label));
+            if !write_linux_media(&job, &format_target, image, label, parameter.as_deref(), write, &mut progress, &cancel).await {
+                if cancel.is_cancelled() {
+                    report_cancelled(&job, write, &mut progress, true).await;
+                }
+                return;
             }
-            Err(e) => {
-                error!("Failed to execute format command: {}", e);
-                send_status(write, "Format failed: Unable to execute format command", 0).await;
-                false
+        }
+
+        // File-copied media takes the installer answers on its own volume
+        #[cfg(target_os = "linux")]
+        if let Some(plan) = provision_plan.as_ref().filter(|_| mode != advisor::WriteMode::Raw) {
+            if !write_payload(&job, plan, &format_target, write, &mut progress, &cancel).await {
+                if cancel.is_cancelled() {
+                    report_cancelled(&job, write, &mut progress, true).await;
+                }
+                return;
             }
         }
     }
-}
 
-async fn write_iso(job: &Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) -> bool {
-    let iso_path = job.iso.as_ref().unwrap();
-    
-    // Validate ISO file
-    if !Path::new(iso_path).exists() {
-        error!("ISO file not found: {}", iso_path);
-        send_status(write, "Error: ISO file not found", 0).await;
-        return false;
+    // If creating bootable USB, write the ISO
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    let image_written = if raw_write {
+        progress.begin(Phase::Write, image_info.as_ref().map(|info| info.progress_total()).unwrap_or(iso_size));
+        send_progress_update(write, &progress, "Writing ISO to device...").await;
+        match write_iso(&job, image_info.as_ref(), write, &mut progress, &cancel).await {
+            Some(written) => written,
+            None => {
+                // Read-back verification leaves a fully written image alone
+                if cancel.is_cancelled() {
+                    let written = progress.phase() == Some(Phase::Verify);
+                    report_cancelled(&job, write, &mut progress, !written).await;
+                }
+                return;
+            }
+        }
+    } else {
+        0
+    };
+
+    #[cfg(target_os = "linux")]
+    let fix_gpt = raw_write && (job.fix_gpt.unwrap_or(true) || job.new_guids.unwrap_or(false));
+    #[cfg(target_os = "linux")]
+    if fix_gpt && !repair_gpt(&job, write, &mut progress, &cancel).await {
+        if cancel.is_cancelled() {
+            report_cancelled(&job, write, &mut progress, true).await;
+        }
+        return;
     }
 
-    // Get ISO file size for progress calculation
-    let iso_size = match fs::metadata(iso_path) {
-        Ok(metadata) => metadata.len(),
-        Err(e) => {
-            error!("Failed to get ISO file size: {}", e);
-            send_status(write, "Error: Cannot read ISO file", 0).await;
-            return false;
+    #[cfg(target_os = "linux")]
+    if let Some(plan) = &persistence_plan {
+        if !add_persistence(&job, plan, image_written, write, &mut progress, &cancel).await {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, true).await;
+            }
+            return;
         }
-    };
+    }
 
     #[cfg(target_os = "linux")]
-    {
-        write_iso_linux(iso_path, &job.device, iso_size, write).await
+    if let Some(plan) = provision_plan.as_ref().filter(|plan| plan.seed_label.is_some()) {
+        if !add_seed_partition(&job, plan, image_written, write, &mut progress, &cancel).await {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, true).await;
+            }
+            return;
+        }
     }
 
-    #[cfg(target_os = "windows")]
-    {
-        write_iso_windows(iso_path, &job.device, write).await
+    #[cfg(target_os = "linux")]
+    if let Some(customization) = &job.customize {
+        if !customize_raspberry_pi(&job, customization, write, &mut progress, &cancel).await {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, true).await;
+            }
+            return;
+        }
     }
 
-    #[cfg(target_os = "macos")]
-    {
-        write_iso_macos(iso_path, &job.device, write).await
+    #[cfg(target_os = "linux")]
+    if let Some(test) = &job.boot_test {
+        // The boot test only reads the finished stick, so a cancel leaves it as written
+        if !boot_test(&job, &job_id, test, write, &mut progress, &cancel).await {
+            if cancel.is_cancelled() {
+                report_cancelled(&job, write, &mut progress, false).await;
+            }
+            return;
+        }
     }
+
+    progress.finish();
+    send_progress_update(write, &progress, "Operation completed successfully!").await;
 }
 
+// Run a multiboot job: lay out a blank stick with GRUB on an ESP and a data
+// partition, or find those on a stick laid out earlier, then add or remove
+// images and rewrite the menu from what the stick holds.
 #[cfg(target_os = "linux")]
-async fn write_iso_linux(iso_path: &str, device: &str, _iso_size: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) -> bool {
-    let mut child = match Command::new("sudo")
-        .args(["dd", &format!("if={}", iso_path), &format!("of={}", device), "bs=4M", "status=progress"])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
+async fn execute_multiboot(job: &Job, job_id: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, cancel: &jobs::CancelToken) {
+    let assessment_job = job.clone();
+    let assessment = tokio::task::spawn_blocking(move || assess_job(&assessment_job))
+        .await
+        .unwrap_or_else(|e| Err(format!("Cannot check job: {}", e)))
+        .and_then(|assessment| match assessment.operation {
+            Some(operation) => Ok((operation, assessment)),
+            None => Err("A multiboot job needs an operation: prepare, add or remove".to_string()),
+        });
+    // Nothing can be planned without knowing what the job does
+    let (operation, assessment) = match assessment {
+        Ok(assessed) => assessed,
         Err(e) => {
-            error!("Failed to start dd command: {}", e);
-            send_status(write, "Error: Failed to start ISO write process", 0).await;
-            return false;
+            let mut progress = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT).for_job(job_id);
+            progress.begin(Phase::Validate, 0);
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
         }
     };
+    let images = assessment.images;
+    let advice = assessment.advice;
+    let copy_size = images.iter().map(|image| image.size).sum();
+    let mut progress = plan_multiboot(operation, copy_size).for_job(job_id);
 
-    // Monitor progress (basic implementation)
-    let mut progress = 50;
-    let progress_increment = 5;
-    
-    while child.try_wait().unwrap_or(Some(std::process::ExitStatus::from_raw(256))).is_none() {
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-        progress = std::cmp::min(progress + progress_increment, 95);
-        send_status(write, &format!("Writing ISO... {}%", progress), progress).await;
+    progress.begin(Phase::Validate, 0);
+    if job.device.is_empty() {
+        send_error(write, &mut progress, "Error: No device selected").await;
+        return;
     }
-
-    match child.wait() {
-        Ok(status) if status.success() => {
-            info!("Successfully wrote ISO to {}", device);
-            true
+    let filesystem = match filesystem::Filesystem::parse(&job.filesystem) {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
         }
-        Ok(status) => {
-            error!("ISO write failed with exit code: {}", status);
-            send_status(write, "Error: ISO write failed", 0).await;
-            false
+    };
+    let names = match operation {
+        multiboot::Operation::Remove => {
+            let names: Vec<String> = job
+                .images
+                .iter()
+                .flatten()
+                .map(|image| Path::new(image).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default())
+                .collect();
+            if let Some(e) = names.iter().find_map(|name| multiboot::check_image_name(name).err()) {
+                send_error(write, &mut progress, &format!("Error: {}", e)).await;
+                return;
+            }
+            names
         }
+        _ => Vec::new(),
+    };
+    let format_options = match filesystem.check_options(&format_options(job)) {
+        Ok(options) => options,
         Err(e) => {
-            error!("Failed to wait for dd process: {}", e);
-            send_status(write, "Error: ISO write process failed", 0).await;
-            false
+            send_error(write, &mut progress, &format!("Error: {}", e)).await;
+            return;
         }
+    };
+
+    if !check_device(job, write, &mut progress).await {
+        return;
     }
-}
 
-#[cfg(target_os = "windows")]
-async fn write_iso_windows(iso_path: &str, device: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) -> bool {
-    // Use Windows-specific tools like Rufus API or PowerShell
-    let write_result = Command::new("powershell")
-        .args(["-Command", &format!("Copy-Item '{}' '{}'", iso_path, device)])
-        .output();
+    for item in &advice {
+        warn!("{:?} {}: {}", item.severity, item.code, item.message);
+    }
+    let msg = serde_json::json!({"job_id": job_id, "operation": operation, "images": images, "advice": advice});
+    let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+    if let Some(blocking) = advice.iter().find(|item| item.severity == advisor::Severity::Error) {
+        send_error(write, &mut progress, &format!("Error: {}. {}", blocking.message, blocking.suggestion)).await;
+        return;
+    }
+
+    if cancel.is_cancelled() {
+        report_cancelled(job, write, &mut progress, false).await;
+        return;
+    }
+
+    let located = match operation {
+        multiboot::Operation::Prepare => prepare_multiboot(job, filesystem, &format_options, write, &mut progress, cancel).await,
+        _ => locate_multiboot(job, write, &mut progress, cancel).await,
+    };
+    let (esp, data) = match located {
+        Some(located) => located,
+        None => {
+            if cancel.is_cancelled() {
+                report_cancelled(job, write, &mut progress, operation == multiboot::Operation::Prepare).await;
+            }
+            return;
+        }
+    };
+
+    if !update_multiboot(job, &images, &names, esp, data, write, &mut progress, cancel).await {
+        if cancel.is_cancelled() {
+            if operation == multiboot::Operation::Prepare {
+                report_cancelled(job, write, &mut progress, true).await;
+            } else {
+                // Wiping would take every other image with it; what was
+                // copied stays, and the next job's menu picks it up
+                info!("Job {:?} cancelled", job.id);
+                progress.cancel();
+                send_progress_update(write, &progress, "Cancelled. Images copied so far were kept; the menu was not updated.").await;
+            }
+        }
+        return;
+    }
+
+    progress.finish();
+    send_progress_update(write, &progress, "Operation completed successfully!").await;
+}
+
+// Lay a blank multiboot stick out: an ESP holding a standalone GRUB, and a
+// data partition in the job's filesystem for the images. Returns the byte
+// offsets of both.
+#[cfg(target_os = "linux")]
+async fn prepare_multiboot(job: &Job, filesystem: filesystem::Filesystem, options: &filesystem::FormatOptions, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<(u64, u64)> {
+    progress.begin(Phase::Partition, 0);
+    let specs = vec![
+        partition::PartitionSpec::new(partition::PartitionType::ESP, Some(multiboot::ESP_SIZE), "EFI system partition").bootable(),
+        partition::PartitionSpec::new(filesystem.partition_type(), None, options.label.as_deref().unwrap_or(DEFAULT_LABEL)),
+    ];
+    let partitions = partition_device(job, specs, write, progress, cancel).await?;
+    let (esp, data) = (&partitions[0], &partitions[1]);
+
+    let esp_options = filesystem::FormatOptions { label: Some(multiboot::ESP_LABEL.to_string()), cluster_size: None, quick: true };
+    if !format_fat32_native(job, &esp_options, esp, write, progress, cancel).await {
+        return None;
+    }
+    if !format_partition(job, filesystem, options, data, write, progress, cancel).await {
+        return None;
+    }
+
+    progress.begin(Phase::Bootloader, 0);
+    send_progress_update(write, progress, "Building the GRUB EFI loader...").await;
+    let work_dir = match jobs::create_work_dir() {
+        Ok(work_dir) => work_dir,
+        Err(e) => {
+            error!("Cannot create a scratch directory: {}", e);
+            send_error(write, progress, &format!("Error: Building the GRUB loader failed: {}", e)).await;
+            return None;
+        }
+    };
+    let config = work_dir.join("grub.cfg");
+    let loader = work_dir.join("BOOTX64.EFI");
+    if let Err(e) = fs::write(&config, multiboot::LOADER_CONFIG) {
+        error!("Cannot stage grub.cfg in {}: {}", work_dir.display(), e);
+        let _ = fs::remove_dir_all(&work_dir);
+        send_error(write, progress, &format!("Error: Building the GRUB loader failed: {}", e)).await;
+        return None;
+    }
+    let built = match bootloader::grub_efi_command(&loader, &config) {
+        Some(command) => {
+            info!("Building multiboot EFI loader for job {:?}: {:?}", job.id, command);
+            match jobs::run_cancellable(command, cancel).await {
+                Ok(output) if output.status.success() => Ok(()),
+                Ok(output) => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+                Err(jobs::CommandError::Cancelled) => Err("cancelled".to_string()),
+                Err(e) => Err(format!("Unable to run grub-mkstandalone: {}", e)),
+            }
+        }
+        None => Err("grub-mkstandalone is not installed".to_string()),
+    };
+    if let Err(e) = built {
+        let _ = fs::remove_dir_all(&work_dir);
+        if !cancel.is_cancelled() {
+            error!("Building the GRUB loader failed: {}", e);
+            send_error(write, progress, &format!("Error: Building the GRUB loader failed: {}", e)).await;
+        }
+        return None;
+    }
+
+    let device = std::path::PathBuf::from(&job.device);
+    let (esp_start, data_start) = (esp.start, data.start);
+    let init_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // mkfs may have written through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        multiboot::initialize(&mut disk, esp_start, data_start, &loader).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())
+    });
+    let initialized = finish_blocking_step(init_task.await, "Installing the GRUB loader", write, progress, cancel).await;
+    if let Err(e) = fs::remove_dir_all(&work_dir) {
+        warn!("Failed to remove {}: {}", work_dir.display(), e);
+    }
+    initialized?;
+
+    info!("Prepared multiboot stick on {}: ESP {}, data {}", job.device, esp.path, data.path);
+    Some((esp_start, data_start))
+}
+
+// Find the ESP and data partition of a stick `prepare_multiboot` laid out.
+#[cfg(target_os = "linux")]
+async fn locate_multiboot(job: &Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<(u64, u64)> {
+    send_progress_update(write, progress, "Reading the multiboot stick...").await;
+    let device = std::path::PathBuf::from(&job.device);
+    let locate_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        multiboot::locate(&mut disk, sector_size).map_err(|e| e.to_string())
+    });
+    finish_blocking_step(locate_task.await, "Reading the multiboot stick", write, progress, cancel).await
+}
+
+// Copy `images` onto, or delete the images named in `names` from, the data
+// partition at `data`, then rewrite the menu on the ESP at `esp`.
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+async fn update_multiboot(job: &Job, images: &[multiboot::MultibootImage], names: &[String], esp: u64, data: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    if !images.is_empty() {
+        let total = images.iter().map(|image| image.size).sum::<u64>();
+        progress.begin(Phase::Copy, total);
+        send_progress_update(write, progress, "Copying images...").await;
+
+        let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+        let device = std::path::PathBuf::from(&job.device);
+        let copy_images = images.to_vec();
+        let copy_cancel = cancel.clone();
+        let copy_task = tokio::task::spawn_blocking(move || {
+            let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+            writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+            let mut volume = fatfs::Volume::open(&mut disk, data).map_err(|e| e.to_string())?;
+            let mut done = 0;
+            for image in &copy_images {
+                done += multiboot::store_image(&mut volume, image, &copy_cancel, |copied| {
+                    let _ = progress_tx.send(done + copied);
+                })
+                .map_err(|e| format!("{}: {}", image.name, e))?;
+            }
+            drop(volume);
+            writer::sync_target(&disk, done).map_err(|e| e.to_string())?;
+            Ok::<_, String>(done)
+        });
+
+        relay_progress(write, progress, progress_rx, |done| {
+            format!("Copying images... {} of {} bytes", done, total)
+        }).await;
+
+        let copied = match finish_blocking_step(copy_task.await, "Copying images", write, progress, cancel).await {
+            Some(copied) => copied,
+            None => return false,
+        };
+        info!("Copied {} images ({} bytes) onto {}", images.len(), copied, job.device);
+    }
+
+    progress.begin(Phase::Bootloader, 0);
+    send_progress_update(write, progress, "Writing the boot menu...").await;
+    let device = std::path::PathBuf::from(&job.device);
+    let names = names.to_vec();
+    let menu_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut missing = Vec::new();
+        if !names.is_empty() {
+            let mut volume = fatfs::Volume::open(&mut disk, data).map_err(|e| e.to_string())?;
+            for name in &names {
+                if !multiboot::remove_image(&mut volume, name).map_err(|e| format!("{}: {}", name, e))? {
+                    missing.push(name.clone());
+                }
+            }
+        }
+        let count = multiboot::write_menu(&mut disk, esp, data).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        Ok::<_, String>((count, missing))
+    });
+    let (count, missing) = match finish_blocking_step(menu_task.await, "Writing the boot menu", write, progress, cancel).await {
+        Some(result) => result,
+        None => return false,
+    };
+    for name in &missing {
+        warn!("{} was not on {}", name, job.device);
+    }
+    info!("Multiboot menu on {} lists {} images", job.device, count);
+    true
+}
+
+// Make sure the job's device exists, is not one the running system depends
+// on, and is not mounted.
+async fn check_device(job: &Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker) -> bool {
+    // Check if device exists and is accessible
+    if !Path::new(&job.device).exists() {
+        send_error(write, progress, &format!("Error: Device {} not found", job.device)).await;
+        return false;
+    }
+
+    // Never touch a disk the running system depends on
+    #[cfg(target_os = "linux")]
+    if let Err(e) = protect::SystemDiskGuard::default().check(&job.device) {
+        error!("{}", e);
+        send_error(write, progress, &format!("Error: {}", e)).await;
+        return false;
+    }
+
+    // Verify device before starting
+    send_progress_update(write, progress, "Verifying device...").await;
+    match verify_device(job.device.clone()) {
+        Ok(device_info) => {
+            if device_info.is_mounted {
+                let mut mount_points = device_info.mount_points.clone();
+                for partition in &device_info.partitions {
+                    mount_points.extend(partition.mount_points.iter().cloned());
+                }
+                send_error(
+                    write,
+                    progress,
+                    &format!("Error: Device is currently mounted at {}. Please unmount first.", mount_points.join(", ")),
+                ).await;
+                return false;
+            }
+            info!("Device verified: {} ({} bytes)", device_info.path, device_info.size);
+            true
+        }
+        Err(e) => {
+            send_error(write, progress, &format!("Device verification failed: {}", e)).await;
+            false
+        }
+    }
+}
+
+// Nominal weights for phases that do not stream image bytes, in bytes of
+// equivalent work, so the overall percentage tracks where the time goes.
+const VALIDATE_WEIGHT: u64 = 1024 * 1024;
+const PARTITION_WEIGHT: u64 = 4 * 1024 * 1024;
+const FORMAT_WEIGHT: u64 = 64 * 1024 * 1024;
+const BOOTLOADER_WEIGHT: u64 = 16 * 1024 * 1024;
+const CUSTOMIZE_WEIGHT: u64 = 1024 * 1024;
+const BOOT_TEST_WEIGHT: u64 = 256 * 1024 * 1024;
+
+const DEFAULT_LABEL: &str = "WEBBOOT";
+
+// How long to wait for udev to create partition nodes after a table is written.
+#[cfg(target_os = "linux")]
+const PARTITION_NODE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+fn plan_job(job: &Job, image: Option<&decompress::ImageInfo>, hashes_image: bool, mode: advisor::WriteMode, copy_size: u64) -> ProgressTracker {
+    // Checksums cover the file as published; writing and verification cover
+    // the decompressed image, or compressed bytes when its size is unknown.
+    let file_size = image.map(|info| info.compressed_size).unwrap_or(0);
+    let image_size = image.map(|info| info.progress_total()).unwrap_or(0);
+
+    let mut tracker = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT);
+    if hashes_image {
+        tracker = tracker.plan_bytes(Phase::Checksum, file_size);
+    }
+    let raw_write = job.action == "create" && mode == advisor::WriteMode::Raw;
+    if !raw_write {
+        #[cfg(target_os = "linux")]
+        {
+            tracker = tracker.plan_fixed(Phase::Partition, PARTITION_WEIGHT);
+        }
+        tracker = tracker.plan_fixed(Phase::Format, FORMAT_WEIGHT);
+    }
+    if job.files.is_some() || mode != advisor::WriteMode::Raw {
+        tracker = tracker.plan_bytes(Phase::Copy, copy_size);
+    }
+    if mode != advisor::WriteMode::Raw {
+        tracker = tracker.plan_fixed(Phase::Bootloader, BOOTLOADER_WEIGHT);
+    }
+
+    let tracker = if raw_write {
+        let tracker = tracker
+            .plan_bytes(Phase::Write, image_size)
+            // Flushing the page cache typically costs a fraction of the copy
+            .plan_fixed(Phase::Sync, image_size / 20);
+
+        #[cfg(target_os = "linux")]
+        {
+            let mode = verify::VerifyMode::parse(job.verify.as_deref()).unwrap_or(verify::VerifyMode::Full);
+            let tracker = tracker.plan_bytes(Phase::Verify, verify::planned_bytes(image_size, mode));
+            let tracker = if job.persistence.unwrap_or(false) || job.provision.is_some() {
+                // Patching boot configs and adding the partition, then its filesystem
+                tracker.plan_fixed(Phase::Partition, PARTITION_WEIGHT).plan_fixed(Phase::Format, FORMAT_WEIGHT)
+            } else {
+                tracker
+            };
+            if job.customize.is_some() {
+                tracker.plan_fixed(Phase::Customize, CUSTOMIZE_WEIGHT)
+            } else {
+                tracker
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            tracker
+        }
+    } else {
+        tracker
+    };
+    if job.boot_test.is_some() {
+        tracker.plan_fixed(Phase::BootTest, BOOT_TEST_WEIGHT)
+    } else {
+        tracker
+    }
+}
+
+fn plan_multiboot(operation: multiboot::Operation, copy_size: u64) -> ProgressTracker {
+    let mut tracker = ProgressTracker::new().plan_fixed(Phase::Validate, VALIDATE_WEIGHT);
+    if operation == multiboot::Operation::Prepare {
+        tracker = tracker
+            .plan_fixed(Phase::Partition, PARTITION_WEIGHT)
+            // The ESP, then the data partition
+            .plan_fixed(Phase::Format, FORMAT_WEIGHT)
+            .plan_fixed(Phase::Format, FORMAT_WEIGHT)
+            .plan_fixed(Phase::Bootloader, BOOTLOADER_WEIGHT);
+    }
+    if operation != multiboot::Operation::Remove {
+        tracker = tracker.plan_bytes(Phase::Copy, copy_size);
+    }
+    // Rewriting the menu
+    tracker.plan_fixed(Phase::Bootloader, BOOTLOADER_WEIGHT)
+}
+
+async fn send_error(
+    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
+    progress: &mut ProgressTracker,
+    status: &str,
+) {
+    progress.fail();
+    send_progress_update(write, progress, status).await;
+}
+
+// Leave a cancelled device in a defined state: once anything has been
+// written, the partition tables at both ends are zeroed so the stick reads as
+// blank instead of as a half-written image.
+async fn report_cancelled(
+    job: &Job,
+    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
+    progress: &mut ProgressTracker,
+    device_touched: bool,
+) {
+    info!("Job {:?} cancelled", job.id);
+
+    #[cfg(target_os = "linux")]
+    if device_touched {
+        let device = std::path::PathBuf::from(&job.device);
+        let wipe = tokio::task::spawn_blocking(move || {
+            let mut target = writer::open_target(&device)?;
+            writer::wipe_partition_tables(&mut target)
+        }).await;
+
+        match wipe {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Failed to clear partition table on {} after cancel: {}", job.device, e);
+                send_error(write, progress, &format!("Error: Cancelled, but clearing the partition table failed: {}", e)).await;
+                return;
+            }
+            Err(e) => {
+                error!("Wipe task failed: {}", e);
+                send_error(write, progress, "Error: Cancelled, but clearing the partition table failed").await;
+                return;
+            }
+        }
+    }
+
+    progress.cancel();
+    let status = if device_touched {
+        "Cancelled. The device partition table was cleared."
+    } else if matches!(progress.phase(), Some(Phase::Verify | Phase::BootTest)) {
+        "Cancelled. The written image was left on the device."
+    } else {
+        "Cancelled before the device was modified."
+    };
+    send_progress_update(write, progress, status).await;
+}
+
+async fn send_progress_update(
+    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
+    progress: &ProgressTracker,
+    status: &str,
+) {
+    let update = progress.snapshot(status);
+    
+    let msg = serde_json::to_string(&update).unwrap_or_else(|_| {
+        serde_json::json!({
+            "status": status,
+            "progress": update.progress,
+            "current_operation": update.current_operation
+        }).to_string()
+    });
+    
+    if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(msg)).await {
+        error!("Failed to send progress update: {}", e);
+    }
+}
+
+// Forward byte counts reported by a blocking task as progress updates, sending
+// one whenever the overall percentage moves. Returns when the task finishes
+// and drops its sender.
+async fn relay_progress(
+    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
+    progress: &mut ProgressTracker,
+    mut progress_rx: tokio::sync::mpsc::UnboundedReceiver<u64>,
+    status: impl Fn(u64) -> String,
+) {
+    let mut last_progress = None;
+    while let Some(done) = progress_rx.recv().await {
+        progress.advance(done);
+        if last_progress != Some(progress.progress()) {
+            last_progress = Some(progress.progress());
+            send_progress_update(write, progress, &status(done)).await;
+        }
+    }
+}
+
+async fn check_image(job: &Job, checks: &checksum::CheckPlan, iso_size: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    if let Some(check) = &checks.signature {
+        progress.begin(Phase::Checksum, 0);
+        send_progress_update(write, progress, &format!("Checking signature {}...", check.signature.display())).await;
+        match checksum::verify_signature(&check.signature, &check.signed, &check.keyring, cancel).await {
+            Ok(fingerprint) => {
+                info!("Good signature on {} from {}", check.signed.display(), fingerprint);
+            }
+            Err(checksum::ChecksumError::Cancelled) => return false,
+            Err(e) => {
+                error!("Signature check of {} failed: {}", check.signed.display(), e);
+                send_error(write, progress, &format!("Error: {}", e)).await;
+                return false;
+            }
+        }
+    }
+
+    let expected = match &checks.expected {
+        Some(expected) => expected.clone(),
+        None => return true,
+    };
+
+    progress.begin(Phase::Checksum, iso_size);
+    send_progress_update(write, progress, &format!("Checking {} against {}...", expected.algorithm, expected.origin)).await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let image = std::path::PathBuf::from(job.iso.as_ref().unwrap());
+    let hash_cancel = cancel.clone();
+    let algorithm = expected.algorithm;
+    let hash_task = tokio::task::spawn_blocking(move || {
+        checksum::verify_file(&image, &expected, &hash_cancel, |hashed| {
+            let _ = progress_tx.send(hashed);
+        })
+    });
+
+    relay_progress(write, progress, progress_rx, |hashed| {
+        format!("Checking {}... {} of {} bytes", algorithm, hashed, iso_size)
+    }).await;
+
+    match hash_task.await {
+        Ok(Ok(())) => {
+            info!("Image {:?} matches its {} checksum", job.iso, algorithm);
+            true
+        }
+        Ok(Err(checksum::ChecksumError::Cancelled)) => false,
+        Ok(Err(e)) => {
+            error!("Checksum verification of {:?} failed: {}", job.iso, e);
+            send_error(write, progress, &format!("Error: {}", e)).await;
+            false
+        }
+        Err(e) => {
+            error!("Checksum task failed: {}", e);
+            send_error(write, progress, "Error: Checksum verification failed").await;
+            false
+        }
+    }
+}
+
+/// The partition `partition_device` created, as both a device node and a
+/// byte range of the whole disk.
+#[cfg(target_os = "linux")]
+struct PreparedPartition {
+    path: String,
+    start: u64,
+    size: u64,
+    sector_size: u64,
+}
+
+// Write a partition table per the job's scheme with the partitions in
+// `specs`, and return them once the kernel exposes their nodes. Every known
+// signature goes first so nothing of the previous image (hybrid MBR, backup
+// GPT, ISO9660 or UDF descriptors) survives.
+#[cfg(target_os = "linux")]
+async fn partition_device(job: &Job, specs: Vec<partition::PartitionSpec>, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<Vec<PreparedPartition>> {
+    let scheme = partition::PartitionScheme::parse(&job.scheme).unwrap_or(partition::PartitionScheme::Mbr);
+    send_progress_update(write, progress, &format!("Wiping old signatures and creating {} partition table...", scheme)).await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let table_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let disk_size = writer::target_size(&mut disk).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        writer::wipe_signatures(&mut disk).map_err(|e| e.to_string())?;
+        let layout = partition::write_table(&mut disk, scheme, disk_size, sector_size, &specs).map_err(|e| e.to_string())?;
+
+        // mkfs does not clear foreign signatures, so remove any left where the partitions start
+        let starts: Vec<(u64, u64)> = layout
+            .partitions
+            .iter()
+            .map(|partition| (partition.start, writer::PARTITION_TABLE_WIPE_SIZE.min(partition.size)))
+            .collect();
+        writer::zero_regions(&mut disk, &starts).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        partition::reread_partition_table(&disk).map_err(|e| format!("kernel did not re-read the partition table: {}", e))?;
+        Ok::<_, String>((layout, sector_size))
+    });
+
+    let (layout, sector_size) = match table_task.await {
+        Ok(Ok(table)) => table,
+        Ok(Err(e)) => {
+            error!("Partitioning {} failed: {}", job.device, e);
+            send_error(write, progress, &format!("Partitioning failed: {}", e)).await;
+            return None;
+        }
+        Err(e) => {
+            error!("Partitioning task failed: {}", e);
+            send_error(write, progress, "Partitioning failed").await;
+            return None;
+        }
+    };
+    info!(
+        "Wrote {} table to {} (disk {}): {:?}",
+        layout.scheme,
+        job.device,
+        layout.disk_guid.map(|guid| guid.to_string()).or(layout.disk_signature.map(|sig| format!("{:08x}", sig))).unwrap_or_default(),
+        layout.partitions
+    );
+
+    let deadline = tokio::time::Instant::now() + PARTITION_NODE_TIMEOUT;
+    let mut prepared = Vec::with_capacity(layout.partitions.len());
+    for placed in &layout.partitions {
+        let partition_path = wait_for_partition(job, placed.number, deadline, write, progress, cancel).await?;
+        prepared.push(PreparedPartition { path: partition_path, start: placed.start, size: placed.size, sector_size });
+    }
+    Some(prepared)
+}
+
+// Wait until udev has created the node of partition `number` on the job's
+// device, and return its path.
+#[cfg(target_os = "linux")]
+async fn wait_for_partition(job: &Job, number: usize, deadline: tokio::time::Instant, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<String> {
+    let partition_path = partition::partition_path(&job.device, number);
+    while !Path::new(&partition_path).exists() {
+        if cancel.is_cancelled() {
+            return None;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            error!("{} did not appear after partitioning {}", partition_path, job.device);
+            send_error(write, progress, &format!("Partitioning failed: {} did not appear", partition_path)).await;
+            return None;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    Some(partition_path)
+}
+
+fn format_options(job: &Job) -> filesystem::FormatOptions {
+    let label = job.label.as_deref().map(str::trim).filter(|label| !label.is_empty()).unwrap_or(DEFAULT_LABEL);
+    filesystem::FormatOptions {
+        label: Some(label.to_string()),
+        cluster_size: job.cluster_size,
+        quick: job.quick.unwrap_or(true),
+    }
+}
+
+async fn format_device(job: &Job, filesystem: filesystem::Filesystem, options: &filesystem::FormatOptions, target: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    #[cfg(target_os = "linux")]
+    let command = {
+        let sector_size = std::fs::File::open(target).map(|disk| partition::logical_sector_size(&disk)).unwrap_or(512);
+        filesystem.mkfs_command(target, sector_size, options)
+    };
+
+    #[cfg(target_os = "windows")]
+    let command = {
+        let mut command = Command::new("format");
+        let label = options.label.as_deref().unwrap_or(DEFAULT_LABEL);
+        let format_name = filesystem.native_format_name().unwrap_or("FAT32");
+        command.args([target.to_string(), format!("/FS:{}", format_name), format!("/V:{}", label)]);
+        if let Some(size) = options.cluster_size {
+            command.arg(format!("/A:{}", size));
+        }
+        if options.quick {
+            command.arg("/Q");
+        }
+        command.arg("/Y");
+        command
+    };
+
+    #[cfg(target_os = "macos")]
+    let command = {
+        let mut command = Command::new("diskutil");
+        let label = options.label.as_deref().unwrap_or(DEFAULT_LABEL);
+        let format_name = filesystem.native_format_name().unwrap_or("FAT32");
+        let scheme = match partition::PartitionScheme::parse(&job.scheme) {
+            Ok(partition::PartitionScheme::Gpt) => "GPTFormat",
+            _ => "MBRFormat",
+        };
+        command.args(["eraseDisk", format_name, &label.to_uppercase(), scheme, target]);
+        command
+    };
+
+    info!("Formatting {} as {} for job {:?}: {:?}", target, filesystem, job.id, command);
+    match jobs::run_cancellable(command, cancel).await {
+        Ok(output) if output.status.success() => {
+            info!("Successfully formatted {} with {}", target, filesystem);
+            true
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!("Format failed: {}", stderr);
+            send_error(write, progress, &format!("Format failed: {}", stderr)).await;
+            false
+        }
+        Err(jobs::CommandError::Cancelled) => false,
+        Err(e) => {
+            error!("Failed to execute format command: {}", e);
+            send_error(write, progress, "Format failed: Unable to execute format command").await;
+            false
+        }
+    }
+}
+
+// Format `partition` with mkfs through its node. Without a usable mkfs.fat
+// (missing, or sudo wants a password) FAT32 is formatted in-process through
+// the whole-disk device instead.
+#[cfg(target_os = "linux")]
+async fn format_partition(job: &Job, filesystem: filesystem::Filesystem, options: &filesystem::FormatOptions, partition: &PreparedPartition, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let native_format = filesystem == filesystem::Filesystem::Fat32
+        && !tokio::task::spawn_blocking(move || capabilities::mkfs_usable(filesystem)).await.unwrap_or(false);
+
+    if native_format {
+        format_fat32_native(job, options, partition, write, progress, cancel).await
+    } else {
+        progress.begin(Phase::Format, 0);
+        send_progress_update(write, progress, "Formatting device...").await;
+        format_device(job, filesystem, options, &partition.path, write, progress, cancel).await
+    }
+}
+
+// Format the partition as FAT32 without mkfs.fat, writing through the
+// whole-disk device at the partition's offset.
+#[cfg(target_os = "linux")]
+async fn format_fat32_native(job: &Job, options: &filesystem::FormatOptions, partition: &PreparedPartition, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let fat_options = fat32::Fat32Options {
+        label: options.label.clone(),
+        cluster_size: options.cluster_size,
+        volume_id: rand::random(),
+        full: !options.quick,
+    };
+    let layout = match fat32::plan(partition.start, partition.size, partition.sector_size, fat_options.cluster_size) {
+        Ok(layout) => layout,
+        Err(e) => {
+            error!("Cannot format {} as FAT32: {}", partition.path, e);
+            send_error(write, progress, &format!("Format failed: {}", e)).await;
+            return false;
+        }
+    };
+
+    let total = fat32::format_bytes(&layout, fat_options.full);
+    progress.begin(Phase::Format, total);
+    send_progress_update(write, progress, "Formatting device as FAT32...").await;
+    info!(
+        "Formatting {} as FAT32 in-process for job {:?}: {} clusters of {} bytes",
+        partition.path, job.id, layout.clusters, layout.cluster_size()
+    );
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let device = std::path::PathBuf::from(&job.device);
+    let (start, size, sector_size) = (partition.start, partition.size, partition.sector_size);
+    let format_cancel = cancel.clone();
+    let format_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        fat32::format(&mut disk, start, size, sector_size, &fat_options, &format_cancel, |done| {
+            let _ = progress_tx.send(done);
+        })
+        .map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, total).map_err(|e| e.to_string())
+    });
+
+    relay_progress(write, progress, progress_rx, |done| {
+        format!("Formatting device as FAT32... {} of {} bytes", done, total)
+    }).await;
+
+    match format_task.await {
+        Ok(Ok(())) => {
+            info!("Successfully formatted {} with FAT32", partition.path);
+            true
+        }
+        Ok(Err(_)) if cancel.is_cancelled() => false,
+        Ok(Err(e)) => {
+            error!("Format failed: {}", e);
+            send_error(write, progress, &format!("Format failed: {}", e)).await;
+            false
+        }
+        Err(e) => {
+            error!("Format task failed: {}", e);
+            send_error(write, progress, "Format failed").await;
+            false
+        }
+    }
+}
+
+// Files are laid out through the mount-free FAT32/exFAT writer, onto the
+// partition a restore creates.
+fn check_files(job: &Job, filesystem: filesystem::Filesystem, files: &str) -> Result<(), String> {
+    if !cfg!(target_os = "linux") {
+        return Err("Copying files onto the device is only supported on Linux".to_string());
+    }
+    if job.action != "restore" {
+        return Err("Files can only be copied onto a restored device".to_string());
+    }
+    if !matches!(filesystem, filesystem::Filesystem::Fat32 | filesystem::Filesystem::Exfat) {
+        return Err(format!("Files can only be copied onto FAT32 or exFAT, not {}", filesystem));
+    }
+    if !Path::new(files).is_dir() {
+        return Err(format!("{} is not a directory", files));
+    }
+    Ok(())
+}
+
+// Copy the job's source tree onto the freshly formatted partition without
+// mounting it, writing through the whole-disk device at the partition's offset.
+#[cfg(target_os = "linux")]
+async fn copy_files(job: &Job, files: &str, partition: &PreparedPartition, total: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    progress.begin(Phase::Copy, total);
+    send_progress_update(write, progress, &format!("Copying files from {}...", files)).await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let device = std::path::PathBuf::from(&job.device);
+    let source = std::path::PathBuf::from(files);
+    let start = partition.start;
+    let copy_cancel = cancel.clone();
+    let copy_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // mkfs may have written through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        let copied = fatfs::copy_tree(&mut volume, &source, "/", &copy_cancel, |done| {
+            let _ = progress_tx.send(done);
+        })
+        .map_err(|e| e.to_string())?;
+        drop(volume);
+        writer::sync_target(&disk, copied).map_err(|e| e.to_string())?;
+        Ok::<_, String>(copied)
+    });
+
+    relay_progress(write, progress, progress_rx, |done| {
+        format!("Copying files... {} of {} bytes", done, total)
+    }).await;
+
+    match copy_task.await {
+        Ok(Ok(copied)) => {
+            info!("Copied {} bytes from {} onto {}", copied, files, partition.path);
+            true
+        }
+        Ok(Err(_)) if cancel.is_cancelled() => false,
+        Ok(Err(e)) => {
+            error!("Copying {} onto {} failed: {}", files, partition.path, e);
+            send_error(write, progress, &format!("Error: Copying files failed: {}", e)).await;
+            false
+        }
+        Err(e) => {
+            error!("Copy task failed: {}", e);
+            send_error(write, progress, "Error: Copying files failed").await;
+            false
+        }
+    }
+}
+
+// Report how a blocking step of a file-by-file write ended. Cancellation is
+// left to the caller, which knows whether the device has been touched.
+#[cfg(target_os = "linux")]
+async fn finish_blocking_step<T>(
+    result: Result<Result<T, String>, tokio::task::JoinError>,
+    step: &str,
+    write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>,
+    progress: &mut ProgressTracker,
+    cancel: &jobs::CancelToken,
+) -> Option<T> {
+    match result {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(_)) if cancel.is_cancelled() => None,
+        Ok(Err(e)) => {
+            error!("{} failed: {}", step, e);
+            send_error(write, progress, &format!("Error: {} failed: {}", step, e)).await;
+            None
+        }
+        Err(e) => {
+            error!("{} task failed: {}", step, e);
+            send_error(write, progress, &format!("Error: {} failed", step)).await;
+            None
+        }
+    }
+}
+
+// Lay a Windows ISO out on the freshly formatted FAT32 partition: its UDF
+// tree file by file, an install.wim too large for FAT32 as .swm parts, and
+// syslinux so legacy BIOS machines reach bootmgr.
+#[cfg(target_os = "linux")]
+async fn write_windows_media(job: &Job, partition: &PreparedPartition, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let iso = std::path::PathBuf::from(job.iso.as_ref().unwrap());
+    let list_iso = iso.clone();
+    let list_task = tokio::task::spawn_blocking(move || {
+        let mut image = std::io::BufReader::new(fs::File::open(&list_iso).map_err(|e| e.to_string())?);
+        let descriptors = iso9660::read_descriptors(&mut image).map_err(|e| e.to_string())?;
+        inspect::read_tree(&mut image, &descriptors).map(|(_, files)| files).map_err(|e| e.to_string())
+    });
+    let files = match finish_blocking_step(list_task.await, "Reading the image", write, progress, cancel).await {
+        Some(files) => files,
+        None => return false,
+    };
+
+    let split = wininstall::oversized_wim(&files).cloned();
+    let total = files.iter().filter(|file| !file.is_dir).map(|file| file.size).sum::<u64>()
+        + split.as_ref().map(|wim| wim.size).unwrap_or(0);
+    let tree: Vec<iso9660::IsoFile> = files
+        .into_iter()
+        .filter(|file| split.as_ref().is_none_or(|wim| wim.path != file.path))
+        .collect();
+
+    progress.begin(Phase::Copy, total);
+    send_progress_update(write, progress, "Copying Windows installation files...").await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let device = std::path::PathBuf::from(&job.device);
+    let copy_iso = iso.clone();
+    let start = partition.start;
+    let copy_cancel = cancel.clone();
+    let copy_task = tokio::task::spawn_blocking(move || {
+        let mut image = std::io::BufReader::new(fs::File::open(&copy_iso).map_err(|e| e.to_string())?);
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // mkfs may have written through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        let copied = fatfs::copy_image_tree(&mut volume, &mut image, &tree, "/", &copy_cancel, |done| {
+            let _ = progress_tx.send(done);
+        })
+        .map_err(|e| e.to_string())?;
+        drop(volume);
+        writer::sync_target(&disk, copied).map_err(|e| e.to_string())?;
+        Ok::<_, String>(copied)
+    });
+
+    relay_progress(write, progress, progress_rx, |done| {
+        format!("Copying Windows installation files... {} of {} bytes", done, total)
+    }).await;
+
+    let copied = match finish_blocking_step(copy_task.await, "Copying Windows installation files", write, progress, cancel).await {
+        Some(copied) => copied,
+        None => return false,
+    };
+    info!("Copied {} bytes of {:?} onto {}", copied, job.iso, partition.path);
+
+    if let Some(wim) = split {
+        let work_dir = match jobs::create_work_dir() {
+            Ok(work_dir) => work_dir,
+            Err(e) => {
+                error!("Cannot create a scratch directory: {}", e);
+                send_error(write, progress, &format!("Error: Splitting install.wim failed: {}", e)).await;
+                return false;
+            }
+        };
+        let split_done = split_install_wim(job, &iso, &wim, &work_dir, partition, copied, total, write, progress, cancel).await;
+        if let Err(e) = fs::remove_dir_all(&work_dir) {
+            warn!("Failed to remove {}: {}", work_dir.display(), e);
+        }
+        if !split_done {
+            return false;
+        }
+    }
+
+    progress.begin(Phase::Bootloader, 0);
+    let setup = bootloader::BootSetup {
+        modules: wininstall::SYSLINUX_MODULES,
+        syslinux_config: wininstall::SYSLINUX_CONFIG.to_string(),
+        efi_loader: None,
+    };
+    install_boot_loader(job, partition, setup, write, progress, cancel).await
+}
+
+// Split install.wim through a scratch copy in `work_dir`, since wimlib needs
+// a seekable file, and copy the parts next to where the WIM would have gone.
+// `done` is the copy progress so far, out of `total`.
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+async fn split_install_wim(job: &Job, iso: &Path, wim: &iso9660::IsoFile, work_dir: &Path, partition: &PreparedPartition, done: u64, total: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    send_progress_update(write, progress, &format!("Extracting {} for splitting...", wim.path)).await;
+
+    let wim_path = work_dir.join("install.wim");
+    let parts_dir = work_dir.join("parts");
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let (extract_iso, extract_wim, extract_path, extract_parts) = (iso.to_path_buf(), wim.clone(), wim_path.clone(), parts_dir.clone());
+    let extract_cancel = cancel.clone();
+    let extract_task = tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&extract_parts)?;
+        let mut image = std::io::BufReader::new(fs::File::open(&extract_iso)?);
+        wininstall::extract_file(&mut image, &extract_wim, &extract_path, &extract_cancel, |extracted| {
+            let _ = progress_tx.send(done + extracted);
+        })
+    });
+
+    relay_progress(write, progress, progress_rx, |copied| {
+        format!("Extracting {}... {} of {} bytes", wim.path, copied, total)
+    }).await;
+
+    let extract_result = extract_task.await.map(|result| result.map_err(|e| e.to_string()));
+    let done = match finish_blocking_step(extract_result, "Extracting install.wim", write, progress, cancel).await {
+        Some(extracted) => done + extracted,
+        None => return false,
+    };
+
+    send_progress_update(write, progress, "Splitting install.wim into .swm parts...").await;
+    let command = match wininstall::split_command(&wim_path, &parts_dir) {
+        Some(command) => command,
+        None => {
+            send_error(write, progress, "Error: wimlib-imagex is needed to split install.wim but is not installed").await;
+            return false;
+        }
+    };
+    info!("Splitting {} for job {:?}: {:?}", wim.path, job.id, command);
+    match jobs::run_cancellable(command, cancel).await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!("Splitting install.wim failed: {}", stderr);
+            send_error(write, progress, &format!("Error: Splitting install.wim failed: {}", stderr)).await;
+            return false;
+        }
+        Err(jobs::CommandError::Cancelled) => return false,
+        Err(e) => {
+            error!("Failed to run wimlib-imagex: {}", e);
+            send_error(write, progress, "Error: Splitting install.wim failed: Unable to run wimlib-imagex").await;
+            return false;
+        }
+    }
+
+    let target = wim.path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("").to_string();
+    send_progress_update(write, progress, &format!("Copying .swm parts into /{}...", target)).await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let device = std::path::PathBuf::from(&job.device);
+    let start = partition.start;
+    let copy_cancel = cancel.clone();
+    let copy_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        let copied = fatfs::copy_tree(&mut volume, &parts_dir, &target, &copy_cancel, |copied| {
+            let _ = progress_tx.send(done + copied);
+        })
+        .map_err(|e| e.to_string())?;
+        drop(volume);
+        writer::sync_target(&disk, copied).map_err(|e| e.to_string())?;
+        Ok::<_, String>(copied)
+    });
+
+    relay_progress(write, progress, progress_rx, |copied| {
+        format!("Copying .swm parts... {} of {} bytes", copied, total)
+    }).await;
+
+    finish_blocking_step(copy_task.await, "Copying the .swm parts", write, progress, cancel).await.is_some()
+}
+
+// Make the file-copied stick bootable: syslinux's boot sector and MBR code
+// for legacy BIOS, and the loader in `setup` for UEFI when the copied files
+// did not bring one. A missing syslinux leaves the stick UEFI-only, which the
+// advisor has already warned about.
+#[cfg(target_os = "linux")]
+async fn install_boot_loader(job: &Job, partition: &PreparedPartition, setup: bootloader::BootSetup, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let scheme = partition::PartitionScheme::parse(&job.scheme).unwrap_or(partition::PartitionScheme::Mbr);
+    let syslinux = bootloader::syslinux_available(scheme, setup.modules);
+    if !syslinux {
+        warn!("syslinux is not installed; {} will not boot on legacy BIOS", job.device);
+        if setup.efi_loader.is_none() {
+            return true;
+        }
+    }
+    send_progress_update(write, progress, "Installing the boot loader...").await;
+
+    if syslinux {
+        // syslinux reads the volume through the partition node, whose cache
+        // predates everything written through the whole disk
+        let partition_path = std::path::PathBuf::from(&partition.path);
+        let flush_task = tokio::task::spawn_blocking(move || {
+            let target = writer::open_target(&partition_path).map_err(|e| e.to_string())?;
+            writer::sync_target(&target, 0).map_err(|e| e.to_string())
+        });
+        if finish_blocking_step(flush_task.await, "Flushing the partition", write, progress, cancel).await.is_none() {
+            return false;
+        }
+
+        let command = match bootloader::syslinux_command(&partition.path) {
+            Some(command) => command,
+            None => {
+                send_error(write, progress, "Error: syslinux is not installed").await;
+                return false;
+            }
+        };
+        info!("Installing syslinux on {} for job {:?}: {:?}", partition.path, job.id, command);
+        match jobs::run_cancellable(command, cancel).await {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                error!("syslinux failed: {}", stderr);
+                send_error(write, progress, &format!("Error: Installing syslinux failed: {}", stderr)).await;
+                return false;
+            }
+            Err(jobs::CommandError::Cancelled) => return false,
+            Err(e) => {
+                error!("Failed to run syslinux: {}", e);
+                send_error(write, progress, "Error: Installing syslinux failed: Unable to run syslinux").await;
+                return false;
+            }
+        }
+    }
+
+    let efi_loader = setup.efi_loader.is_some();
+    let device = std::path::PathBuf::from(&job.device);
+    let start = partition.start;
+    let install_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // syslinux wrote through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        if syslinux {
+            bootloader::install_syslinux_files(&mut volume, setup.modules, &setup.syslinux_config).map_err(|e| e.to_string())?;
+        }
+        if let Some(loader) = &setup.efi_loader {
+            bootloader::install_efi_loader(&mut volume, loader).map_err(|e| e.to_string())?;
+        }
+        drop(volume);
+        if syslinux {
+            bootloader::install_mbr_code(&mut disk, scheme).map_err(|e| e.to_string())?;
+        }
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())
+    });
+    if finish_blocking_step(install_task.await, "Installing the boot loader", write, progress, cancel).await.is_none() {
+        return false;
+    }
+
+    info!("Installed boot loader on {} (syslinux: {}, EFI loader: {})", partition.path, syslinux, efi_loader);
+    true
+}
+
+// Extract a non-hybrid Linux ISO onto the freshly formatted FAT32 partition:
+// its files, its boot configs rewritten for the FAT label `label` and with
+// `parameter` added, and a boot menu for syslinux and, when the image has no
+// EFI loader, a standalone GRUB.
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+async fn write_linux_media(job: &Job, partition: &PreparedPartition, image: &inspect::IsoReport, label: &str, parameter: Option<&str>, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let iso = std::path::PathBuf::from(job.iso.as_ref().unwrap());
+    let plan_iso = iso.clone();
+    let report_label = image.label.clone();
+    let distribution = image.distribution.clone();
+    let fat_label = label.to_string();
+    let parameter = parameter.map(str::to_string);
+    let plan_task = tokio::task::spawn_blocking(move || {
+        let mut image = std::io::BufReader::new(fs::File::open(&plan_iso).map_err(|e| e.to_string())?);
+        let descriptors = iso9660::read_descriptors(&mut image).map_err(|e| e.to_string())?;
+        let (_, files) = inspect::read_tree(&mut image, &descriptors).map_err(|e| e.to_string())?;
+        // Configs may name the volume by its primary or its Joliet label
+        let mut labels: Vec<String> = [descriptors.primary.map(|primary| primary.label), descriptors.joliet.map(|joliet| joliet.label), report_label]
+            .into_iter()
+            .flatten()
+            .filter(|label| !label.is_empty())
+            .collect();
+        labels.dedup();
+        let plan = linuxinstall::plan(&mut image, &files, &labels, &fat_label, distribution.as_deref(), parameter.as_deref()).map_err(|e| e.to_string())?;
+        Ok::<_, String>((files, plan))
+    });
+    let (files, plan) = match finish_blocking_step(plan_task.await, "Reading the image", write, progress, cancel).await {
+        Some(result) => result,
+        None => return false,
+    };
+    if plan.entries.is_empty() {
+        error!("No kernel found in {:?}", job.iso);
+        send_error(write, progress, "Error: No bootable Linux kernel was found in the image").await;
+        return false;
+    }
+    info!("Boot menu for {:?}: {:?}", job.iso, plan.entries);
+
+    let total = files.iter().filter(|file| !file.is_dir).map(|file| file.size).sum::<u64>();
+    progress.begin(Phase::Copy, total);
+    send_progress_update(write, progress, "Copying files from the image...").await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let device = std::path::PathBuf::from(&job.device);
+    let start = partition.start;
+    let copy_cancel = cancel.clone();
+    let patched = plan.patched.clone();
+    let copy_task = tokio::task::spawn_blocking(move || {
+        let mut image = std::io::BufReader::new(fs::File::open(&iso).map_err(|e| e.to_string())?);
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // mkfs may have written through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        let copied = fatfs::copy_image_tree(&mut volume, &mut image, &files, "/", &copy_cancel, |done| {
+            let _ = progress_tx.send(done);
+        })
+        .map_err(|e| e.to_string())?;
+        for (path, text) in &patched {
+            volume.write_file(path, &mut text.as_bytes(), text.len() as u64).map_err(|e| e.to_string())?;
+        }
+        volume.flush().map_err(|e| e.to_string())?;
+        drop(volume);
+        writer::sync_target(&disk, copied).map_err(|e| e.to_string())?;
+        Ok::<_, String>(copied)
+    });
+
+    relay_progress(write, progress, progress_rx, |done| {
+        format!("Copying files from the image... {} of {} bytes", done, total)
+    }).await;
+
+    let copied = match finish_blocking_step(copy_task.await, "Copying files from the image", write, progress, cancel).await {
+        Some(copied) => copied,
+        None => return false,
+    };
+    info!("Copied {} bytes of {:?} onto {}, rewrote {} boot configs", copied, job.iso, partition.path, plan.patched.len());
+
+    progress.begin(Phase::Bootloader, 0);
+    let work_dir = match jobs::create_work_dir() {
+        Ok(work_dir) => work_dir,
+        Err(e) => {
+            error!("Cannot create a scratch directory: {}", e);
+            send_error(write, progress, &format!("Error: Installing the boot loader failed: {}", e)).await;
+            return false;
+        }
+    };
+    let efi_loader = if plan.has_efi_loader {
+        None
+    } else {
+        build_grub_efi_loader(label, &plan.entries, &work_dir, write, progress, cancel).await
+    };
+    if cancel.is_cancelled() {
+        let _ = fs::remove_dir_all(&work_dir);
+        return false;
+    }
+
+    let title = image.label.clone().unwrap_or_else(|| DEFAULT_LABEL.to_string());
+    let setup = bootloader::BootSetup {
+        modules: linuxinstall::SYSLINUX_MODULES,
+        syslinux_config: bootmenu::syslinux_config(&title, &plan.entries),
+        efi_loader,
+    };
+    let installed = install_boot_loader(job, partition, setup, write, progress, cancel).await;
+    if let Err(e) = fs::remove_dir_all(&work_dir) {
+        warn!("Failed to remove {}: {}", work_dir.display(), e);
+    }
+    installed
+}
+
+// Build a standalone GRUB EFI loader carrying the generated menu in
+// `work_dir`. Without GRUB, or if the build fails, the stick is left to boot
+// through syslinux only.
+#[cfg(target_os = "linux")]
+async fn build_grub_efi_loader(label: &str, entries: &[bootmenu::MenuEntry], work_dir: &Path, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<std::path::PathBuf> {
+    if !bootloader::grub_efi_available() {
+        warn!("The image has no EFI loader and GRUB is not installed; the stick will not boot through UEFI");
+        return None;
+    }
+    send_progress_update(write, progress, "Building a GRUB EFI loader...").await;
 
-    match write_result {
+    let config = work_dir.join("grub.cfg");
+    let loader = work_dir.join("BOOTX64.EFI");
+    if let Err(e) = fs::write(&config, bootmenu::grub_config(label, entries)) {
+        warn!("Cannot stage grub.cfg in {}: {}", work_dir.display(), e);
+        return None;
+    }
+    let command = bootloader::grub_efi_command(&loader, &config)?;
+    info!("Building EFI loader: {:?}", command);
+    match jobs::run_cancellable(command, cancel).await {
+        Ok(output) if output.status.success() => Some(loader),
+        Ok(output) => {
+            warn!("grub-mkstandalone failed: {}", String::from_utf8_lossy(&output.stderr));
+            None
+        }
+        Err(e) => {
+            warn!("Failed to run grub-mkstandalone: {}", e);
+            None
+        }
+    }
+}
+
+// Returns the number of image bytes now on the device, which for a
+// compressed image is its decompressed size.
+async fn write_iso(job: &Job, image_info: Option<&decompress::ImageInfo>, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<u64> {
+    let iso_path = job.iso.as_ref().unwrap();
+    
+    // Validate ISO file
+    if !Path::new(iso_path).exists() {
+        error!("ISO file not found: {}", iso_path);
+        send_error(write, progress, "Error: ISO file not found").await;
+        return None;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let verify_mode = verify::VerifyMode::parse(job.verify.as_deref()).unwrap_or(verify::VerifyMode::Full);
+        write_iso_linux(iso_path, image_info.cloned(), &job.device, verify_mode, write, progress, cancel).await
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let written = fs::metadata(iso_path).map(|metadata| metadata.len()).unwrap_or(0);
+        write_iso_windows(iso_path, &job.device, write, progress, cancel).await.then_some(written)
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let written = fs::metadata(iso_path).map(|metadata| metadata.len()).unwrap_or(0);
+        write_iso_macos(iso_path, &job.device, write, progress, cancel).await.then_some(written)
+    }
+}
+
+// A hybrid image's GPT was sized for the image, so on a larger stick its
+// backup table lies mid-disk where firmware and partitioning tools flag it.
+// Move it to the end of the device, with fresh GUIDs if the job asks.
+#[cfg(target_os = "linux")]
+async fn repair_gpt(job: &Job, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    send_progress_update(write, progress, "Fixing up the partition table...").await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let new_guids = job.new_guids.unwrap_or(false);
+    let repair_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let disk_size = writer::target_size(&mut disk).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        let repair = partition::repair_gpt(&mut disk, disk_size, sector_size, new_guids).map_err(|e| e.to_string())?;
+        if repair.is_some() {
+            writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+            partition::reread_partition_table(&disk).map_err(|e| format!("kernel did not re-read the partition table: {}", e))?;
+        }
+        Ok::<_, String>(repair)
+    });
+    match finish_blocking_step(repair_task.await, "Fixing up the partition table", write, progress, cancel).await {
+        Some(Some(repair)) => {
+            info!(
+                "Moved the backup GPT of {} from LBA {} to {}{}",
+                job.device,
+                repair.backup_from,
+                repair.backup_to,
+                if repair.new_guids { " with new GUIDs" } else { "" }
+            );
+            true
+        }
+        Some(None) => true,
+        None => false,
+    }
+}
+
+// Give a live image written as-is somewhere to keep its changes: patch its
+// boot configs to ask for persistence, add a partition after the image's own
+// layout, and format that under the label the live system looks for.
+#[cfg(target_os = "linux")]
+async fn add_persistence(job: &Job, plan: &persistence::PersistencePlan, image_size: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    progress.begin(Phase::Partition, 0);
+    send_progress_update(write, progress, "Adding the persistence partition...").await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let table_plan = plan.clone();
+    let table_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let disk_size = writer::target_size(&mut disk).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        persistence::apply(&mut disk, &table_plan).map_err(|e| format!("patching the boot configs failed: {}", e))?;
+        let spec = partition::PartitionSpec::new(partition::PartitionType::LINUX, None, table_plan.label);
+        let placed = partition::append_partition(&mut disk, disk_size, sector_size, image_size, &spec).map_err(|e| e.to_string())?;
+        writer::zero_regions(&mut disk, &[(placed.start, writer::PARTITION_TABLE_WIPE_SIZE.min(placed.size))]).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        partition::reread_partition_table(&disk).map_err(|e| format!("kernel did not re-read the partition table: {}", e))?;
+        Ok::<_, String>(placed)
+    });
+    let placed = match finish_blocking_step(table_task.await, "Adding the persistence partition", write, progress, cancel).await {
+        Some(placed) => placed,
+        None => return false,
+    };
+    for config in &plan.configs {
+        info!("Added {} to {} of the boot entries in {} ({} without room)", plan.parameter, config.patched, config.path, config.skipped);
+    }
+    info!("Added persistence partition {} to {}: {} bytes at {}", placed.number, job.device, placed.size, placed.start);
+
+    let deadline = tokio::time::Instant::now() + PARTITION_NODE_TIMEOUT;
+    let Some(partition_path) = wait_for_partition(job, placed.number, deadline, write, progress, cancel).await else {
+        return false;
+    };
+
+    progress.begin(Phase::Format, 0);
+    send_progress_update(write, progress, &format!("Formatting the {} partition...", plan.label)).await;
+    let work_dir = match jobs::create_work_dir() {
+        Ok(work_dir) => work_dir,
+        Err(e) => {
+            error!("Cannot create a scratch directory: {}", e);
+            send_error(write, progress, &format!("Format failed: {}", e)).await;
+            return false;
+        }
+    };
+    let root = work_dir.join("persistence");
+    if let Err(e) = persistence::stage_root(plan, &root) {
+        error!("Cannot stage the persistence files in {}: {}", root.display(), e);
+        let _ = fs::remove_dir_all(&work_dir);
+        send_error(write, progress, &format!("Format failed: {}", e)).await;
+        return false;
+    }
+    let command = persistence::mkfs_command(&partition_path, plan, &root);
+    info!("Formatting {} for persistence for job {:?}: {:?}", partition_path, job.id, command);
+    let result = jobs::run_cancellable(command, cancel).await;
+    if let Err(e) = fs::remove_dir_all(&work_dir) {
+        warn!("Failed to remove {}: {}", work_dir.display(), e);
+    }
+    match result {
+        Ok(output) if output.status.success() => {
+            info!("Formatted {} as ext4 labelled {}", partition_path, plan.label);
+            true
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!("Format failed: {}", stderr);
+            send_error(write, progress, &format!("Format failed: {}", stderr)).await;
+            false
+        }
+        Err(jobs::CommandError::Cancelled) => false,
+        Err(e) => {
+            error!("Failed to execute format command: {}", e);
+            send_error(write, progress, "Format failed: Unable to execute format command").await;
+            false
+        }
+    }
+}
+
+// Give an image written as-is its installer answers on a FAT32 seed
+// partition after the image's own layout, labelled for the installer to find,
+// and patch its boot configs where the installer has to be told to look.
+#[cfg(target_os = "linux")]
+async fn add_seed_partition(job: &Job, plan: &provision::ProvisionPlan, image_size: u64, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let label = plan.seed_label.unwrap_or(DEFAULT_LABEL);
+    progress.begin(Phase::Partition, 0);
+    send_progress_update(write, progress, &format!("Adding the {} partition...", label)).await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let table_plan = plan.clone();
+    let table_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        let disk_size = writer::target_size(&mut disk).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        bootpatch::apply(&mut disk, &table_plan.configs).map_err(|e| format!("patching the boot configs failed: {}", e))?;
+        let spec = partition::PartitionSpec::new(partition::PartitionType::FAT32, Some(provision::seed_size(sector_size)), label);
+        let placed = partition::append_partition(&mut disk, disk_size, sector_size, image_size, &spec).map_err(|e| e.to_string())?;
+        writer::zero_regions(&mut disk, &[(placed.start, writer::PARTITION_TABLE_WIPE_SIZE.min(placed.size))]).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        partition::reread_partition_table(&disk).map_err(|e| format!("kernel did not re-read the partition table: {}", e))?;
+        Ok::<_, String>((placed, sector_size))
+    });
+    let (placed, sector_size) = match finish_blocking_step(table_task.await, "Adding the seed partition", write, progress, cancel).await {
+        Some(result) => result,
+        None => return false,
+    };
+    for config in &plan.configs {
+        info!("Added autoinstall to {} of the boot entries in {} ({} without room)", config.patched, config.path, config.skipped);
+    }
+    info!("Added {} partition {} to {}: {} bytes at {}", label, placed.number, job.device, placed.size, placed.start);
+
+    let deadline = tokio::time::Instant::now() + PARTITION_NODE_TIMEOUT;
+    let Some(path) = wait_for_partition(job, placed.number, deadline, write, progress, cancel).await else {
+        return false;
+    };
+    let seed = PreparedPartition { path, start: placed.start, size: placed.size, sector_size };
+    let options = filesystem::FormatOptions { label: Some(label.to_string()), ..Default::default() };
+    format_fat32_native(job, &options, &seed, write, progress, cancel).await && write_payload(job, plan, &seed, write, progress, cancel).await
+}
+
+// Apply first-boot settings to the Raspberry Pi OS image just written: find
+// its boot partition in the MBR and write firstrun.sh and cmdline.txt there.
+#[cfg(target_os = "linux")]
+async fn customize_raspberry_pi(job: &Job, customization: &raspberrypi::Customization, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    progress.begin(Phase::Customize, 0);
+    send_progress_update(write, progress, "Customizing the Raspberry Pi image...").await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let customization = customization.clone();
+    let customize_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // The image went through the page cache; read its partitions back from the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let sector_size = partition::logical_sector_size(&disk);
+        let boot = raspberrypi::boot_partition(&mut disk, sector_size).map_err(|e| e.to_string())?;
+        let boot_dir = raspberrypi::customize(&mut disk, boot.start, &customization).map_err(|e| e.to_string())?;
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        Ok::<_, String>((boot, boot_dir))
+    });
+    let Some((boot, boot_dir)) = finish_blocking_step(customize_task.await, "Customizing the image", write, progress, cancel).await else {
+        return false;
+    };
+    info!("Wrote firstrun.sh to partition {} of {} for a system booting from {}", boot.number, job.device, boot_dir);
+    true
+}
+
+// Boot the finished stick in QEMU under each firmware the test asks for,
+// through a snapshot so the stick is left as written. The serial logs and
+// screendumps stay in the run's scratch directory for a look afterwards.
+#[cfg(target_os = "linux")]
+async fn boot_test(job: &Job, job_id: &str, test: &boottest::BootTest, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let firmwares = match test.firmwares() {
+        Ok(firmwares) => firmwares,
+        Err(e) => {
+            progress.begin(Phase::BootTest, 0);
+            send_error(write, progress, &format!("Error: {}", e)).await;
+            return false;
+        }
+    };
+    let timeout = test.timeout().as_secs();
+    progress.begin(Phase::BootTest, timeout * firmwares.len() as u64);
+
+    let dir = match jobs::create_work_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            send_error(write, progress, &format!("Error: Cannot run the boot test: {}", e)).await;
+            return false;
+        }
+    };
+    let device = std::path::PathBuf::from(&job.device);
+    let mut results = Vec::new();
+    for (index, firmware) in firmwares.into_iter().enumerate() {
+        send_progress_update(write, progress, &format!("Booting the stick under {}...", firmware)).await;
+        let offset = timeout * index as u64;
+        let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        let run = boottest::run(&device, firmware, test, &dir, cancel, move |seconds| {
+            let _ = progress_tx.send(offset + seconds);
+        });
+        let (result, _) = tokio::join!(run, relay_progress(write, progress, progress_rx, |done| format!("Booting the stick under {}... {} s", firmware, done - offset)));
+        match result {
+            Ok(result) => {
+                info!("{} boot test of {} {} after {} s; serial log in {}", firmware, job.device, if result.passed { "passed" } else { "failed" }, result.seconds, result.serial_log.display());
+                results.push(result);
+            }
+            Err(boottest::BootTestError::Cancelled) => return false,
+            Err(e) => {
+                send_error(write, progress, &format!("Error: Cannot run the {} boot test: {}", firmware, e)).await;
+                return false;
+            }
+        }
+    }
+
+    let msg = serde_json::json!({"job_id": job_id, "boot_test": results});
+    let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+    if let Some(failed) = results.iter().find(|result| !result.passed) {
+        let reason = match &failed.error {
+            Some(error) => format!("QEMU stopped: {}", error),
+            None => format!("\"{}\" did not show up within {} s", test.marker, timeout),
+        };
+        send_error(write, progress, &format!("Error: The {} boot test failed: {}; see {}", failed.firmware, reason, failed.serial_log.display())).await;
+        return false;
+    }
+    true
+}
+
+// Write the payload's files onto the FAT32 volume on `partition`.
+#[cfg(target_os = "linux")]
+async fn write_payload(job: &Job, plan: &provision::ProvisionPlan, partition: &PreparedPartition, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    send_progress_update(write, progress, &format!("Adding the {} files...", plan.kind)).await;
+
+    let device = std::path::PathBuf::from(&job.device);
+    let start = partition.start;
+    let files = plan.files.clone();
+    let total = plan.size();
+    let payload_task = tokio::task::spawn_blocking(move || {
+        let mut disk = writer::open_target(&device).map_err(|e| e.to_string())?;
+        // mkfs may have written through the partition node; drop stale cached blocks of the disk
+        writer::sync_target(&disk, 0).map_err(|e| e.to_string())?;
+        let mut volume = fatfs::Volume::open(&mut disk, start).map_err(|e| e.to_string())?;
+        for file in &files {
+            volume.write_file(&file.path, &mut file.data.as_slice(), file.size).map_err(|e| e.to_string())?;
+        }
+        volume.flush().map_err(|e| e.to_string())?;
+        drop(volume);
+        writer::sync_target(&disk, total).map_err(|e| e.to_string())
+    });
+    if finish_blocking_step(payload_task.await, "Adding the provisioning files", write, progress, cancel).await.is_none() {
+        return false;
+    }
+    for file in &plan.files {
+        info!("Wrote {} ({} bytes) onto {}", file.path, file.size, partition.path);
+    }
+    true
+}
+
+#[cfg(target_os = "linux")]
+async fn write_iso_linux(iso_path: &str, image_info: Option<decompress::ImageInfo>, device: &str, verify_mode: verify::VerifyMode, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> Option<u64> {
+    let opened = match image_info {
+        Some(info) => decompress::ImageSource::open_probed(Path::new(iso_path), info),
+        None => decompress::ImageSource::open(Path::new(iso_path)),
+    };
+    let source = match opened {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Failed to open image {}: {}", iso_path, e);
+            send_error(write, progress, &format!("Error: Cannot read image: {}", e)).await;
+            return None;
+        }
+    };
+    let info = source.info.clone();
+    if info.format != decompress::ImageFormat::Raw {
+        info!("Decompressing {} image {} ({:?} bytes uncompressed)", info.format, iso_path, info.size);
+    }
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let target = std::path::PathBuf::from(device);
+    let writer_cancel = cancel.clone();
+    let consumed = source.consumed();
+    let image_size = info.size;
+
+    let writer_task = tokio::task::spawn_blocking(move || {
+        // Hash the image on its way to the device for read-back verification
+        let mut image = verify::HashingReader::new(source);
+        let mut target = writer::open_target(&target)?;
+        let written = writer::copy_image(&mut image, &mut target, image_size, &writer_cancel, |written| {
+            // Without a recorded size, progress follows the compressed input
+            let done = if image_size.is_some() { written } else { consumed.load(std::sync::atomic::Ordering::Relaxed) };
+            let _ = progress_tx.send(done);
+        })?;
+        Ok::<_, writer::WriteError>((target, written, image.finish()))
+    });
+
+    let progress_total = info.progress_total();
+    let unit = if info.size.is_some() { "" } else { " compressed" };
+    relay_progress(write, progress, progress_rx, |done| {
+        format!("Writing ISO... {} of {}{} bytes", done, progress_total, unit)
+    }).await;
+
+    let (target, written, digest) = match writer_task.await {
+        Ok(Ok(result)) => result,
+        Ok(Err(writer::WriteError::Cancelled { offset })) => {
+            info!("ISO write to {} cancelled after {} bytes", device, offset);
+            return None;
+        }
+        Ok(Err(e)) => {
+            error!("ISO write to {} failed: {}", device, e);
+            send_error(write, progress, &format!("Error: ISO write failed: {}", e)).await;
+            return None;
+        }
+        Err(e) => {
+            error!("ISO writer task failed: {}", e);
+            send_error(write, progress, "Error: ISO write process failed").await;
+            return None;
+        }
+    };
+
+    progress.begin(Phase::Sync, 0);
+    send_progress_update(write, progress, "Flushing data to device...").await;
+    match tokio::task::spawn_blocking(move || writer::sync_target(&target, written)).await {
+        Ok(Ok(())) => {
+            info!("Successfully wrote {} bytes of ISO to {} (sha256 {})", written, device, digest.hex());
+        }
+        Ok(Err(e)) => {
+            error!("Flushing {} failed: {}", device, e);
+            send_error(write, progress, &format!("Error: ISO write failed: {}", e)).await;
+            return None;
+        }
+        Err(e) => {
+            error!("Sync task failed: {}", e);
+            send_error(write, progress, "Error: ISO write process failed").await;
+            return None;
+        }
+    }
+
+    if verify_mode == verify::VerifyMode::None {
+        return Some(written);
+    }
+
+    let bytes_to_check = verify::planned_bytes(digest.len, verify_mode);
+    progress.begin(Phase::Verify, bytes_to_check);
+    send_progress_update(write, progress, "Verifying write operation...").await;
+
+    let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    // Only a raw image can be read at an offset to pin down a mismatch
+    let source = (info.format == decompress::ImageFormat::Raw).then(|| std::path::PathBuf::from(iso_path));
+    let target = std::path::PathBuf::from(device);
+    let verify_cancel = cancel.clone();
+    let verify_task = tokio::task::spawn_blocking(move || {
+        verify::verify_device(&target, &digest, source.as_deref(), verify_mode, &verify_cancel, |checked| {
+            let _ = progress_tx.send(checked);
+        })
+    });
+
+    relay_progress(write, progress, progress_rx, |checked| {
+        format!("Verifying... {} of {} bytes", checked, bytes_to_check)
+    }).await;
+
+    match verify_task.await {
+        Ok(Ok(report)) => {
+            info!("Verified {} bytes on {} ({:?} mode)", report.bytes_checked, device, report.mode);
+            Some(written)
+        }
+        Ok(Err(verify::VerifyError::Cancelled { offset })) => {
+            info!("Verification of {} cancelled at offset {}", device, offset);
+            None
+        }
+        Ok(Err(e)) => {
+            error!("Verification of {} failed: {}", device, e);
+            send_error(write, progress, &format!("Error: Verification failed: {}", e)).await;
+            None
+        }
+        Err(e) => {
+            error!("Verification task failed: {}", e);
+            send_error(write, progress, "Error: Verification process failed").await;
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+async fn write_iso_windows(iso_path: &str, device: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    // Use Windows-specific tools like Rufus API or PowerShell
+    let mut command = Command::new("powershell");
+    command.args(["-Command", &format!("Copy-Item '{}' '{}'", iso_path, device)]);
+
+    match jobs::run_cancellable(command, cancel).await {
         Ok(output) if output.status.success() => {
             info!("Successfully wrote ISO to {}", device);
             true
@@ -436,24 +2429,24 @@ async fn write_iso_windows(iso_path: &str, device: &str, write: &mut futures_uti
         Ok(output) => {
             let stderr = String::from_utf8_lossy(&output.stderr);
             error!("ISO write failed: {}", stderr);
-            send_status(write, &format!("ISO write failed: {}", stderr), 0).await;
+            send_error(write, progress, &format!("ISO write failed: {}", stderr)).await;
             false
         }
+        Err(jobs::CommandError::Cancelled) => false,
         Err(e) => {
             error!("Failed to execute ISO write command: {}", e);
-            send_status(write, "Error: Failed to execute ISO write command", 0).await;
+            send_error(write, progress, "Error: Failed to execute ISO write command").await;
             false
         }
     }
 }
 
 #[cfg(target_os = "macos")]
-async fn write_iso_macos(iso_path: &str, device: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) -> bool {
-    let write_result = Command::new("dd")
-        .args([&format!("if={}", iso_path), &format!("of={}", device), "bs=4m"])
-        .output();
+async fn write_iso_macos(iso_path: &str, device: &str, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>, progress: &mut ProgressTracker, cancel: &jobs::CancelToken) -> bool {
+    let mut command = Command::new("dd");
+    command.args([&format!("if={}", iso_path), &format!("of={}", device), "bs=4m"]);
 
-    match write_result {
+    match jobs::run_cancellable(command, cancel).await {
         Ok(output) if output.status.success() => {
             info!("Successfully wrote ISO to {}", device);
             true
@@ -461,17 +2454,278 @@ async fn write_iso_macos(iso_path: &str, device: &str, write: &mut futures_util:
         Ok(output) => {
             let stderr = String::from_utf8_lossy(&output.stderr);
             error!("ISO write failed: {}", stderr);
-            send_status(write, &format!("ISO write failed: {}", stderr), 0).await;
+            send_error(write, progress, &format!("ISO write failed: {}", stderr)).await;
             false
         }
+        Err(jobs::CommandError::Cancelled) => false,
         Err(e) => {
             error!("Failed to execute ISO write command: {}", e);
-            send_status(write, "Error: Failed to execute ISO write command", 0).await;
+            send_error(write, progress, "Error: Failed to execute ISO write command").await;
             false
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct CancelRequest {
+    action: String,
+    job_id: String,
+}
+
+fn parse_cancel_request(text: &str) -> Option<CancelRequest> {
+    serde_json::from_str::<CancelRequest>(text)
+        .ok()
+        .filter(|request| request.action == "cancel")
+}
+
+async fn handle_cancel_request(cancel: &CancelRequest, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) {
+    // Nothing is running when a cancel arrives here, so there is no job to stop
+    warn!("Cancel requested for job {} but no job is running", cancel.job_id);
+    let msg = serde_json::json!({
+        "job_id": cancel.job_id,
+        "status": format!("Error: Job {} is not running", cancel.job_id)
+    });
+    let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+}
+
+/// How a job will write its image and what the advisor makes of it.
+#[derive(Serialize, Debug)]
+struct JobAssessment {
+    mode: advisor::WriteMode,
+    image: Option<inspect::IsoReport>,
+    /// Images of a multiboot job and how each one boots.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<multiboot::MultibootImage>,
+    /// What a multiboot job does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation: Option<multiboot::Operation>,
+    /// Boot config patches for a job that adds persistence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persistence: Option<persistence::PersistencePlan>,
+    /// Where a job's unattended-install data goes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provision: Option<provision::ProvisionPlan>,
+    advice: Vec<advisor::Advice>,
+}
+
+// Gather what is known about a job (device size, image inspection, oversized
+// files), settle its write mode and run it past the advisor. Inspection
+// failures are not errors for raw writes: an image that cannot be inspected
+// simply gets no image advice.
+fn assess_job(job: &Job) -> Result<JobAssessment, String> {
+    assess_probed_job(job, None)
+}
+
+// Assess a job whose image was already probed as `image_info`; without it
+// the image is probed here.
+fn assess_probed_job(job: &Job, image_info: Option<&decompress::ImageInfo>) -> Result<JobAssessment, String> {
+    let filesystem = filesystem::Filesystem::parse(&job.filesystem)?;
+    let scheme = partition::PartitionScheme::parse(&job.scheme)?;
+    let requested = job.mode.as_deref().map(advisor::WriteMode::parse).transpose()?;
+    if job.action != "create" && requested.is_some_and(|mode| mode != advisor::WriteMode::Raw) {
+        return Err("A write mode only applies when creating bootable media".to_string());
+    }
+    let operation = match job.action.as_str() {
+        "multiboot" => {
+            let operation = job.operation.as_deref().ok_or("A multiboot job needs an operation: prepare, add or remove")?;
+            Some(multiboot::Operation::parse(operation)?)
+        }
+        _ => None,
+    };
+    let provision = job.provision.as_deref().map(provision::PayloadKind::parse).transpose()?;
+    match (provision, &job.provision_file) {
+        (Some(_), None) => return Err("No provisioning file specified".to_string()),
+        (None, Some(_)) => return Err("A provisioning file needs a provisioning kind".to_string()),
+        _ => {}
+    }
+    let device_size = (!job.device.is_empty()).then(|| get_device_size(&Some(job.device.clone()))).flatten();
+
+    let (image_size, image) = match (&job.iso, job.action.as_str()) {
+        (Some(iso), "create") => {
+            let probed = image_info.cloned().map(Ok).unwrap_or_else(|| decompress::probe(Path::new(iso)));
+            let (image_size, inspected) = match probed {
+                Ok(info) => (info.size, inspect::inspect_probed(Path::new(iso), &info)),
+                Err(e) => (None, Err(e.into())),
+            };
+            let image = match inspected {
+                Ok(report) => Some(report),
+                Err(e) if requested.is_some_and(|mode| mode != advisor::WriteMode::Raw) => {
+                    return Err(format!("Cannot read {} as an ISO: {}", iso, e));
+                }
+                Err(e) => {
+                    info!("No image advice for {}: {}", iso, e);
+                    None
+                }
+            };
+            (image_size, image)
+        }
+        _ => (None, None),
+    };
+
+    let large_files = match &job.files {
+        Some(files) => advisor::oversized_files(Path::new(files)).map_err(|e| format!("Cannot read {}: {}", files, e))?,
+        None => Vec::new(),
+    };
+
+    let requested_images = job.images.as_deref().unwrap_or_default();
+    let images = match operation {
+        Some(multiboot::Operation::Add | multiboot::Operation::Remove) if requested_images.is_empty() => {
+            return Err("No images specified".to_string());
+        }
+        Some(multiboot::Operation::Prepare | multiboot::Operation::Add) => requested_images
+            .iter()
+            .map(|image| multiboot::assess_image(Path::new(image)))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => Vec::new(),
+    };
+    // A fresh multiboot stick has to hold its ESP and every image
+    let image_size = match operation {
+        Some(multiboot::Operation::Prepare) => Some(multiboot::ESP_SIZE + images.iter().map(|image| image.size).sum::<u64>()),
+        _ => image_size,
+    };
+
+    let mode = match job.action.as_str() {
+        "create" => advisor::choose_mode(requested, image.as_ref()),
+        _ => advisor::WriteMode::Raw,
+    };
+    // The boot config patches come from the ISO itself, so an image that
+    // cannot take them is turned away before the device is touched
+    let persistence = job.persistence.unwrap_or(false);
+    let persistence_plan = match (&job.iso, &image) {
+        (Some(iso), Some(image)) if persistence && mode == advisor::WriteMode::Raw && image.isohybrid => {
+            match persistence::flavour(image.distribution.as_deref()) {
+                Some(flavour) => Some(
+                    persistence::plan(Path::new(iso), flavour).map_err(|e| format!("Cannot set up persistence for {}: {}", iso, e))?,
+                ),
+                None => None,
+            }
+        }
+        _ => None,
+    };
+    // Installer answers are checked here too, so a malformed file never
+    // gets as far as the device
+    let provision_plan = match (provision, &job.provision_file, &job.iso, &image) {
+        (Some(kind), Some(source), Some(iso), Some(image)) if job.action == "create" && !persistence && provision::fits(kind, mode, image) => Some(
+            provision::plan(kind, Path::new(source), Path::new(iso), mode).map_err(|e| format!("Cannot use {} as {} data: {}", source, kind, e))?,
+        ),
+        _ => None,
+    };
+    // The boot partition is looked up in the image's MBR up front, so an
+    // image that is not Raspberry Pi OS is turned away before it is written
+    if let Some(customization) = &job.customize {
+        customization.check()?;
+        match (&job.iso, job.action.as_str()) {
+            (Some(iso), "create") if mode == advisor::WriteMode::Raw => {
+                raspberrypi::check_image(Path::new(iso)).map_err(|e| format!("Cannot customize {}: {}", iso, e))?;
+            }
+            _ => return Err("Raspberry Pi customization only applies to images written in raw image mode".to_string()),
+        }
+    }
+    if let Some(test) = &job.boot_test {
+        if job.action != "create" {
+            return Err("Boot tests only apply to create jobs".to_string());
+        }
+        test.check()?;
+    }
+
+    let syslinux_modules = match mode {
+        advisor::WriteMode::Extract => linuxinstall::SYSLINUX_MODULES,
+        _ => wininstall::SYSLINUX_MODULES,
+    };
+    let advice = advisor::advise(&advisor::JobFacts {
+        action: &job.action,
+        mode,
+        filesystem,
+        scheme,
+        device_size,
+        image_size,
+        image: image.as_ref(),
+        large_files: &large_files,
+        wimlib: wininstall::wimlib_available(),
+        syslinux: bootloader::syslinux_available(scheme, syslinux_modules),
+        grub_efi: bootloader::grub_efi_available(),
+        operation,
+        images: &images,
+        persistence,
+        persistence_plan: persistence_plan.as_ref(),
+        provision,
+        provision_plan: provision_plan.as_ref(),
+    });
+    Ok(JobAssessment { mode, image, images, operation, persistence: persistence_plan, provision: provision_plan, advice })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AdviseRequest {
+    action: String,
+    job: Job,
+}
+
+fn parse_advise_request(text: &str) -> Option<AdviseRequest> {
+    serde_json::from_str::<AdviseRequest>(text)
+        .ok()
+        .filter(|request| request.action == "advise")
+}
+
+async fn handle_advise_request(request: AdviseRequest, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) {
+    let job_id = request.job.id.clone();
+    let msg = match tokio::task::spawn_blocking(move || assess_job(&request.job)).await {
+        Ok(Ok(assessment)) => serde_json::json!({"job_id": job_id, "mode": assessment.mode, "images": assessment.images, "persistence": assessment.persistence, "provision": assessment.provision, "advice": assessment.advice}),
+        Ok(Err(e)) => serde_json::json!({"job_id": job_id, "status": format!("Error: {}", e)}),
+        Err(e) => {
+            error!("Advice task failed: {}", e);
+            serde_json::json!({"job_id": job_id, "status": "Error: Cannot check job"})
+        }
+    };
+    let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+}
+
+#[tauri::command]
+fn check_job(job: Job) -> Result<JobAssessment, String> {
+    assess_job(&job)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct InspectRequest {
+    action: String,
+    iso: String,
+}
+
+fn parse_inspect_request(text: &str) -> Option<InspectRequest> {
+    serde_json::from_str::<InspectRequest>(text)
+        .ok()
+        .filter(|request| request.action == "inspect")
+}
+
+async fn handle_inspect_request(request: &InspectRequest, write: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, tokio_tungstenite::tungstenite::Message>) {
+    let path = request.iso.clone();
+    let msg = match tokio::task::spawn_blocking(move || inspect::inspect(Path::new(&path))).await {
+        Ok(Ok(report)) => serde_json::json!({"iso": request.iso, "inspection": report}),
+        Ok(Err(e)) => {
+            warn!("Cannot inspect {}: {}", request.iso, e);
+            serde_json::json!({"iso": request.iso, "status": format!("Error: Cannot inspect {}: {}", request.iso, e)})
+        }
+        Err(e) => {
+            error!("Inspection of {} panicked: {}", request.iso, e);
+            serde_json::json!({"iso": request.iso, "status": format!("Error: Cannot inspect {}", request.iso)})
+        }
+    };
+    let _ = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await;
+}
+
+#[tauri::command]
+fn inspect_iso(path: String) -> Result<inspect::IsoReport, String> {
+    inspect::inspect(Path::new(&path)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn cancel_job(job_id: String) -> Result<(), String> {
+    if jobs::cancel(&job_id) {
+        Ok(())
+    } else {
+        Err(format!("Job {} is not running", job_id))
+    }
+}
+
 async fn handle_websocket(_app: tauri::AppHandle) {
     TermLogger::init(
         LevelFilter::Info, 
@@ -490,6 +2744,16 @@ async fn handle_websocket(_app: tauri::AppHandle) {
     
     info!("WebSocket server started on ws://localhost:8080");
 
+    // Tools don't come and go while the companion runs, so probe once
+    let capabilities = match tokio::task::spawn_blocking(capabilities::probe).await {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            error!("Capability probe failed: {}", e);
+            return;
+        }
+    };
+    info!("Capabilities: {:?}", capabilities);
+
     while let Ok((stream, addr)) = listener.accept().await {
         info!("New WebSocket connection from: {}", addr);
         
@@ -503,9 +2767,9 @@ async fn handle_websocket(_app: tauri::AppHandle) {
         
         let (mut write, mut read) = ws_stream.split();
 
-        // Send initial device list
+        // Send initial device list and what this machine can do with them
         let devices = list_usb_devices();
-        let msg = serde_json::json!({"devices": devices});
+        let msg = serde_json::json!({"devices": devices, "capabilities": capabilities});
         if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(msg.to_string())).await {
             error!("Failed to send initial device list: {}", e);
             continue;
@@ -516,10 +2780,54 @@ async fn handle_websocket(_app: tauri::AppHandle) {
             match msg_result {
                 Ok(msg) => {
                     if let Ok(text) = msg.to_text() {
+                        if let Some(cancel) = parse_cancel_request(text) {
+                            handle_cancel_request(&cancel, &mut write).await;
+                            continue;
+                        }
+                        if let Some(request) = parse_advise_request(text) {
+                            handle_advise_request(request, &mut write).await;
+                            continue;
+                        }
+                        if let Some(request) = parse_inspect_request(text) {
+                            handle_inspect_request(&request, &mut write).await;
+                            continue;
+                        }
+
                         match serde_json::from_str::<Job>(text) {
                             Ok(job) => {
                                 info!("Processing job: {:?}", job);
-                                execute_job(job, &mut write).await;
+                                let job_future = execute_job(job, &mut write);
+                                tokio::pin!(job_future);
+
+                                // Keep reading while the job runs so it can be cancelled
+                                let mut connection_open = true;
+                                loop {
+                                    tokio::select! {
+                                        _ = &mut job_future => break,
+                                        next = read.next(), if connection_open => match next {
+                                            Some(Ok(msg)) => {
+                                                let text = msg.to_text().unwrap_or_default();
+                                                match parse_cancel_request(text) {
+                                                    Some(cancel) if !jobs::cancel(&cancel.job_id) => {
+                                                        warn!("Cancel requested for unknown job {}", cancel.job_id);
+                                                    }
+                                                    Some(_) => {}
+                                                    None if !text.is_empty() => warn!("Ignoring message while a job is running: {}", text),
+                                                    None => {}
+                                                }
+                                            }
+                                            Some(Err(e)) => {
+                                                error!("WebSocket error: {}", e);
+                                                connection_open = false;
+                                            }
+                                            None => connection_open = false,
+                                        },
+                                    }
+                                }
+
+                                if !connection_open {
+                                    break;
+                                }
                             }
                             Err(e) => {
                                 warn!("Failed to parse job JSON: {}", e);
@@ -554,7 +2862,7 @@ pub fn run() {
             Ok(())
         })
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![list_usb_devices, verify_device])
+        .invoke_handler(tauri::generate_handler![list_usb_devices, verify_device, cancel_job, inspect_iso, check_job])
         .run(tauri::generate_context!())
         .expect("Error running WebBoot Companion");
 }
@@ -565,40 +2873,45 @@ fn main() {
 
 // Add device verification
 #[tauri::command]
-fn verify_device(device_path: String) -> Result<DeviceInfo, String> {
+fn verify_device(device_path: String) -> Result<DeviceInfo, DeviceError> {
     let path = std::path::Path::new(&device_path);
     if !path.exists() {
-        return Err("Device does not exist".to_string());
+        return Err(DeviceError::NotFound);
     }
 
+    // A disk the running system depends on is never offered as a target
+    #[cfg(target_os = "linux")]
+    protect::SystemDiskGuard::default().check(&device_path).map_err(DeviceError::Protected)?;
+
     #[cfg(target_os = "linux")]
     {
-        // Get device information using lsblk
-        if let Ok(output) = Command::new("lsblk")
-            .args(["-b", "-n", "-o", "SIZE,FSTYPE,MOUNTPOINT", &device_path])
-            .output()
-        {
-            if let Ok(output_str) = String::from_utf8(output.stdout) {
-                let lines: Vec<&str> = output_str.trim().split('\n').collect();
-                if let Some(line) = lines.first() {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if let Ok(size) = parts.get(0).unwrap_or(&"0").parse::<u64>() {
-                        let filesystem = parts.get(1).map(|s| s.to_string());
-                        let mount_points: Vec<String> = if parts.len() > 2 {
-                            parts[2..].iter().map(|s| s.to_string()).collect()
-                        } else {
-                            vec![]
-                        };
-                        
-                        return Ok(DeviceInfo {
-                            path: device_path,
-                            size,
-                            filesystem,
-                            is_mounted: !mount_points.is_empty(),
-                            mount_points,
-                        });
-                    }
-                }
+        // Get the device tree using lsblk so mounted partitions are seen too
+        match lsblk::query(&device_path) {
+            Ok(tree) => {
+                let partitions: Vec<PartitionInfo> = tree
+                    .children
+                    .iter()
+                    .map(|child| PartitionInfo {
+                        path: child.path.clone(),
+                        size: child.size,
+                        filesystem: child.fstype.clone(),
+                        label: child.label.clone(),
+                        uuid: child.uuid.clone(),
+                        mount_points: child.all_mount_points(),
+                    })
+                    .collect();
+
+                return Ok(DeviceInfo {
+                    path: device_path,
+                    size: tree.size,
+                    filesystem: tree.fstype.clone(),
+                    is_mounted: tree.is_mounted(),
+                    mount_points: tree.mount_points.clone(),
+                    partitions,
+                });
+            }
+            Err(e) => {
+                warn!("Failed to query {} with lsblk: {}", device_path, e);
             }
         }
     }
@@ -613,9 +2926,10 @@ fn verify_device(device_path: String) -> Result<DeviceInfo, String> {
                 filesystem: None,
                 is_mounted: false,
                 mount_points: vec![],
+                partitions: vec![],
             });
         }
     }
 
-    Err("Unable to get device information".to_string())
+    Err(DeviceError::Unavailable)
 }
\ No newline at end of file
@@ -0,0 +1,418 @@
+// Multiboot sticks.
+//
+// One stick carries several ISOs as plain files. An EFI System Partition
+// holds a standalone GRUB whose built-in config only hands over to the
+// grub.cfg next to it, and the data partition after it holds the images
+// under isos/ with one menu snippet per image under webbboot/entries/. Each
+// snippet boots its image through GRUB's loopback, with a kernel command line
+// from a per-distribution recipe that tells the live system where its ISO
+// is. The menu is those snippets joined, rewritten whenever images come or go.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::bootloader::EFI_LOADER;
+use crate::bootmenu::{self, MenuEntry};
+use crate::fatfs::{self, Volume, VolumeError};
+use crate::inspect::{self, IsoReport, OsFamily};
+use crate::iso9660::{self, IsoFile};
+use crate::jobs::CancelToken;
+use crate::partition::{self, PartitionError, PartitionType};
+
+/// Size of the EFI System Partition; GRUB needs a few MiB of it, and FAT32
+/// needs at least 32 MiB at the smallest cluster size.
+pub const ESP_SIZE: u64 = 256 * 1024 * 1024;
+pub const ESP_LABEL: &str = "WEBBOOT-EFI";
+
+/// Where the images go on the data partition.
+pub const ISO_DIR: &str = "isos";
+/// Where each image's menu snippet goes on the data partition.
+pub const ENTRY_DIR: &str = "webbboot/entries";
+/// The generated menu, next to the loader on the ESP.
+pub const MENU_PATH: &str = "EFI/BOOT/grub.cfg";
+
+/// Built into the GRUB loader: read the menu from the directory the loader
+/// was started from.
+pub const LOADER_CONFIG: &str = "configfile \"${cmdpath}/grub.cfg\"\n";
+
+/// Images that bring a menu meant for booting them from an ISO file.
+const LOOPBACK_CONFIG: &str = "boot/grub/loopback.cfg";
+
+/// Characters a kernel command line or GRUB script cannot carry in an
+/// image's file name.
+const UNSAFE_NAME_CHARS: &str = "'\"$\\;`";
+
+/// What a multiboot job does to the stick. Each operation ends by
+/// regenerating the menu.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Lay out a blank stick: ESP with GRUB, and a data partition.
+    Prepare,
+    /// Copy images onto a prepared stick.
+    Add,
+    /// Delete images from a prepared stick.
+    Remove,
+}
+
+impl Operation {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "prepare" => Ok(Operation::Prepare),
+            "add" => Ok(Operation::Add),
+            "remove" => Ok(Operation::Remove),
+            _ => Err(format!("Unsupported multiboot operation: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Prepare => write!(f, "prepare"),
+            Operation::Add => write!(f, "add"),
+            Operation::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+/// How a distribution's live system finds its ISO when booted from a file.
+/// `${isofile}` and `${isouuid}` are left for GRUB to expand; `{label}` is
+/// the image's volume label.
+struct Recipe {
+    distribution: &'static str,
+    /// Directory holding the kernel and initrd.
+    kernel_dir: &'static str,
+    /// A path the image must contain for the recipe to apply.
+    marker: Option<&'static str>,
+    args: &'static str,
+}
+
+const RECIPES: &[Recipe] = &[
+    Recipe {
+        distribution: "ubuntu",
+        kernel_dir: "casper",
+        marker: None,
+        args: "boot=casper iso-scan/filename=${isofile} ---",
+    },
+    Recipe {
+        distribution: "debian",
+        kernel_dir: "live",
+        marker: None,
+        args: "boot=live components findiso=${isofile}",
+    },
+    Recipe {
+        distribution: "arch",
+        kernel_dir: "arch/boot/x86_64",
+        marker: None,
+        args: "img_dev=/dev/disk/by-uuid/${isouuid} img_loop=${isofile} earlymodules=loop",
+    },
+    Recipe {
+        distribution: "redhat",
+        kernel_dir: "images/pxeboot",
+        marker: Some("LiveOS/squashfs.img"),
+        args: "iso-scan/filename=${isofile} root=live:CDLABEL={label} rd.live.image",
+    },
+    Recipe {
+        distribution: "redhat",
+        kernel_dir: "images/pxeboot",
+        marker: None,
+        args: "inst.stage2=hd:UUID=${isouuid}:${isofile}",
+    },
+    Recipe {
+        distribution: "opensuse",
+        kernel_dir: "boot/x86_64/loader",
+        marker: None,
+        args: "isofrom_device=/dev/disk/by-uuid/${isouuid} isofrom_system=${isofile}",
+    },
+];
+
+impl Recipe {
+    fn applies(&self, distribution: Option<&str>, files: &[IsoFile]) -> bool {
+        distribution == Some(self.distribution)
+            && self.marker.is_none_or(|marker| files.iter().any(|file| !file.is_dir && file.path.eq_ignore_ascii_case(marker)))
+    }
+}
+
+/// An image headed for a multiboot stick.
+#[derive(Serialize, Debug, Clone)]
+pub struct MultibootImage {
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// File name on the stick, under `ISO_DIR`.
+    pub name: String,
+    pub size: u64,
+    pub os_family: OsFamily,
+    /// What boots it: a distribution's recipe, or "loopback.cfg" for images
+    /// with a menu of their own for booting from a file.
+    pub recipe: Option<String>,
+    /// The image's menu snippet, when something boots it.
+    #[serde(skip)]
+    pub entry: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MultibootError {
+    Partition(PartitionError),
+    Volume(VolumeError),
+    NotMultiboot(String),
+    NoRecipe(String),
+}
+
+impl fmt::Display for MultibootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultibootError::Partition(e) => write!(f, "{}", e),
+            MultibootError::Volume(e) => write!(f, "{}", e),
+            MultibootError::NotMultiboot(why) => write!(f, "not a multiboot stick ({}); prepare it first", why),
+            MultibootError::NoRecipe(name) => write!(f, "no boot recipe for {}", name),
+        }
+    }
+}
+
+impl std::error::Error for MultibootError {}
+
+impl From<PartitionError> for MultibootError {
+    fn from(e: PartitionError) -> Self {
+        MultibootError::Partition(e)
+    }
+}
+
+impl From<VolumeError> for MultibootError {
+    fn from(e: VolumeError) -> Self {
+        MultibootError::Volume(e)
+    }
+}
+
+impl From<io::Error> for MultibootError {
+    fn from(e: io::Error) -> Self {
+        MultibootError::Volume(VolumeError::Io(e))
+    }
+}
+
+/// Reject image names that cannot be stored, or that would break the kernel
+/// command line or menu they end up in.
+pub fn check_image_name(name: &str) -> Result<(), String> {
+    fatfs::check_name(name).map_err(|e| e.to_string())?;
+    if name.contains(|c: char| c.is_whitespace() || UNSAFE_NAME_CHARS.contains(c)) {
+        return Err(format!("'{}' cannot go on a multiboot stick: rename it without spaces or quotes", name));
+    }
+    Ok(())
+}
+
+/// Inspect the image at `path` and work out how the menu boots it.
+pub fn assess_image(path: &Path) -> Result<MultibootImage, String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("{} has no usable file name", path.display()))?
+        .to_string();
+    check_image_name(&name)?;
+
+    let report = inspect::inspect(path).map_err(|e| format!("Cannot read {} as an ISO: {}", path.display(), e))?;
+    let files = list_files(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let (recipe, entry) = match menu_entry(&name, &report, &files) {
+        Some((recipe, entry)) => (Some(recipe), Some(entry)),
+        None => (None, None),
+    };
+    Ok(MultibootImage { path: path.to_path_buf(), name, size: report.size, os_family: report.os_family, recipe, entry })
+}
+
+fn list_files(path: &Path) -> Result<Vec<IsoFile>, iso9660::IsoError> {
+    let mut image = BufReader::new(File::open(path)?);
+    let descriptors = iso9660::read_descriptors(&mut image)?;
+    inspect::read_tree(&mut image, &descriptors).map(|(_, files)| files)
+}
+
+// The recipe that boots an image stored as `name`, and its menu snippet.
+fn menu_entry(name: &str, image: &IsoReport, files: &[IsoFile]) -> Option<(String, String)> {
+    let isofile = format!("/{}/{}", ISO_DIR, name);
+    let title = match &image.label {
+        Some(label) => format!("{} ({})", label, name),
+        None => name.to_string(),
+    };
+
+    if let Some(recipe) = RECIPES.iter().find(|recipe| recipe.applies(image.distribution.as_deref(), files)) {
+        // The kernel sees the label through udev, which escapes spaces
+        let label = image.label.as_deref().unwrap_or_default().replace(' ', "\\x20");
+        let args = recipe.args.replace("{label}", &label);
+        let entries: Vec<MenuEntry> = bootmenu::discover(files, |_| args.clone())
+            .into_iter()
+            .filter(|entry| bootmenu::parent(entry.kernel.trim_start_matches('/')).eq_ignore_ascii_case(recipe.kernel_dir))
+            .collect();
+        if !entries.is_empty() {
+            let several = entries.len() > 1;
+            let text = entries
+                .iter()
+                .map(|entry| {
+                    let title = if several { format!("{} - {}", title, bootmenu::file_name(&entry.kernel)) } else { title.clone() };
+                    loop_entry(&title, &isofile, entry)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Some((recipe.distribution.to_string(), text));
+        }
+    }
+
+    if files.iter().any(|file| !file.is_dir && file.path.eq_ignore_ascii_case(LOOPBACK_CONFIG)) {
+        return Some(("loopback.cfg".to_string(), loopback_entry(&title, &isofile)));
+    }
+    None
+}
+
+fn loop_entry(title: &str, isofile: &str, entry: &MenuEntry) -> String {
+    let mut text = format!(
+        "menuentry '{}' {{\n  set isofile='{}'\n  loopback loop \"${{isopart}}${{isofile}}\"\n  linux (loop){} {}\n",
+        bootmenu::grub_quote(title),
+        bootmenu::grub_quote(isofile),
+        entry.kernel,
+        entry.args
+    );
+    if !entry.initrds.is_empty() {
+        let initrds: Vec<String> = entry.initrds.iter().map(|initrd| format!("(loop){}", initrd)).collect();
+        text.push_str(&format!("  initrd {}\n", initrds.join(" ")));
+    }
+    text.push_str("}\n");
+    text
+}
+
+// Hand over to the image's own loopback.cfg, which expects `iso_path`.
+fn loopback_entry(title: &str, isofile: &str) -> String {
+    format!(
+        "menuentry '{}' {{\n  set isofile='{}'\n  set iso_path=\"${{isofile}}\"\n  export iso_path\n  loopback loop \"${{isopart}}${{isofile}}\"\n  set root=(loop)\n  configfile /{}\n}}\n",
+        bootmenu::grub_quote(title),
+        bootmenu::grub_quote(isofile),
+        LOOPBACK_CONFIG
+    )
+}
+
+/// The menu for a stick holding images with the snippets `entries`.
+pub fn menu(entries: &[String]) -> String {
+    let mut menu = format!("set timeout={}\nset default=0\n", bootmenu::MENU_TIMEOUT_SECONDS);
+    // The loader runs from partition 1, so the images are on partition 2 of
+    // the disk in `cmdpath`
+    menu.push_str("regexp --set=1:disk '^\\(([^,)]+)' \"${cmdpath}\"\n");
+    menu.push_str("set isopart=\"(${disk},2)\"\n");
+    menu.push_str("probe --set=isouuid --fs-uuid \"${isopart}\"\n");
+    menu.push_str("export isopart isouuid\n");
+    for entry in entries {
+        menu.push('\n');
+        menu.push_str(entry);
+    }
+    menu.push_str("\nmenuentry 'Reboot' {\n  reboot\n}\n\nmenuentry 'Firmware settings' {\n  fwsetup\n}\n");
+    menu
+}
+
+/// Byte offsets of the ESP and the data partition of a stick `Prepare` laid
+/// out on `disk`.
+pub fn locate<D: Read + Write + Seek>(disk: &mut D, sector_size: u64) -> Result<(u64, u64), MultibootError> {
+    let (_, partitions) = match partition::read_table(disk, sector_size) {
+        Err(PartitionError::NoTable) => return Err(MultibootError::NotMultiboot("no partition table".to_string())),
+        result => result?,
+    };
+    let esp = partitions
+        .iter()
+        .find(|partition| partition.number == 1)
+        .filter(|partition| partition.kind.gpt == PartitionType::ESP.gpt || partition.kind.mbr == PartitionType::ESP.mbr)
+        .ok_or_else(|| MultibootError::NotMultiboot("the first partition is not an EFI System Partition".to_string()))?;
+    let data = partitions
+        .iter()
+        .find(|partition| partition.number == 2)
+        .ok_or_else(|| MultibootError::NotMultiboot("there is no data partition".to_string()))?;
+
+    let not_multiboot = |e: VolumeError, what: &str| match e {
+        VolumeError::NotFound(_) | VolumeError::Unsupported(_) => MultibootError::NotMultiboot(format!("{} is missing", what)),
+        e => MultibootError::Volume(e),
+    };
+    let loader = Volume::open(&mut *disk, esp.start)
+        .and_then(|mut volume| volume.list_dir(bootmenu::parent(EFI_LOADER)))
+        .map_err(|e| not_multiboot(e, "the GRUB loader"))?
+        .iter()
+        .any(|entry| !entry.is_dir && fatfs::names_equal(&entry.name, bootmenu::file_name(EFI_LOADER)));
+    if !loader {
+        return Err(MultibootError::NotMultiboot("the GRUB loader is missing".to_string()));
+    }
+    Volume::open(&mut *disk, data.start)
+        .and_then(|mut volume| volume.list_dir(ENTRY_DIR))
+        .map_err(|e| not_multiboot(e, "the menu entry directory"))?;
+    Ok((esp.start, data.start))
+}
+
+/// Set up freshly formatted partitions: the GRUB loader at `loader` on the
+/// ESP, and the image and menu entry directories on the data partition.
+pub fn initialize<D: Read + Write + Seek>(disk: &mut D, esp: u64, data: u64, loader: &Path) -> Result<(), MultibootError> {
+    let mut volume = Volume::open(&mut *disk, esp)?;
+    let mut source = File::open(loader)?;
+    let len = source.metadata()?.len();
+    volume.write_file(EFI_LOADER, &mut source, len)?;
+    volume.flush()?;
+    drop(volume);
+
+    let mut volume = Volume::open(&mut *disk, data)?;
+    volume.create_dir_all(ISO_DIR)?;
+    volume.create_dir_all(ENTRY_DIR)?;
+    volume.flush()?;
+    Ok(())
+}
+
+/// Copy `image` onto the data partition, replacing one of the same name,
+/// then record its menu snippet. `progress` gets the number of bytes copied
+/// so far.
+pub fn store_image<D: Read + Write + Seek>(
+    volume: &mut Volume<D>,
+    image: &MultibootImage,
+    cancel: &CancelToken,
+    progress: impl FnMut(u64),
+) -> Result<u64, MultibootError> {
+    let entry = image.entry.as_deref().ok_or_else(|| MultibootError::NoRecipe(image.name.clone()))?;
+    let copied = fatfs::copy_file_to(volume, &image.path, &format!("{}/{}", ISO_DIR, image.name), cancel, progress)?;
+    volume.write_file(&entry_path(&image.name), &mut entry.as_bytes(), entry.len() as u64)?;
+    volume.flush()?;
+    Ok(copied)
+}
+
+/// Delete the image `name` and its menu snippet. Returns whether the image
+/// was on the stick.
+pub fn remove_image<D: Read + Write + Seek>(volume: &mut Volume<D>, name: &str) -> Result<bool, MultibootError> {
+    let ignore_missing = |result: Result<(), VolumeError>| match result {
+        Ok(()) => Ok(true),
+        Err(VolumeError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    };
+    ignore_missing(volume.remove(&entry_path(name)))?;
+    let found = ignore_missing(volume.remove(&format!("{}/{}", ISO_DIR, name)))?;
+    volume.flush()?;
+    Ok(found)
+}
+
+fn entry_path(name: &str) -> String {
+    format!("{}/{}.cfg", ENTRY_DIR, name)
+}
+
+/// Rebuild the menu on the ESP from the snippets on the data partition.
+/// Returns the number of images in it.
+pub fn write_menu<D: Read + Write + Seek>(disk: &mut D, esp: u64, data: u64) -> Result<usize, MultibootError> {
+    let mut volume = Volume::open(&mut *disk, data)?;
+    let mut names: Vec<String> = volume
+        .list_dir(ENTRY_DIR)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && entry.name.to_lowercase().ends_with(".cfg"))
+        .map(|entry| entry.name)
+        .collect();
+    names.sort_by_key(|name| name.to_lowercase());
+    let mut entries = Vec::with_capacity(names.len());
+    for name in &names {
+        let data = volume.read_file(&format!("{}/{}", ENTRY_DIR, name))?;
+        entries.push(String::from_utf8_lossy(&data).into_owned());
+    }
+    drop(volume);
+
+    let menu = menu(&entries);
+    let mut volume = Volume::open(&mut *disk, esp)?;
+    volume.write_file(MENU_PATH, &mut menu.as_bytes(), menu.len() as u64)?;
+    volume.flush()?;
+    Ok(entries.len())
+}
@@ -0,0 +1,943 @@
+// Native MBR and GPT partition-table writer.
+//
+// Lays out partitions on 1 MiB boundaries and writes either a DOS MBR or a
+// protective MBR with primary and backup GPT, and reads existing tables back
+// to find partitions on media it laid out earlier. Everything goes through
+// `Write + Seek`, so a table can be built on a device, a file or a
+// `Cursor<Vec<u8>>` alike.
+
+use rand::RngCore;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Partition start and size granularity.
+pub const ALIGNMENT: u64 = 1024 * 1024;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_DISK_SIGNATURE_OFFSET: usize = 440;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_MAX_PARTITIONS: usize = 4;
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+const MBR_BOOTABLE: u8 = 0x80;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+const GPT_ENTRY_COUNT: u32 = 128;
+const GPT_ENTRY_SIZE: u32 = 128;
+const GPT_NAME_UNITS: usize = 36;
+/// Attribute bit 2: legacy BIOS bootable.
+const GPT_LEGACY_BOOTABLE: u64 = 1 << 2;
+
+/// A GUID in its on-disk byte order (first three fields little-endian).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+
+    /// A random (version 4) GUID.
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        // Version lives in the high nibble of the little-endian third field
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Guid(bytes)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+/// Partition type, as a GPT type GUID and the matching MBR type byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionType {
+    pub gpt: Guid,
+    pub mbr: u8,
+}
+
+const BASIC_DATA_GUID: Guid =
+    Guid::from_fields(0xebd0_a0a2, 0xb9e5, 0x4433, [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+
+impl PartitionType {
+    /// FAT32 with LBA addressing.
+    pub const FAT32: PartitionType = PartitionType { gpt: BASIC_DATA_GUID, mbr: 0x0c };
+    /// NTFS and exFAT share type 0x07 on MBR and Basic Data on GPT.
+    pub const NTFS: PartitionType = PartitionType { gpt: BASIC_DATA_GUID, mbr: 0x07 };
+    pub const LINUX: PartitionType = PartitionType {
+        gpt: Guid::from_fields(0x0fc6_3daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4]),
+        mbr: 0x83,
+    };
+    /// EFI System Partition.
+    pub const ESP: PartitionType = PartitionType {
+        gpt: Guid::from_fields(0xc12a_7328, 0xf81f, 0x11d2, [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]),
+        mbr: 0xef,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt,
+}
+
+impl PartitionScheme {
+    /// Parse the job's `scheme` field.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_uppercase().as_str() {
+            "MBR" | "DOS" | "MSDOS" => Ok(PartitionScheme::Mbr),
+            "GPT" => Ok(PartitionScheme::Gpt),
+            other => Err(format!("Unknown partition scheme '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for PartitionScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionScheme::Mbr => f.write_str("MBR"),
+            PartitionScheme::Gpt => f.write_str("GPT"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionSpec {
+    pub kind: PartitionType,
+    /// Size in bytes, rounded up to the alignment; None takes the rest of the disk.
+    pub size: Option<u64>,
+    /// GPT partition name; ignored on MBR.
+    pub name: String,
+    pub bootable: bool,
+}
+
+impl PartitionSpec {
+    pub fn new(kind: PartitionType, size: Option<u64>, name: &str) -> Self {
+        PartitionSpec { kind, size, name: name.to_string(), bootable: false }
+    }
+
+    pub fn bootable(mut self) -> Self {
+        self.bootable = true;
+        self
+    }
+}
+
+/// A partition as placed on the disk, in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlacedPartition {
+    /// 1-based partition number.
+    pub number: usize,
+    pub start: u64,
+    pub size: u64,
+    pub kind: PartitionType,
+    pub guid: Option<Guid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableLayout {
+    pub scheme: PartitionScheme,
+    pub sector_size: u64,
+    pub disk_size: u64,
+    pub disk_guid: Option<Guid>,
+    pub disk_signature: Option<u32>,
+    pub partitions: Vec<PlacedPartition>,
+}
+
+#[derive(Debug)]
+pub enum PartitionError {
+    Io(io::Error),
+    DiskTooSmall { needed: u64, available: u64 },
+    TooManyPartitions { scheme: PartitionScheme, max: usize },
+    BeyondMbrLimit { end: u64 },
+    InvalidSectorSize(u64),
+    NoTable,
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionError::Io(e) => write!(f, "{}", e),
+            PartitionError::DiskTooSmall { needed, available } => {
+                write!(f, "partitions need {} bytes but the disk only has {} usable", needed, available)
+            }
+            PartitionError::TooManyPartitions { scheme, max } => {
+                write!(f, "{} supports at most {} partitions here", scheme, max)
+            }
+            PartitionError::BeyondMbrLimit { end } => {
+                write!(f, "partition ends at byte {}, beyond what MBR can address; use GPT", end)
+            }
+            PartitionError::InvalidSectorSize(size) => write!(f, "unsupported sector size {}", size),
+            PartitionError::NoTable => write!(f, "no MBR or GPT partition table found"),
+        }
+    }
+}
+
+impl std::error::Error for PartitionError {}
+
+impl From<io::Error> for PartitionError {
+    fn from(e: io::Error) -> Self {
+        PartitionError::Io(e)
+    }
+}
+
+/// Work out where `specs` go on a disk of `disk_size` bytes without writing.
+pub fn plan(
+    scheme: PartitionScheme,
+    disk_size: u64,
+    sector_size: u64,
+    specs: &[PartitionSpec],
+) -> Result<TableLayout, PartitionError> {
+    if !sector_size.is_power_of_two() || !(512..=ALIGNMENT).contains(&sector_size) {
+        return Err(PartitionError::InvalidSectorSize(sector_size));
+    }
+
+    let max = match scheme {
+        PartitionScheme::Mbr => MBR_MAX_PARTITIONS,
+        PartitionScheme::Gpt => GPT_ENTRY_COUNT as usize,
+    };
+    if specs.len() > max {
+        return Err(PartitionError::TooManyPartitions { scheme, max });
+    }
+
+    // GPT keeps its backup entries and header in the last sectors
+    let usable_end = match scheme {
+        PartitionScheme::Mbr => disk_size,
+        PartitionScheme::Gpt => disk_size.saturating_sub(gpt_table_sectors(sector_size) * sector_size + sector_size),
+    };
+    let usable_end = usable_end / ALIGNMENT * ALIGNMENT;
+
+    let mut partitions = Vec::with_capacity(specs.len());
+    let mut start = ALIGNMENT;
+    for (index, spec) in specs.iter().enumerate() {
+        let size = match spec.size {
+            Some(size) => size.div_ceil(ALIGNMENT) * ALIGNMENT,
+            None => usable_end.saturating_sub(start),
+        };
+        if size == 0 || start + size > usable_end {
+            return Err(PartitionError::DiskTooSmall {
+                needed: start + size.max(ALIGNMENT),
+                available: usable_end,
+            });
+        }
+        if scheme == PartitionScheme::Mbr && (start + size) / sector_size > u32::MAX as u64 {
+            return Err(PartitionError::BeyondMbrLimit { end: start + size });
+        }
+
+        partitions.push(PlacedPartition {
+            number: index + 1,
+            start,
+            size,
+            kind: spec.kind,
+            guid: (scheme == PartitionScheme::Gpt).then(Guid::random),
+        });
+        start += size;
+    }
+
+    let mut signature = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut signature);
+    Ok(TableLayout {
+        scheme,
+        sector_size,
+        disk_size,
+        disk_guid: (scheme == PartitionScheme::Gpt).then(Guid::random),
+        disk_signature: (scheme == PartitionScheme::Mbr).then(|| u32::from_le_bytes(signature)),
+        partitions,
+    })
+}
+
+/// Plan and write a fresh partition table. Stale GPT structures at either
+/// end of the disk are cleared when writing MBR, so nothing mistakes an old
+/// backup GPT for the current table.
+pub fn write_table<D: Write + Seek>(
+    disk: &mut D,
+    scheme: PartitionScheme,
+    disk_size: u64,
+    sector_size: u64,
+    specs: &[PartitionSpec],
+) -> Result<TableLayout, PartitionError> {
+    let layout = plan(scheme, disk_size, sector_size, specs)?;
+    let names: Vec<&str> = specs.iter().map(|spec| spec.name.as_str()).collect();
+    let bootable: Vec<bool> = specs.iter().map(|spec| spec.bootable).collect();
+
+    match scheme {
+        PartitionScheme::Mbr => {
+            let table_sectors = gpt_table_sectors(sector_size) + 1;
+            write_zeros(disk, sector_size, table_sectors * sector_size)?;
+            write_zeros(disk, disk_size - table_sectors * sector_size, table_sectors * sector_size)?;
+            write_mbr(disk, &layout, &bootable)?;
+        }
+        PartitionScheme::Gpt => write_gpt(disk, &layout, &names, &bootable)?,
+    }
+
+    disk.flush()?;
+    Ok(layout)
+}
+
+/// Read the partition table on `disk`. A GPT behind a protective MBR takes
+/// precedence; partitions of an MBR carry a zero GPT type and vice versa.
+/// Every MBR entry with sectors counts, whatever its type.
+pub fn read_table<D: Read + Seek>(disk: &mut D, sector_size: u64) -> Result<(PartitionScheme, Vec<PlacedPartition>), PartitionError> {
+    let mut mbr = vec![0u8; sector_size as usize];
+    read_at(disk, 0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    let entries: Vec<&[u8]> = (0..MBR_MAX_PARTITIONS)
+        .map(|index| &mbr[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..MBR_ENTRIES_OFFSET + (index + 1) * MBR_ENTRY_SIZE])
+        .collect();
+
+    if entries.iter().any(|entry| entry[4] == MBR_PROTECTIVE_TYPE) {
+        return read_gpt(disk, sector_size).map(|partitions| (PartitionScheme::Gpt, partitions));
+    }
+
+    let u32_at = |entry: &[u8], at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap()) as u64;
+    let partitions = entries
+        .iter()
+        .enumerate()
+        // Hybrid ISOs cover themselves with a type 0 entry, which Linux still
+        // exposes as a partition
+        .filter(|(_, entry)| u32_at(entry, 12) != 0)
+        .map(|(index, entry)| PlacedPartition {
+            number: index + 1,
+            start: u32_at(entry, 8) * sector_size,
+            size: u32_at(entry, 12) * sector_size,
+            kind: PartitionType { gpt: Guid([0; 16]), mbr: entry[4] },
+            guid: None,
+        })
+        .collect();
+    Ok((PartitionScheme::Mbr, partitions))
+}
+
+fn read_gpt<D: Read + Seek>(disk: &mut D, sector_size: u64) -> Result<Vec<PlacedPartition>, PartitionError> {
+    let (header, entries) = read_gpt_raw(disk, sector_size)?;
+    let u64_at = |data: &[u8], at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let partitions = entries
+        .chunks_exact(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16].iter().any(|byte| *byte != 0))
+        .filter(|(_, entry)| u64_at(entry, 40) >= u64_at(entry, 32))
+        .map(|(index, entry)| PlacedPartition {
+            number: index + 1,
+            start: u64_at(entry, 32) * sector_size,
+            size: (u64_at(entry, 40) - u64_at(entry, 32) + 1) * sector_size,
+            kind: PartitionType { gpt: Guid(entry[0..16].try_into().unwrap()), mbr: 0 },
+            guid: Some(Guid(entry[16..32].try_into().unwrap())),
+        })
+        .collect();
+    Ok(partitions)
+}
+
+/// Add a partition for `spec` to the table already on `disk`, in the space
+/// after both its last partition and the first `after` bytes, and leave
+/// everything else in place. This is how a hybrid image written as-is gets
+/// more room: its own table was sized for the image, so on GPT the backup
+/// table moves to the real end of the disk as well.
+pub fn append_partition<D: Read + Write + Seek>(
+    disk: &mut D,
+    disk_size: u64,
+    sector_size: u64,
+    after: u64,
+    spec: &PartitionSpec,
+) -> Result<PlacedPartition, PartitionError> {
+    let (scheme, existing) = read_table(disk, sector_size)?;
+    let start = existing
+        .iter()
+        .map(|partition| partition.start + partition.size)
+        .chain([after])
+        .max()
+        .unwrap_or(after)
+        .div_ceil(ALIGNMENT)
+        * ALIGNMENT;
+
+    let placed = match scheme {
+        PartitionScheme::Mbr => append_mbr(disk, disk_size, sector_size, start, spec)?,
+        PartitionScheme::Gpt => append_gpt(disk, disk_size, sector_size, start, spec)?,
+    };
+    disk.flush()?;
+    Ok(placed)
+}
+
+fn append_mbr<D: Read + Write + Seek>(disk: &mut D, disk_size: u64, sector_size: u64, start: u64, spec: &PartitionSpec) -> Result<PlacedPartition, PartitionError> {
+    let mut mbr = vec![0u8; sector_size as usize];
+    read_at(disk, 0, &mut mbr)?;
+    let free = (0..MBR_MAX_PARTITIONS)
+        .find(|index| {
+            let offset = MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE;
+            mbr[offset + 12..offset + 16] == [0; 4]
+        })
+        .ok_or(PartitionError::TooManyPartitions { scheme: PartitionScheme::Mbr, max: MBR_MAX_PARTITIONS })?;
+
+    // Whatever lies past what 32-bit sector numbers reach stays unused
+    let usable_end = disk_size.min((u32::MAX as u64 + 1) * sector_size) / ALIGNMENT * ALIGNMENT;
+    let size = fit_appended(spec, start, usable_end)?;
+    if (start + size) / sector_size > u32::MAX as u64 {
+        return Err(PartitionError::BeyondMbrLimit { end: start + size });
+    }
+
+    let offset = MBR_ENTRIES_OFFSET + free * MBR_ENTRY_SIZE;
+    let status = if spec.bootable { MBR_BOOTABLE } else { 0 };
+    mbr[offset..offset + MBR_ENTRY_SIZE].copy_from_slice(&mbr_entry(status, spec.kind.mbr, start / sector_size, size / sector_size));
+    write_at(disk, 0, &mbr)?;
+
+    Ok(PlacedPartition { number: free + 1, start, size, kind: spec.kind, guid: None })
+}
+
+fn append_gpt<D: Read + Write + Seek>(disk: &mut D, disk_size: u64, sector_size: u64, start: u64, spec: &PartitionSpec) -> Result<PlacedPartition, PartitionError> {
+    let (mut header, mut entries) = read_gpt_raw(disk, sector_size)?;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let table_sectors = (entries.len() as u64).div_ceil(sector_size);
+    let last_lba = disk_size / sector_size - 1;
+
+    let free = entries
+        .chunks_exact(entry_size)
+        .position(|entry| entry[0..16].iter().all(|byte| *byte == 0))
+        .ok_or(PartitionError::TooManyPartitions { scheme: PartitionScheme::Gpt, max: entries.len() / entry_size })?;
+
+    let usable_end = (last_lba - table_sectors) * sector_size / ALIGNMENT * ALIGNMENT;
+    let size = fit_appended(spec, start, usable_end)?;
+    let guid = Guid::random();
+
+    let entry = &mut entries[free * entry_size..(free + 1) * entry_size];
+    entry.fill(0);
+    entry[0..16].copy_from_slice(&spec.kind.gpt.0);
+    entry[16..32].copy_from_slice(&guid.0);
+    entry[32..40].copy_from_slice(&(start / sector_size).to_le_bytes());
+    entry[40..48].copy_from_slice(&((start + size) / sector_size - 1).to_le_bytes());
+    let attributes = if spec.bootable { GPT_LEGACY_BOOTABLE } else { 0 };
+    entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+    for (unit, chunk) in spec.name.encode_utf16().take(GPT_NAME_UNITS).zip(entry[56..].chunks_exact_mut(2)) {
+        chunk.copy_from_slice(&unit.to_le_bytes());
+    }
+
+    relocate_gpt(disk, disk_size, sector_size, &mut header, &entries)?;
+    Ok(PlacedPartition { number: free + 1, start, size, kind: spec.kind, guid: Some(guid) })
+}
+
+/// What [`repair_gpt`] changed.
+#[derive(Debug, Clone)]
+pub struct GptRepair {
+    /// LBA the backup header was found at, and the one it was moved to.
+    pub backup_from: u64,
+    pub backup_to: u64,
+    /// The disk and its partitions got new GUIDs.
+    pub new_guids: bool,
+}
+
+/// Fix up the GPT of an image written as-is to a larger disk: its backup
+/// table sits where the image ended, so move it to the real end and extend
+/// the usable range to match. With `new_guids`, the disk and every partition
+/// also get fresh GUIDs so cloned sticks can be told apart; anything that
+/// finds a partition by PARTUUID stops finding it. Returns None when the disk
+/// has no GPT or it needed nothing.
+pub fn repair_gpt<D: Read + Write + Seek>(disk: &mut D, disk_size: u64, sector_size: u64, new_guids: bool) -> Result<Option<GptRepair>, PartitionError> {
+    let (mut header, mut entries) = match read_gpt_raw(disk, sector_size) {
+        Ok(table) => table,
+        Err(PartitionError::NoTable) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let last_lba = disk_size / sector_size - 1;
+    let table_sectors = (entries.len() as u64).div_ceil(sector_size);
+    let backup_from = u64::from_le_bytes(header[32..40].try_into().unwrap());
+    let last_usable = u64::from_le_bytes(header[48..56].try_into().unwrap());
+    if !new_guids && backup_from == last_lba && last_usable == last_lba - table_sectors - 1 {
+        return Ok(None);
+    }
+    // Shrinking the table under existing partitions is never right
+    if backup_from > last_lba {
+        return Err(PartitionError::DiskTooSmall { needed: (backup_from + 1) * sector_size, available: disk_size });
+    }
+
+    if new_guids {
+        header[56..72].copy_from_slice(&Guid::random().0);
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+        for entry in entries.chunks_exact_mut(entry_size).filter(|entry| entry[0..16].iter().any(|byte| *byte != 0)) {
+            entry[16..32].copy_from_slice(&Guid::random().0);
+        }
+    }
+    relocate_gpt(disk, disk_size, sector_size, &mut header, &entries)?;
+    disk.flush()?;
+    Ok(Some(GptRepair { backup_from, backup_to: last_lba, new_guids }))
+}
+
+// The size an appended partition gets between `start` and `usable_end`.
+fn fit_appended(spec: &PartitionSpec, start: u64, usable_end: u64) -> Result<u64, PartitionError> {
+    let size = match spec.size {
+        Some(size) => size.div_ceil(ALIGNMENT) * ALIGNMENT,
+        None => usable_end.saturating_sub(start),
+    };
+    if size == 0 || start + size > usable_end {
+        return Err(PartitionError::DiskTooSmall { needed: start + size.max(ALIGNMENT), available: usable_end });
+    }
+    Ok(size)
+}
+
+// The primary GPT header and its whole entry array, as found on disk.
+fn read_gpt_raw<D: Read + Seek>(disk: &mut D, sector_size: u64) -> Result<(Vec<u8>, Vec<u8>), PartitionError> {
+    let mut header = vec![0u8; sector_size as usize];
+    read_at(disk, sector_size, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    let count = u32::from_le_bytes(header[80..84].try_into().unwrap()).min(GPT_ENTRY_COUNT * 8) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < GPT_ENTRY_SIZE as usize || !entry_size.is_power_of_two() {
+        return Err(PartitionError::NoTable);
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let mut entries = vec![0u8; count * entry_size];
+    read_at(disk, entries_lba * sector_size, &mut entries)?;
+    Ok((header, entries))
+}
+
+// Write `entries` under the primary `header` and as a backup copy at the end
+// of a disk of `disk_size` bytes, with the usable range, both CRCs and the
+// protective MBR entry following the new end. A backup header left where the
+// old end was is cleared.
+fn relocate_gpt<D: Read + Write + Seek>(disk: &mut D, disk_size: u64, sector_size: u64, header: &mut [u8], entries: &[u8]) -> Result<(), PartitionError> {
+    let last_lba = disk_size / sector_size - 1;
+    let table_sectors = (entries.len() as u64).div_ceil(sector_size);
+    let backup_entries_lba = last_lba - table_sectors;
+    let old_backup = u64::from_le_bytes(header[32..40].try_into().unwrap());
+    let header_size = (u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize).clamp(GPT_HEADER_SIZE as usize, header.len());
+
+    let entries_crc = crc32fast::hash(entries);
+    header[32..40].copy_from_slice(&last_lba.to_le_bytes());
+    header[48..56].copy_from_slice(&(backup_entries_lba - 1).to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let primary = with_header_crc(header, header_size);
+
+    let mut backup = header.to_vec();
+    backup[24..32].copy_from_slice(&last_lba.to_le_bytes());
+    backup[32..40].copy_from_slice(&1u64.to_le_bytes());
+    backup[72..80].copy_from_slice(&backup_entries_lba.to_le_bytes());
+    let backup = with_header_crc(&backup, header_size);
+
+    let mut table = vec![0u8; (table_sectors * sector_size) as usize];
+    table[..entries.len()].copy_from_slice(entries);
+    write_at(disk, backup_entries_lba * sector_size, &table)?;
+    write_at(disk, last_lba * sector_size, &backup)?;
+    let entries_lba = u64::from_le_bytes(primary[72..80].try_into().unwrap());
+    write_at(disk, entries_lba * sector_size, &table)?;
+    write_at(disk, sector_size, &primary)?;
+    if old_backup != last_lba && old_backup > 1 && old_backup < backup_entries_lba {
+        write_zeros(disk, old_backup * sector_size, sector_size)?;
+    }
+
+    let mut mbr = vec![0u8; sector_size as usize];
+    read_at(disk, 0, &mut mbr)?;
+    for index in 0..MBR_MAX_PARTITIONS {
+        let offset = MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE;
+        let first = u32::from_le_bytes(mbr[offset + 8..offset + 12].try_into().unwrap());
+        if mbr[offset + 4] == MBR_PROTECTIVE_TYPE && first == 1 {
+            let covered = last_lba.min(u32::MAX as u64);
+            let entry = mbr_entry(mbr[offset], MBR_PROTECTIVE_TYPE, 1, covered);
+            mbr[offset..offset + MBR_ENTRY_SIZE].copy_from_slice(&entry);
+        }
+    }
+    write_at(disk, 0, &mbr)?;
+    Ok(())
+}
+
+// `header` with its CRC over the first `header_size` bytes filled in.
+fn with_header_crc(header: &[u8], header_size: usize) -> Vec<u8> {
+    let mut header = header.to_vec();
+    header[16..20].fill(0);
+    let crc = crc32fast::hash(&header[..header_size]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn write_mbr<D: Write + Seek>(disk: &mut D, layout: &TableLayout, bootable: &[bool]) -> io::Result<()> {
+    let mut sector = vec![0u8; layout.sector_size as usize];
+    let signature = layout.disk_signature.unwrap_or_default();
+    sector[MBR_DISK_SIGNATURE_OFFSET..MBR_DISK_SIGNATURE_OFFSET + 4].copy_from_slice(&signature.to_le_bytes());
+
+    for (partition, bootable) in layout.partitions.iter().zip(bootable) {
+        let first = partition.start / layout.sector_size;
+        let count = partition.size / layout.sector_size;
+        let offset = MBR_ENTRIES_OFFSET + (partition.number - 1) * MBR_ENTRY_SIZE;
+        let status = if *bootable { MBR_BOOTABLE } else { 0 };
+        sector[offset..offset + MBR_ENTRY_SIZE].copy_from_slice(&mbr_entry(status, partition.kind.mbr, first, count));
+    }
+
+    sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    write_at(disk, 0, &sector)
+}
+
+fn write_gpt<D: Write + Seek>(disk: &mut D, layout: &TableLayout, names: &[&str], bootable: &[bool]) -> io::Result<()> {
+    let sector_size = layout.sector_size;
+    let last_lba = layout.disk_size / sector_size - 1;
+    let table_sectors = gpt_table_sectors(sector_size);
+
+    // Protective MBR covering the whole disk, capped at what 32 bits can say
+    let mut mbr = vec![0u8; sector_size as usize];
+    let covered = last_lba.min(u32::MAX as u64);
+    mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE]
+        .copy_from_slice(&mbr_entry(0, MBR_PROTECTIVE_TYPE, 1, covered));
+    mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+    write_at(disk, 0, &mbr)?;
+
+    let mut entries = vec![0u8; (table_sectors * sector_size) as usize];
+    for ((partition, name), bootable) in layout.partitions.iter().zip(names).zip(bootable) {
+        let offset = (partition.number - 1) * GPT_ENTRY_SIZE as usize;
+        let entry = &mut entries[offset..offset + GPT_ENTRY_SIZE as usize];
+        entry[0..16].copy_from_slice(&partition.kind.gpt.0);
+        entry[16..32].copy_from_slice(&partition.guid.unwrap_or_else(Guid::random).0);
+        entry[32..40].copy_from_slice(&(partition.start / sector_size).to_le_bytes());
+        entry[40..48].copy_from_slice(&((partition.start + partition.size) / sector_size - 1).to_le_bytes());
+        let attributes = if *bootable { GPT_LEGACY_BOOTABLE } else { 0 };
+        entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+        for (unit, chunk) in name.encode_utf16().take(GPT_NAME_UNITS).zip(entry[56..].chunks_exact_mut(2)) {
+            chunk.copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32fast::hash(&entries[..(GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize]);
+
+    let first_usable = 2 + table_sectors;
+    let last_usable = last_lba - table_sectors - 1;
+    let disk_guid = layout.disk_guid.unwrap_or_else(Guid::random);
+    let backup_entries_lba = last_lba - table_sectors;
+
+    let primary = gpt_header(sector_size, 1, last_lba, first_usable, last_usable, disk_guid, 2, entries_crc);
+    let backup = gpt_header(sector_size, last_lba, 1, first_usable, last_usable, disk_guid, backup_entries_lba, entries_crc);
+
+    write_at(disk, sector_size, &primary)?;
+    write_at(disk, 2 * sector_size, &entries)?;
+    write_at(disk, backup_entries_lba * sector_size, &entries)?;
+    write_at(disk, last_lba * sector_size, &backup)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn gpt_header(
+    sector_size: u64,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    entries_crc: u32,
+) -> Vec<u8> {
+    let mut header = vec![0u8; sector_size as usize];
+    header[0..8].copy_from_slice(GPT_SIGNATURE);
+    header[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+    header[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+    header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+    header[40..48].copy_from_slice(&first_usable.to_le_bytes());
+    header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+    header[56..72].copy_from_slice(&disk_guid.0);
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&GPT_ENTRY_COUNT.to_le_bytes());
+    header[84..88].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+    // The header CRC is computed with its own field zeroed
+    let crc = crc32fast::hash(&header[..GPT_HEADER_SIZE as usize]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn mbr_entry(status: u8, kind: u8, first_lba: u64, sectors: u64) -> [u8; MBR_ENTRY_SIZE] {
+    let mut entry = [0u8; MBR_ENTRY_SIZE];
+    entry[0] = status;
+    entry[1..4].copy_from_slice(&lba_to_chs(first_lba));
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&lba_to_chs(first_lba + sectors.max(1) - 1));
+    entry[8..12].copy_from_slice(&(first_lba as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors.min(u32::MAX as u64) as u32).to_le_bytes());
+    entry
+}
+
+// CHS with the conventional 255 heads and 63 sectors per track; addresses past
+// cylinder 1023 get the "use LBA" marker.
+fn lba_to_chs(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [head as u8, (sector as u8) | (((cylinder >> 2) & 0xc0) as u8), cylinder as u8]
+}
+
+/// Sectors taken by the 128-entry partition array.
+fn gpt_table_sectors(sector_size: u64) -> u64 {
+    (GPT_ENTRY_COUNT as u64 * GPT_ENTRY_SIZE as u64).div_ceil(sector_size)
+}
+
+fn read_at<D: Read + Seek>(disk: &mut D, offset: u64, data: &mut [u8]) -> io::Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(data)
+}
+
+fn write_at<D: Write + Seek>(disk: &mut D, offset: u64, data: &[u8]) -> io::Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.write_all(data)
+}
+
+fn write_zeros<D: Write + Seek>(disk: &mut D, offset: u64, len: u64) -> io::Result<()> {
+    write_at(disk, offset, &vec![0u8; len as usize])
+}
+
+/// Path of partition `number` on `device`: `/dev/sdb` gives `/dev/sdb1`,
+/// while names ending in a digit (`nvme0n1`, `mmcblk0`, `loop0`) take a `p`.
+pub fn partition_path(device: &str, number: usize) -> String {
+    if device.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+#[cfg(target_os = "linux")]
+const BLKRRPART: libc::c_ulong = 0x125f;
+#[cfg(target_os = "linux")]
+const BLKSSZGET: libc::c_ulong = 0x1268;
+
+/// Logical sector size of a block device; 512 for regular files.
+#[cfg(target_os = "linux")]
+pub fn logical_sector_size(disk: &std::fs::File) -> u64 {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    let is_block_device = disk.metadata().map(|m| m.file_type().is_block_device()).unwrap_or(false);
+    if !is_block_device {
+        return 512;
+    }
+    let mut size: libc::c_int = 0;
+    // SAFETY: BLKSSZGET writes one int through the pointer.
+    let result = unsafe { libc::ioctl(disk.as_raw_fd(), BLKSSZGET as _, &mut size) };
+    if result == 0 && size > 0 {
+        size as u64
+    } else {
+        512
+    }
+}
+
+/// Ask the kernel to re-read the partition table so partition nodes appear.
+/// Regular files have no table to re-read and succeed trivially.
+#[cfg(target_os = "linux")]
+pub fn reread_partition_table(disk: &std::fs::File) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    let is_block_device = disk.metadata().map(|m| m.file_type().is_block_device()).unwrap_or(false);
+    if !is_block_device {
+        return Ok(());
+    }
+    // SAFETY: BLKRRPART takes no argument and only acts on the fd.
+    let result = unsafe { libc::ioctl(disk.as_raw_fd(), BLKRRPART as _, 0) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn disk(size: u64) -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0u8; size as usize])
+    }
+
+    fn sector(disk: &Cursor<Vec<u8>>, lba: u64, sector_size: u64) -> &[u8] {
+        let start = (lba * sector_size) as usize;
+        &disk.get_ref()[start..start + sector_size as usize]
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    /// Check the GPT header at `lba` and its entry array, and return both.
+    fn checked_gpt(disk: &Cursor<Vec<u8>>, lba: u64, alternate: u64, sector_size: u64) -> (Vec<u8>, Vec<u8>) {
+        let header = sector(disk, lba, sector_size).to_vec();
+        assert_eq!(&header[0..8], GPT_SIGNATURE);
+        assert_eq!(u64_at(&header, 24), lba);
+        assert_eq!(u64_at(&header, 32), alternate);
+
+        let header_size = u32_at(&header, 12) as usize;
+        let mut zeroed = header[..header_size].to_vec();
+        zeroed[16..20].fill(0);
+        assert_eq!(u32_at(&header, 16), crc32fast::hash(&zeroed), "header CRC at LBA {}", lba);
+
+        let entries_start = (u64_at(&header, 72) * sector_size) as usize;
+        let entries_len = (u32_at(&header, 80) * u32_at(&header, 84)) as usize;
+        let entries = disk.get_ref()[entries_start..entries_start + entries_len].to_vec();
+        assert_eq!(u32_at(&header, 88), crc32fast::hash(&entries), "entry array CRC for LBA {}", lba);
+        (header, entries)
+    }
+
+    fn check_protective_mbr(disk: &Cursor<Vec<u8>>, last_lba: u64, sector_size: u64) {
+        let mbr = sector(disk, 0, sector_size);
+        assert_eq!(mbr[510..512], MBR_SIGNATURE);
+        let entry = &mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE];
+        assert_eq!(entry[4], MBR_PROTECTIVE_TYPE);
+        assert_eq!(u32_at(entry, 8), 1);
+        assert_eq!(u32_at(entry, 12) as u64, last_lba.min(u32::MAX as u64));
+        assert!(mbr[MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE..510].iter().all(|byte| *byte == 0));
+    }
+
+    fn gpt_specs() -> Vec<PartitionSpec> {
+        vec![
+            PartitionSpec::new(PartitionType::ESP, Some(16 * MIB - 1), "EFI").bootable(),
+            PartitionSpec::new(PartitionType::FAT32, None, "WEBBOOT"),
+        ]
+    }
+
+    #[test]
+    fn gpt_tables_are_consistent() {
+        for sector_size in [512, 4096] {
+            let disk_size = 64 * MIB;
+            let last_lba = disk_size / sector_size - 1;
+            let mut disk = disk(disk_size);
+            let layout = write_table(&mut disk, PartitionScheme::Gpt, disk_size, sector_size, &gpt_specs()).unwrap();
+
+            check_protective_mbr(&disk, last_lba, sector_size);
+            let (primary, primary_entries) = checked_gpt(&disk, 1, last_lba, sector_size);
+            let (backup, backup_entries) = checked_gpt(&disk, last_lba, 1, sector_size);
+            assert_eq!(primary_entries, backup_entries);
+            assert_eq!(u64_at(&primary, 72), 2);
+            assert_eq!(u64_at(&backup, 72), last_lba - gpt_table_sectors(sector_size));
+            assert_eq!(primary[40..72], backup[40..72]);
+            assert_eq!(Guid(primary[56..72].try_into().unwrap()), layout.disk_guid.unwrap());
+
+            // The ESP is rounded up to whole MiB, the data partition takes
+            // the aligned rest
+            assert_eq!(layout.partitions[0].start, MIB);
+            assert_eq!(layout.partitions[0].size, 16 * MIB);
+            assert_eq!(layout.partitions[1].start, 17 * MIB);
+            for partition in &layout.partitions {
+                assert_eq!(partition.start % ALIGNMENT, 0);
+                assert_eq!(partition.size % ALIGNMENT, 0);
+                assert!((partition.start + partition.size) / sector_size <= u64_at(&primary, 48) + 1);
+            }
+            assert_eq!(u64_at(&primary_entries, 48) & GPT_LEGACY_BOOTABLE, GPT_LEGACY_BOOTABLE);
+            assert_eq!(u64_at(&primary_entries[128..], 48), 0);
+            let name: Vec<u16> = primary_entries[56..62].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+            assert_eq!(String::from_utf16(&name).unwrap(), "EFI");
+
+            let (scheme, partitions) = read_table(&mut disk, sector_size).unwrap();
+            assert_eq!(scheme, PartitionScheme::Gpt);
+            assert_eq!(partitions.len(), 2);
+            for (read, written) in partitions.iter().zip(&layout.partitions) {
+                assert_eq!((read.number, read.start, read.size, read.guid), (written.number, written.start, written.size, written.guid));
+                assert_eq!(read.kind.gpt, written.kind.gpt);
+            }
+        }
+    }
+
+    #[test]
+    fn mbr_table_round_trips_and_clears_an_old_gpt() {
+        let (disk_size, sector_size) = (64 * MIB, 512);
+        let mut disk = disk(disk_size);
+        write_table(&mut disk, PartitionScheme::Gpt, disk_size, sector_size, &gpt_specs()).unwrap();
+
+        let specs = [PartitionSpec::new(PartitionType::FAT32, None, "WEBBOOT").bootable()];
+        let layout = write_table(&mut disk, PartitionScheme::Mbr, disk_size, sector_size, &specs).unwrap();
+
+        let mbr = sector(&disk, 0, sector_size);
+        assert_eq!(mbr[510..512], MBR_SIGNATURE);
+        assert_eq!(u32_at(mbr, MBR_DISK_SIGNATURE_OFFSET), layout.disk_signature.unwrap());
+        let entry = &mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE];
+        assert_eq!(entry[0], MBR_BOOTABLE);
+        assert_eq!(entry[4], 0x0c);
+        assert_eq!(u32_at(entry, 8) as u64, MIB / sector_size);
+        assert_eq!(u32_at(entry, 12) as u64, (disk_size - MIB) / sector_size);
+        assert!(sector(&disk, 1, sector_size).iter().all(|byte| *byte == 0));
+        assert!(sector(&disk, disk_size / sector_size - 1, sector_size).iter().all(|byte| *byte == 0));
+
+        let (scheme, partitions) = read_table(&mut disk, sector_size).unwrap();
+        assert_eq!(scheme, PartitionScheme::Mbr);
+        assert_eq!(partitions.len(), 1);
+        assert_eq!((partitions[0].start, partitions[0].size, partitions[0].kind.mbr), (MIB, disk_size - MIB, 0x0c));
+    }
+
+    #[test]
+    fn plan_rejects_impossible_layouts() {
+        let one = |size| PartitionSpec::new(PartitionType::FAT32, size, "DATA");
+        assert!(matches!(plan(PartitionScheme::Mbr, 64 * MIB, 1000, &[one(None)]), Err(PartitionError::InvalidSectorSize(1000))));
+        assert!(matches!(
+            plan(PartitionScheme::Mbr, 64 * MIB, 512, &vec![one(Some(MIB)); 5]),
+            Err(PartitionError::TooManyPartitions { max: 4, .. })
+        ));
+        assert!(matches!(plan(PartitionScheme::Gpt, 64 * MIB, 512, &[one(Some(64 * MIB))]), Err(PartitionError::DiskTooSmall { .. })));
+        assert!(matches!(plan(PartitionScheme::Mbr, MIB, 512, &[one(None)]), Err(PartitionError::DiskTooSmall { .. })));
+        assert!(matches!(plan(PartitionScheme::Mbr, 3 * 1024 * 1024 * MIB, 512, &[one(None)]), Err(PartitionError::BeyondMbrLimit { .. })));
+        // 4K sectors take MBR further
+        assert!(plan(PartitionScheme::Mbr, 3 * 1024 * 1024 * MIB, 4096, &[one(None)]).is_ok());
+        assert!(matches!(read_table(&mut disk(MIB), 512), Err(PartitionError::NoTable)));
+    }
+
+    #[test]
+    fn repair_moves_the_backup_gpt_to_the_end_of_a_larger_disk() {
+        let (image_size, disk_size, sector_size) = (32 * MIB, 64 * MIB, 512);
+        let mut image = disk(image_size);
+        let layout = write_table(&mut image, PartitionScheme::Gpt, image_size, sector_size, &gpt_specs()).unwrap();
+        let image_last = image_size / sector_size - 1;
+
+        // The image written as-is onto a bigger stick
+        let mut disk = image.clone();
+        disk.get_mut().resize(disk_size as usize, 0);
+        let last_lba = disk_size / sector_size - 1;
+
+        let repair = repair_gpt(&mut disk, disk_size, sector_size, false).unwrap().unwrap();
+        assert_eq!((repair.backup_from, repair.backup_to, repair.new_guids), (image_last, last_lba, false));
+        check_protective_mbr(&disk, last_lba, sector_size);
+        let (primary, primary_entries) = checked_gpt(&disk, 1, last_lba, sector_size);
+        let (backup, backup_entries) = checked_gpt(&disk, last_lba, 1, sector_size);
+        assert_eq!(primary_entries, backup_entries);
+        assert_eq!(u64_at(&primary, 48), last_lba - gpt_table_sectors(sector_size) - 1);
+        assert_eq!(primary[56..72], backup[56..72]);
+        assert!(sector(&disk, image_last, sector_size).iter().all(|byte| *byte == 0));
+
+        let (_, partitions) = read_table(&mut disk, sector_size).unwrap();
+        assert_eq!(partitions.iter().map(|partition| partition.guid).collect::<Vec<_>>(), layout.partitions.iter().map(|partition| partition.guid).collect::<Vec<_>>());
+        assert!(repair_gpt(&mut disk, disk_size, sector_size, false).unwrap().is_none());
+
+        let repair = repair_gpt(&mut disk, disk_size, sector_size, true).unwrap().unwrap();
+        assert!(repair.new_guids);
+        let (renamed, _) = checked_gpt(&disk, 1, last_lba, sector_size);
+        checked_gpt(&disk, last_lba, 1, sector_size);
+        assert_ne!(renamed[56..72], primary[56..72]);
+        let (_, partitions) = read_table(&mut disk, sector_size).unwrap();
+        for (read, written) in partitions.iter().zip(&layout.partitions) {
+            assert_ne!(read.guid, written.guid);
+            assert_eq!((read.start, read.size), (written.start, written.size));
+        }
+
+        // A GPT that claims more than the disk holds is left alone
+        assert!(matches!(repair_gpt(&mut image.clone(), 16 * MIB, sector_size, false), Err(PartitionError::DiskTooSmall { .. })));
+    }
+}
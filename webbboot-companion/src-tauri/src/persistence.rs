@@ -0,0 +1,92 @@
+// Persistence for live images written as-is.
+//
+// Ubuntu's casper and Debian's live-boot keep changes on a partition they
+// find by label, once the kernel command line asks for it. A hybrid image
+// brings its own partition table sized for the image, so the partition goes
+// into the space after it, and the boot configs inside the ISO9660 tree are
+// patched in place to ask for it.
+
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::Path;
+use std::process::Command;
+
+use crate::bootpatch::{self, ConfigPatch, PatchError};
+
+/// Smallest persistence partition worth making.
+pub const MIN_SIZE: u64 = 256 * 1024 * 1024;
+
+/// How a distribution's live system finds and enables its persistence.
+pub struct Flavour {
+    distribution: &'static str,
+    /// Label the live system looks for.
+    pub label: &'static str,
+    /// Kernel parameter that turns persistence on.
+    pub parameter: &'static str,
+    /// Contents of `persistence.conf`, for live systems that want one.
+    config: Option<&'static str>,
+    /// Text found on the kernel command lines that boot the live system.
+    markers: &'static [&'static str],
+}
+
+const FLAVOURS: &[Flavour] = &[
+    Flavour {
+        distribution: "ubuntu",
+        label: "casper-rw",
+        parameter: "persistent",
+        config: None,
+        markers: &["boot=casper", "/casper/"],
+    },
+    Flavour {
+        distribution: "debian",
+        label: "persistence",
+        parameter: "persistence",
+        config: Some("/ union\n"),
+        markers: &["boot=live", "/live/"],
+    },
+];
+
+/// The persistence flavour of images of `distribution`, if it has one.
+pub fn flavour(distribution: Option<&str>) -> Option<&'static Flavour> {
+    FLAVOURS.iter().find(|flavour| Some(flavour.distribution) == distribution)
+}
+
+/// Everything adding persistence to an image does to the stick.
+#[derive(Serialize, Debug, Clone)]
+pub struct PersistencePlan {
+    pub label: &'static str,
+    pub parameter: &'static str,
+    pub configs: Vec<ConfigPatch>,
+    #[serde(skip)]
+    config: Option<&'static str>,
+}
+
+/// Work out the boot config patches that enable `flavour`'s persistence on
+/// the ISO at `path`.
+pub fn plan(path: &Path, flavour: &'static Flavour) -> Result<PersistencePlan, PatchError> {
+    let configs = bootpatch::plan(path, flavour.markers, flavour.parameter)?;
+    Ok(PersistencePlan { label: flavour.label, parameter: flavour.parameter, configs, config: flavour.config })
+}
+
+/// Patch the boot configs of the image written at the start of `disk`.
+pub fn apply<D: Write + Seek>(disk: &mut D, plan: &PersistencePlan) -> io::Result<()> {
+    bootpatch::apply(disk, &plan.configs)
+}
+
+/// Fill `dir` with what the new filesystem starts out holding.
+pub fn stage_root(plan: &PersistencePlan, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if let Some(config) = plan.config {
+        fs::write(dir.join("persistence.conf"), config)?;
+    }
+    Ok(())
+}
+
+/// The mkfs command line that formats `target` as ext4 under the plan's
+/// label, populated from `root` so nothing has to be mounted.
+pub fn mkfs_command(target: &str, plan: &PersistencePlan, root: &Path) -> Command {
+    let mut command = Command::new("sudo");
+    command.args(["mkfs.ext4", "-F", "-L", plan.label, "-d"]).arg(root).arg(target);
+    command
+}
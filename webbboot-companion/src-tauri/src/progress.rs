@@ -0,0 +1,360 @@
+// Byte-accurate job progress.
+//
+// A job is planned as a list of phases, each weighted by the amount of work it
+// does expressed in bytes (fixed-cost phases get a nominal byte weight). The
+// tracker turns per-phase byte counts into an overall percentage that never
+// goes backwards, plus throughput and an ETA for the byte-driven phases.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Validate,
+    Checksum,
+    Partition,
+    Format,
+    Copy,
+    Bootloader,
+    Write,
+    Sync,
+    Verify,
+    Customize,
+    BootTest,
+}
+
+impl Phase {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Phase::Validate => "validation",
+            Phase::Checksum => "checksum verification",
+            Phase::Partition => "partitioning",
+            Phase::Format => "formatting",
+            Phase::Copy => "copying files",
+            Phase::Bootloader => "installing the boot loader",
+            Phase::Write => "writing",
+            Phase::Sync => "syncing",
+            Phase::Verify => "verification",
+            Phase::Customize => "customizing",
+            Phase::BootTest => "boot test",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProgressUpdate {
+    pub job_id: Option<String>,
+    pub state: JobState,
+    pub status: String,
+    pub progress: u8,
+    pub current_operation: String,
+    pub phase: Option<Phase>,
+    pub bytes_done: Option<u64>,
+    pub bytes_total: Option<u64>,
+    /// Throughput over roughly the last second, in bytes per second.
+    pub rate: Option<f64>,
+    /// Throughput since the current phase started, in bytes per second.
+    pub average_rate: Option<f64>,
+    /// Estimated seconds until the remaining byte-driven work is done.
+    pub eta_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PlannedPhase {
+    phase: Phase,
+    weight: u64,
+    /// Whether `weight` is real bytes that will be streamed, so it counts
+    /// towards the ETA.
+    byte_driven: bool,
+}
+
+pub struct ProgressTracker {
+    job_id: Option<String>,
+    state: JobState,
+    plan: Vec<PlannedPhase>,
+    current: Option<usize>,
+    phase_started: Instant,
+    bytes_done: u64,
+    bytes_total: u64,
+    window_started: Instant,
+    window_bytes: u64,
+    rate: Option<f64>,
+    last_progress: u8,
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        ProgressTracker {
+            job_id: None,
+            state: JobState::Running,
+            plan: Vec::new(),
+            current: None,
+            phase_started: now,
+            bytes_done: 0,
+            bytes_total: 0,
+            window_started: now,
+            window_bytes: 0,
+            rate: None,
+            last_progress: 0,
+        }
+    }
+
+    pub fn for_job(mut self, job_id: &str) -> Self {
+        self.job_id = Some(job_id.to_string());
+        self
+    }
+
+    /// Add a phase whose cost is a fixed nominal weight in bytes.
+    pub fn plan_fixed(mut self, phase: Phase, weight: u64) -> Self {
+        self.plan.push(PlannedPhase { phase, weight: weight.max(1), byte_driven: false });
+        self
+    }
+
+    /// Add a phase that streams `bytes` bytes.
+    pub fn plan_bytes(mut self, phase: Phase, bytes: u64) -> Self {
+        self.plan.push(PlannedPhase { phase, weight: bytes.max(1), byte_driven: true });
+        self
+    }
+
+    /// Enter `phase`. Phases skipped in the plan count as done; entering a
+    /// phase that is not in the plan keeps the overall percentage where it is.
+    pub fn begin(&mut self, phase: Phase, bytes_total: u64) {
+        let start = self.current.map(|index| index + 1).unwrap_or(0);
+        if let Some(offset) = self.plan[start.min(self.plan.len())..].iter().position(|p| p.phase == phase) {
+            self.current = Some(start + offset);
+        }
+
+        let now = Instant::now();
+        self.phase_started = now;
+        self.window_started = now;
+        self.window_bytes = 0;
+        self.bytes_done = 0;
+        self.bytes_total = bytes_total;
+        self.rate = None;
+        self.update_progress();
+    }
+
+    /// Record that `bytes_done` bytes of the current phase are complete.
+    pub fn advance(&mut self, bytes_done: u64) {
+        self.bytes_done = bytes_done.max(self.bytes_done);
+
+        let now = Instant::now();
+        let window = now.duration_since(self.window_started);
+        if window >= RATE_WINDOW {
+            let delta = self.bytes_done - self.window_bytes;
+            self.rate = Some(delta as f64 / window.as_secs_f64());
+            self.window_started = now;
+            self.window_bytes = self.bytes_done;
+        }
+
+        self.update_progress();
+    }
+
+    pub fn finish(&mut self) {
+        self.state = JobState::Completed;
+        self.last_progress = 100;
+    }
+
+    /// Mark the job as failed; the percentage stays where it stopped.
+    pub fn fail(&mut self) {
+        self.state = JobState::Failed;
+    }
+
+    /// Mark the job as cancelled; the percentage stays where it stopped.
+    pub fn cancel(&mut self) {
+        self.state = JobState::Cancelled;
+    }
+
+    pub fn phase(&self) -> Option<Phase> {
+        self.current.map(|index| self.plan[index].phase)
+    }
+
+    pub fn progress(&self) -> u8 {
+        self.last_progress
+    }
+
+    pub fn average_rate(&self) -> Option<f64> {
+        let elapsed = self.phase_started.elapsed().as_secs_f64();
+        if self.bytes_done == 0 || elapsed <= 0.0 {
+            return None;
+        }
+        Some(self.bytes_done as f64 / elapsed)
+    }
+
+    pub fn eta_seconds(&self) -> Option<u64> {
+        let index = self.current?;
+        let rate = self.rate.or_else(|| self.average_rate())?;
+        if rate <= 0.0 || !self.plan[index].byte_driven {
+            return None;
+        }
+
+        let remaining_here = self.bytes_total.saturating_sub(self.bytes_done);
+        let remaining_later: u64 = self.plan[index + 1..]
+            .iter()
+            .filter(|p| p.byte_driven)
+            .map(|p| p.weight)
+            .sum();
+        Some(((remaining_here + remaining_later) as f64 / rate).ceil() as u64)
+    }
+
+    pub fn snapshot(&self, status: &str) -> ProgressUpdate {
+        let byte_driven = self.current.map(|index| self.plan[index].byte_driven).unwrap_or(false);
+
+        ProgressUpdate {
+            job_id: self.job_id.clone(),
+            state: self.state,
+            status: status.to_string(),
+            progress: self.last_progress,
+            current_operation: if self.state == JobState::Completed {
+                "complete".to_string()
+            } else {
+                self.phase().map(|phase| phase.label().to_string()).unwrap_or_default()
+            },
+            phase: self.phase(),
+            bytes_done: byte_driven.then_some(self.bytes_done),
+            bytes_total: byte_driven.then_some(self.bytes_total),
+            rate: if byte_driven { self.rate } else { None },
+            average_rate: if byte_driven { self.average_rate() } else { None },
+            eta_seconds: self.eta_seconds(),
+        }
+    }
+
+    fn update_progress(&mut self) {
+        if self.state != JobState::Running {
+            return;
+        }
+        let index = match self.current {
+            Some(index) => index,
+            None => return,
+        };
+
+        let total: u64 = self.plan.iter().map(|p| p.weight).sum();
+        let before: u64 = self.plan[..index].iter().map(|p| p.weight).sum();
+        let fraction = if self.bytes_total == 0 {
+            0.0
+        } else {
+            (self.bytes_done as f64 / self.bytes_total as f64).min(1.0)
+        };
+        let done = before as f64 + self.plan[index].weight as f64 * fraction;
+
+        // 100 is reserved for `finish`, so a job never looks done while work remains.
+        let progress = ((done / total as f64) * 100.0).floor().min(99.0) as u8;
+        self.last_progress = self.last_progress.max(progress);
+    }
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> ProgressTracker {
+        ProgressTracker::new()
+            .for_job("job")
+            .plan_fixed(Phase::Validate, 100)
+            .plan_bytes(Phase::Write, 700)
+            .plan_bytes(Phase::Verify, 200)
+    }
+
+    #[test]
+    fn weights_phases_by_their_bytes() {
+        let mut progress = tracker();
+        progress.begin(Phase::Validate, 0);
+        assert_eq!(progress.progress(), 0);
+        progress.begin(Phase::Write, 700);
+        assert_eq!(progress.progress(), 10);
+        progress.advance(350);
+        assert_eq!(progress.progress(), 45);
+        progress.begin(Phase::Verify, 200);
+        assert_eq!(progress.progress(), 80);
+        progress.advance(100);
+        assert_eq!(progress.progress(), 90);
+    }
+
+    #[test]
+    fn never_goes_backwards() {
+        let mut progress = tracker();
+        progress.begin(Phase::Write, 700);
+        progress.advance(700);
+        assert_eq!(progress.progress(), 80);
+
+        // A phase that is not in the plan, or comes earlier, keeps the percentage
+        progress.begin(Phase::Sync, 0);
+        assert_eq!((progress.progress(), progress.phase()), (80, Some(Phase::Write)));
+        progress.begin(Phase::Validate, 0);
+        assert_eq!(progress.progress(), 80);
+
+        progress.begin(Phase::Verify, 200);
+        progress.advance(50);
+        progress.advance(10);
+        assert_eq!(progress.progress(), 85);
+        assert_eq!(progress.snapshot("").bytes_done, Some(50));
+    }
+
+    #[test]
+    fn holds_at_99_until_finished() {
+        let mut progress = tracker();
+        progress.begin(Phase::Verify, 200);
+        progress.advance(200);
+        progress.advance(u64::MAX);
+        assert_eq!(progress.progress(), 99);
+        assert_eq!(progress.snapshot("").state, JobState::Running);
+
+        progress.finish();
+        let update = progress.snapshot("done");
+        assert_eq!((update.progress, update.state), (100, JobState::Completed));
+        assert_eq!(update.current_operation, "complete");
+    }
+
+    #[test]
+    fn failing_keeps_the_percentage() {
+        let mut progress = tracker();
+        progress.begin(Phase::Write, 700);
+        progress.advance(350);
+        progress.fail();
+        progress.begin(Phase::Verify, 200);
+        let update = progress.snapshot("failed");
+        assert_eq!((update.progress, update.state), (45, JobState::Failed));
+    }
+
+    #[test]
+    fn has_no_eta_without_a_rate() {
+        let mut progress = tracker();
+        assert_eq!(progress.eta_seconds(), None);
+
+        progress.begin(Phase::Write, 700);
+        assert_eq!(progress.eta_seconds(), None);
+        progress.rate = Some(0.0);
+        assert_eq!(progress.eta_seconds(), None);
+
+        // Remaining bytes here plus every later byte-driven phase
+        progress.bytes_done = 300;
+        progress.rate = Some(100.0);
+        assert_eq!(progress.eta_seconds(), Some(6));
+
+        // Fixed-cost phases have no meaningful ETA
+        let mut progress = tracker();
+        progress.begin(Phase::Validate, 0);
+        progress.rate = Some(100.0);
+        assert_eq!(progress.eta_seconds(), None);
+        assert_eq!(progress.snapshot("").bytes_total, None);
+    }
+}
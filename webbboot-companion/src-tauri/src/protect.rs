@@ -0,0 +1,483 @@
+// Refuse jobs against disks the running system depends on.
+//
+// A disk is protected when it (or one of its partitions) backs `/`, `/boot`
+// or `/boot/efi`, holds active swap, or has holders in sysfs. Holders cover
+// LVM physical volumes, md RAID members and dm-crypt backing devices, since
+// each of those stacks a kernel device on top of the disk.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PROC_ROOT: &str = "/proc";
+pub const DEFAULT_SYS_ROOT: &str = "/sys";
+
+const PROTECTED_MOUNT_POINTS: &[&str] = &["/", "/boot", "/boot/efi"];
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ProtectionReason {
+    Mount { mount_point: String },
+    Swap { source: String },
+    Holder { holder: String },
+}
+
+impl fmt::Display for ProtectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtectionReason::Mount { mount_point } => write!(f, "it backs the filesystem mounted at {}", mount_point),
+            ProtectionReason::Swap { source } => write!(f, "it holds active swap ({})", source),
+            ProtectionReason::Holder { holder } => write!(f, "it is in use by {} (LVM, RAID or encrypted volume)", holder),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ProtectionError {
+    /// The target is, or sits on, a disk the running system needs.
+    SystemDisk { device: String, disk: String, reason: ProtectionReason },
+    /// The target could not be mapped to a kernel block device, so it cannot
+    /// be proven safe.
+    Unresolvable { device: String },
+    /// A file listing mounts or swap could not be read, so no disk can be
+    /// proven safe.
+    Unreadable { device: String, path: String, error: String },
+}
+
+impl fmt::Display for ProtectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtectionError::SystemDisk { device, disk, reason } => {
+                write!(f, "Refusing to touch {}: disk {} is protected because {}", device, disk, reason)
+            }
+            ProtectionError::Unresolvable { device } => {
+                write!(f, "Refusing to touch {}: it is not a recognised block device", device)
+            }
+            ProtectionError::Unreadable { device, path, error } => {
+                write!(f, "Refusing to touch {}: cannot read {} to find the system disks: {}", device, path, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtectionError {}
+
+pub struct SystemDiskGuard {
+    proc_root: PathBuf,
+    sys_root: PathBuf,
+}
+
+impl Default for SystemDiskGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROC_ROOT, DEFAULT_SYS_ROOT)
+    }
+}
+
+impl SystemDiskGuard {
+    pub fn new<P: Into<PathBuf>, S: Into<PathBuf>>(proc_root: P, sys_root: S) -> Self {
+        SystemDiskGuard {
+            proc_root: proc_root.into(),
+            sys_root: sys_root.into(),
+        }
+    }
+
+    /// Return an error if `device` (a disk or partition node) must not be written.
+    pub fn check(&self, device: &str) -> Result<(), ProtectionError> {
+        let name = self
+            .kernel_name_for_path(Path::new(device))
+            .ok_or_else(|| ProtectionError::Unresolvable { device: device.to_string() })?;
+        let disk = self.disk_for(&name);
+
+        let protected = self.protected_devices().map_err(|(path, e)| ProtectionError::Unreadable {
+            device: device.to_string(),
+            path: path.display().to_string(),
+            error: e.to_string(),
+        })?;
+        for candidate in [&name, &disk] {
+            if let Some(reason) = protected.get(candidate) {
+                return Err(ProtectionError::SystemDisk {
+                    device: device.to_string(),
+                    disk: disk.clone(),
+                    reason: reason.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Every kernel block device name the system depends on, with the first
+    /// reason found for it. Stacked devices (dm, md) are listed together with
+    /// each disk underneath them. Fails with the file that could not be read
+    /// when the mount or swap list is unavailable.
+    pub fn protected_devices(&self) -> Result<BTreeMap<String, ProtectionReason>, (PathBuf, io::Error)> {
+        let mut protected = BTreeMap::new();
+
+        for (mount_point, name) in self.protected_mounts()? {
+            self.mark_stack(&name, &ProtectionReason::Mount { mount_point }, &mut protected);
+        }
+
+        for (source, name) in self.active_swaps()? {
+            self.mark_stack(&name, &ProtectionReason::Swap { source }, &mut protected);
+        }
+
+        for (name, holder) in self.held_devices() {
+            self.mark_stack(&name, &ProtectionReason::Holder { holder }, &mut protected);
+        }
+
+        Ok(protected)
+    }
+
+    fn protected_mounts(&self) -> Result<Vec<(String, String)>, (PathBuf, io::Error)> {
+        let path = self.proc_root.join("self/mountinfo");
+        let mountinfo = fs::read_to_string(&path).map_err(|e| (path, e))?;
+
+        Ok(mountinfo
+            .lines()
+            .filter_map(|line| {
+                let entry = MountInfoEntry::parse(line)?;
+                if !PROTECTED_MOUNT_POINTS.contains(&entry.mount_point.as_str()) {
+                    return None;
+                }
+                // Filesystems like btrfs report an anonymous 0:N device, so
+                // fall back to the mount source when it names a device node.
+                let name = self
+                    .kernel_name_for_dev_number(&entry.dev_number)
+                    .or_else(|| self.kernel_name_for_path(Path::new(&entry.source)))?;
+                Some((entry.mount_point, name))
+            })
+            .collect())
+    }
+
+    fn active_swaps(&self) -> Result<Vec<(String, String)>, (PathBuf, io::Error)> {
+        let path = self.proc_root.join("swaps");
+        let swaps = fs::read_to_string(&path).map_err(|e| (path, e))?;
+
+        Ok(swaps
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let source = unescape_octal(fields.next()?);
+                let kind = fields.next()?;
+                let name = if kind == "file" {
+                    // A swap file pins the filesystem that contains it.
+                    let metadata = fs::metadata(&source).ok()?;
+                    self.kernel_name_for_dev_number(&dev_number(metadata.dev()))?
+                } else {
+                    self.kernel_name_for_path(Path::new(&source))?
+                };
+                Some((source, name))
+            })
+            .collect())
+    }
+
+    fn held_devices(&self) -> Vec<(String, String)> {
+        let mut held = Vec::new();
+        for disk in dir_names(&self.sys_root.join("block")) {
+            let disk_dir = self.sys_root.join("block").join(&disk);
+            let mut candidates = vec![disk.clone()];
+            candidates.extend(dir_names(&disk_dir).into_iter().filter(|child| {
+                child.starts_with(disk.as_str()) && disk_dir.join(child).join("partition").exists()
+            }));
+
+            for name in candidates {
+                let holders_dir = if name == disk {
+                    disk_dir.join("holders")
+                } else {
+                    disk_dir.join(&name).join("holders")
+                };
+                if let Some(holder) = dir_names(&holders_dir).into_iter().next() {
+                    held.push((name, holder));
+                }
+            }
+        }
+        held
+    }
+
+    /// Mark `name`, every device it is stacked on, and their disks.
+    fn mark_stack(&self, name: &str, reason: &ProtectionReason, protected: &mut BTreeMap<String, ProtectionReason>) {
+        let mut pending = vec![name.to_string()];
+        let mut seen = Vec::new();
+
+        while let Some(current) = pending.pop() {
+            if seen.contains(&current) {
+                continue;
+            }
+            seen.push(current.clone());
+
+            protected.entry(current.clone()).or_insert_with(|| reason.clone());
+            let disk = self.disk_for(&current);
+            protected.entry(disk).or_insert_with(|| reason.clone());
+
+            pending.extend(dir_names(&self.class_block(&current).join("slaves")));
+        }
+    }
+
+    fn class_block(&self, name: &str) -> PathBuf {
+        self.sys_root.join("class/block").join(name)
+    }
+
+    /// Map a partition to its parent disk; whole disks map to themselves.
+    fn disk_for(&self, name: &str) -> String {
+        let dir = self.class_block(name);
+        if !dir.join("partition").exists() {
+            return name.to_string();
+        }
+        fs::canonicalize(&dir)
+            .ok()
+            .and_then(|path| path.parent().and_then(|parent| parent.file_name()).map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn kernel_name_for_dev_number(&self, dev_number: &str) -> Option<String> {
+        let link = self.sys_root.join("dev/block").join(dev_number);
+        let target = fs::canonicalize(link).ok()?;
+        Some(target.file_name()?.to_string_lossy().into_owned())
+    }
+
+    fn kernel_name_for_path(&self, path: &Path) -> Option<String> {
+        if !path.starts_with("/dev") {
+            return None;
+        }
+
+        // Block device nodes identify themselves by device number, which also
+        // sees through /dev/disk/by-* and /dev/mapper symlinks.
+        if let Ok(metadata) = fs::metadata(path) {
+            if metadata.rdev() != 0 {
+                if let Some(name) = self.kernel_name_for_dev_number(&dev_number(metadata.rdev())) {
+                    return Some(name);
+                }
+            }
+        }
+
+        let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let name = resolved.file_name()?.to_string_lossy().into_owned();
+        if self.class_block(&name).exists() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+}
+
+struct MountInfoEntry {
+    dev_number: String,
+    mount_point: String,
+    source: String,
+}
+
+impl MountInfoEntry {
+    // Format: id parent major:minor root mount_point options [optional...] - fstype source super_options
+    fn parse(line: &str) -> Option<Self> {
+        let (before, after) = line.split_once(" - ")?;
+        let fields: Vec<&str> = before.split_whitespace().collect();
+        let tail: Vec<&str> = after.split_whitespace().collect();
+
+        Some(MountInfoEntry {
+            dev_number: fields.get(2)?.to_string(),
+            mount_point: unescape_octal(fields.get(4)?),
+            source: unescape_octal(tail.get(1)?),
+        })
+    }
+}
+
+fn dev_number(dev: u64) -> String {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    format!("{}:{}", major, minor)
+}
+
+// The kernel escapes spaces, tabs, newlines and backslashes in mount and swap
+// paths as three-digit octal sequences.
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(value) = digits.and_then(|digits| u8::from_str_radix(digits, 8).ok()) {
+                out.push(value);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn dir_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => vec![],
+    };
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fake /proc and /sys laid out the way the kernel does: devices under
+    /// /sys/devices, linked from /sys/block, /sys/class/block and
+    /// /sys/dev/block by device number.
+    struct FakeRoot {
+        dir: tempfile::TempDir,
+    }
+
+    impl FakeRoot {
+        fn new(mountinfo: &str, swaps: &str) -> Self {
+            let root = FakeRoot { dir: tempfile::tempdir().unwrap() };
+            fs::create_dir_all(root.proc().join("self")).unwrap();
+            fs::write(root.proc().join("self/mountinfo"), mountinfo).unwrap();
+            fs::write(root.proc().join("swaps"), format!("Filename\tType\tSize\tUsed\tPriority\n{}", swaps)).unwrap();
+            for dir in ["block", "class/block", "dev/block"] {
+                fs::create_dir_all(root.sys().join(dir)).unwrap();
+            }
+            root
+        }
+
+        fn proc(&self) -> PathBuf {
+            self.dir.path().join("proc")
+        }
+
+        fn sys(&self) -> PathBuf {
+            self.dir.path().join("sys")
+        }
+
+        fn guard(&self) -> SystemDiskGuard {
+            SystemDiskGuard::new(self.proc(), self.sys())
+        }
+
+        fn link(&self, path: &Path, name: &str, dev: &str) {
+            symlink(path, self.sys().join("class/block").join(name)).unwrap();
+            symlink(path, self.sys().join("dev/block").join(dev)).unwrap();
+        }
+
+        fn disk(&self, name: &str, dev: &str, partitions: &[(&str, &str)]) -> PathBuf {
+            let path = self.sys().join("devices/pci0000:00/host0/block").join(name);
+            fs::create_dir_all(&path).unwrap();
+            symlink(&path, self.sys().join("block").join(name)).unwrap();
+            self.link(&path, name, dev);
+            for (partition, dev) in partitions {
+                fs::create_dir_all(path.join(partition)).unwrap();
+                fs::write(path.join(partition).join("partition"), "1\n").unwrap();
+                self.link(&path.join(partition), partition, dev);
+            }
+            path
+        }
+
+        // A device-mapper or md device stacked on `slaves`.
+        fn stacked(&self, name: &str, dev: &str, slaves: &[&str]) {
+            let path = self.sys().join("devices/virtual/block").join(name);
+            for slave in slaves {
+                fs::create_dir_all(path.join("slaves").join(slave)).unwrap();
+            }
+            symlink(&path, self.sys().join("block").join(name)).unwrap();
+            self.link(&path, name, dev);
+        }
+    }
+
+    fn mount(id: u32, dev: &str, mount_point: &str, source: &str) -> String {
+        format!("{} 1 {} / {} rw,relatime shared:{} - ext4 {} rw\n", id, dev, mount_point, id, source)
+    }
+
+    fn reason(result: Result<(), ProtectionError>) -> Option<ProtectionReason> {
+        match result {
+            Ok(()) => None,
+            Err(ProtectionError::SystemDisk { reason, .. }) => Some(reason),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn protects_the_disk_under_root_on_lvm_on_dm_crypt() {
+        let mountinfo = [
+            mount(22, "253:1", "/", "/dev/mapper/vg-root"),
+            mount(23, "8:1", "/boot", "/dev/sda1"),
+            mount(24, "8:17", "/media/usb\\040stick", "/dev/sdb1"),
+        ]
+        .concat();
+        let root = FakeRoot::new(&mountinfo, "");
+        root.disk("sda", "8:0", &[("sda1", "8:1"), ("sda2", "8:2")]);
+        root.disk("sdb", "8:16", &[("sdb1", "8:17")]);
+        root.stacked("dm-0", "253:0", &["sda2"]);
+        root.stacked("dm-1", "253:1", &["dm-0"]);
+        let guard = root.guard();
+
+        // Reaching sda through dm-1 -> dm-0 -> sda2 gives it the root reason
+        let root_mount = ProtectionReason::Mount { mount_point: "/".to_string() };
+        let protected = guard.protected_devices().unwrap();
+        for name in ["dm-1", "dm-0", "sda2", "sda"] {
+            assert_eq!(protected.get(name), Some(&root_mount), "{}", name);
+        }
+        assert_eq!(reason(guard.check("/dev/sda")), Some(root_mount));
+        assert!(reason(guard.check("/dev/sda1")).is_some());
+        // A stick mounted somewhere else is fair game
+        assert_eq!(reason(guard.check("/dev/sdb")), None);
+        assert_eq!(reason(guard.check("/dev/sdb1")), None);
+    }
+
+    #[test]
+    fn protects_swap_partitions_and_held_disks() {
+        let root = FakeRoot::new(&mount(22, "8:2", "/", "/dev/sda2"), "/dev/sdc2\tpartition\t1048572\t0\t-2\n");
+        root.disk("sda", "8:0", &[("sda2", "8:2")]);
+        root.disk("sdc", "8:32", &[("sdc1", "8:33"), ("sdc2", "8:34")]);
+        let sde = root.disk("sde", "8:64", &[("sde1", "8:65")]);
+        fs::create_dir_all(sde.join("sde1/holders/md0")).unwrap();
+        let guard = root.guard();
+
+        assert_eq!(reason(guard.check("/dev/sdc")), Some(ProtectionReason::Swap { source: "/dev/sdc2".to_string() }));
+        assert!(reason(guard.check("/dev/sdc1")).is_some());
+        assert_eq!(reason(guard.check("/dev/sde")), Some(ProtectionReason::Holder { holder: "md0".to_string() }));
+    }
+
+    #[test]
+    fn escaped_mount_points_are_not_confused_with_protected_ones() {
+        let mountinfo = [mount(22, "8:2", "/", "/dev/sda2"), mount(23, "8:49", "/boot\\040old", "/dev/sdd1")].concat();
+        let root = FakeRoot::new(&mountinfo, "");
+        root.disk("sda", "8:0", &[("sda2", "8:2")]);
+        root.disk("sdd", "8:48", &[("sdd1", "8:49")]);
+        assert_eq!(reason(root.guard().check("/dev/sdd")), None);
+
+        let entry = MountInfoEntry::parse("36 35 98:0 /mnt1 /mnt\\040two rw,noatime master:1 - ext3 /dev/root\\134x rw").unwrap();
+        assert_eq!((entry.dev_number.as_str(), entry.mount_point.as_str(), entry.source.as_str()), ("98:0", "/mnt two", "/dev/root\\x"));
+        assert_eq!(unescape_octal("tab\\011nl\\012"), "tab\tnl\n");
+        // Anything that is not a full three-digit escape stays as it is
+        assert_eq!(unescape_octal("a\\09b\\04"), "a\\09b\\04");
+    }
+
+    #[test]
+    fn unreadable_proc_files_protect_everything() {
+        let root = FakeRoot::new("", "");
+        root.disk("sdb", "8:16", &[]);
+        assert_eq!(reason(root.guard().check("/dev/sdb")), None);
+
+        fs::remove_file(root.proc().join("swaps")).unwrap();
+        let error = root.guard().check("/dev/sdb").unwrap_err();
+        assert!(matches!(&error, ProtectionError::Unreadable { path, .. } if path.ends_with("swaps")), "{}", error);
+
+        fs::remove_file(root.proc().join("self/mountinfo")).unwrap();
+        let error = root.guard().check("/dev/sdb").unwrap_err();
+        assert!(matches!(&error, ProtectionError::Unreadable { path, .. } if path.ends_with("self/mountinfo")), "{}", error);
+    }
+
+    #[test]
+    fn device_numbers_use_the_kernel_encoding() {
+        assert_eq!(dev_number(libc::makedev(8, 1)), "8:1");
+        assert_eq!(dev_number(libc::makedev(259, 70000)), "259:70000");
+        assert_eq!(dev_number(libc::makedev(4095, 255)), "4095:255");
+    }
+}
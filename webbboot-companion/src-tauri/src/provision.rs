@@ -0,0 +1,297 @@
+// Unattended-install data placed on written media.
+//
+// Each installer looks for its answers somewhere different. Ubuntu's
+// subiquity and cloud-init read a NoCloud seed from a volume labelled CIDATA,
+// Anaconda picks up ks.cfg from one labelled OEMDRV, the Debian installer
+// takes a preseed file named on its command line, and Windows Setup finds
+// autounattend.xml at the root of any removable drive. An image written as-is
+// keeps its read-only ISO9660 tree, so its data goes onto a small FAT32 seed
+// partition added after the image; file-copied media takes the files on its
+// own FAT32 volume. Payloads are checked before anything is written.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::advisor::WriteMode;
+use crate::bootpatch::{self, ConfigPatch, PatchError};
+use crate::inspect::{IsoReport, OsFamily};
+
+/// Size of the seed partition added after an image written as-is, for
+/// 512-byte sectors: enough clusters for FAT32 at the smallest cluster size.
+pub const SEED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Installer answers are text files; anything larger is the wrong file.
+const MAX_PAYLOAD_SIZE: u64 = 1024 * 1024;
+
+const CIDATA_LABEL: &str = "CIDATA";
+const OEMDRV_LABEL: &str = "OEMDRV";
+
+/// Kernel command lines that boot Ubuntu's installer.
+const CASPER_MARKERS: &[&str] = &["boot=casper", "/casper/"];
+
+/// What kind of unattended-install data a job carries.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadKind {
+    /// Ubuntu autoinstall config, delivered as NoCloud user-data.
+    Autoinstall,
+    /// cloud-init user-data for a NoCloud seed.
+    NoCloud,
+    /// Anaconda Kickstart file.
+    Kickstart,
+    /// Debian installer preseed file.
+    Preseed,
+    /// Windows Setup answer file.
+    Autounattend,
+}
+
+impl PayloadKind {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "autoinstall" => Ok(PayloadKind::Autoinstall),
+            "nocloud" | "cloud-init" => Ok(PayloadKind::NoCloud),
+            "kickstart" => Ok(PayloadKind::Kickstart),
+            "preseed" => Ok(PayloadKind::Preseed),
+            "autounattend" => Ok(PayloadKind::Autounattend),
+            _ => Err(format!("Unsupported provisioning kind: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadKind::Autoinstall => write!(f, "Ubuntu autoinstall"),
+            PayloadKind::NoCloud => write!(f, "cloud-init NoCloud"),
+            PayloadKind::Kickstart => write!(f, "Kickstart"),
+            PayloadKind::Preseed => write!(f, "preseed"),
+            PayloadKind::Autounattend => write!(f, "autounattend.xml"),
+        }
+    }
+}
+
+/// Whether data of `kind` has somewhere to go on media written in `mode`
+/// from `image`.
+pub fn fits(kind: PayloadKind, mode: WriteMode, image: &IsoReport) -> bool {
+    let distribution = image.distribution.as_deref();
+    let raw = mode == WriteMode::Raw && image.isohybrid;
+    match kind {
+        PayloadKind::Autoinstall => raw && distribution == Some("ubuntu"),
+        PayloadKind::NoCloud => raw && image.os_family == OsFamily::Linux,
+        PayloadKind::Kickstart => (raw || mode == WriteMode::Extract) && distribution == Some("redhat"),
+        PayloadKind::Preseed => mode == WriteMode::Extract && distribution == Some("debian"),
+        PayloadKind::Autounattend => mode == WriteMode::Windows,
+    }
+}
+
+/// A file the payload becomes.
+#[derive(Serialize, Debug, Clone)]
+pub struct PayloadFile {
+    /// Absolute path on the volume it goes onto.
+    pub path: String,
+    pub size: u64,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+/// Everything placing a payload does to the stick.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProvisionPlan {
+    pub kind: PayloadKind,
+    /// Label of the seed partition added after an image written as-is.
+    pub seed_label: Option<&'static str>,
+    pub files: Vec<PayloadFile>,
+    /// Boot config patches for an image written as-is.
+    pub configs: Vec<ConfigPatch>,
+    /// Kernel parameter extracted media boots with, `{label}` standing for
+    /// the FAT label.
+    #[serde(skip)]
+    parameter: Option<&'static str>,
+}
+
+impl ProvisionPlan {
+    /// The kernel parameter extracted media labelled `label` boots with.
+    pub fn parameter(&self, label: &str) -> Option<String> {
+        self.parameter.map(|parameter| parameter.replace("{label}", &label.replace(' ', "\\x20")))
+    }
+
+    /// Bytes written for the payload's files.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+#[derive(Debug)]
+pub enum ProvisionError {
+    Io(io::Error),
+    TooLarge(u64),
+    Yaml(serde_yaml::Error),
+    Xml(roxmltree::Error),
+    Invalid(String),
+    Patch(PatchError),
+}
+
+impl fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvisionError::Io(e) => write!(f, "{}", e),
+            ProvisionError::TooLarge(size) => write!(f, "{} bytes is too large for installer answers", size),
+            ProvisionError::Yaml(e) => write!(f, "invalid YAML: {}", e),
+            ProvisionError::Xml(e) => write!(f, "invalid XML: {}", e),
+            ProvisionError::Invalid(message) => write!(f, "{}", message),
+            ProvisionError::Patch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProvisionError {}
+
+impl From<io::Error> for ProvisionError {
+    fn from(e: io::Error) -> Self {
+        ProvisionError::Io(e)
+    }
+}
+
+impl From<serde_yaml::Error> for ProvisionError {
+    fn from(e: serde_yaml::Error) -> Self {
+        ProvisionError::Yaml(e)
+    }
+}
+
+impl From<roxmltree::Error> for ProvisionError {
+    fn from(e: roxmltree::Error) -> Self {
+        ProvisionError::Xml(e)
+    }
+}
+
+impl From<PatchError> for ProvisionError {
+    fn from(e: PatchError) -> Self {
+        ProvisionError::Patch(e)
+    }
+}
+
+/// Check the payload at `source` and work out where it goes on media
+/// written in `mode` from the ISO at `iso`. Only call this for combinations
+/// that [`fits`].
+pub fn plan(kind: PayloadKind, source: &Path, iso: &Path, mode: WriteMode) -> Result<ProvisionPlan, ProvisionError> {
+    let size = fs::metadata(source)?.len();
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(ProvisionError::TooLarge(size));
+    }
+    let text = String::from_utf8(fs::read(source)?).map_err(|_| ProvisionError::Invalid("not a UTF-8 text file".to_string()))?;
+
+    let raw = mode == WriteMode::Raw;
+    let (seed_label, files, parameter) = match kind {
+        PayloadKind::Autoinstall => (Some(CIDATA_LABEL), nocloud_seed(autoinstall_user_data(&text)?), None),
+        PayloadKind::NoCloud => (Some(CIDATA_LABEL), nocloud_seed(cloud_config_user_data(&text)?), None),
+        // Anaconda loads ks.cfg from an OEMDRV volume without being asked
+        PayloadKind::Kickstart if raw => (Some(OEMDRV_LABEL), vec![file("/ks.cfg", checked_kickstart(text)?)], None),
+        PayloadKind::Kickstart => (None, vec![file("/ks.cfg", checked_kickstart(text)?)], Some("inst.ks=hd:LABEL={label}:/ks.cfg")),
+        PayloadKind::Preseed => (
+            None,
+            vec![file("/preseed.cfg", checked_preseed(text)?)],
+            Some("auto=true priority=critical preseed/file=/cdrom/preseed.cfg"),
+        ),
+        PayloadKind::Autounattend => (None, vec![file("/autounattend.xml", checked_autounattend(text)?)], None),
+    };
+
+    // subiquity asks before wiping disks unless the command line says autoinstall
+    let configs = match kind {
+        PayloadKind::Autoinstall => bootpatch::plan(iso, CASPER_MARKERS, "autoinstall")?,
+        _ => Vec::new(),
+    };
+    Ok(ProvisionPlan { kind, seed_label, files, configs, parameter })
+}
+
+/// The FAT32 seed partition size for disks with `sector_size` sectors.
+pub fn seed_size(sector_size: u64) -> u64 {
+    SEED_SIZE / 512 * sector_size.max(512)
+}
+
+fn file(path: &str, text: String) -> PayloadFile {
+    PayloadFile { path: path.to_string(), size: text.len() as u64, data: text.into_bytes() }
+}
+
+// user-data and a meta-data that gives the seed an instance of its own.
+fn nocloud_seed(user_data: String) -> Vec<PayloadFile> {
+    let meta_data = format!("instance-id: iid-webbboot-{:016x}\n", rand::random::<u64>());
+    vec![file("/user-data", user_data), file("/meta-data", meta_data)]
+}
+
+// An autoinstall config as cloud-config user-data. A bare config, as
+// subiquity reads it from autoinstall.yaml, is nested under `autoinstall:`.
+fn autoinstall_user_data(text: &str) -> Result<String, ProvisionError> {
+    let document: serde_yaml::Value = serde_yaml::from_str(text)?;
+    let mapping = document
+        .as_mapping()
+        .ok_or_else(|| ProvisionError::Invalid("an autoinstall config must be a YAML mapping".to_string()))?;
+    let body = if let Some(autoinstall) = mapping.get("autoinstall") {
+        if !autoinstall.is_mapping() {
+            return Err(ProvisionError::Invalid("`autoinstall` must be a mapping".to_string()));
+        }
+        text.to_string()
+    } else if mapping.contains_key("version") {
+        let nested: String = text.lines().filter(|line| line.trim() != "---").map(|line| format!("  {}\n", line)).collect();
+        format!("autoinstall:\n{}", nested)
+    } else {
+        return Err(ProvisionError::Invalid("no `autoinstall` section and no `version` key".to_string()));
+    };
+    Ok(match body.starts_with("#cloud-config") {
+        true => body,
+        false => format!("#cloud-config\n{}", body),
+    })
+}
+
+// cloud-init takes a #cloud-config mapping or a script as user-data.
+fn cloud_config_user_data(text: &str) -> Result<String, ProvisionError> {
+    if text.starts_with("#!") {
+        return Ok(text.to_string());
+    }
+    if !text.starts_with("#cloud-config") {
+        return Err(ProvisionError::Invalid("user-data must start with #cloud-config or #!".to_string()));
+    }
+    let document: serde_yaml::Value = serde_yaml::from_str(text)?;
+    if !document.is_mapping() && !document.is_null() {
+        return Err(ProvisionError::Invalid("cloud-config must be a YAML mapping".to_string()));
+    }
+    Ok(text.to_string())
+}
+
+fn checked_kickstart(text: String) -> Result<String, ProvisionError> {
+    let has_command = text.lines().map(str::trim).any(|line| !line.is_empty() && !line.starts_with('#'));
+    if !has_command {
+        return Err(ProvisionError::Invalid("the Kickstart file has no commands".to_string()));
+    }
+    Ok(text)
+}
+
+// Every preseed line names an owner, a question and a type before its value;
+// a trailing backslash continues a line.
+fn checked_preseed(text: String) -> Result<String, ProvisionError> {
+    let mut pending = String::new();
+    for (index, line) in text.lines().enumerate() {
+        if let Some(continued) = line.strip_suffix('\\') {
+            pending.push_str(continued);
+            continue;
+        }
+        pending.push_str(line);
+        let entry = std::mem::take(&mut pending);
+        let entry = entry.trim();
+        if !entry.is_empty() && !entry.starts_with('#') && entry.split_whitespace().count() < 3 {
+            return Err(ProvisionError::Invalid(format!("line {} is not `owner question type value`", index + 1)));
+        }
+    }
+    Ok(text)
+}
+
+fn checked_autounattend(text: String) -> Result<String, ProvisionError> {
+    let document = roxmltree::Document::parse(&text)?;
+    let root = document.root_element().tag_name().name();
+    if root != "unattend" {
+        return Err(ProvisionError::Invalid(format!("the root element is <{}>, not <unattend>", root)));
+    }
+    Ok(text)
+}
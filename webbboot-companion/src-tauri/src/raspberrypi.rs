@@ -0,0 +1,376 @@
+// First-boot customization of Raspberry Pi OS images.
+//
+// Raspberry Pi OS boots from a FAT32 partition listed first in the image's
+// MBR, and runs a script from it on first boot when cmdline.txt names one
+// through systemd.run. The same firstrun.sh Raspberry Pi Imager writes sets
+// the hostname, user, SSH, Wi-Fi and locale: through raspberrypi-sys-mods'
+// imager_custom where the image has it, by hand otherwise. The script removes
+// itself and its cmdline.txt entry once it has run. Everything is written
+// through the in-process FAT32 writer, so nothing is mounted.
+
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::Path;
+
+use crate::decompress::{DecompressError, ImageSource};
+use crate::fatfs::{Volume, VolumeError};
+use crate::partition::{self, PartitionScheme, PlacedPartition};
+
+const FIRSTRUN: &str = "firstrun.sh";
+const CMDLINE: &str = "cmdline.txt";
+
+/// Only images for the Pi 5 and later boot it, and those mount the boot
+/// partition at /boot/firmware rather than /boot.
+const FIRMWARE_MARKER: &str = "kernel_2712.img";
+
+const IMAGER_CUSTOM: &str = "/usr/lib/raspberrypi-sys-mods/imager_custom";
+const USERCONF: &str = "/usr/lib/userconf-pi/userconf";
+
+/// MBR partition types of FAT volumes.
+const FAT_TYPES: &[u8] = &[0x0b, 0x0c, 0x0e];
+
+/// What to set up on first boot. Absent settings keep the image's defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Customization {
+    pub hostname: Option<String>,
+    /// Name of the first user, which replaces the image's default one.
+    pub user: Option<String>,
+    /// crypt(3) hash of the user's password, as `openssl passwd -6` prints it.
+    pub password_hash: Option<String>,
+    /// Turn on the SSH server; implied by `authorized_keys`.
+    pub ssh: Option<bool>,
+    /// Public keys allowed to log in as the first user. With keys, SSH
+    /// password logins are turned off.
+    pub authorized_keys: Option<Vec<String>>,
+    pub wifi_ssid: Option<String>,
+    /// WPA passphrase, or the 64-digit hex PSK derived from it.
+    pub wifi_password: Option<String>,
+    /// Two-letter regulatory domain the Wi-Fi radio runs under.
+    pub wifi_country: Option<String>,
+    pub wifi_hidden: Option<bool>,
+    /// Keyboard layout, such as "gb" or "us".
+    pub keymap: Option<String>,
+    /// Time zone, such as "Europe/London".
+    pub timezone: Option<String>,
+    /// System locale, such as "en_GB.UTF-8".
+    pub locale: Option<String>,
+}
+
+impl Customization {
+    /// Check every setting, so a bad one is caught before the device is
+    /// touched rather than on the Pi.
+    pub fn check(&self) -> Result<(), String> {
+        let settings = [&self.hostname, &self.user, &self.wifi_ssid, &self.keymap, &self.timezone, &self.locale];
+        if settings.iter().all(|setting| setting.is_none()) && !self.ssh_enabled() {
+            return Err("The customization sets nothing".to_string());
+        }
+
+        if let Some(hostname) = &self.hostname {
+            let valid = (1..=63).contains(&hostname.len())
+                && hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !hostname.starts_with('-')
+                && !hostname.ends_with('-');
+            if !valid {
+                return Err(format!("Invalid hostname: {}", hostname));
+            }
+        }
+
+        match (&self.user, &self.password_hash) {
+            (Some(user), Some(hash)) => {
+                let valid = (1..=32).contains(&user.len())
+                    && user.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                    && user.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+                    && user != "root";
+                if !valid {
+                    return Err(format!("Invalid user name: {}", user));
+                }
+                // $id$salt$hash, with nothing a shell or chpasswd would split on
+                let crypt = hash.starts_with('$') && hash.matches('$').count() >= 3;
+                if !crypt || hash.contains(|c: char| c.is_whitespace() || c == '\'' || c == ':') {
+                    return Err("The password hash is not a crypt(3) hash; make one with openssl passwd -6".to_string());
+                }
+            }
+            (Some(_), None) => return Err("A user needs a password hash".to_string()),
+            (None, Some(_)) => return Err("A password hash needs a user name".to_string()),
+            (None, None) => {}
+        }
+
+        for key in self.authorized_keys.iter().flatten() {
+            let known = ["ssh-", "ecdsa-", "sk-"].iter().any(|prefix| key.starts_with(prefix));
+            if !known || key.contains(['\n', '\r']) {
+                return Err(format!("Not an SSH public key: {}", key));
+            }
+        }
+
+        match (&self.wifi_ssid, &self.wifi_password, &self.wifi_country) {
+            (Some(ssid), password, Some(country)) => {
+                if !(1..=32).contains(&ssid.len()) {
+                    return Err("A Wi-Fi SSID is 1 to 32 bytes".to_string());
+                }
+                if let Some(password) = password {
+                    let psk = password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit());
+                    let passphrase = (8..=63).contains(&password.len()) && password.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+                    if !psk && !passphrase {
+                        return Err("A Wi-Fi passphrase is 8 to 63 printable ASCII characters".to_string());
+                    }
+                }
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+                    return Err(format!("Invalid Wi-Fi country code: {}", country));
+                }
+            }
+            (Some(_), _, None) => return Err("Wi-Fi needs a country code".to_string()),
+            (None, Some(_), _) | (None, _, Some(_)) => return Err("Wi-Fi settings need an SSID".to_string()),
+            (None, None, None) => {}
+        }
+
+        check_word("keyboard layout", self.keymap.as_deref(), |c| c.is_ascii_alphanumeric() || c == '_' || c == '-')?;
+        check_word("time zone", self.timezone.as_deref(), |c| c.is_ascii_alphanumeric() || "_+-/".contains(c))?;
+        check_word("locale", self.locale.as_deref(), |c| c.is_ascii_alphanumeric() || "_.-@".contains(c))?;
+        if self.timezone.as_deref().is_some_and(|timezone| timezone.contains("..")) {
+            return Err("Invalid time zone".to_string());
+        }
+        Ok(())
+    }
+
+    fn ssh_enabled(&self) -> bool {
+        self.ssh.unwrap_or(false) || self.authorized_keys.as_ref().is_some_and(|keys| !keys.is_empty())
+    }
+}
+
+fn check_word(what: &str, value: Option<&str>, allowed: impl Fn(char) -> bool) -> Result<(), String> {
+    match value {
+        Some(value) if value.is_empty() || !value.chars().all(allowed) => Err(format!("Invalid {}: {}", what, value)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+pub enum CustomizeError {
+    Io(io::Error),
+    Image(DecompressError),
+    Volume(VolumeError),
+    /// The disk has no MBR with a FAT partition holding cmdline.txt.
+    NotRaspberryPi,
+}
+
+impl fmt::Display for CustomizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomizeError::Io(e) => write!(f, "{}", e),
+            CustomizeError::Image(e) => write!(f, "{}", e),
+            CustomizeError::Volume(e) => write!(f, "{}", e),
+            CustomizeError::NotRaspberryPi => write!(f, "no Raspberry Pi boot partition found"),
+        }
+    }
+}
+
+impl std::error::Error for CustomizeError {}
+
+impl From<io::Error> for CustomizeError {
+    fn from(e: io::Error) -> Self {
+        CustomizeError::Io(e)
+    }
+}
+
+impl From<DecompressError> for CustomizeError {
+    fn from(e: DecompressError) -> Self {
+        CustomizeError::Image(e)
+    }
+}
+
+impl From<VolumeError> for CustomizeError {
+    fn from(e: VolumeError) -> Self {
+        CustomizeError::Volume(e)
+    }
+}
+
+/// The boot partition in the MBR at the start of `disk`: the first FAT one.
+pub fn boot_partition<D: Read + Seek>(disk: &mut D, sector_size: u64) -> Result<PlacedPartition, CustomizeError> {
+    match partition::read_table(disk, sector_size) {
+        Ok((PartitionScheme::Mbr, partitions)) => {
+            partitions.into_iter().find(|partition| FAT_TYPES.contains(&partition.kind.mbr)).ok_or(CustomizeError::NotRaspberryPi)
+        }
+        _ => Err(CustomizeError::NotRaspberryPi),
+    }
+}
+
+/// Check that the image at `path`, compressed or not, starts with an MBR
+/// listing a boot partition, without reading past its first sector.
+pub fn check_image(path: &Path) -> Result<(), CustomizeError> {
+    let mut mbr = vec![0u8; 512];
+    ImageSource::open(path)?.read_exact(&mut mbr)?;
+    boot_partition(&mut Cursor::new(mbr), 512).map(|_| ())
+}
+
+/// Write firstrun.sh onto the boot partition starting at `offset` in
+/// `disk` and point cmdline.txt at it. Returns the directory the running
+/// system finds the partition in.
+pub fn customize<D: Read + Write + Seek>(disk: D, offset: u64, customization: &Customization) -> Result<&'static str, CustomizeError> {
+    let mut volume = Volume::open(disk, offset)?;
+    let cmdline = match volume.read_file(CMDLINE) {
+        Ok(cmdline) => String::from_utf8_lossy(&cmdline).into_owned(),
+        Err(VolumeError::NotFound(_)) => return Err(CustomizeError::NotRaspberryPi),
+        Err(e) => return Err(e.into()),
+    };
+    let boot_dir = match volume.list_dir("/")?.iter().any(|entry| entry.name.eq_ignore_ascii_case(FIRMWARE_MARKER)) {
+        true => "/boot/firmware",
+        false => "/boot",
+    };
+
+    let script = firstrun_script(customization, boot_dir);
+    volume.write_file(FIRSTRUN, &mut script.as_bytes(), script.len() as u64)?;
+    let cmdline = patch_cmdline(&cmdline, boot_dir);
+    volume.write_file(CMDLINE, &mut cmdline.as_bytes(), cmdline.len() as u64)?;
+    volume.flush()?;
+    Ok(boot_dir)
+}
+
+// cmdline.txt is one line; any earlier systemd.run entries are replaced.
+fn patch_cmdline(cmdline: &str, boot_dir: &str) -> String {
+    let kept: Vec<&str> = cmdline.split_whitespace().filter(|arg| !arg.starts_with("systemd.run") && *arg != "systemd.unit=kernel-command-line.target").collect();
+    format!(
+        "{} systemd.run={}/{} systemd.run_success_action=reboot systemd.unit=kernel-command-line.target\n",
+        kept.join(" "),
+        boot_dir,
+        FIRSTRUN
+    )
+}
+
+/// The first-boot script applying `customization`, for a system that mounts
+/// the boot partition at `boot_dir`.
+pub fn firstrun_script(customization: &Customization, boot_dir: &str) -> String {
+    let mut script = String::from("#!/bin/bash\n\nset +e\n\n");
+
+    if let Some(hostname) = &customization.hostname {
+        script.push_str(&format!(
+            "CURRENT_HOSTNAME=$(tr -d \" \\t\\n\\r\" </etc/hostname)\n\
+             if [ -f {custom} ]; then\n   {custom} set_hostname {name}\n\
+             else\n   echo {name} >/etc/hostname\n   sed -i \"s/127.0.1.1.*$CURRENT_HOSTNAME/127.0.1.1\\t\"{name}\"/g\" /etc/hosts\nfi\n",
+            custom = IMAGER_CUSTOM,
+            name = quote(hostname)
+        ));
+    }
+
+    script.push_str("FIRSTUSER=$(getent passwd 1000 | cut -d: -f1)\nFIRSTUSERHOME=$(getent passwd 1000 | cut -d: -f6)\n");
+    if customization.ssh_enabled() {
+        let keys = customization.authorized_keys.clone().unwrap_or_default();
+        if keys.is_empty() {
+            script.push_str(&format!(
+                "if [ -f {custom} ]; then\n   {custom} enable_ssh\nelse\n   systemctl enable ssh\nfi\n",
+                custom = IMAGER_CUSTOM
+            ));
+        } else {
+            let quoted: Vec<String> = keys.iter().map(|key| quote(key)).collect();
+            script.push_str(&format!(
+                "if [ -f {custom} ]; then\n   {custom} enable_ssh -k {keys}\n\
+                 else\n   install -o \"$FIRSTUSER\" -m 700 -d \"$FIRSTUSERHOME/.ssh\"\n\
+                 \x20  printf '%s\\n' {keys} >\"$FIRSTUSERHOME/.ssh/authorized_keys\"\n\
+                 \x20  chown \"$FIRSTUSER:$FIRSTUSER\" \"$FIRSTUSERHOME/.ssh/authorized_keys\"\n\
+                 \x20  chmod 600 \"$FIRSTUSERHOME/.ssh/authorized_keys\"\n\
+                 \x20  echo 'PasswordAuthentication no' >>/etc/ssh/sshd_config\n\
+                 \x20  systemctl enable ssh\nfi\n",
+                custom = IMAGER_CUSTOM,
+                keys = quoted.join(" ")
+            ));
+        }
+    }
+
+    if let (Some(user), Some(hash)) = (&customization.user, &customization.password_hash) {
+        script.push_str(&format!(
+            "if [ -f {userconf} ]; then\n   {userconf} {user} {hash}\n\
+             else\n   echo \"$FIRSTUSER:\"{hash} | chpasswd -e\n   if [ \"$FIRSTUSER\" != {user} ]; then\n\
+             \x20     usermod -l {user} \"$FIRSTUSER\"\n      usermod -m -d /home/{name} {user}\n      groupmod -n {user} \"$FIRSTUSER\"\n\
+             \x20     if grep -q \"^autologin-user=\" /etc/lightdm/lightdm.conf; then\n\
+             \x20        sed /etc/lightdm/lightdm.conf -i -e \"s/^autologin-user=.*/autologin-user={name}/\"\n      fi\n\
+             \x20     if [ -f /etc/systemd/system/getty@tty1.service.d/autologin.conf ]; then\n\
+             \x20        sed /etc/systemd/system/getty@tty1.service.d/autologin.conf -i -e \"s/$FIRSTUSER/{name}/\"\n      fi\n\
+             \x20     if [ -f /etc/sudoers.d/010_pi-nopasswd ]; then\n\
+             \x20        sed -i \"s/^$FIRSTUSER /{name} /\" /etc/sudoers.d/010_pi-nopasswd\n      fi\n   fi\nfi\n",
+            userconf = USERCONF,
+            user = quote(user),
+            name = user,
+            hash = quote(hash)
+        ));
+    }
+
+    if let (Some(ssid), Some(country)) = (&customization.wifi_ssid, &customization.wifi_country) {
+        let hidden = customization.wifi_hidden.unwrap_or(false);
+        let psk = customization.wifi_password.as_deref().map(|password| wifi_psk(ssid, password));
+        let ssid_hex: String = ssid.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        let (custom_args, network) = match &psk {
+            Some(psk) => (format!("{} {}", quote(ssid), quote(psk)), format!("\tssid={}\n\tpsk={}\n", ssid_hex, psk)),
+            None => (format!("{} ''", quote(ssid)), format!("\tssid={}\n\tkey_mgmt=NONE\n", ssid_hex)),
+        };
+        let scan = if hidden { "\tscan_ssid=1\n" } else { "" };
+        script.push_str(&format!(
+            "if [ -f {custom} ]; then\n   {custom} set_wlan{flag} {args} {country}\n\
+             else\n   cat >/etc/wpa_supplicant/wpa_supplicant.conf <<'WPAEOF'\n\
+             country={country}\nctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\nap_scan=1\nupdate_config=1\n\
+             network={{\n{scan}{network}}}\nWPAEOF\n\
+             \x20  chmod 600 /etc/wpa_supplicant/wpa_supplicant.conf\n   rfkill unblock wifi\n\
+             \x20  for filename in /var/lib/systemd/rfkill/*:wlan; do\n      echo 0 >\"$filename\"\n   done\nfi\n",
+            custom = IMAGER_CUSTOM,
+            flag = if hidden { " -h" } else { "" },
+            args = custom_args,
+            country = country,
+            scan = scan,
+            network = network
+        ));
+    }
+
+    if let Some(keymap) = &customization.keymap {
+        script.push_str(&format!(
+            "if [ -f {custom} ]; then\n   {custom} set_keymap {keymap}\n\
+             else\n   cat >/etc/default/keyboard <<'KBEOF'\nXKBMODEL=\"pc105\"\nXKBLAYOUT=\"{layout}\"\nXKBVARIANT=\"\"\nXKBOPTIONS=\"\"\nKBEOF\n\
+             \x20  dpkg-reconfigure -f noninteractive keyboard-configuration\nfi\n",
+            custom = IMAGER_CUSTOM,
+            keymap = quote(keymap),
+            layout = keymap
+        ));
+    }
+
+    if let Some(timezone) = &customization.timezone {
+        script.push_str(&format!(
+            "if [ -f {custom} ]; then\n   {custom} set_timezone {timezone}\n\
+             else\n   rm -f /etc/localtime\n   echo {timezone} >/etc/timezone\n   dpkg-reconfigure -f noninteractive tzdata\nfi\n",
+            custom = IMAGER_CUSTOM,
+            timezone = quote(timezone)
+        ));
+    }
+
+    if let Some(locale) = &customization.locale {
+        let charset = locale.split_once('.').map(|(_, charset)| charset.to_uppercase().replace("UTF8", "UTF-8")).unwrap_or_else(|| "ISO-8859-1".to_string());
+        script.push_str(&format!(
+            "grep -q \"^{locale} \" /etc/locale.gen || echo \"{locale} {charset}\" >>/etc/locale.gen\n\
+             locale-gen\nupdate-locale LANG={locale}\n",
+            locale = locale,
+            charset = charset
+        ));
+    }
+
+    script.push_str(&format!(
+        "rm -f {boot}/{firstrun}\nsed -i 's| systemd.run.*||g' {boot}/{cmdline}\nexit 0\n",
+        boot = boot_dir,
+        firstrun = FIRSTRUN,
+        cmdline = CMDLINE
+    ));
+    script
+}
+
+// The WPA PSK for a passphrase, so the passphrase itself is not left on the
+// boot partition; a PSK given as hex is used as it is.
+fn wifi_psk(ssid: &str, password: &str) -> String {
+    if password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit()) {
+        return password.to_lowercase();
+    }
+    let mut psk = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), ssid.as_bytes(), 4096, &mut psk);
+    psk.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `text` as one single-quoted shell word.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
@@ -0,0 +1,245 @@
+// Resolve USB mass-storage devices to their block devices through sysfs.
+//
+// A USB device lives at /sys/bus/usb/devices/<bus>-<port>[.<port>...]. Its
+// mass-storage interface exposes a SCSI host, and every LUN behind that host
+// carries a `block/sdX` directory. Walking that chain is the only reliable way
+// to tie a USB descriptor to the /dev node it backs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedBlockDevice {
+    /// Kernel name, e.g. `sdb`.
+    pub name: String,
+    /// Device node, e.g. `/dev/sdb`.
+    pub dev_path: String,
+    /// Partition device nodes, e.g. `/dev/sdb1`, in kernel order.
+    pub partitions: Vec<String>,
+    /// Canonical sysfs directory of the block device.
+    pub sysfs_path: String,
+    /// Size in bytes, from the 512-byte sector count in sysfs.
+    pub size: Option<u64>,
+}
+
+pub struct SysfsResolver {
+    root: PathBuf,
+}
+
+impl Default for SysfsResolver {
+    fn default() -> Self {
+        Self::new(DEFAULT_SYSFS_ROOT)
+    }
+}
+
+impl SysfsResolver {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        SysfsResolver { root: root.into() }
+    }
+
+    /// Find every block device exposed by the USB device at `bus`/`port_path`.
+    ///
+    /// When `serial` is given and sysfs reports one too, both must match, so a
+    /// device that was swapped on the same port between enumeration and
+    /// resolution is not picked up. Card readers with several slots return one
+    /// entry per LUN.
+    pub fn resolve(&self, bus: u8, port_path: &[u8], serial: Option<&str>) -> Vec<ResolvedBlockDevice> {
+        if port_path.is_empty() {
+            return vec![];
+        }
+
+        let usb_name = usb_device_name(bus, port_path);
+        let usb_dir = self.root.join("bus/usb/devices").join(&usb_name);
+        if !usb_dir.is_dir() {
+            return vec![];
+        }
+
+        if !self.matches_identity(&usb_dir, bus, port_path, serial) {
+            return vec![];
+        }
+
+        let mut devices = Vec::new();
+        for interface in child_dirs_with_prefix(&usb_dir, &format!("{}:", usb_name)) {
+            for host in child_dirs_with_prefix(&interface, "host") {
+                for target in child_dirs_with_prefix(&host, "target") {
+                    for lun in child_dirs(&target) {
+                        for block in child_dirs(&lun.join("block")) {
+                            if let Some(device) = self.describe_block(&block) {
+                                devices.push(device);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    fn matches_identity(&self, usb_dir: &Path, bus: u8, port_path: &[u8], serial: Option<&str>) -> bool {
+        if let Some(busnum) = read_trimmed(&usb_dir.join("busnum")) {
+            if busnum.parse::<u8>().ok() != Some(bus) {
+                return false;
+            }
+        }
+
+        if let Some(devpath) = read_trimmed(&usb_dir.join("devpath")) {
+            if devpath != port_path_string(port_path) {
+                return false;
+            }
+        }
+
+        match (serial, read_trimmed(&usb_dir.join("serial"))) {
+            (Some(expected), Some(actual)) => expected.trim() == actual,
+            _ => true,
+        }
+    }
+
+    fn describe_block(&self, block_dir: &Path) -> Option<ResolvedBlockDevice> {
+        let name = block_dir.file_name()?.to_str()?.to_string();
+        let sysfs_path = fs::canonicalize(block_dir).unwrap_or_else(|_| block_dir.to_path_buf());
+
+        let size = read_trimmed(&block_dir.join("size"))
+            .and_then(|sectors| sectors.parse::<u64>().ok())
+            .map(|sectors| sectors * 512);
+
+        let mut partitions: Vec<(u32, String)> = child_dirs_with_prefix(block_dir, &name)
+            .into_iter()
+            .filter_map(|dir| {
+                let part_name = dir.file_name()?.to_str()?.to_string();
+                let number = read_trimmed(&dir.join("partition"))?.parse::<u32>().ok()?;
+                Some((number, format!("/dev/{}", part_name)))
+            })
+            .collect();
+        partitions.sort();
+
+        Some(ResolvedBlockDevice {
+            dev_path: format!("/dev/{}", name),
+            name,
+            partitions: partitions.into_iter().map(|(_, path)| path).collect(),
+            sysfs_path: sysfs_path.to_string_lossy().into_owned(),
+            size,
+        })
+    }
+}
+
+fn usb_device_name(bus: u8, port_path: &[u8]) -> String {
+    format!("{}-{}", bus, port_path_string(port_path))
+}
+
+fn port_path_string(port_path: &[u8]) -> String {
+    port_path
+        .iter()
+        .map(|port| port.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn child_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(_) => vec![],
+    };
+    dirs.sort();
+    dirs
+}
+
+fn child_dirs_with_prefix(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    child_dirs(dir)
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(prefix))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Add a USB stick at `usb_name` with one LUN backing `disk` and its
+    /// `partitions` to the fake tree under `root`.
+    fn add_stick(root: &Path, usb_name: &str, serial: Option<&str>, disk: &str, partitions: &[u32]) {
+        let (bus, devpath) = usb_name.split_once('-').unwrap();
+        let usb_dir = root.join("bus/usb/devices").join(usb_name);
+        fs::create_dir_all(&usb_dir).unwrap();
+        fs::write(usb_dir.join("busnum"), format!("{}\n", bus)).unwrap();
+        fs::write(usb_dir.join("devpath"), format!("{}\n", devpath)).unwrap();
+        if let Some(serial) = serial {
+            fs::write(usb_dir.join("serial"), format!("{}\n", serial)).unwrap();
+        }
+
+        let block_dir = usb_dir.join(format!("{}:1.0/host6/target6:0:0/6:0:0:0/block/{}", usb_name, disk));
+        fs::create_dir_all(&block_dir).unwrap();
+        fs::write(block_dir.join("size"), "15633408\n").unwrap();
+        for number in partitions {
+            let partition_dir = block_dir.join(format!("{}{}", disk, number));
+            fs::create_dir_all(&partition_dir).unwrap();
+            fs::write(partition_dir.join("partition"), format!("{}\n", number)).unwrap();
+        }
+    }
+
+    #[test]
+    fn resolves_stick_by_bus_and_port() {
+        let root = tempfile::tempdir().unwrap();
+        add_stick(root.path(), "1-2", None, "sdb", &[2, 1]);
+
+        let devices = SysfsResolver::new(root.path()).resolve(1, &[2], None);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "sdb");
+        assert_eq!(devices[0].dev_path, "/dev/sdb");
+        assert_eq!(devices[0].partitions, vec!["/dev/sdb1", "/dev/sdb2"]);
+        assert_eq!(devices[0].size, Some(15633408 * 512));
+
+        assert!(SysfsResolver::new(root.path()).resolve(2, &[2], None).is_empty());
+        assert!(SysfsResolver::new(root.path()).resolve(1, &[3], None).is_empty());
+        assert!(SysfsResolver::new(root.path()).resolve(1, &[], None).is_empty());
+    }
+
+    #[test]
+    fn serial_must_match_when_both_are_known() {
+        let root = tempfile::tempdir().unwrap();
+        add_stick(root.path(), "1-2", Some("4C530001"), "sdb", &[]);
+        let resolver = SysfsResolver::new(root.path());
+
+        assert_eq!(resolver.resolve(1, &[2], Some("4C530001")).len(), 1);
+        assert_eq!(resolver.resolve(1, &[2], Some(" 4C530001 ")).len(), 1);
+        assert!(resolver.resolve(1, &[2], Some("4C530002")).is_empty());
+        assert_eq!(resolver.resolve(1, &[2], None).len(), 1);
+
+        add_stick(root.path(), "1-3", None, "sdc", &[]);
+        assert_eq!(resolver.resolve(1, &[3], Some("anything")).len(), 1);
+    }
+
+    #[test]
+    fn sticks_never_share_a_disk() {
+        let root = tempfile::tempdir().unwrap();
+        add_stick(root.path(), "1-2", Some("A"), "sdb", &[1]);
+        add_stick(root.path(), "1-3", Some("B"), "sdc", &[1]);
+        // Behind a hub on port 2, so its directory name starts with "1-2"
+        add_stick(root.path(), "1-2.4", Some("C"), "sdd", &[1]);
+        let resolver = SysfsResolver::new(root.path());
+
+        let first = resolver.resolve(1, &[2], Some("A"));
+        let second = resolver.resolve(1, &[3], Some("B"));
+        let behind_hub = resolver.resolve(1, &[2, 4], Some("C"));
+        assert_eq!(first.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), vec!["sdb"]);
+        assert_eq!(second.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), vec!["sdc"]);
+        assert_eq!(behind_hub.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), vec!["sdd"]);
+        assert_eq!(behind_hub[0].partitions, vec!["/dev/sdd1"]);
+    }
+}
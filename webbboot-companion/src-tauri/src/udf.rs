@@ -0,0 +1,564 @@
+// UDF image reader.
+//
+// Windows installer ISOs are UDF-primary: the ISO9660 side holds only a
+// README, and the real tree (with install.wim often past 4 GiB) is reachable
+// through UDF alone. This follows the anchor to the main volume descriptor
+// sequence, resolves the file set through type 1 partition maps, and walks
+// file entries into the same extent lists the ISO9660 reader produces.
+
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::iso9660::{Extent, IsoError, IsoFile, SECTOR_SIZE};
+
+/// The anchor volume descriptor pointer lives at sector 256 on optical media.
+const ANCHOR_SECTOR: u64 = 256;
+/// Sectors of the main volume descriptor sequence read before giving up.
+const MAX_SEQUENCE: u64 = 256;
+/// Allocation extent chains followed per file before giving up.
+const MAX_CONTINUATIONS: usize = 1024;
+const MAX_DEPTH: usize = 64;
+
+const TAG_PRIMARY_VOLUME: u16 = 1;
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+
+const AD_SHORT: u16 = 0;
+const AD_LONG: u16 = 1;
+const AD_EMBEDDED: u16 = 3;
+
+const EXTENT_RECORDED: u32 = 0;
+const EXTENT_NEXT: u32 = 3;
+
+const FID_DELETED: u8 = 0x04;
+const FID_PARENT: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct LongAd {
+    block: u32,
+    partition: u16,
+}
+
+impl LongAd {
+    fn parse(bytes: &[u8]) -> Self {
+        LongAd { block: le32(&bytes[4..8]), partition: le16(&bytes[8..10]) }
+    }
+}
+
+pub struct UdfVolume {
+    pub label: String,
+    block_size: u64,
+    /// Start block of each partition, indexed by partition reference number.
+    /// Metadata, virtual and sparable maps are `None`.
+    partitions: Vec<Option<u64>>,
+    root: LongAd,
+}
+
+struct Node {
+    is_dir: bool,
+    size: u64,
+    extents: Vec<Extent>,
+}
+
+impl UdfVolume {
+    /// Find the UDF file set in `image`. Fails with `NotIso` when there is no
+    /// anchor.
+    pub fn open<R: Read + Seek>(image: &mut R) -> Result<Self, IsoError> {
+        let mut anchor = vec![0u8; SECTOR_SIZE as usize];
+        image.seek(SeekFrom::Start(ANCHOR_SECTOR * SECTOR_SIZE))?;
+        if image.read_exact(&mut anchor).is_err() || tag_id(&anchor) != Some(TAG_ANCHOR) {
+            return Err(IsoError::NotIso);
+        }
+        let sequence_len = le32(&anchor[16..20]) as u64;
+        let sequence_start = le32(&anchor[20..24]) as u64;
+
+        let mut volume_label = None;
+        let mut logical_label = None;
+        let mut partition_starts = Vec::new();
+        let mut logical = None;
+
+        let mut sector = vec![0u8; SECTOR_SIZE as usize];
+        for index in 0..sequence_len.div_ceil(SECTOR_SIZE).min(MAX_SEQUENCE) {
+            image.seek(SeekFrom::Start((sequence_start + index) * SECTOR_SIZE))?;
+            image.read_exact(&mut sector)?;
+            match tag_id(&sector) {
+                Some(TAG_PRIMARY_VOLUME) => volume_label = Some(dstring(&sector[24..56])),
+                Some(TAG_PARTITION) => partition_starts.push((le16(&sector[22..24]), le32(&sector[188..192]) as u64)),
+                Some(TAG_LOGICAL_VOLUME) => {
+                    logical_label = Some(dstring(&sector[84..212]));
+                    logical = Some(sector.clone());
+                }
+                Some(TAG_TERMINATING) | None => break,
+                Some(_) => {}
+            }
+        }
+
+        let Some(logical) = logical else {
+            return Err(IsoError::Corrupt("UDF volume has no logical volume descriptor".to_string()));
+        };
+        let block_size = le32(&logical[212..216]) as u64;
+        if block_size != SECTOR_SIZE {
+            return Err(IsoError::Unsupported(format!("UDF logical block size {}", block_size)));
+        }
+
+        // Partition maps start at byte 440; only type 1 maps point straight
+        // at a partition descriptor.
+        let map_count = le32(&logical[268..272]) as usize;
+        let mut partitions = Vec::with_capacity(map_count);
+        let mut pos = 440;
+        for _ in 0..map_count {
+            if pos + 2 > logical.len() {
+                break;
+            }
+            let (kind, len) = (logical[pos], logical[pos + 1] as usize);
+            if len < 2 || pos + len > logical.len() {
+                return Err(IsoError::Corrupt("bad UDF partition map".to_string()));
+            }
+            let start = match kind {
+                1 if len >= 6 => {
+                    let number = le16(&logical[pos + 4..pos + 6]);
+                    partition_starts.iter().find(|(n, _)| *n == number).map(|(_, start)| *start)
+                }
+                _ => None,
+            };
+            partitions.push(start);
+            pos += len;
+        }
+
+        let mut volume = UdfVolume {
+            label: volume_label.or(logical_label).unwrap_or_default(),
+            block_size,
+            partitions,
+            root: LongAd { block: 0, partition: 0 },
+        };
+
+        let file_set = volume.read_block(image, LongAd::parse(&logical[248..264]))?;
+        if tag_id(&file_set) != Some(TAG_FILE_SET) {
+            return Err(IsoError::Corrupt("UDF file set descriptor not found".to_string()));
+        }
+        volume.root = LongAd::parse(&file_set[400..416]);
+        Ok(volume)
+    }
+
+    /// List every file and directory in the UDF tree.
+    pub fn files<R: Read + Seek>(&self, image: &mut R) -> Result<Vec<IsoFile>, IsoError> {
+        let mut out = Vec::new();
+        let mut visited = HashSet::new();
+        self.walk(image, self.root, "", 0, &mut visited, &mut out)?;
+        Ok(out)
+    }
+
+    fn walk<R: Read + Seek>(
+        &self,
+        image: &mut R,
+        dir: LongAd,
+        prefix: &str,
+        depth: usize,
+        visited: &mut HashSet<LongAd>,
+        out: &mut Vec<IsoFile>,
+    ) -> Result<(), IsoError> {
+        if depth > MAX_DEPTH || !visited.insert(dir) {
+            return Err(IsoError::Corrupt(format!("UDF directory loop at block {}", dir.block)));
+        }
+
+        let node = self.read_node(image, dir)?;
+        let listing = IsoFile { path: prefix.to_string(), is_dir: true, size: node.size, extents: node.extents };
+        let mut data = Vec::with_capacity(node.size as usize);
+        listing.reader(image).read_to_end(&mut data)?;
+
+        let mut pos = 0;
+        while pos + 38 <= data.len() {
+            let fid = &data[pos..];
+            if tag_id(fid) != Some(TAG_FILE_IDENTIFIER) {
+                return Err(IsoError::Corrupt(format!("bad UDF file identifier in {}", display_dir(prefix))));
+            }
+            let characteristics = fid[18];
+            let name_len = fid[19] as usize;
+            let icb = LongAd::parse(&fid[20..36]);
+            let impl_len = le16(&fid[36..38]) as usize;
+            let len = (38 + impl_len + name_len + 3) & !3;
+            if 38 + impl_len + name_len > fid.len() {
+                return Err(IsoError::Corrupt(format!("bad UDF file identifier in {}", display_dir(prefix))));
+            }
+            let name = dchars(&fid[38 + impl_len..38 + impl_len + name_len]);
+            pos += len;
+
+            if characteristics & (FID_PARENT | FID_DELETED) != 0 {
+                continue;
+            }
+
+            let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            let child = self.read_node(image, icb)?;
+            if child.is_dir {
+                out.push(IsoFile { path: path.clone(), is_dir: true, size: 0, extents: Vec::new() });
+                self.walk(image, icb, &path, depth + 1, visited, out)?;
+            } else {
+                out.push(IsoFile { path, is_dir: false, size: child.size, extents: child.extents });
+            }
+        }
+        Ok(())
+    }
+
+    fn read_node<R: Read + Seek>(&self, image: &mut R, icb: LongAd) -> Result<Node, IsoError> {
+        let entry_offset = self.block_offset(icb)?;
+        let entry = self.read_block(image, icb)?;
+        let (lengths, base) = match tag_id(&entry) {
+            Some(TAG_FILE_ENTRY) => (168, 176),
+            Some(TAG_EXTENDED_FILE_ENTRY) => (208, 216),
+            _ => return Err(IsoError::Corrupt(format!("no UDF file entry at block {}", icb.block))),
+        };
+
+        let is_dir = entry[27] == FILE_TYPE_DIRECTORY;
+        let ad_type = le16(&entry[34..36]) & 0x07;
+        let size = le64(&entry[56..64]);
+        let ea_len = le32(&entry[lengths..lengths + 4]) as usize;
+        let ad_len = le32(&entry[lengths + 4..lengths + 8]) as usize;
+        let ad_start = base + ea_len;
+        if ad_start + ad_len > entry.len() {
+            return Err(IsoError::Corrupt(format!("bad UDF file entry at block {}", icb.block)));
+        }
+
+        let extents = match ad_type {
+            // Small files and directories live inside the entry itself
+            AD_EMBEDDED => vec![Extent { offset: Some(entry_offset + ad_start as u64), len: ad_len as u64 }],
+            AD_SHORT | AD_LONG => {
+                self.allocation(image, entry[ad_start..ad_start + ad_len].to_vec(), ad_type, icb.partition)?
+            }
+            _ => return Err(IsoError::Unsupported("UDF extended allocation descriptors".to_string())),
+        };
+        Ok(Node { is_dir, size, extents })
+    }
+
+    // Decode short or long allocation descriptors, following continuation
+    // extents into allocation extent descriptors.
+    fn allocation<R: Read + Seek>(
+        &self,
+        image: &mut R,
+        mut descriptors: Vec<u8>,
+        ad_type: u16,
+        partition: u16,
+    ) -> Result<Vec<Extent>, IsoError> {
+        let size = if ad_type == AD_SHORT { 8 } else { 16 };
+        let mut extents = Vec::new();
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut next = None;
+            for ad in descriptors.chunks_exact(size) {
+                let raw = le32(&ad[0..4]);
+                let (kind, len) = (raw >> 30, (raw & 0x3fff_ffff) as u64);
+                if len == 0 {
+                    break;
+                }
+                let location = if ad_type == AD_SHORT {
+                    LongAd { block: le32(&ad[4..8]), partition }
+                } else {
+                    LongAd::parse(ad)
+                };
+                match kind {
+                    EXTENT_NEXT => {
+                        next = Some(location);
+                        break;
+                    }
+                    EXTENT_RECORDED => extents.push(Extent { offset: Some(self.block_offset(location)?), len }),
+                    // Allocated-but-unrecorded and unallocated extents read as zeros
+                    _ => extents.push(Extent { offset: None, len }),
+                }
+            }
+
+            let Some(next) = next else {
+                return Ok(extents);
+            };
+            let block = self.read_block(image, next)?;
+            if tag_id(&block) != Some(TAG_ALLOCATION_EXTENT) {
+                return Err(IsoError::Corrupt(format!("bad UDF allocation extent at block {}", next.block)));
+            }
+            let len = (le32(&block[20..24]) as usize).min(block.len() - 24);
+            descriptors = block[24..24 + len].to_vec();
+        }
+        Err(IsoError::Corrupt("UDF allocation extent chain too long".to_string()))
+    }
+
+    fn block_offset(&self, address: LongAd) -> Result<u64, IsoError> {
+        match self.partitions.get(address.partition as usize) {
+            Some(Some(start)) => Ok((start + address.block as u64) * self.block_size),
+            Some(None) => Err(IsoError::Unsupported("UDF metadata, virtual or sparable partitions".to_string())),
+            None => Err(IsoError::Corrupt(format!("UDF partition reference {} out of range", address.partition))),
+        }
+    }
+
+    fn read_block<R: Read + Seek>(&self, image: &mut R, address: LongAd) -> Result<Vec<u8>, IsoError> {
+        let mut block = vec![0u8; self.block_size as usize];
+        image.seek(SeekFrom::Start(self.block_offset(address)?))?;
+        image.read_exact(&mut block)?;
+        Ok(block)
+    }
+}
+
+// The descriptor tag's identifier, if its checksum (the byte sum of the
+// other fifteen tag bytes) holds.
+fn tag_id(descriptor: &[u8]) -> Option<u16> {
+    if descriptor.len() < 16 {
+        return None;
+    }
+    let sum = descriptor[..16]
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != 4)
+        .fold(0u8, |sum, (_, byte)| sum.wrapping_add(*byte));
+    (sum == descriptor[4]).then(|| le16(&descriptor[0..2])).filter(|id| *id != 0)
+}
+
+// OSTA compressed Unicode: a compression ID of 8 means one byte per
+// character, 16 means big-endian UTF-16.
+fn dchars(bytes: &[u8]) -> String {
+    match bytes.split_first() {
+        Some((8, rest)) => rest.iter().map(|&byte| byte as char).collect(),
+        Some((16, rest)) => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
+
+// A fixed-size dstring records its used length in the last byte.
+fn dstring(field: &[u8]) -> String {
+    let len = (field[field.len() - 1] as usize).min(field.len() - 1);
+    dchars(&field[..len])
+}
+
+fn display_dir(prefix: &str) -> &str {
+    if prefix.is_empty() {
+        "/"
+    } else {
+        prefix
+    }
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::{self, OsFamily};
+    use crate::iso9660;
+    use crate::wininstall;
+    use std::io::Cursor;
+
+    const SECTOR: usize = SECTOR_SIZE as usize;
+    const PARTITION_START: usize = 64;
+    const WIM_EXTENT: u64 = 0x3fff_f800;
+
+    /// A UDF image with a root holding `README.TXT` (embedded data, UTF-16
+    /// name) and `sources/install.wim` (over 4 GiB of short allocation
+    /// descriptors, continued through an allocation extent descriptor).
+    struct Image(Vec<u8>);
+
+    impl Image {
+        fn new() -> Self {
+            let mut image = Image(vec![0u8; (ANCHOR_SECTOR as usize + 1) * SECTOR]);
+            for (index, id) in [b"BEA01", b"NSR02", b"TEA01"].into_iter().enumerate() {
+                let sector = image.sector(16 + index);
+                sector[1..6].copy_from_slice(id);
+                sector[6] = 1;
+            }
+
+            let anchor = image.sector(ANCHOR_SECTOR as usize);
+            anchor[16..20].copy_from_slice(&(4 * SECTOR as u32).to_le_bytes());
+            anchor[20..24].copy_from_slice(&32u32.to_le_bytes());
+            tag(anchor, TAG_ANCHOR);
+
+            let primary = image.sector(32);
+            dstring_into(&mut primary[24..56], "CCCOMA_X64FRE");
+            tag(primary, TAG_PRIMARY_VOLUME);
+
+            let partition = image.sector(33);
+            partition[188..192].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+            tag(partition, TAG_PARTITION);
+
+            let logical = image.sector(34);
+            logical[212..216].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+            long_ad(&mut logical[248..264], 0);
+            logical[268..272].copy_from_slice(&1u32.to_le_bytes());
+            logical[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+            tag(logical, TAG_LOGICAL_VOLUME);
+
+            tag(image.sector(35), TAG_TERMINATING);
+
+            let file_set = image.block(0);
+            long_ad(&mut file_set[400..416], 1);
+            tag(file_set, TAG_FILE_SET);
+
+            let mut root = fid("", FID_PARENT, 1, 8);
+            root.extend(fid("sources", 0, 2, 8));
+            root.extend(fid("README.TXT", 0, 4, 16));
+            image.entry(1, TAG_FILE_ENTRY, FILE_TYPE_DIRECTORY, AD_EMBEDDED, root.len() as u64, &root);
+
+            let mut sources = fid("", FID_PARENT, 1, 8);
+            sources.extend(fid("old.wim", FID_DELETED, 9, 8));
+            sources.extend(fid("install.wim", 0, 3, 8));
+            image.entry(2, TAG_FILE_ENTRY, FILE_TYPE_DIRECTORY, AD_EMBEDDED, sources.len() as u64, &sources);
+
+            // Two extents in the entry, three more behind a continuation
+            let mut ads = short_ad(EXTENT_RECORDED, WIM_EXTENT, 100);
+            ads.extend(short_ad(EXTENT_RECORDED, WIM_EXTENT, 200));
+            ads.extend(short_ad(EXTENT_NEXT, SECTOR as u64, 5));
+            image.entry(3, TAG_EXTENDED_FILE_ENTRY, 5, AD_SHORT, 5 * WIM_EXTENT, &ads);
+
+            let mut more = short_ad(EXTENT_RECORDED, WIM_EXTENT, 300);
+            more.extend(short_ad(EXTENT_RECORDED, WIM_EXTENT, 400));
+            more.extend(short_ad(1, WIM_EXTENT, 0));
+            let continuation = image.block(5);
+            continuation[20..24].copy_from_slice(&(more.len() as u32).to_le_bytes());
+            continuation[24..24 + more.len()].copy_from_slice(&more);
+            tag(continuation, TAG_ALLOCATION_EXTENT);
+
+            image.entry(4, TAG_FILE_ENTRY, 5, AD_EMBEDDED, 6, b"hello\n");
+            image
+        }
+
+        fn sector(&mut self, index: usize) -> &mut [u8] {
+            &mut self.0[index * SECTOR..(index + 1) * SECTOR]
+        }
+
+        fn block(&mut self, block: usize) -> &mut [u8] {
+            self.sector(PARTITION_START + block)
+        }
+
+        fn entry(&mut self, block: usize, tag_id: u16, file_type: u8, ad_type: u16, size: u64, ads: &[u8]) {
+            let entry = self.block(block);
+            entry.fill(0);
+            let (lengths, base) = if tag_id == TAG_FILE_ENTRY { (168, 176) } else { (208, 216) };
+            entry[27] = file_type;
+            entry[34..36].copy_from_slice(&ad_type.to_le_bytes());
+            entry[56..64].copy_from_slice(&size.to_le_bytes());
+            entry[lengths + 4..lengths + 8].copy_from_slice(&(ads.len() as u32).to_le_bytes());
+            entry[base..base + ads.len()].copy_from_slice(ads);
+            tag(entry, tag_id);
+        }
+
+        fn cursor(self) -> Cursor<Vec<u8>> {
+            Cursor::new(self.0)
+        }
+    }
+
+    fn tag(descriptor: &mut [u8], id: u16) {
+        descriptor[0..2].copy_from_slice(&id.to_le_bytes());
+        descriptor[4] = 0;
+        descriptor[4] = descriptor[..16].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    }
+
+    fn long_ad(field: &mut [u8], block: u32) {
+        field[0..4].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+        field[4..8].copy_from_slice(&block.to_le_bytes());
+    }
+
+    fn short_ad(kind: u32, len: u64, block: u32) -> Vec<u8> {
+        let mut ad = ((kind << 30) | len as u32).to_le_bytes().to_vec();
+        ad.extend(block.to_le_bytes());
+        ad
+    }
+
+    fn encode(name: &str, compression: u8) -> Vec<u8> {
+        let mut bytes = vec![compression];
+        match compression {
+            8 => bytes.extend(name.bytes()),
+            _ => bytes.extend(name.encode_utf16().flat_map(u16::to_be_bytes)),
+        }
+        bytes
+    }
+
+    fn dstring_into(field: &mut [u8], value: &str) {
+        let bytes = encode(value, 8);
+        field[..bytes.len()].copy_from_slice(&bytes);
+        let last = field.len() - 1;
+        field[last] = bytes.len() as u8;
+    }
+
+    fn fid(name: &str, characteristics: u8, block: u32, compression: u8) -> Vec<u8> {
+        let name = if name.is_empty() { Vec::new() } else { encode(name, compression) };
+        let mut fid = vec![0u8; (38 + name.len() + 3) & !3];
+        fid[18] = characteristics;
+        fid[19] = name.len() as u8;
+        long_ad(&mut fid[20..36], block);
+        fid[38..38 + name.len()].copy_from_slice(&name);
+        tag(&mut fid, TAG_FILE_IDENTIFIER);
+        fid
+    }
+
+    #[test]
+    fn lists_a_synthetic_udf_tree() {
+        let mut image = Image::new().cursor();
+        let volume = UdfVolume::open(&mut image).unwrap();
+        assert_eq!(volume.label, "CCCOMA_X64FRE");
+
+        let files = volume.files(&mut image).unwrap();
+        let listing: Vec<(&str, bool)> = files.iter().map(|file| (file.path.as_str(), file.is_dir)).collect();
+        assert_eq!(listing, [("sources", true), ("sources/install.wim", false), ("README.TXT", false)]);
+
+        let wim = &files[1];
+        assert_eq!(wim.size, 5 * WIM_EXTENT);
+        let offsets: Vec<Option<u64>> = wim.extents.iter().map(|extent| extent.offset).collect();
+        let block = |n: u64| Some((PARTITION_START as u64 + n) * SECTOR_SIZE);
+        assert_eq!(offsets, [block(100), block(200), block(300), block(400), None]);
+        assert!(wim.extents.iter().all(|extent| extent.len == WIM_EXTENT));
+        assert!(wininstall::oversized_wim(&files).is_some());
+
+        let mut readme = String::new();
+        files[2].reader(&mut image).read_to_string(&mut readme).unwrap();
+        assert_eq!(readme, "hello\n");
+    }
+
+    #[test]
+    fn read_tree_prefers_udf() {
+        let mut image = Image::new().cursor();
+        let descriptors = iso9660::read_descriptors(&mut image).unwrap();
+        assert!(descriptors.udf && descriptors.primary.is_none());
+        let (label, files) = inspect::read_tree(&mut image, &descriptors).unwrap();
+        assert_eq!(label.as_deref(), Some("CCCOMA_X64FRE"));
+        assert_eq!(files.len(), 3);
+
+        let size = image.get_ref().len() as u64;
+        let report = inspect::inspect_image(&mut image, size).unwrap();
+        assert_eq!(report.os_family, OsFamily::Windows);
+        assert_eq!(report.large_files.len(), 1);
+        assert_eq!(report.content_size, 5 * WIM_EXTENT + 6);
+    }
+
+    #[test]
+    fn rejects_directory_loops_and_bad_tags() {
+        let mut image = Image::new();
+        let mut root = fid("", FID_PARENT, 1, 8);
+        root.extend(fid("loop", 0, 1, 8));
+        image.entry(1, TAG_FILE_ENTRY, FILE_TYPE_DIRECTORY, AD_EMBEDDED, root.len() as u64, &root);
+        let mut image = image.cursor();
+        let volume = UdfVolume::open(&mut image).unwrap();
+        assert!(matches!(volume.files(&mut image), Err(IsoError::Corrupt(_))));
+
+        // A checksum that does not hold hides the anchor
+        let mut image = Image::new();
+        image.sector(ANCHOR_SECTOR as usize)[4] ^= 0xff;
+        assert!(matches!(UdfVolume::open(&mut image.cursor()), Err(IsoError::NotIso)));
+    }
+}
@@ -0,0 +1,515 @@
+// Read-back verification of a written image.
+//
+// The source is hashed while it is written: one SHA-256 over the whole image
+// plus one per fixed-size chunk. Verification reads the device back with the
+// page cache bypassed and compares chunk digests, so a failure can be pinned
+// to a chunk and, with the source at hand, to the first differing byte.
+
+use rand::seq::index::sample;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::jobs::CancelToken;
+use crate::writer::AlignedBuffer;
+
+/// Granularity of chunk digests and of sampled reads.
+pub const VERIFY_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunks read in sample mode, on top of the first and last chunk.
+pub const SAMPLE_CHUNKS: usize = 64;
+
+const READ_ALIGNMENT: usize = 4096;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// Read back and compare every byte of the image.
+    Full,
+    /// Compare the first and last chunk plus a random sample of the rest.
+    Sample,
+    /// Skip read-back verification.
+    None,
+}
+
+impl VerifyMode {
+    /// Parse the job's `verify` field; a missing value means full verification.
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.map(|v| v.to_lowercase()).as_deref() {
+            None | Some("full") => Ok(VerifyMode::Full),
+            Some("sample") => Ok(VerifyMode::Sample),
+            Some("none") => Ok(VerifyMode::None),
+            Some(other) => Err(format!("Unknown verification mode '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageDigest {
+    pub len: u64,
+    pub sha256: [u8; 32],
+    pub chunks: Vec<[u8; 32]>,
+}
+
+impl ImageDigest {
+    pub fn hex(&self) -> String {
+        to_hex(&self.sha256)
+    }
+
+    /// Number of bytes covered by chunk `index`.
+    fn chunk_len(&self, index: usize) -> usize {
+        let start = index as u64 * VERIFY_CHUNK_SIZE as u64;
+        (self.len - start).min(VERIFY_CHUNK_SIZE as u64) as usize
+    }
+}
+
+/// Reader adapter that hashes everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    len: u64,
+    total: Sha256,
+    chunk: Sha256,
+    chunk_filled: usize,
+    chunks: Vec<[u8; 32]>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            len: 0,
+            total: Sha256::new(),
+            chunk: Sha256::new(),
+            chunk_filled: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn finish(mut self) -> ImageDigest {
+        if self.chunk_filled > 0 {
+            self.chunks.push(self.chunk.finalize().into());
+        }
+        ImageDigest {
+            len: self.len,
+            sha256: self.total.finalize().into(),
+            chunks: self.chunks,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut data = &buf[..n];
+        self.total.update(data);
+        self.len += n as u64;
+
+        while !data.is_empty() {
+            let take = data.len().min(VERIFY_CHUNK_SIZE - self.chunk_filled);
+            self.chunk.update(&data[..take]);
+            self.chunk_filled += take;
+            data = &data[take..];
+
+            if self.chunk_filled == VERIFY_CHUNK_SIZE {
+                let finished = std::mem::take(&mut self.chunk);
+                self.chunks.push(finished.finalize().into());
+                self.chunk_filled = 0;
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub mode: VerifyMode,
+    pub bytes_checked: u64,
+    /// Digest of the whole image as read back; only known in full mode.
+    pub device_sha256: Option<String>,
+    pub source_sha256: String,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io { offset: u64, source: io::Error },
+    Mismatch { offset: u64, exact: bool },
+    Cancelled { offset: u64 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Io { offset, source } => {
+                write!(f, "read-back failed at offset {} (0x{:x}): {}", offset, offset, source)
+            }
+            VerifyError::Mismatch { offset, exact: true } => {
+                write!(f, "device content differs from the image at offset {} (0x{:x})", offset, offset)
+            }
+            VerifyError::Mismatch { offset, exact: false } => {
+                write!(
+                    f,
+                    "device content differs from the image in the {} byte block starting at offset {} (0x{:x})",
+                    VERIFY_CHUNK_SIZE, offset, offset
+                )
+            }
+            VerifyError::Cancelled { offset } => write!(f, "verification cancelled at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Bytes that `mode` will read back for an image of `digest_len` bytes.
+pub fn planned_bytes(digest_len: u64, mode: VerifyMode) -> u64 {
+    match mode {
+        VerifyMode::Full => digest_len,
+        VerifyMode::Sample => {
+            let chunks = digest_len.div_ceil(VERIFY_CHUNK_SIZE as u64);
+            (chunks.min(SAMPLE_CHUNKS as u64 + 2) * VERIFY_CHUNK_SIZE as u64).min(digest_len)
+        }
+        VerifyMode::None => 0,
+    }
+}
+
+/// Read the image back from `device` and compare it with `digest`. When the
+/// uncompressed `source` file is available, a mismatch is narrowed down to
+/// the first differing byte.
+pub fn verify_device(
+    device: &Path,
+    digest: &ImageDigest,
+    source: Option<&Path>,
+    mode: VerifyMode,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<VerifyReport, VerifyError> {
+    let mut reader = UncachedReader::open(device).map_err(|e| VerifyError::Io { offset: 0, source: e })?;
+
+    let chunk_count = digest.chunks.len();
+    let indices: Vec<usize> = match mode {
+        VerifyMode::Full => (0..chunk_count).collect(),
+        VerifyMode::Sample => sample_indices(chunk_count),
+        VerifyMode::None => vec![],
+    };
+
+    let mut total = Sha256::new();
+    let mut bytes_checked = 0u64;
+
+    for index in indices {
+        let offset = index as u64 * VERIFY_CHUNK_SIZE as u64;
+        if cancel.is_cancelled() {
+            return Err(VerifyError::Cancelled { offset });
+        }
+
+        let data = reader
+            .read_at(offset, digest.chunk_len(index))
+            .map_err(|e| VerifyError::Io { offset, source: e })?;
+
+        let chunk_digest: [u8; 32] = Sha256::digest(data).into();
+        if chunk_digest != digest.chunks[index] {
+            let exact_offset = source.and_then(|source| first_difference(source, offset, data).ok().flatten());
+            return Err(VerifyError::Mismatch {
+                offset: exact_offset.unwrap_or(offset),
+                exact: exact_offset.is_some(),
+            });
+        }
+
+        if mode == VerifyMode::Full {
+            total.update(data);
+        }
+        bytes_checked += data.len() as u64;
+        progress(bytes_checked);
+    }
+
+    let device_sha256 = if mode == VerifyMode::Full {
+        let sha: [u8; 32] = total.finalize().into();
+        if sha != digest.sha256 {
+            return Err(VerifyError::Mismatch { offset: 0, exact: false });
+        }
+        Some(to_hex(&sha))
+    } else {
+        None
+    };
+
+    Ok(VerifyReport {
+        mode,
+        bytes_checked,
+        device_sha256,
+        source_sha256: digest.hex(),
+    })
+}
+
+// Always check both ends of the image, where partition tables and boot
+// records live, plus a random spread of the chunks in between.
+fn sample_indices(chunk_count: usize) -> Vec<usize> {
+    if chunk_count <= SAMPLE_CHUNKS + 2 {
+        return (0..chunk_count).collect();
+    }
+
+    let mut indices: Vec<usize> = sample(&mut rand::thread_rng(), chunk_count - 2, SAMPLE_CHUNKS)
+        .into_iter()
+        .map(|index| index + 1)
+        .collect();
+    indices.push(0);
+    indices.push(chunk_count - 1);
+    indices.sort_unstable();
+    indices
+}
+
+fn first_difference(source: &Path, offset: u64, device_data: &[u8]) -> io::Result<Option<u64>> {
+    let mut file = File::open(source)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut expected = vec![0u8; device_data.len()];
+    file.read_exact(&mut expected)?;
+
+    Ok(expected
+        .iter()
+        .zip(device_data)
+        .position(|(a, b)| a != b)
+        .map(|position| offset + position as u64))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads that bypass the page cache, so verification sees what the stick
+/// returns rather than what the kernel still remembers writing.
+struct UncachedReader {
+    file: File,
+    direct: bool,
+    buffer: AlignedBuffer,
+}
+
+impl UncachedReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        let buffer = AlignedBuffer::new(VERIFY_CHUNK_SIZE + READ_ALIGNMENT);
+
+        // Not every filesystem supports O_DIRECT (tmpfs does not), so a
+        // regular-file target falls back to dropping its cached pages.
+        if let Ok(file) = OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(path) {
+            return Ok(UncachedReader { file, direct: true, buffer });
+        }
+
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(UncachedReader { file, direct: false, buffer })
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> io::Result<&[u8]> {
+        // O_DIRECT needs aligned offsets and lengths; chunk offsets are aligned
+        // already, so only the length of the final chunk needs rounding up.
+        let aligned_len = if self.direct { len.div_ceil(READ_ALIGNMENT) * READ_ALIGNMENT } else { len };
+
+        if !self.direct {
+            drop_cached_pages(&self.file, offset, len);
+        }
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        let buffer = &mut self.buffer.as_mut_slice()[..aligned_len];
+        let mut filled = 0;
+        while filled < len {
+            match self.file.read(&mut buffer[filled..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("device ended {} bytes into a {} byte block", filled, len),
+                    ));
+                }
+                // A short O_DIRECT read would leave the next one unaligned,
+                // which fails; read the block again through the page cache
+                Ok(n) if self.direct && filled + n < len && (filled + n) % READ_ALIGNMENT != 0 => {
+                    self.stop_direct_io()?;
+                    return self.read_at(offset, len);
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(&self.buffer.as_slice()[..len])
+    }
+
+    /// Switch to cached reads that drop their pages after each block.
+    fn stop_direct_io(&mut self) -> io::Result<()> {
+        // SAFETY: fcntl only reads and sets the status flags of an open descriptor.
+        let result = unsafe {
+            let flags = libc::fcntl(self.file.as_raw_fd(), libc::F_GETFL);
+            if flags < 0 {
+                flags
+            } else {
+                libc::fcntl(self.file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_DIRECT)
+            }
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.direct = false;
+        Ok(())
+    }
+}
+
+fn drop_cached_pages(file: &File, offset: u64, len: usize) {
+    // SAFETY: posix_fadvise only reads its integer arguments.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    // Three whole chunks and a partial one, with each byte depending on its offset
+    const IMAGE_LEN: usize = 3 * VERIFY_CHUNK_SIZE + 12_345;
+
+    fn image() -> Vec<u8> {
+        (0..IMAGE_LEN).map(|offset| (offset % 251) as u8 ^ (offset / VERIFY_CHUNK_SIZE) as u8).collect()
+    }
+
+    // Hash `data` through a reader that hands it out in odd-sized pieces.
+    fn digest(data: &[u8]) -> ImageDigest {
+        let mut reader = HashingReader::new(data);
+        let mut buffer = vec![0u8; 1_000_003];
+        while reader.read(&mut buffer).unwrap() > 0 {}
+        reader.finish()
+    }
+
+    /// The image as source file and as a larger "device" holding it.
+    fn write_files(dir: &tempfile::TempDir, data: &[u8]) -> (PathBuf, PathBuf) {
+        let source = dir.path().join("source.img");
+        std::fs::write(&source, data).unwrap();
+        let device = dir.path().join("device.img");
+        let mut file = File::create(&device).unwrap();
+        file.write_all(data).unwrap();
+        file.write_all(&[0xee; 100_000]).unwrap();
+        (source, device)
+    }
+
+    fn flip(path: &Path, offset: u64) {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 0x40]).unwrap();
+    }
+
+    fn verify(device: &Path, digest: &ImageDigest, source: Option<&Path>, mode: VerifyMode) -> Result<VerifyReport, VerifyError> {
+        verify_device(device, digest, source, mode, &CancelToken::default(), |_| {})
+    }
+
+    #[test]
+    fn hashing_reader_digests_whole_image_and_chunks() {
+        let data = image();
+        let digest = digest(&data);
+        assert_eq!(digest.len, IMAGE_LEN as u64);
+        assert_eq!(digest.sha256, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(digest.chunks.len(), 4);
+        for (index, chunk) in data.chunks(VERIFY_CHUNK_SIZE).enumerate() {
+            assert_eq!(digest.chunks[index], <[u8; 32]>::from(Sha256::digest(chunk)));
+            assert_eq!(digest.chunk_len(index), chunk.len());
+        }
+    }
+
+    #[test]
+    fn verifies_an_intact_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = image();
+        let digest = digest(&data);
+        let (source, device) = write_files(&dir, &data);
+
+        let mut reported = Vec::new();
+        let report = verify_device(&device, &digest, Some(&source), VerifyMode::Full, &CancelToken::default(), |done| reported.push(done)).unwrap();
+        assert_eq!(report.bytes_checked, IMAGE_LEN as u64);
+        assert_eq!(report.device_sha256.as_deref(), Some(digest.hex().as_str()));
+        assert_eq!(reported.last(), Some(&(IMAGE_LEN as u64)));
+
+        let report = verify(&device, &digest, None, VerifyMode::Sample).unwrap();
+        assert_eq!((report.bytes_checked, report.device_sha256), (planned_bytes(digest.len, VerifyMode::Sample), None));
+    }
+
+    #[test]
+    fn pins_a_flipped_byte_to_its_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = image();
+        let digest = digest(&data);
+        let (source, device) = write_files(&dir, &data);
+        let flipped = 2 * VERIFY_CHUNK_SIZE as u64 + 777;
+        flip(&device, flipped);
+
+        for mode in [VerifyMode::Full, VerifyMode::Sample] {
+            match verify(&device, &digest, Some(&source), mode) {
+                Err(VerifyError::Mismatch { offset, exact: true }) => assert_eq!(offset, flipped, "{:?}", mode),
+                other => panic!("{:?}: expected an exact mismatch, got {:?}", mode, other),
+            }
+            // Without the source only the chunk is known
+            match verify(&device, &digest, None, mode) {
+                Err(VerifyError::Mismatch { offset, exact: false }) => assert_eq!(offset, 2 * VERIFY_CHUNK_SIZE as u64),
+                other => panic!("{:?}: expected a chunk mismatch, got {:?}", mode, other),
+            }
+        }
+
+        // Sample mode always reads the last, partial chunk
+        flip(&device, flipped);
+        let last = IMAGE_LEN as u64 - 1;
+        flip(&device, last);
+        assert!(matches!(verify(&device, &digest, Some(&source), VerifyMode::Sample), Err(VerifyError::Mismatch { offset, exact: true }) if offset == last));
+    }
+
+    #[test]
+    fn reports_short_devices_and_cancellation() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = image();
+        let digest = digest(&data);
+        let device = dir.path().join("short.img");
+        std::fs::write(&device, &data[..IMAGE_LEN - 10]).unwrap();
+        let last_chunk = 3 * VERIFY_CHUNK_SIZE as u64;
+        assert!(matches!(verify(&device, &digest, None, VerifyMode::Full), Err(VerifyError::Io { offset, .. }) if offset == last_chunk));
+
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let result = verify_device(&device, &digest, None, VerifyMode::Full, &cancel, |_| {});
+        assert!(matches!(result, Err(VerifyError::Cancelled { offset: 0 })));
+    }
+
+    #[test]
+    fn short_direct_reads_fall_back_to_cached_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.img");
+        let data: Vec<u8> = (0..5000u32).map(|n| n as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        // Asking for more than the file holds gives a short, unaligned read;
+        // it must come back as end-of-device, not as an O_DIRECT EINVAL
+        let mut reader = UncachedReader::open(&path).unwrap();
+        let error = reader.read_at(0, 2 * READ_ALIGNMENT).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{}", error);
+        assert!(!reader.direct);
+        assert_eq!(reader.read_at(0, data.len()).unwrap(), &data[..]);
+        assert_eq!(reader.read_at(4096, 904).unwrap(), &data[4096..]);
+
+        let mut reader = UncachedReader::open(&path).unwrap();
+        reader.stop_direct_io().unwrap();
+        // SAFETY: F_GETFL only reads the descriptor's status flags.
+        let flags = unsafe { libc::fcntl(reader.file.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_DIRECT, 0);
+        assert_eq!(reader.read_at(1, 10).unwrap(), &data[1..11]);
+    }
+
+    #[test]
+    fn samples_both_ends_and_a_spread() {
+        assert_eq!(sample_indices(5), [0, 1, 2, 3, 4]);
+        let indices = sample_indices(1000);
+        assert_eq!(indices.len(), SAMPLE_CHUNKS + 2);
+        assert_eq!((indices[0], indices[indices.len() - 1]), (0, 999));
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,95 @@
+// Windows installer media.
+//
+// Windows ISOs are UDF-only and carry no partition table, so written raw they
+// do not boot from a stick. Instead the stick gets one FAT32 partition holding
+// the ISO's files: UEFI firmware starts efi/boot/bootx64.efi from it directly,
+// and legacy BIOS reaches bootmgr through syslinux's chain loader. An
+// install.wim past FAT32's 4 GiB limit is split into .swm parts, which Setup
+// picks up on its own.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::process::Command;
+
+use crate::capabilities;
+use crate::inspect::{IsoReport, FAT32_MAX_FILE_SIZE};
+use crate::iso9660::IsoFile;
+use crate::jobs::CancelToken;
+
+pub const INSTALL_WIM: &str = "sources/install.wim";
+
+/// Size of each .swm part in MiB, comfortably under FAT32's file limit.
+const SWM_PART_MIB: u64 = 3800;
+
+const COPY_CHUNK: usize = 4 * 1024 * 1024;
+
+/// syslinux modules needed to chain-load bootmgr.
+pub const SYSLINUX_MODULES: &[&str] = &["chain.c32", "libcom32.c32", "libutil.c32"];
+
+/// syslinux.cfg handing legacy BIOS boot straight to bootmgr.
+pub const SYSLINUX_CONFIG: &str = "DEFAULT windows\nLABEL windows\n  COM32 chain.c32\n  APPEND fs ntldr=/bootmgr\n";
+
+/// The install.wim in `files` that FAT32 cannot hold, if there is one.
+pub fn oversized_wim(files: &[IsoFile]) -> Option<&IsoFile> {
+    files
+        .iter()
+        .find(|file| !file.is_dir && file.path.eq_ignore_ascii_case(INSTALL_WIM) && file.size > FAT32_MAX_FILE_SIZE)
+}
+
+/// Bytes a Windows installer copy moves: the image's files, plus install.wim
+/// a second time when it is split (extracted, then copied as parts).
+pub fn copy_size(image: &IsoReport) -> u64 {
+    let split = image
+        .large_files
+        .iter()
+        .find(|file| file.path.eq_ignore_ascii_case(INSTALL_WIM))
+        .map(|file| file.size);
+    image.content_size + split.unwrap_or(0)
+}
+
+pub fn wimlib_available() -> bool {
+    capabilities::find_tool("wimlib-imagex").is_some()
+}
+
+/// Copy `file` out of the image into `target`. `progress` gets the number of
+/// bytes written so far.
+pub fn extract_file<R: Read + Seek>(
+    image: &mut R,
+    file: &IsoFile,
+    target: &Path,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut source = file.reader(image);
+    let mut output = File::create(target)?;
+    let mut buffer = vec![0u8; COPY_CHUNK];
+    let mut written = 0u64;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(io::Error::other("cancelled"));
+        }
+        let n = source.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buffer[..n])?;
+        written += n as u64;
+        progress(written);
+    }
+    output.sync_all()?;
+    Ok(written)
+}
+
+/// `wimlib-imagex split` of `wim` into install.swm, install2.swm, ... in
+/// `parts_dir`.
+pub fn split_command(wim: &Path, parts_dir: &Path) -> Option<Command> {
+    let tool = capabilities::find_tool("wimlib-imagex")?;
+    let mut command = Command::new(tool);
+    command
+        .arg("split")
+        .arg(wim)
+        .arg(parts_dir.join("install.swm"))
+        .arg(SWM_PART_MIB.to_string());
+    Some(command)
+}
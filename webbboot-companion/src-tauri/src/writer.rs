@@ -0,0 +1,414 @@
+// In-process image writer.
+//
+// Copies an image onto a block device (or a regular file, which is how the
+// writer is exercised without hardware) in aligned chunks, reports real byte
+// counts, and flushes both the file and the kernel buffer cache at the end.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+
+use crate::jobs::CancelToken;
+
+/// Chunk size used for copying; a multiple of every sector and page size in use.
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Alignment of the copy buffer, enough for O_DIRECT on 4Kn disks.
+pub const BUFFER_ALIGNMENT: usize = 4096;
+
+/// Region zeroed at each end of a device to invalidate MBR, GPT and backup GPT.
+pub const PARTITION_TABLE_WIPE_SIZE: u64 = 1024 * 1024;
+
+/// Btrfs superblock mirrors, the only common signatures that live outside
+/// the regions cleared at either end of a device.
+const BTRFS_MIRROR_OFFSETS: [u64; 2] = [64 * 1024 * 1024, 256 * 1024 * 1024 * 1024];
+const SUPERBLOCK_WIPE_SIZE: u64 = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+const BLKFLSBUF: libc::c_ulong = 0x1261;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStage {
+    Open,
+    Read,
+    Write,
+    Sync,
+}
+
+impl fmt::Display for WriteStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            WriteStage::Open => "open",
+            WriteStage::Read => "read",
+            WriteStage::Write => "write",
+            WriteStage::Sync => "sync",
+        };
+        f.write_str(stage)
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    Io { stage: WriteStage, offset: u64, source: io::Error },
+    TargetTooSmall { image_size: u64, target_size: u64 },
+    Cancelled { offset: u64 },
+}
+
+impl WriteError {
+    fn io(stage: WriteStage, offset: u64, source: io::Error) -> Self {
+        WriteError::Io { stage, offset, source }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Io { stage, offset, source } => {
+                write!(f, "{} failed at offset {} (0x{:x}): {}", stage, offset, offset, source)
+            }
+            WriteError::TargetTooSmall { image_size, target_size } => {
+                write!(f, "image is {} bytes but the target only holds {} bytes", image_size, target_size)
+            }
+            WriteError::Cancelled { offset } => write!(f, "cancelled after {} bytes", offset),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::Io { source, .. } => Some(source),
+            WriteError::TargetTooSmall { .. } | WriteError::Cancelled { .. } => None,
+        }
+    }
+}
+
+/// Heap buffer whose start is aligned to `BUFFER_ALIGNMENT`.
+pub struct AlignedBuffer {
+    blocks: Vec<AlignedBlock>,
+    len: usize,
+}
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct AlignedBlock([u8; BUFFER_ALIGNMENT]);
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> Self {
+        let block_count = len.div_ceil(BUFFER_ALIGNMENT);
+        AlignedBuffer {
+            blocks: vec![AlignedBlock([0; BUFFER_ALIGNMENT]); block_count],
+            len,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: `blocks` is a contiguous allocation of at least `len` bytes
+        // of plain `u8` arrays.
+        unsafe { std::slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and we hold the only reference to `blocks`.
+        unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+/// Open `path` for writing. Block devices are opened with O_EXCL so the
+/// kernel refuses while anything else (a mounted filesystem, another writer)
+/// holds the device.
+pub fn open_target(path: &Path) -> Result<File, WriteError> {
+    let is_block_device = std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false);
+
+    let mut options = OpenOptions::new();
+    options.read(true).write(true);
+    if is_block_device {
+        options.custom_flags(libc::O_EXCL);
+    } else {
+        options.create(true).truncate(false);
+    }
+
+    options.open(path).map_err(|e| WriteError::io(WriteStage::Open, 0, e))
+}
+
+/// Size of an open target in bytes; for block devices this is the device size.
+pub fn target_size(target: &mut File) -> io::Result<u64> {
+    let size = target.seek(SeekFrom::End(0))?;
+    target.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+/// Copy `source` to the start of `target`, calling `progress` with the total
+/// number of bytes written after every chunk. `expected_size` is checked
+/// against the size of block device targets before anything is written, and
+/// `cancel` is checked before every chunk.
+pub fn copy_image<R: Read>(
+    source: &mut R,
+    target: &mut File,
+    expected_size: Option<u64>,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, WriteError> {
+    let is_block_device = target
+        .metadata()
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false);
+
+    if let (true, Some(image_size)) = (is_block_device, expected_size) {
+        let target_size = target_size(target).map_err(|e| WriteError::io(WriteStage::Open, 0, e))?;
+        if image_size > target_size {
+            return Err(WriteError::TargetTooSmall { image_size, target_size });
+        }
+    }
+
+    target
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| WriteError::io(WriteStage::Write, 0, e))?;
+
+    let mut buffer = AlignedBuffer::new(CHUNK_SIZE);
+    let mut offset = 0u64;
+
+    loop {
+        if cancel.is_cancelled() {
+            return Err(WriteError::Cancelled { offset });
+        }
+
+        let filled = fill_chunk(source, buffer.as_mut_slice()).map_err(|e| WriteError::io(WriteStage::Read, offset, e))?;
+        if filled == 0 {
+            break;
+        }
+
+        write_chunk(target, &buffer.as_slice()[..filled], offset)?;
+        offset += filled as u64;
+        progress(offset);
+
+        if filled < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok(offset)
+}
+
+/// Flush written data to the medium and drop the kernel's cached copy of the
+/// device so later reads come from the stick.
+pub fn sync_target(target: &File, written: u64) -> Result<(), WriteError> {
+    target.sync_all().map_err(|e| WriteError::io(WriteStage::Sync, written, e))?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let is_block_device = target
+            .metadata()
+            .map(|metadata| metadata.file_type().is_block_device())
+            .unwrap_or(false);
+        if is_block_device {
+            // SAFETY: BLKFLSBUF takes no argument and only acts on the fd.
+            let result = unsafe { libc::ioctl(target.as_raw_fd(), BLKFLSBUF as _, 0) };
+            if result != 0 {
+                return Err(WriteError::io(WriteStage::Sync, written, io::Error::last_os_error()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Zero the first and last `PARTITION_TABLE_WIPE_SIZE` bytes of `target`,
+/// leaving a device without MBR, GPT or filesystem signatures rather than a
+/// half-written image that firmware may try to boot.
+pub fn wipe_partition_tables(target: &mut File) -> Result<(), WriteError> {
+    let size = target_size(target).map_err(|e| WriteError::io(WriteStage::Open, 0, e))?;
+    let wipe = PARTITION_TABLE_WIPE_SIZE.min(size);
+    zero_regions(target, &[(0, wipe), (size - wipe, wipe)])?;
+    sync_target(target, size)
+}
+
+/// Clear every signature that firmware, an OS or blkid could pick up: MBR,
+/// hybrid MBR and both GPTs, ISO9660 volume descriptors, UDF anchors, and
+/// filesystem superblocks at either end, plus btrfs superblock mirrors.
+pub fn wipe_signatures(target: &mut File) -> Result<(), WriteError> {
+    let size = target_size(target).map_err(|e| WriteError::io(WriteStage::Open, 0, e))?;
+    let wipe = PARTITION_TABLE_WIPE_SIZE.min(size);
+
+    let mut regions = vec![(0, wipe), (size - wipe, wipe)];
+    for offset in BTRFS_MIRROR_OFFSETS {
+        if offset + SUPERBLOCK_WIPE_SIZE <= size {
+            regions.push((offset, SUPERBLOCK_WIPE_SIZE));
+        }
+    }
+
+    zero_regions(target, &regions)?;
+    sync_target(target, size)
+}
+
+/// Zero `len` bytes at `offset` for each region, which may overlap.
+pub fn zero_regions(target: &mut File, regions: &[(u64, u64)]) -> Result<(), WriteError> {
+    let largest = regions.iter().map(|(_, len)| *len).max().unwrap_or(0);
+    let zeros = AlignedBuffer::new(largest as usize);
+
+    for &(offset, len) in regions {
+        target
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| WriteError::io(WriteStage::Write, offset, e))?;
+        write_chunk(target, &zeros.as_slice()[..len as usize], offset)?;
+    }
+    Ok(())
+}
+
+// Read until the buffer is full or the source is exhausted, so every write
+// except the last is a whole chunk even when the source returns short reads.
+fn fill_chunk<R: Read>(source: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn write_chunk(target: &mut File, chunk: &[u8], offset: u64) -> Result<(), WriteError> {
+    let mut done = 0;
+    while done < chunk.len() {
+        match target.write(&chunk[done..]) {
+            Ok(0) => {
+                return Err(WriteError::io(
+                    WriteStage::Write,
+                    offset + done as u64,
+                    io::Error::new(io::ErrorKind::WriteZero, "device accepted no more data"),
+                ));
+            }
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(WriteError::io(WriteStage::Write, offset + done as u64, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A source that hands out at most `step` bytes per read and then fails
+    /// once `fail_at` bytes have been read, if set.
+    struct TrickleSource {
+        data: Vec<u8>,
+        position: usize,
+        step: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl Read for TrickleSource {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            if self.fail_at.is_some_and(|fail_at| self.position >= fail_at) {
+                return Err(io::Error::other("source went away"));
+            }
+            let end = self.data.len().min(self.position + self.step.min(buffer.len()));
+            let end = self.fail_at.map_or(end, |fail_at| end.min(fail_at));
+            let read = end - self.position;
+            buffer[..read].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn copies_into_a_file_with_byte_accurate_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stick.img");
+        let image = pattern(2 * CHUNK_SIZE + 12345);
+
+        let mut target = open_target(&path).unwrap();
+        let mut reports = Vec::new();
+        let written = copy_image(&mut image.as_slice(), &mut target, Some(image.len() as u64), &CancelToken::default(), |done| reports.push(done)).unwrap();
+        sync_target(&target, written).unwrap();
+
+        assert_eq!(written, image.len() as u64);
+        assert_eq!(reports, vec![CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64, image.len() as u64]);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+    }
+
+    #[test]
+    fn short_reads_still_fill_whole_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut target = open_target(&dir.path().join("stick.img")).unwrap();
+        let image = pattern(CHUNK_SIZE + 4096);
+        let mut source = TrickleSource { data: image.clone(), position: 0, step: 65537, fail_at: None };
+
+        let mut reports = Vec::new();
+        // The source ends well before the size it was announced with
+        let written = copy_image(&mut source, &mut target, Some(4 * CHUNK_SIZE as u64), &CancelToken::default(), |done| reports.push(done)).unwrap();
+
+        assert_eq!(written, image.len() as u64);
+        assert_eq!(reports, vec![CHUNK_SIZE as u64, image.len() as u64]);
+        let mut copied = Vec::new();
+        target.seek(SeekFrom::Start(0)).unwrap();
+        target.read_to_end(&mut copied).unwrap();
+        assert_eq!(copied, image);
+    }
+
+    #[test]
+    fn read_failure_reports_the_chunk_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut target = open_target(&dir.path().join("stick.img")).unwrap();
+        let mut source = TrickleSource { data: pattern(3 * CHUNK_SIZE), position: 0, step: CHUNK_SIZE, fail_at: Some(CHUNK_SIZE + 100) };
+
+        let mut reports = Vec::new();
+        let error = copy_image(&mut source, &mut target, None, &CancelToken::default(), |done| reports.push(done)).unwrap_err();
+
+        match error {
+            WriteError::Io { stage, offset, .. } => {
+                assert_eq!(stage, WriteStage::Read);
+                assert_eq!(offset, CHUNK_SIZE as u64);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(reports, vec![CHUNK_SIZE as u64]);
+    }
+
+    #[test]
+    fn write_failure_reports_the_offset() {
+        let full = Path::new("/dev/full");
+        if !full.exists() {
+            return;
+        }
+        let mut target = open_target(full).unwrap();
+        let image = pattern(4096);
+
+        let error = copy_image(&mut image.as_slice(), &mut target, None, &CancelToken::default(), |_| {}).unwrap_err();
+        match error {
+            WriteError::Io { stage, offset, .. } => {
+                assert_eq!(stage, WriteStage::Write);
+                assert_eq!(offset, 0);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn cancelled_copy_stops_before_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stick.img");
+        let mut target = open_target(&path).unwrap();
+        let cancel = CancelToken::default();
+        cancel.cancel();
+
+        let error = copy_image(&mut pattern(4096).as_slice(), &mut target, None, &cancel, |_| {}).unwrap_err();
+        assert!(matches!(error, WriteError::Cancelled { offset: 0 }));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
@@ -4,6 +4,10 @@ function BootForm() {
   const [iso, setIso] = useState(null);
   const [fileSystem, setFileSystem] = useState("FAT32");
   const [scheme, setScheme] = useState("MBR");
+  const [verifyMode, setVerifyMode] = useState("full");
+  const [checksum, setChecksum] = useState("");
+  const [label, setLabel] = useState("WEBBOOT");
+  const [quickFormat, setQuickFormat] = useState(true);
   const [usbDevices, setUsbDevices] = useState([]);
   const [selectedDevice, setSelectedDevice] = useState("");
   const [status, setStatus] = useState("Download WebBoot Companion to start");
@@ -12,6 +16,9 @@ function BootForm() {
   const [currentOperation, setCurrentOperation] = useState("");
   const [isVerifying, setIsVerifying] = useState(false);
   const [deviceInfo, setDeviceInfo] = useState(null);
+  const [jobId, setJobId] = useState(null);
+  const [jobState, setJobState] = useState(null);
+  const [capabilities, setCapabilities] = useState(null);
 
   useEffect(() => {
     const websocket = new WebSocket("ws://localhost:8080");
@@ -22,9 +29,11 @@ function BootForm() {
         setUsbDevices(data.devices);
         setSelectedDevice(data.devices[0]?.id || "");
       }
+      if (data.capabilities) setCapabilities(data.capabilities);
       if (data.status) setStatus(data.status);
       if (data.progress !== undefined) setProgress(data.progress);
       if (data.current_operation) setCurrentOperation(data.current_operation);
+      if (data.state) setJobState(data.state);
     };
     websocket.onerror = () =>
       setStatus("Companion app not running. Download WebBoot Companion");
@@ -48,18 +57,47 @@ function BootForm() {
       setStatus("Please select a USB device");
       return;
     }
+    // The id is ours, so Cancel never targets the previous job
+    const id = crypto.randomUUID();
     const job = {
+      id,
       action,
       iso: iso ? iso.path || iso.name : null,
       filesystem: fileSystem,
       scheme,
       device: selectedDevice,
+      verify: verifyMode,
+      checksum: checksum.trim() || null,
+      label: label.trim() || null,
+      quick: quickFormat,
     };
     ws.send(JSON.stringify(job));
+    setJobId(id);
     setStatus(`Starting ${action}...`);
     setProgress(0);
+    setJobState("running");
   };
 
+  const cancelJob = () => {
+    if (!ws || ws.readyState !== WebSocket.OPEN || !jobId) return;
+    ws.send(JSON.stringify({ action: "cancel", job_id: jobId }));
+    setStatus("Cancelling...");
+  };
+
+  const isRunning = jobState === "running";
+
+  // Reason the companion gave for not supporting `name`, or null when it
+  // can (or when it hasn't told us yet).
+  const unsupported = (group, name) => {
+    const entry = capabilities?.[group]?.find((c) => c.name === name);
+    return entry && !entry.available ? entry.reason : null;
+  };
+
+  const fileSystems = ["FAT32", "NTFS", "exFAT", "ext4", "btrfs"];
+  const fileSystemReason = unsupported("filesystems", fileSystem);
+  const createReason = unsupported("operations", "create");
+  const restoreReason = unsupported("operations", "restore");
+
   const verifyDevice = async (devicePath) => {
     if (!devicePath) return;
 
@@ -96,7 +134,7 @@ function BootForm() {
         <label>Select ISO File:</label>
         <input
           type="file"
-          accept=".iso"
+          accept=".iso,.img,.bin,.raw,.xz,.gz,.zst,.bz2,.zip"
           onChange={handleIsoUpload}
           className="input-file"
         />
@@ -110,10 +148,38 @@ function BootForm() {
           onChange={(e) => setFileSystem(e.target.value)}
           className="select"
         >
-          <option value="FAT32">FAT32</option>
-          <option value="NTFS">NTFS</option>
-          <option value="exFAT">exFAT</option>
+          {fileSystems.map((fs) => {
+            const reason = unsupported("filesystems", fs);
+            return (
+              <option key={fs} value={fs} disabled={!!reason} title={reason || ""}>
+                {reason ? `${fs} (unavailable: ${reason})` : fs}
+              </option>
+            );
+          })}
         </select>
+        {fileSystemReason && (
+          <div className="capability-warning">
+            {fileSystem} is unavailable: {fileSystemReason}
+          </div>
+        )}
+        <label className="checkbox-label">
+          <input
+            type="checkbox"
+            checked={quickFormat}
+            onChange={(e) => setQuickFormat(e.target.checked)}
+          />
+          Quick format
+        </label>
+      </div>
+
+      <div className="form-section">
+        <label>Volume Label:</label>
+        <input
+          type="text"
+          value={label}
+          onChange={(e) => setLabel(e.target.value)}
+          className="input-text"
+        />
       </div>
 
       <div className="form-section">
@@ -128,6 +194,30 @@ function BootForm() {
         </select>
       </div>
 
+      <div className="form-section">
+        <label>Expected Checksum (optional):</label>
+        <input
+          type="text"
+          value={checksum}
+          onChange={(e) => setChecksum(e.target.value)}
+          placeholder="sha256:…, sha512:… or blake3:…"
+          className="input-text"
+        />
+      </div>
+
+      <div className="form-section">
+        <label>Verification:</label>
+        <select
+          value={verifyMode}
+          onChange={(e) => setVerifyMode(e.target.value)}
+          className="select"
+        >
+          <option value="full">Full read-back</option>
+          <option value="sample">Random sample</option>
+          <option value="none">None</option>
+        </select>
+      </div>
+
       <div className="form-section">
         <label>USB Device:</label>
         <select
@@ -163,17 +253,32 @@ function BootForm() {
         <button
           onClick={() => sendJob("create")}
           className="button create-btn"
-          disabled={!iso || !selectedDevice || isVerifying}
+          disabled={
+            !iso || !selectedDevice || isVerifying || isRunning ||
+            !!createReason || !!fileSystemReason
+          }
+          title={createReason || fileSystemReason || ""}
         >
           Create Bootable USB
         </button>
         <button
           onClick={() => sendJob("restore")}
           className="button restore-btn"
-          disabled={!selectedDevice || isVerifying}
+          disabled={
+            !selectedDevice || isVerifying || isRunning ||
+            !!restoreReason || !!fileSystemReason
+          }
+          title={restoreReason || fileSystemReason || ""}
         >
           Restore USB
         </button>
+        <button
+          onClick={cancelJob}
+          className="button cancel-btn"
+          disabled={!isRunning || !jobId}
+        >
+          Cancel
+        </button>
       </div>
 
       <div className="progress-section">